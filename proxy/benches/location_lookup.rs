@@ -0,0 +1,91 @@
+//! Location lookup cost as a host grows to hundreds of locations, next to a linear scan
+//! over the same locations. Run with `cargo bench --bench location_lookup`.
+
+use pingora_manager_proxy::config::{HostConfig, LocationConfig};
+use pingora_manager_proxy::router::{RequestView, Router};
+use regex::Regex;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 20_000;
+
+fn location(path: &str, match_type: &str) -> LocationConfig {
+    LocationConfig {
+        path: path.to_string(),
+        match_type: match_type.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Reference: try every location in turn (exact, then the first regex, then the longest prefix)
+fn linear_match(locations: &[(LocationConfig, Option<Regex>)], path: &str) -> Option<usize> {
+    let exact = locations
+        .iter()
+        .position(|(loc, _)| loc.match_type == "exact" && loc.path == path);
+    let regex = || locations.iter().position(|(_, re)| re.as_ref().is_some_and(|re| re.is_match(path)));
+    let prefix = || {
+        locations
+            .iter()
+            .enumerate()
+            .filter(|(_, (loc, _))| loc.match_type == "prefix" && path.starts_with(loc.path.as_str()))
+            .max_by_key(|(_, (loc, _))| loc.path.len())
+            .map(|(i, _)| i)
+    };
+    exact.or_else(regex).or_else(prefix)
+}
+
+/// Average time of one `lookup` over `paths`
+fn time(paths: &[String], lookup: impl Fn(&RequestView) -> Option<usize>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for path in paths {
+            black_box(lookup(&RequestView::get(path)));
+        }
+    }
+    start.elapsed() / (ROUNDS * paths.len() as u32)
+}
+
+fn main() {
+    for count in [10, 100, 1000] {
+        let mut locations: Vec<LocationConfig> = (0..count)
+            .map(|i| location(&format!("/service-{}/api", i), "prefix"))
+            .collect();
+        locations.extend((0..count / 10).map(|i| location(&format!("/exact-{}", i), "exact")));
+        locations.extend((0..count / 10).map(|i| location(&format!(r"^/report-{}/\d+\.pdf$", i), "regex")));
+        let host = HostConfig {
+            id: 1,
+            domains: vec!["example.com".to_string()],
+            locations,
+            ..Default::default()
+        };
+        let router = Router::build(std::slice::from_ref(&host));
+        let compiled: Vec<(LocationConfig, Option<Regex>)> = host
+            .locations
+            .iter()
+            .map(|loc| {
+                let re = (loc.match_type == "regex").then(|| Regex::new(&loc.path).unwrap());
+                (loc.clone(), re)
+            })
+            .collect();
+        let paths = [
+            format!("/service-{}/api/users", count - 1),
+            format!("/exact-{}", count / 10 - 1),
+            format!("/report-{}/7.pdf", count / 10 - 1),
+            "/missing/path".to_string(),
+        ];
+
+        for path in &paths {
+            let request = RequestView::get(path);
+            assert_eq!(
+                router.match_location(&host, &request).1,
+                linear_match(&compiled, path),
+                "indexed and linear lookups disagree on {}",
+                path
+            );
+        }
+
+        let indexed = time(&paths, |r| router.match_location(&host, r).1);
+        let linear = time(&paths, |r| linear_match(&compiled, r.path));
+        println!("{:>5} locations: indexed {:?}/lookup, linear scan {:?}/lookup", count, indexed, linear);
+    }
+}
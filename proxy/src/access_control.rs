@@ -1,6 +1,84 @@
-use crate::config::{AccessListConfig, ParsedCidr};
+use crate::config::{AccessListConfig, ClientCertRule, ParsedCidr};
+use openssl::hash::MessageDigest;
+use openssl::stack::Stack;
+use openssl::x509::{X509StoreContext, X509};
 use std::net::IpAddr;
 
+/// Upstream request headers carrying the verified client certificate identity.
+/// Always stripped from incoming requests so clients cannot forge them.
+pub const CLIENT_CERT_HEADERS: [&str; 3] = [
+    "X-Client-Cert-Subject",
+    "X-Client-Cert-SAN",
+    "X-Client-Cert-Fingerprint",
+];
+
+/// Certificate presented by the client during the TLS handshake
+pub struct ClientCert {
+    pub leaf: X509,
+    /// Intermediates sent after the leaf
+    pub chain: Vec<X509>,
+}
+
+/// Identity of a client certificate that passed an access list's `client_cert` rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    /// Subject DN, e.g. "CN=alice,O=Ops"
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// DNS, email and URI subject alternative names
+    pub sans: Vec<String>,
+    /// Lowercase hex SHA-256 of the DER certificate
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    fn from_cert(cert: &X509) -> ClientIdentity {
+        let mut common_name = None;
+        let subject = cert
+            .subject_name()
+            .entries()
+            .filter_map(|entry| {
+                let key = entry.object().nid().short_name().ok()?;
+                let value = entry.data().to_string().ok()?;
+                if key == "CN" && common_name.is_none() {
+                    common_name = Some(value.clone());
+                }
+                Some(format!("{}={}", key, value))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|n| n.dnsname().or_else(|| n.email()).or_else(|| n.uri()))
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .map(|d| d.iter().map(|b| format!("{:02x}", b)).collect())
+            .unwrap_or_default();
+        ClientIdentity {
+            subject,
+            common_name,
+            sans,
+            fingerprint,
+        }
+    }
+
+    /// Header name/value pairs forwarded to the upstream
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            (CLIENT_CERT_HEADERS[0], self.subject.clone()),
+            (CLIENT_CERT_HEADERS[1], self.sans.join(",")),
+            (CLIENT_CERT_HEADERS[2], self.fingerprint.clone()),
+        ]
+    }
+}
+
 /// Result of access control check
 #[derive(Debug)]
 pub enum AccessResult {
@@ -12,52 +90,138 @@ pub enum AccessResult {
     AuthRequired,
 }
 
-/// Check access control for a request.
+/// Check access control for a request that carries no client certificate.
 ///
-/// Evaluates IP rules and Basic Auth credentials against the access list.
-/// Respects the `satisfy` mode:
-/// - "any": access is granted if EITHER IP rules pass OR auth passes
-/// - "all": access is granted only if BOTH IP rules pass AND auth passes
+/// Evaluates IP rules and Basic Auth credentials against the access list; a
+/// `client_cert` rule counts as failed. See `check_access_with_identity`.
 pub fn check_access(
     access_list: &AccessListConfig,
     client_ip: Option<&IpAddr>,
     auth_header: Option<&str>,
 ) -> AccessResult {
+    check_access_with_identity(access_list, client_ip, auth_header, None).0
+}
+
+/// Check access control for a request, also returning the verified client certificate
+/// identity (if the access list has a `client_cert` rule and the certificate passed it).
+///
+/// Evaluates IP rules, Basic Auth credentials and the client certificate
+/// against the access list. Respects the `satisfy` mode:
+/// - "any": access is granted if ANY configured check passes
+/// - "all": access is granted only if EVERY configured check passes
+pub fn check_access_with_identity(
+    access_list: &AccessListConfig,
+    client_ip: Option<&IpAddr>,
+    auth_header: Option<&str>,
+    client_cert: Option<&ClientCert>,
+) -> (AccessResult, Option<ClientIdentity>) {
     let ip_ok = check_ip_access(access_list, client_ip);
     let auth_ok = check_auth_access(access_list, auth_header);
+    let identity = access_list
+        .client_cert
+        .as_ref()
+        .and_then(|rule| verify_client_cert(rule, client_cert));
+    let cert_ok = access_list.client_cert.is_none() || identity.is_some();
 
-    match access_list.satisfy.as_str() {
+    let result = match access_list.satisfy.as_str() {
         "all" => {
-            // Both must pass
-            if !ip_ok {
-                return AccessResult::Denied;
-            }
-            if !access_list.auth.is_empty() && !auth_ok {
-                return AccessResult::AuthRequired;
+            // All must pass
+            if !ip_ok || !cert_ok {
+                AccessResult::Denied
+            } else if !access_list.auth.is_empty() && !auth_ok {
+                AccessResult::AuthRequired
+            } else {
+                AccessResult::Allowed
             }
-            AccessResult::Allowed
         }
         _ => {
             // "any" - either one passing is sufficient
-            if access_list.clients.is_empty() && access_list.auth.is_empty() {
-                return AccessResult::Allowed;
-            }
-            if ip_ok && !access_list.clients.is_empty() {
-                return AccessResult::Allowed;
-            }
-            if auth_ok && !access_list.auth.is_empty() {
-                return AccessResult::Allowed;
-            }
-            // Neither passed
-            if !access_list.auth.is_empty() {
+            let unrestricted = access_list.clients.is_empty()
+                && access_list.auth.is_empty()
+                && access_list.client_cert.is_none();
+            if unrestricted
+                || (ip_ok && !access_list.clients.is_empty())
+                || (auth_ok && !access_list.auth.is_empty())
+                || (cert_ok && access_list.client_cert.is_some())
+            {
+                AccessResult::Allowed
+            } else if !access_list.auth.is_empty() {
+                // None passed
                 AccessResult::AuthRequired
             } else {
                 AccessResult::Denied
             }
         }
+    };
+    (result, identity)
+}
+
+/// Whether a client certificate passing the `client_cert` rule would grant a request
+/// from `client_ip` that is denied without one
+pub fn client_cert_would_allow(access_list: &AccessListConfig, client_ip: Option<&IpAddr>) -> bool {
+    access_list.client_cert.is_some()
+        && (access_list.satisfy != "all" || check_ip_access(access_list, client_ip))
+}
+
+/// Verify the client certificate chain against the rule's CAs and subject patterns
+pub fn verify_client_cert(
+    rule: &ClientCertRule,
+    client_cert: Option<&ClientCert>,
+) -> Option<ClientIdentity> {
+    let cert = client_cert?;
+    let trust = rule.trust.as_ref()?;
+    let mut chain = Stack::new().ok()?;
+    for intermediate in &cert.chain {
+        chain.push(intermediate.clone()).ok()?;
+    }
+    let mut ctx = X509StoreContext::new().ok()?;
+    let verified = ctx
+        .init(&trust.0, &cert.leaf, &chain, |c| c.verify_cert())
+        .ok()?;
+    if !verified {
+        return None;
+    }
+
+    let identity = ClientIdentity::from_cert(&cert.leaf);
+    if rule.subjects.is_empty() {
+        return Some(identity);
+    }
+    let matched = identity
+        .common_name
+        .iter()
+        .chain(identity.sans.iter())
+        .any(|name| rule.subjects.iter().any(|pattern| glob_matches(pattern, name)));
+    if matched {
+        Some(identity)
+    } else {
+        None
     }
 }
 
+/// Case-insensitive match where `*` stands for any run of characters
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
 /// Check IP against the client rules in the access list.
 /// Returns true if the IP is allowed (or if there are no client rules).
 fn check_ip_access(access_list: &AccessListConfig, client_ip: Option<&IpAddr>) -> bool {
@@ -250,6 +414,7 @@ mod tests {
                     password: p.to_string(),
                 })
                 .collect(),
+            client_cert: None,
         }
     }
 
@@ -592,4 +757,171 @@ mod tests {
             AccessResult::Allowed
         ));
     }
+
+    // ─── Client certificates (mTLS) ─────────────────────────
+
+    use crate::config::{load_client_ca, AppConfig, ClientCertRule};
+    use crate::ssl::test_certs;
+
+    fn mtls_rule(name: &str, ca: &crate::ssl::LoadedCert, subjects: &[&str]) -> ClientCertRule {
+        let dir = std::env::temp_dir().join(format!("pingora-test-mtls-{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ca.pem");
+        std::fs::write(&path, ca.leaf.to_pem().unwrap()).unwrap();
+        let trust = load_client_ca(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        ClientCertRule {
+            ca_bundle: path.to_str().unwrap().to_string(),
+            subjects: subjects.iter().map(|s| s.to_string()).collect(),
+            trust: Some(trust),
+        }
+    }
+
+    fn client_cert(cert: &crate::ssl::LoadedCert) -> ClientCert {
+        ClientCert {
+            leaf: cert.leaf.clone(),
+            chain: vec![],
+        }
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("alice", "alice"));
+        assert!(glob_matches("alice", "ALICE"));
+        assert!(!glob_matches("alice", "alice2"));
+        assert!(glob_matches("*.ops.example.com", "db.ops.example.com"));
+        assert!(!glob_matches("*.ops.example.com", "ops.example.com"));
+        assert!(glob_matches("svc-*-prod", "svc-billing-prod"));
+        assert!(!glob_matches("svc-*-prod", "svc-billing-dev"));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("a*b*c", "abc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn test_client_cert_signed_by_ca_verifies() {
+        let ca = test_certs::ca("Internal CA");
+        let leaf = test_certs::issue(&ca, "alice", &["alice@ops.example.com", "laptop.ops.example.com"]);
+        let rule = mtls_rule("verify", &ca, &[]);
+
+        let identity = verify_client_cert(&rule, Some(&client_cert(&leaf))).unwrap();
+        assert_eq!(identity.subject, "CN=alice,O=Ops");
+        assert_eq!(identity.common_name.as_deref(), Some("alice"));
+        assert_eq!(identity.sans, vec!["alice@ops.example.com", "laptop.ops.example.com"]);
+        assert_eq!(identity.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn test_client_cert_from_other_ca_rejected() {
+        let ca = test_certs::ca("Internal CA");
+        let rogue = test_certs::ca("Internal CA");
+        let leaf = test_certs::issue(&rogue, "mallory", &[]);
+        let rule = mtls_rule("rogue", &ca, &[]);
+        assert!(verify_client_cert(&rule, Some(&client_cert(&leaf))).is_none());
+        assert!(verify_client_cert(&rule, None).is_none());
+    }
+
+    #[test]
+    fn test_client_cert_subject_patterns() {
+        let ca = test_certs::ca("Internal CA");
+        let rule = mtls_rule("subjects", &ca, &["*.ops.example.com", "bob"]);
+        let by_san = test_certs::issue(&ca, "alice", &["laptop.ops.example.com"]);
+        let by_cn = test_certs::issue(&ca, "bob", &[]);
+        let other = test_certs::issue(&ca, "carol", &["carol.example.com"]);
+
+        assert!(verify_client_cert(&rule, Some(&client_cert(&by_san))).is_some());
+        assert!(verify_client_cert(&rule, Some(&client_cert(&by_cn))).is_some());
+        assert!(verify_client_cert(&rule, Some(&client_cert(&other))).is_none());
+    }
+
+    #[test]
+    fn test_client_cert_without_trust_store_fails_closed() {
+        let ca = test_certs::ca("Internal CA");
+        let leaf = test_certs::issue(&ca, "alice", &[]);
+        let mut rule = mtls_rule("no-trust", &ca, &[]);
+        rule.trust = None;
+        assert!(verify_client_cert(&rule, Some(&client_cert(&leaf))).is_none());
+    }
+
+    #[test]
+    fn test_check_access_client_cert_only() {
+        let ca = test_certs::ca("Internal CA");
+        let leaf = client_cert(&test_certs::issue(&ca, "alice", &[]));
+        let mut acl = make_acl("any", vec![], vec![]);
+        acl.client_cert = Some(mtls_rule("acl-only", &ca, &[]));
+
+        let (result, identity) = check_access_with_identity(&acl, None, None, Some(&leaf));
+        assert!(matches!(result, AccessResult::Allowed));
+        assert_eq!(identity.unwrap().common_name.as_deref(), Some("alice"));
+        assert!(matches!(check_access_with_identity(&acl, None, None, None).0, AccessResult::Denied));
+    }
+
+    #[test]
+    fn test_check_access_client_cert_satisfy_any_with_auth() {
+        let ca = test_certs::ca("Internal CA");
+        let leaf = client_cert(&test_certs::issue(&ca, "alice", &[]));
+        let mut acl = make_acl("any", vec![], vec![("u", "p")]);
+        acl.client_cert = Some(mtls_rule("acl-any", &ca, &[]));
+
+        // Either a certificate or the password is enough
+        assert!(matches!(check_access_with_identity(&acl, None, None, Some(&leaf)).0, AccessResult::Allowed));
+        let header = basic_auth("u", "p");
+        assert!(matches!(check_access_with_identity(&acl, None, Some(&header), None).0, AccessResult::Allowed));
+        assert!(matches!(check_access_with_identity(&acl, None, None, None).0, AccessResult::AuthRequired));
+    }
+
+    #[test]
+    fn test_check_access_client_cert_satisfy_all() {
+        let ca = test_certs::ca("Internal CA");
+        let leaf = client_cert(&test_certs::issue(&ca, "alice", &[]));
+        let mut acl = make_acl("all", vec![("10.0.0.0/8", "allow")], vec![]);
+        acl.client_cert = Some(mtls_rule("acl-all", &ca, &[]));
+        let inside: IpAddr = "10.1.2.3".parse().unwrap();
+        let outside: IpAddr = "192.168.1.1".parse().unwrap();
+
+        assert!(matches!(check_access_with_identity(&acl, Some(&inside), None, Some(&leaf)).0, AccessResult::Allowed));
+        assert!(matches!(check_access_with_identity(&acl, Some(&inside), None, None).0, AccessResult::Denied));
+        assert!(matches!(check_access_with_identity(&acl, Some(&outside), None, Some(&leaf)).0, AccessResult::Denied));
+    }
+
+    #[test]
+    fn test_identity_headers() {
+        let identity = ClientIdentity {
+            subject: "CN=alice,O=Ops".to_string(),
+            common_name: Some("alice".to_string()),
+            sans: vec!["a@x.com".to_string(), "a.x.com".to_string()],
+            fingerprint: "ab".repeat(32),
+        };
+        let headers = identity.headers();
+        assert_eq!(headers[0], ("X-Client-Cert-Subject", "CN=alice,O=Ops".to_string()));
+        assert_eq!(headers[1], ("X-Client-Cert-SAN", "a@x.com,a.x.com".to_string()));
+        assert_eq!(headers[2].1, "ab".repeat(32));
+    }
+
+    #[test]
+    fn test_load_access_list_client_cert() {
+        let dir = std::env::temp_dir().join("pingora-test-config-acl-mtls");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (ca, _) = test_certs::write_self_signed(&dir.join("ca"), &["Test CA"], 30);
+
+        let global_yaml = "listen:\n  http: 80\n  https: 443\n  admin: 81\nadmin_upstream: 'x'";
+        std::fs::write(dir.join("global.yaml"), global_yaml).unwrap();
+        let acl_yaml = format!(
+            "- id: 1\n  clientCert:\n    caBundle: '{}'\n    subjects: ['*.ops.example.com']\n- id: 2\n  client_cert:\n    ca_bundle: '/nonexistent/ca.pem'",
+            ca.display()
+        );
+        std::fs::write(dir.join("access-lists.yaml"), acl_yaml).unwrap();
+
+        let cfg = AppConfig::load(dir.to_str().unwrap()).unwrap();
+        let rule = cfg.access_lists[&1].client_cert.as_ref().unwrap();
+        assert_eq!(rule.subjects, vec!["*.ops.example.com"]);
+        assert!(rule.trust.is_some());
+        // An unreadable bundle leaves no trust store, so nothing verifies
+        let rule = cfg.access_lists[&2].client_cert.as_ref().unwrap();
+        assert!(rule.subjects.is_empty());
+        assert!(rule.trust.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,1189 @@
+use crate::config::{AcmeConfig, AppConfig};
+use crate::ssl::{self, LoadedCert};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use dashmap::DashMap;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Extension, X509NameBuilder, X509ReqBuilder, X509};
+use pingora_core::connectors::http::Connector;
+use pingora_core::upstreams::peer::HttpPeer;
+use pingora_http::RequestHeader;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// ALPN protocol negotiated by TLS-ALPN-01 validation servers (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// `ACME_TLS_ALPN` in ALPN wire format, for `select_next_proto`
+pub const ACME_TLS_ALPN_WIRE: &[u8] = b"\x0aacme-tls/1";
+/// id-pe-acmeIdentifier, carries the key authorization digest in the challenge certificate
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const BAD_NONCE_RETRIES: u32 = 3;
+
+// ─── Challenge responses ────────────────────────────────────
+
+/// Pending challenge responses, answered from memory by the HTTP and TLS listeners.
+/// Lives for the whole process so config reloads do not drop in-flight validations.
+#[derive(Default)]
+pub struct ChallengeStore {
+    /// HTTP-01: token -> key authorization
+    http01: DashMap<String, String>,
+    /// TLS-ALPN-01: lowercase domain -> self-signed validation certificate
+    tls_alpn01: DashMap<String, Arc<LoadedCert>>,
+}
+
+impl ChallengeStore {
+    /// Key authorization to serve at `/.well-known/acme-challenge/{token}`
+    pub fn http01(&self, token: &str) -> Option<String> {
+        self.http01.get(token).map(|v| v.clone())
+    }
+
+    /// Validation certificate for a TLS-ALPN-01 handshake on `domain`
+    pub fn tls_alpn01(&self, domain: &str) -> Option<Arc<LoadedCert>> {
+        self.tls_alpn01
+            .get(&domain.to_lowercase())
+            .map(|v| Arc::clone(&v))
+    }
+
+    pub fn has_tls_alpn01(&self, domain: &str) -> bool {
+        self.tls_alpn01.contains_key(&domain.to_lowercase())
+    }
+}
+
+/// Challenges published for one order; withdrawn when the order finishes or fails
+struct PublishedChallenges<'a> {
+    store: &'a ChallengeStore,
+    tokens: Vec<String>,
+    domains: Vec<String>,
+}
+
+impl<'a> PublishedChallenges<'a> {
+    fn new(store: &'a ChallengeStore) -> Self {
+        PublishedChallenges {
+            store,
+            tokens: Vec::new(),
+            domains: Vec::new(),
+        }
+    }
+
+    fn http01(&mut self, token: &str, key_authorization: String) {
+        self.store.http01.insert(token.to_string(), key_authorization);
+        self.tokens.push(token.to_string());
+    }
+
+    fn tls_alpn01(&mut self, domain: &str, cert: LoadedCert) {
+        let domain = domain.to_lowercase();
+        self.store.tls_alpn01.insert(domain.clone(), Arc::new(cert));
+        self.domains.push(domain);
+    }
+}
+
+impl Drop for PublishedChallenges<'_> {
+    fn drop(&mut self) {
+        for token in &self.tokens {
+            self.store.http01.remove(token);
+        }
+        for domain in &self.domains {
+            self.store.tls_alpn01.remove(domain);
+        }
+    }
+}
+
+// ─── Crypto helpers ─────────────────────────────────────────
+
+fn b64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn new_ec_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// ES256 account key with its public JWK and RFC 7638 thumbprint
+struct AccountKey {
+    key: PKey<Private>,
+    jwk: Value,
+    thumbprint: String,
+}
+
+impl AccountKey {
+    fn from_key(key: PKey<Private>) -> Result<Self, ErrorStack> {
+        let ec = key.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec.public_key()
+            .affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx)?;
+        // Members in lexicographic order without whitespace, as the thumbprint requires
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64(&x.to_vec_padded(32)?),
+            b64(&y.to_vec_padded(32)?)
+        );
+        let thumbprint = b64(&hash(MessageDigest::sha256(), canonical.as_bytes())?);
+        let jwk = serde_json::from_str(&canonical).expect("canonical JWK is valid JSON");
+        Ok(AccountKey {
+            key,
+            jwk,
+            thumbprint,
+        })
+    }
+
+    /// Load the account key from `path`, generating and saving a new one if missing
+    fn load_or_create(path: &Path) -> Result<Self, String> {
+        let key = if path.exists() {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            PKey::private_key_from_pem(&pem)
+                .map_err(|e| format!("invalid account key {}: {}", path.display(), e))?
+        } else {
+            let key = new_ec_key().map_err(|e| e.to_string())?;
+            let pem = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+            write_file(path, &pem, 0o600)
+                .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+            log::info!("Generated ACME account key {}", path.display());
+            key
+        };
+        Self::from_key(key).map_err(|e| format!("unusable account key {}: {}", path.display(), e))
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint)
+    }
+
+    /// Flattened JWS body. `None` payload produces a POST-as-GET request.
+    fn sign(&self, protected: &Value, payload: Option<&Value>) -> Result<Vec<u8>, ErrorStack> {
+        let protected = b64(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|p| b64(p.to_string().as_bytes()))
+            .unwrap_or_default();
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+        // JWS wants the raw r || s form, OpenSSL produces DER
+        let sig = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut raw = sig.r().to_vec_padded(32)?;
+        raw.extend(sig.s().to_vec_padded(32)?);
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(&raw),
+        })
+        .to_string()
+        .into_bytes())
+    }
+}
+
+/// Self-signed certificate answering a TLS-ALPN-01 challenge for `domain`
+fn tls_alpn01_cert(domain: &str, key_authorization: &str) -> Result<LoadedCert, ErrorStack> {
+    let key = new_ec_key()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(7)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let san = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+
+    // extnValue is the DER OCTET STRING of SHA-256(key authorization)
+    let digest = hash(MessageDigest::sha256(), key_authorization.as_bytes())?;
+    let mut der = vec![0x04, 0x20];
+    der.extend_from_slice(&digest);
+    let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID)?;
+    let value = Asn1OctetString::new_from_bytes(&der)?;
+    let ext = X509Extension::new_from_der(&oid, true, &value)?;
+    builder.append_extension(ext)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok(LoadedCert {
+        leaf: builder.build(),
+        chain: Vec::new(),
+        key,
+    })
+}
+
+/// DER-encoded CSR listing every domain as a SAN
+fn build_csr(domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut req = X509ReqBuilder::new()?;
+    // CAs reject a CN longer than 64 bytes; the SAN list is authoritative anyway
+    if let Some(primary) = domains.first().filter(|d| d.len() <= 64) {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, primary)?;
+        req.set_subject_name(&name.build())?;
+    }
+    req.set_pubkey(key)?;
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains {
+        san.dns(domain);
+    }
+    let san = san.build(&req.x509v3_context(None))?;
+    let mut extensions = Stack::new()?;
+    extensions.push(san)?;
+    req.add_extensions(&extensions)?;
+    req.sign(key, MessageDigest::sha256())?;
+    req.build().to_der()
+}
+
+// ─── HTTP transport ─────────────────────────────────────────
+
+pub(crate) struct AcmeResponse {
+    pub(crate) status: u16,
+    location: Option<String>,
+    nonce: Option<String>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl AcmeResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("invalid ACME response: {}", e))
+    }
+}
+
+/// RFC 7807 problem document returned with ACME errors
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// Minimal HTTP/1.1 client for the ACME directory (also used for OCSP responders),
+/// built on Pingora's connector
+pub(crate) struct AcmeHttp {
+    connector: Connector,
+    /// Extra trust roots for the directory (e.g. Pebble's test CA)
+    ca: Option<Arc<Box<[X509]>>>,
+}
+
+impl AcmeHttp {
+    pub(crate) fn new(ca_bundle: Option<&str>) -> Result<Self, String> {
+        let ca = match ca_bundle {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+                let certs = X509::stack_from_pem(&pem)
+                    .map_err(|e| format!("invalid CA bundle {}: {}", path, e))?;
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+        Ok(AcmeHttp {
+            connector: Connector::new(None),
+            ca,
+        })
+    }
+
+    /// Send a request; `body` is the content type and payload
+    pub(crate) async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<(&str, Vec<u8>)>,
+    ) -> Result<AcmeResponse, String> {
+        let uri: http::Uri = url.parse().map_err(|e| format!("invalid URL {}: {}", url, e))?;
+        let tls = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(format!("unsupported URL {}", url)),
+        };
+        let host = uri.host().ok_or_else(|| format!("URL without host: {}", url))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("no addresses for {}", host))?;
+
+        let mut peer = HttpPeer::new(addr, tls, host.to_string());
+        peer.options.connection_timeout = Some(Duration::from_secs(10));
+        peer.options.read_timeout = Some(Duration::from_secs(30));
+        if let Some(ca) = &self.ca {
+            peer.options.ca = Some(Arc::clone(ca));
+        }
+
+        let (mut session, _) = self
+            .connector
+            .get_http_session(&peer)
+            .await
+            .map_err(|e| format!("cannot connect to {}: {}", url, e))?;
+
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let mut req = RequestHeader::build(method, path.as_bytes(), None).map_err(|e| e.to_string())?;
+        let authority = uri.authority().map(|a| a.as_str()).unwrap_or(host);
+        let _ = req.insert_header(http::header::HOST, authority);
+        let _ = req.insert_header(
+            http::header::USER_AGENT,
+            concat!("pingora-manager/", env!("CARGO_PKG_VERSION")),
+        );
+        if let Some((content_type, ref body)) = body {
+            let _ = req.insert_header(http::header::CONTENT_TYPE, content_type);
+            let _ = req.insert_header(http::header::CONTENT_LENGTH, body.len());
+        }
+
+        let io_err = |e: Box<pingora_core::Error>| format!("request to {} failed: {}", url, e);
+        session.write_request_header(Box::new(req)).await.map_err(io_err)?;
+        if let Some((_, body)) = body {
+            session
+                .write_request_body(bytes::Bytes::from(body), true)
+                .await
+                .map_err(io_err)?;
+        }
+        session.finish_request_body().await.map_err(io_err)?;
+        session.read_response_header().await.map_err(io_err)?;
+
+        let resp = session
+            .response_header()
+            .ok_or_else(|| format!("no response from {}", url))?;
+        let header = |name: &str| {
+            resp.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let status = resp.status.as_u16();
+        let location = header("location");
+        let nonce = header("replay-nonce");
+
+        let mut data = Vec::new();
+        while let Some(chunk) = session.read_response_body().await.map_err(io_err)? {
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(AcmeResponse {
+            status,
+            location,
+            nonce,
+            body: data,
+        })
+    }
+}
+
+// ─── ACME protocol (RFC 8555) ───────────────────────────────
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+struct AcmeClient {
+    http: AcmeHttp,
+    directory: Directory,
+    account: AccountKey,
+    /// Account URL, used as `kid` once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetch the directory and register (or look up) the account
+    async fn connect(config: &AcmeConfig, account: AccountKey) -> Result<Self, String> {
+        let http = AcmeHttp::new(config.ca_bundle.as_deref())?;
+        let resp = http.request("GET", &config.directory_url, None).await?;
+        if resp.status != 200 {
+            return Err(format!(
+                "directory {} returned {}",
+                config.directory_url, resp.status
+            ));
+        }
+        let directory = resp.json()?;
+        let mut client = AcmeClient {
+            http,
+            directory,
+            account,
+            kid: None,
+            nonce: None,
+        };
+
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(ref email) = config.email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = client.directory.new_account.clone();
+        let resp = client.post(&url, Some(&payload)).await?;
+        client.kid = Some(
+            resp.location
+                .ok_or_else(|| "account response without Location".to_string())?,
+        );
+        Ok(client)
+    }
+
+    async fn next_nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = self.directory.new_nonce.clone();
+        self.http
+            .request("GET", &url, None)
+            .await?
+            .nonce
+            .ok_or_else(|| "newNonce response without Replay-Nonce".to_string())
+    }
+
+    /// Signed POST; `None` payload is a POST-as-GET. Retries on `badNonce`.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse, String> {
+        let mut attempt = 0;
+        loop {
+            let nonce = self.next_nonce().await?;
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match self.kid {
+                Some(ref kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.account.jwk.clone(),
+            }
+            let body = self
+                .account
+                .sign(&protected, payload)
+                .map_err(|e| format!("cannot sign request: {}", e))?;
+            let resp = self.http.request("POST", url, Some(("application/jose+json", body))).await?;
+            self.nonce = resp.nonce.clone();
+            if resp.status < 400 {
+                return Ok(resp);
+            }
+            let problem: Problem = resp.json().unwrap_or_default();
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && attempt < BAD_NONCE_RETRIES {
+                attempt += 1;
+                continue;
+            }
+            return Err(format!(
+                "{} returned {}: {} {}",
+                url, resp.status, problem.kind, problem.detail
+            ));
+        }
+    }
+
+    /// Run a full order for `domains`, returning the PEM chain and its private key
+    async fn issue(
+        &mut self,
+        domains: &[String],
+        challenge_type: &str,
+        store: &ChallengeStore,
+    ) -> Result<(Vec<u8>, PKey<Private>), String> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let resp = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = resp
+            .location
+            .clone()
+            .ok_or_else(|| "order response without Location".to_string())?;
+        let order: Order = resp.json()?;
+
+        {
+            let mut published = PublishedChallenges::new(store);
+            for authz_url in &order.authorizations {
+                self.authorize(authz_url, challenge_type, &mut published).await?;
+            }
+        }
+
+        let key = new_ec_key().map_err(|e| e.to_string())?;
+        let csr = build_csr(domains, &key).map_err(|e| format!("cannot build CSR: {}", e))?;
+        self.post(&order.finalize, Some(&json!({ "csr": b64(&csr) })))
+            .await?;
+
+        let order = self.poll_order(&order_url).await?;
+        let cert_url = order
+            .certificate
+            .ok_or_else(|| "valid order without certificate URL".to_string())?;
+        let chain = self.post(&cert_url, None).await?.body;
+        Ok((chain, key))
+    }
+
+    async fn authorize(
+        &mut self,
+        authz_url: &str,
+        challenge_type: &str,
+        published: &mut PublishedChallenges<'_>,
+    ) -> Result<(), String> {
+        let authz: Authorization = self.post(authz_url, None).await?.json()?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let domain = authz.identifier.value;
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.kind == challenge_type)
+            .ok_or_else(|| format!("no {} challenge offered for {}", challenge_type, domain))?;
+
+        let key_authorization = self.account.key_authorization(&challenge.token);
+        if challenge_type == "tls-alpn-01" {
+            let cert = tls_alpn01_cert(&domain, &key_authorization)
+                .map_err(|e| format!("cannot build TLS-ALPN-01 certificate: {}", e))?;
+            published.tls_alpn01(&domain, cert);
+        } else {
+            published.http01(&challenge.token, key_authorization);
+        }
+        self.post(&challenge.url, Some(&json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let authz: Authorization = self.post(authz_url, None).await?.json()?;
+            match authz.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => continue,
+                status => {
+                    let reason = authz
+                        .challenges
+                        .iter()
+                        .find_map(|c| c.error.as_ref())
+                        .map(|p| format!("{} {}", p.kind, p.detail))
+                        .unwrap_or_else(|| "no error detail".to_string());
+                    return Err(format!("authorization for {} is {}: {}", domain, status, reason));
+                }
+            }
+        }
+        Err(format!("authorization for {} timed out", domain))
+    }
+
+    async fn poll_order(&mut self, order_url: &str) -> Result<Order, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post(order_url, None).await?.json()?;
+            match order.status.as_str() {
+                "valid" => return Ok(order),
+                "pending" | "ready" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    let reason = order
+                        .error
+                        .map(|p| format!("{} {}", p.kind, p.detail))
+                        .unwrap_or_else(|| "no error detail".to_string());
+                    return Err(format!("order is {}: {}", status, reason));
+                }
+            }
+        }
+        Err("order did not become valid in time".to_string())
+    }
+}
+
+// ─── Renewal ────────────────────────────────────────────────
+
+fn live_dir(ssl_dir: &str, primary_domain: &str) -> PathBuf {
+    PathBuf::from(ssl_dir)
+        .join("live")
+        .join(primary_domain.to_lowercase())
+}
+
+fn account_key_path(ssl_dir: &str) -> PathBuf {
+    PathBuf::from(ssl_dir).join("acme").join("account.key")
+}
+
+/// Domain lists of `letsencrypt` hosts whose certificate is missing, does not
+/// cover every configured domain, or expires within `renew_before_days`.
+/// The first domain names the `live/` directory, matching `resolve_cert_pair`.
+pub fn pending_orders(config: &AppConfig) -> Vec<Vec<String>> {
+    let ssl_dir = &config.global.ssl_dir;
+    let renew_before_days = config.global.acme.renew_before_days;
+    let mut orders = Vec::new();
+
+    for host in &config.hosts {
+        if !host.enabled || host.domains.is_empty() {
+            continue;
+        }
+        match host.ssl {
+            Some(ref ssl) if ssl.ssl_type == "letsencrypt" => {}
+            _ => continue,
+        }
+        let mut domains: Vec<String> = Vec::new();
+        for domain in &host.domains {
+            let domain = domain.to_lowercase();
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+        // Wildcards can only be validated with DNS-01, regex names not at all
+        if domains.iter().any(|d| d.contains('*') || ssl::is_regex_name(d)) {
+            log::debug!("Host {} has wildcard or regex domains, skipping ACME issuance", host.id);
+            continue;
+        }
+        let cert_path = live_dir(ssl_dir, &domains[0]).join("fullchain.pem");
+        if needs_certificate(&cert_path, &domains, renew_before_days) {
+            orders.push(domains);
+        }
+    }
+    orders
+}
+
+fn needs_certificate(cert_path: &Path, domains: &[String], renew_before_days: u32) -> bool {
+    let leaf = match std::fs::read(cert_path).ok().and_then(|pem| X509::from_pem(&pem).ok()) {
+        Some(leaf) => leaf,
+        None => return true,
+    };
+    match Asn1Time::days_from_now(renew_before_days) {
+        Ok(threshold) if leaf.not_after() < threshold => return true,
+        Ok(_) => {}
+        Err(_) => return true,
+    }
+    let names = ssl::dns_names(&leaf);
+    domains.iter().any(|d| !names.contains(d))
+}
+
+/// Certificate versions kept per domain in `ssl_dir/archive/`: the live one and the one before
+const KEPT_VERSIONS: usize = 2;
+
+/// Write an issued chain and key to `ssl_dir/archive/{primary_domain}/{version}/` and
+/// point the `ssl_dir/live/{primary_domain}` symlink at it, so both files change in one rename
+fn store_certificate(
+    ssl_dir: &str,
+    primary_domain: &str,
+    chain_pem: &[u8],
+    key: &PKey<Private>,
+) -> Result<(), String> {
+    let chain = X509::stack_from_pem(chain_pem)
+        .map_err(|e| format!("CA returned an invalid certificate chain: {}", e))?;
+    if chain.is_empty() {
+        return Err("CA returned an empty certificate chain".to_string());
+    }
+    let domain = primary_domain.to_lowercase();
+    let archive = PathBuf::from(ssl_dir).join("archive").join(&domain);
+    let version = chrono::Utc::now().format("%Y%m%d%H%M%S%9f").to_string();
+    let dir = archive.join(&version);
+    let key_pem = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+    write_file(&dir.join("privkey.pem"), &key_pem, 0o600)
+        .map_err(|e| format!("cannot write key to {}: {}", dir.display(), e))?;
+    write_file(&dir.join("fullchain.pem"), chain_pem, 0o644)
+        .map_err(|e| format!("cannot write certificate to {}: {}", dir.display(), e))?;
+
+    let live = live_dir(ssl_dir, primary_domain);
+    let target = Path::new("..").join("archive").join(&domain).join(&version);
+    swap_live_link(&live, &target, &archive)
+        .map_err(|e| format!("cannot link {} to {}: {}", live.display(), dir.display(), e))?;
+    prune_versions(&archive);
+    Ok(())
+}
+
+/// Replace `live` with a symlink to `target` in a single rename. A plain directory
+/// written by earlier versions is moved to `archive/legacy` first.
+fn swap_live_link(live: &Path, target: &Path, archive: &Path) -> std::io::Result<()> {
+    let parent = live.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    let name = live.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let tmp = parent.join(format!(".{}.tmp", name));
+    let _ = std::fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(target, &tmp)?;
+    if std::fs::symlink_metadata(live).is_ok_and(|meta| meta.is_dir()) {
+        let legacy = archive.join("legacy");
+        let _ = std::fs::remove_dir_all(&legacy);
+        std::fs::rename(live, &legacy)?;
+    }
+    std::fs::rename(&tmp, live)
+}
+
+/// Delete archived versions older than the last `KEPT_VERSIONS`
+fn prune_versions(archive: &Path) {
+    let entries = match std::fs::read_dir(archive) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut versions: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .collect();
+    versions.sort();
+    let stale = versions.len().saturating_sub(KEPT_VERSIONS);
+    for old in &versions[..stale] {
+        if let Err(e) = std::fs::remove_dir_all(old) {
+            log::warn!("ACME: cannot remove old certificate {}: {}", old.display(), e);
+        }
+    }
+}
+
+/// Write via a temporary file and rename, so readers never see a partial file
+fn write_file(path: &Path, data: &[u8], mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Issue certificates for every pending order, skipping domains that failed
+/// within `retry_after_secs`. Returns the number of certificates written.
+async fn renew_pending(
+    config: &AppConfig,
+    challenges: &ChallengeStore,
+    failures: &mut HashMap<String, Instant>,
+) -> usize {
+    let acme = &config.global.acme;
+    let retry_after = Duration::from_secs(acme.retry_after_secs);
+    let orders: Vec<Vec<String>> = pending_orders(config)
+        .into_iter()
+        .filter(|d| failures.get(&d[0]).is_none_or(|t| t.elapsed() >= retry_after))
+        .collect();
+    if orders.is_empty() {
+        return 0;
+    }
+    if acme.challenge != "http-01" && acme.challenge != "tls-alpn-01" {
+        log::error!("Unsupported ACME challenge type '{}'", acme.challenge);
+        return 0;
+    }
+
+    let ssl_dir = &config.global.ssl_dir;
+    let connected = match AccountKey::load_or_create(&account_key_path(ssl_dir)) {
+        Ok(account) => AcmeClient::connect(acme, account).await,
+        Err(e) => Err(e),
+    };
+    let mut client = match connected {
+        Ok(c) => c,
+        Err(e) => {
+            log::error!("ACME directory {} unavailable: {}", acme.directory_url, e);
+            for domains in &orders {
+                failures.insert(domains[0].clone(), Instant::now());
+            }
+            return 0;
+        }
+    };
+
+    let mut issued = 0;
+    for domains in orders {
+        log::info!("Requesting ACME certificate for {}", domains.join(", "));
+        let result = match client.issue(&domains, &acme.challenge, challenges).await {
+            Ok((chain, key)) => store_certificate(ssl_dir, &domains[0], &chain, &key),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                log::info!("Issued certificate for {}", domains.join(", "));
+                failures.remove(&domains[0]);
+                issued += 1;
+            }
+            Err(e) => {
+                log::error!("ACME order for {} failed: {}", domains.join(", "), e);
+                failures.insert(domains[0].clone(), Instant::now());
+            }
+        }
+    }
+    issued
+}
+
+/// Start the background issuance and renewal loop. `current_config` is read on
+/// every pass so reloads apply; `on_issued` runs after new certificates are written.
+pub fn spawn_renewal<F, R>(challenges: Arc<ChallengeStore>, current_config: F, on_issued: R)
+where
+    F: Fn() -> AppConfig + Send + 'static,
+    R: Fn() + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let mut failures: HashMap<String, Instant> = HashMap::new();
+            loop {
+                // Waiting first also gives the listeners time to bind before validation
+                let interval = current_config().global.acme.check_interval_secs.max(1);
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+
+                let config = current_config();
+                if config.global.acme.enabled
+                    && renew_pending(&config, &challenges, &mut failures).await > 0
+                {
+                    on_issued();
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::ssl::test_certs;
+    use openssl::sign::Verifier;
+    use openssl::x509::X509Req;
+    use std::fs;
+
+    fn make_app_config(ssl_dir: &str, hosts: Vec<HostConfig>) -> AppConfig {
+        AppConfig {
+            global: GlobalConfig {
+                ssl_dir: ssl_dir.to_string(),
+                ..Default::default()
+            },
+            hosts,
+            access_lists: HashMap::new(),
+        }
+    }
+
+    fn host(id: u64, domains: &[&str], ssl_type: &str) -> HostConfig {
+        HostConfig {
+            id,
+            domains: domains.iter().map(|s| s.to_string()).collect(),
+            ssl: Some(SslConfig {
+                ssl_type: ssl_type.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // ─── ChallengeStore ─────────────────────────────────────
+
+    #[test]
+    fn test_http01_published_and_withdrawn() {
+        let store = ChallengeStore::default();
+        {
+            let mut published = PublishedChallenges::new(&store);
+            published.http01("tok", "tok.thumb".to_string());
+            assert_eq!(store.http01("tok").as_deref(), Some("tok.thumb"));
+            assert!(store.http01("other").is_none());
+        }
+        assert!(store.http01("tok").is_none());
+    }
+
+    #[test]
+    fn test_tls_alpn01_lookup_case_insensitive() {
+        let store = ChallengeStore::default();
+        let mut published = PublishedChallenges::new(&store);
+        let cert = tls_alpn01_cert("acme.example.com", "tok.thumb").unwrap();
+        published.tls_alpn01("ACME.example.com", cert);
+        assert!(store.has_tls_alpn01("acme.example.com"));
+        assert!(store.tls_alpn01("Acme.Example.com").is_some());
+        drop(published);
+        assert!(!store.has_tls_alpn01("acme.example.com"));
+    }
+
+    // ─── Account key and JWS ────────────────────────────────
+
+    #[test]
+    fn test_account_key_persisted() {
+        let dir = fresh_dir("pingora-test-acme-account");
+        let path = account_key_path(dir.to_str().unwrap());
+        let first = AccountKey::load_or_create(&path).unwrap();
+        assert!(path.is_file());
+        let second = AccountKey::load_or_create(&path).unwrap();
+        assert_eq!(first.thumbprint, second.thumbprint);
+        assert_eq!(first.jwk, second.jwk);
+        assert_eq!(first.jwk["kty"], "EC");
+        assert_eq!(first.jwk["crv"], "P-256");
+
+        let mode = fs::metadata(&path).unwrap().permissions();
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777, 0o600);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_key_authorization_format() {
+        let account = AccountKey::from_key(new_ec_key().unwrap()).unwrap();
+        let ka = account.key_authorization("token-1");
+        let (token, thumb) = ka.split_once('.').unwrap();
+        assert_eq!(token, "token-1");
+        // base64url of a SHA-256 digest, no padding
+        assert_eq!(thumb.len(), 43);
+        assert!(!thumb.contains('='));
+    }
+
+    #[test]
+    fn test_jws_signature_verifies() {
+        let account = AccountKey::from_key(new_ec_key().unwrap()).unwrap();
+        let protected = json!({ "alg": "ES256", "nonce": "n", "url": "https://acme.test/x" });
+        let payload = json!({ "termsOfServiceAgreed": true });
+        let body: Value = serde_json::from_slice(&account.sign(&protected, Some(&payload)).unwrap()).unwrap();
+
+        let raw = URL_SAFE_NO_PAD
+            .decode(body["signature"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(raw.len(), 64);
+        let sig = EcdsaSig::from_private_components(
+            BigNum::from_slice(&raw[..32]).unwrap(),
+            BigNum::from_slice(&raw[32..]).unwrap(),
+        )
+        .unwrap();
+        let input = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &account.key).unwrap();
+        verifier.update(input.as_bytes()).unwrap();
+        assert!(verifier.verify(&sig.to_der().unwrap()).unwrap());
+
+        let decoded = URL_SAFE_NO_PAD
+            .decode(body["payload"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&decoded).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_post_as_get_has_empty_payload() {
+        let account = AccountKey::from_key(new_ec_key().unwrap()).unwrap();
+        let body: Value =
+            serde_json::from_slice(&account.sign(&json!({ "alg": "ES256" }), None).unwrap()).unwrap();
+        assert_eq!(body["payload"], "");
+    }
+
+    // ─── Certificates ───────────────────────────────────────
+
+    #[test]
+    fn test_tls_alpn01_cert_carries_acme_identifier() {
+        let cert = tls_alpn01_cert("alpn.example.com", "tok.thumb").unwrap();
+        let names: Vec<String> = cert
+            .leaf
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|n| n.dnsname().map(|d| d.to_string()))
+            .collect();
+        assert_eq!(names, vec!["alpn.example.com"]);
+
+        let text = String::from_utf8(cert.leaf.to_text().unwrap()).unwrap();
+        assert!(text.contains("1.3.6.1.5.5.7.1.31: critical"));
+        let digest = hash(MessageDigest::sha256(), b"tok.thumb").unwrap();
+        let expected_der = [&[0x04u8, 0x20][..], &digest[..]].concat();
+        assert!(cert.leaf.to_der().unwrap().windows(34).any(|w| w == expected_der.as_slice()));
+    }
+
+    #[test]
+    fn test_csr_lists_all_domains() {
+        let key = new_ec_key().unwrap();
+        let domains = vec!["a.example.com".to_string(), "b.example.com".to_string()];
+        let csr = X509Req::from_der(&build_csr(&domains, &key).unwrap()).unwrap();
+        assert!(csr.verify(&key).unwrap());
+        let text = String::from_utf8(csr.to_text().unwrap()).unwrap();
+        assert!(text.contains("DNS:a.example.com"));
+        assert!(text.contains("DNS:b.example.com"));
+    }
+
+    #[test]
+    fn test_csr_omits_overlong_common_name() {
+        let key = new_ec_key().unwrap();
+        let long = format!("{}.example.com", "a".repeat(60));
+        let csr = X509Req::from_der(&build_csr(std::slice::from_ref(&long), &key).unwrap()).unwrap();
+        assert_eq!(csr.subject_name().entries().count(), 0);
+        let text = String::from_utf8(csr.to_text().unwrap()).unwrap();
+        assert!(text.contains(&format!("DNS:{}", long)));
+    }
+
+    #[test]
+    fn test_store_certificate_writes_live_dir() {
+        let dir = fresh_dir("pingora-test-acme-store");
+        let src = dir.join("src");
+        let (cert, _) = test_certs::write_self_signed(&src, &["store.example.com"], 30);
+        let key = new_ec_key().unwrap();
+        let chain = fs::read(&cert).unwrap();
+        store_certificate(dir.to_str().unwrap(), "Store.example.com", &chain, &key).unwrap();
+
+        let live = dir.join("live").join("store.example.com");
+        assert!(fs::symlink_metadata(&live).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(live.join("fullchain.pem")).unwrap(), chain);
+        let written = PKey::private_key_from_pem(&fs::read(live.join("privkey.pem")).unwrap()).unwrap();
+        assert!(written.public_eq(&key));
+        assert!(!live.join("fullchain.tmp").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_certificate_swaps_versions() {
+        let dir = fresh_dir("pingora-test-acme-store-swap");
+        let (cert, _) = test_certs::write_self_signed(&dir.join("src"), &["swap.example.com"], 30);
+        let chain = fs::read(&cert).unwrap();
+        // A plain directory from before versioned storage is replaced
+        let live = dir.join("live").join("swap.example.com");
+        fs::create_dir_all(&live).unwrap();
+        fs::write(live.join("fullchain.pem"), b"old").unwrap();
+
+        let mut key = new_ec_key().unwrap();
+        for _ in 0..3 {
+            key = new_ec_key().unwrap();
+            store_certificate(dir.to_str().unwrap(), "swap.example.com", &chain, &key).unwrap();
+        }
+        let written = PKey::private_key_from_pem(&fs::read(live.join("privkey.pem")).unwrap()).unwrap();
+        assert!(written.public_eq(&key));
+
+        let archive = dir.join("archive").join("swap.example.com");
+        assert_eq!(fs::read(archive.join("legacy").join("fullchain.pem")).unwrap(), b"old");
+        let versions = fs::read_dir(&archive).unwrap().count();
+        assert_eq!(versions, KEPT_VERSIONS + 1);
+        assert!(!dir.join("live").join(".swap.example.com.tmp").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_certificate_rejects_garbage_chain() {
+        let dir = fresh_dir("pingora-test-acme-store-bad");
+        let key = new_ec_key().unwrap();
+        let result = store_certificate(dir.to_str().unwrap(), "bad.example.com", b"not a cert", &key);
+        assert!(result.is_err());
+        assert!(!dir.join("live").join("bad.example.com").join("fullchain.pem").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── pending_orders ─────────────────────────────────────
+
+    #[test]
+    fn test_pending_when_certificate_missing() {
+        let dir = fresh_dir("pingora-test-acme-pending-missing");
+        let config = make_app_config(
+            dir.to_str().unwrap(),
+            vec![host(1, &["New.example.com", "www.new.example.com"], "letsencrypt")],
+        );
+        assert_eq!(
+            pending_orders(&config),
+            vec![vec!["new.example.com".to_string(), "www.new.example.com".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_not_pending_when_certificate_fresh() {
+        let dir = fresh_dir("pingora-test-acme-pending-fresh");
+        test_certs::write_self_signed(&dir.join("src"), &["fresh.example.com"], 90);
+        let live = dir.join("live").join("fresh.example.com");
+        fs::create_dir_all(&live).unwrap();
+        fs::copy(dir.join("src").join("cert.pem"), live.join("fullchain.pem")).unwrap();
+        let config = make_app_config(
+            dir.to_str().unwrap(),
+            vec![host(1, &["fresh.example.com"], "letsencrypt")],
+        );
+        assert!(pending_orders(&config).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pending_when_certificate_expiring() {
+        let dir = fresh_dir("pingora-test-acme-pending-expiring");
+        test_certs::write_self_signed(&dir.join("src"), &["old.example.com"], 10);
+        let live = dir.join("live").join("old.example.com");
+        fs::create_dir_all(&live).unwrap();
+        fs::copy(dir.join("src").join("cert.pem"), live.join("fullchain.pem")).unwrap();
+        let mut config = make_app_config(
+            dir.to_str().unwrap(),
+            vec![host(1, &["old.example.com"], "letsencrypt")],
+        );
+        assert_eq!(pending_orders(&config).len(), 1);
+
+        config.global.acme.renew_before_days = 5;
+        assert!(pending_orders(&config).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pending_when_domain_added() {
+        let dir = fresh_dir("pingora-test-acme-pending-added");
+        test_certs::write_self_signed(&dir.join("src"), &["site.example.com"], 90);
+        let live = dir.join("live").join("site.example.com");
+        fs::create_dir_all(&live).unwrap();
+        fs::copy(dir.join("src").join("cert.pem"), live.join("fullchain.pem")).unwrap();
+        let config = make_app_config(
+            dir.to_str().unwrap(),
+            vec![host(1, &["site.example.com", "api.example.com"], "letsencrypt")],
+        );
+        assert_eq!(pending_orders(&config).len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pending_skips_other_hosts() {
+        let mut disabled = host(3, &["off.example.com"], "letsencrypt");
+        disabled.enabled = false;
+        let mut plain = host(4, &["plain.example.com"], "none");
+        plain.ssl = None;
+        let config = make_app_config(
+            "/nonexistent",
+            vec![
+                host(1, &["custom.example.com"], "custom"),
+                host(2, &["*.wild.example.com"], "letsencrypt"),
+                disabled,
+                plain,
+                host(5, &[], "letsencrypt"),
+            ],
+        );
+        assert!(pending_orders(&config).is_empty());
+    }
+}
@@ -16,6 +16,38 @@ pub struct GlobalConfig {
     pub logs_dir: String,
     #[serde(default = "ssl_dir")]
     pub ssl_dir: String,
+    #[serde(default)]
+    pub acme: AcmeConfig,
+    /// Warn about certificates expiring within this many days
+    #[serde(alias = "certExpiryWarnDays", default = "default_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u32,
+    /// Certificate for TLS handshakes whose SNI matches no host
+    #[serde(alias = "defaultCert", default)]
+    pub default_cert: DefaultCertConfig,
+    #[serde(default)]
+    pub ocsp: OcspConfig,
+    /// Requests matching no host (and no `default_server`): "default_page",
+    /// "not_found" (404) or "close" (drop the connection without a response, like nginx's 444)
+    #[serde(alias = "unknownHost", default = "default_unknown_host")]
+    pub unknown_host: String,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            listen: ListenConfig::default(),
+            admin_upstream: "127.0.0.1:3001".to_string(),
+            default_page: default_page(),
+            error_pages_dir: error_pages_dir(),
+            logs_dir: logs_dir(),
+            ssl_dir: ssl_dir(),
+            acme: AcmeConfig::default(),
+            cert_expiry_warn_days: default_cert_expiry_warn_days(),
+            default_cert: DefaultCertConfig::default(),
+            ocsp: OcspConfig::default(),
+            unknown_host: default_unknown_host(),
+        }
+    }
 }
 
 fn default_page() -> String {
@@ -30,6 +62,122 @@ fn logs_dir() -> String {
 fn ssl_dir() -> String {
     "/etc/letsencrypt".to_string()
 }
+fn default_cert_expiry_warn_days() -> u32 {
+    14
+}
+fn default_unknown_host() -> String {
+    "default_page".to_string()
+}
+
+/// Built-in ACME issuance for `ssl.type: letsencrypt` hosts (global.yaml `acme:` section)
+#[derive(Debug, Clone, Deserialize)]
+pub struct AcmeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// ACME directory URL, e.g. Let's Encrypt staging or a local Pebble (`https://localhost:14000/dir`)
+    #[serde(alias = "directoryUrl", default = "default_acme_directory")]
+    pub directory_url: String,
+    pub email: Option<String>,
+    /// Challenge type: "http-01" or "tls-alpn-01"
+    #[serde(default = "default_acme_challenge")]
+    pub challenge: String,
+    /// Renew when the certificate expires within this many days
+    #[serde(alias = "renewBeforeDays", default = "default_renew_before_days")]
+    pub renew_before_days: u32,
+    /// How often certificates are checked for issuance or renewal
+    #[serde(alias = "checkIntervalSecs", default = "default_acme_check_interval")]
+    pub check_interval_secs: u64,
+    /// How long to wait before retrying a domain whose order failed
+    #[serde(alias = "retryAfterSecs", default = "default_acme_retry_after")]
+    pub retry_after_secs: u64,
+    /// PEM bundle used to verify the directory's TLS certificate (e.g. Pebble's root)
+    #[serde(alias = "caBundle")]
+    pub ca_bundle: Option<String>,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            enabled: false,
+            directory_url: default_acme_directory(),
+            email: None,
+            challenge: default_acme_challenge(),
+            renew_before_days: default_renew_before_days(),
+            check_interval_secs: default_acme_check_interval(),
+            retry_after_secs: default_acme_retry_after(),
+            ca_bundle: None,
+        }
+    }
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+fn default_acme_challenge() -> String {
+    "http-01".to_string()
+}
+fn default_renew_before_days() -> u32 {
+    30
+}
+fn default_acme_check_interval() -> u64 {
+    60
+}
+fn default_acme_retry_after() -> u64 {
+    3600
+}
+
+/// Handling of TLS clients whose SNI is missing or matches no certificate (global.yaml `default_cert:` section)
+#[derive(Debug, Clone, Deserialize)]
+pub struct DefaultCertConfig {
+    /// "default": complete the handshake with the default certificate; "reject": abort it
+    #[serde(alias = "unknownSni", default = "default_unknown_sni")]
+    pub unknown_sni: String,
+    /// Default certificate; a self-signed one is generated when unset
+    #[serde(alias = "certPath")]
+    pub cert_path: Option<String>,
+    #[serde(alias = "keyPath")]
+    pub key_path: Option<String>,
+}
+
+impl Default for DefaultCertConfig {
+    fn default() -> Self {
+        DefaultCertConfig {
+            unknown_sni: default_unknown_sni(),
+            cert_path: None,
+            key_path: None,
+        }
+    }
+}
+
+fn default_unknown_sni() -> String {
+    "default".to_string()
+}
+
+/// OCSP stapling for served certificates (global.yaml `ocsp:` section)
+#[derive(Debug, Clone, Deserialize)]
+pub struct OcspConfig {
+    #[serde(default = "default_ocsp_stapling")]
+    pub stapling: bool,
+    /// How often responses are checked; each is refreshed halfway to its nextUpdate
+    #[serde(alias = "checkIntervalSecs", default = "default_ocsp_check_interval")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for OcspConfig {
+    fn default() -> Self {
+        OcspConfig {
+            stapling: default_ocsp_stapling(),
+            check_interval_secs: default_ocsp_check_interval(),
+        }
+    }
+}
+
+fn default_ocsp_stapling() -> bool {
+    true
+}
+fn default_ocsp_check_interval() -> u64 {
+    300
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ListenConfig {
@@ -41,6 +189,16 @@ pub struct ListenConfig {
     pub admin: u16,
 }
 
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            http: default_http_port(),
+            https: default_https_port(),
+            admin: default_admin_port(),
+        }
+    }
+}
+
 fn default_http_port() -> u16 {
     80
 }
@@ -65,8 +223,8 @@ pub struct HostConfig {
     pub stream_ports: Vec<StreamPortConfig>,
     #[serde(default)]
     pub hsts: bool,
+    /// Advertise h2 via ALPN on TLS connections for this host
     #[serde(default)]
-    #[allow(dead_code)] // deserialized for schema completeness
     pub http2: bool,
     #[serde(default = "default_enabled")]
     pub enabled: bool,
@@ -74,6 +232,28 @@ pub struct HostConfig {
     pub compression: bool,
     #[serde(alias = "redirect_www", default)]
     pub redirect_www: bool,
+    /// Serve requests whose host matches no other host through this one
+    #[serde(alias = "defaultServer", default)]
+    pub default_server: bool,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        HostConfig {
+            id: 0,
+            domains: Vec::new(),
+            group_id: None,
+            ssl: None,
+            locations: Vec::new(),
+            stream_ports: Vec::new(),
+            hsts: false,
+            http2: false,
+            enabled: default_enabled(),
+            compression: default_compression(),
+            redirect_www: false,
+            default_server: false,
+        }
+    }
 }
 
 fn default_balance_method() -> String {
@@ -97,6 +277,35 @@ pub struct SslConfig {
     pub force_https: bool,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
+    /// Lowest accepted protocol version ("TLSv1" .. "TLSv1.3"); listener default is TLSv1.2
+    #[serde(alias = "minVersion")]
+    pub min_version: Option<String>,
+    /// Highest accepted protocol version
+    #[serde(alias = "maxVersion")]
+    pub max_version: Option<String>,
+    /// OpenSSL cipher list for TLS 1.2 and below (TLS 1.0/1.1 also need "@SECLEVEL=0")
+    pub ciphers: Option<String>,
+    /// TLS 1.3 cipher suites, colon separated
+    pub ciphersuites: Option<String>,
+    /// Pre-fetched DER OCSP response to staple instead of querying the certificate's responder
+    #[serde(alias = "ocspFile")]
+    pub ocsp_file: Option<String>,
+}
+
+impl Default for SslConfig {
+    fn default() -> Self {
+        SslConfig {
+            ssl_type: default_ssl_type(),
+            force_https: false,
+            cert_path: None,
+            key_path: None,
+            min_version: None,
+            max_version: None,
+            ciphers: None,
+            ciphersuites: None,
+            ocsp_file: None,
+        }
+    }
 }
 
 fn default_ssl_type() -> String {
@@ -122,9 +331,11 @@ fn default_weight() -> usize {
 #[derive(Debug, Clone, Deserialize)]
 pub struct LocationConfig {
     pub path: String,
+    /// "prefix", "prefix_priority" (`^~`), "exact" (`=`), "regex" (`~`) or
+    /// "regex_case_insensitive" (`~*`); the nginx modifiers are accepted as well
     #[serde(alias = "matchType", default = "default_match_type")]
     pub match_type: String,
-    /// Location type: "proxy", "static", or "redirect"
+    /// Location type: "proxy", "grpc" (proxied over HTTP/2 end to end), "static", or "redirect"
     #[serde(alias = "type", default = "default_location_type")]
     pub location_type: Option<String>,
     #[serde(default)]
@@ -135,7 +346,8 @@ pub struct LocationConfig {
     pub static_dir: Option<String>,
     #[serde(alias = "cacheExpires")]
     pub cache_expires: Option<String>,
-    // Redirect fields
+    // Redirect fields; for regex locations, forward_domain, forward_path and header
+    // values may reference capture groups (`$1`, `${name}`)
     #[serde(alias = "forwardScheme")]
     pub forward_scheme: Option<String>,
     #[serde(alias = "forwardDomain")]
@@ -151,11 +363,73 @@ pub struct LocationConfig {
     pub headers: HashMap<String, String>,
     #[serde(alias = "accessListId", alias = "access_list_id")]
     pub access_list_id: Option<u64>,
+    /// Protocol used to reach the upstreams of a proxy location: "http" (default) or "https"
+    #[serde(alias = "upstreamScheme")]
+    pub upstream_scheme: Option<String>,
+    /// TLS options for `upstream_scheme: https`
+    #[serde(alias = "upstreamTls")]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    /// Talk HTTP/2 to the upstreams: h2 via ALPN over https, prior-knowledge h2c over http
+    #[serde(alias = "upstreamHttp2", default)]
+    pub upstream_http2: bool,
+    /// Request conditions checked after the path matches; all must hold
+    pub conditions: Option<LocationConditions>,
+    /// URL rewrite rules, applied in order before the request is served
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+    /// Active health check of the upstreams; unhealthy ones receive no traffic
+    #[serde(alias = "healthCheck")]
+    pub health_check: Option<HealthCheckConfig>,
+    /// Passive checks: eject upstreams that keep failing proxied requests
+    #[serde(alias = "outlierDetection")]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
     /// Pre-compiled HTTP headers for response injection (built at config load, not per-request)
     #[serde(skip)]
     pub compiled_headers: Vec<(http::header::HeaderName, Arc<str>)>,
 }
 
+impl Default for LocationConfig {
+    fn default() -> Self {
+        LocationConfig {
+            path: "/".to_string(),
+            match_type: default_match_type(),
+            location_type: default_location_type(),
+            upstreams: Vec::new(),
+            balance_method: default_balance_method(),
+            static_dir: None,
+            cache_expires: None,
+            forward_scheme: None,
+            forward_domain: None,
+            forward_path: None,
+            preserve_path: false,
+            status_code: None,
+            headers: HashMap::new(),
+            access_list_id: None,
+            upstream_scheme: None,
+            upstream_tls: None,
+            upstream_http2: false,
+            conditions: None,
+            rewrites: Vec::new(),
+            health_check: None,
+            outlier_detection: None,
+            compiled_headers: Vec::new(),
+        }
+    }
+}
+
+impl LocationConfig {
+    /// Whether the path is a regex (case-sensitive or not)
+    pub fn is_regex(&self) -> bool {
+        matches!(self.match_type.as_str(), "regex" | "~" | "regex_case_insensitive" | "~*")
+    }
+
+    /// Whether both locations match the same requests (path, match type and conditions),
+    /// i.e. one is the other after a reload even if its index or upstreams changed
+    pub fn same_route(&self, other: &LocationConfig) -> bool {
+        self.path == other.path && self.match_type == other.match_type && self.conditions == other.conditions
+    }
+}
+
 fn default_match_type() -> String {
     "prefix".to_string()
 }
@@ -164,6 +438,151 @@ fn default_location_type() -> Option<String> {
     Some("proxy".to_string())
 }
 
+/// Per-location request conditions (method, headers, query parameters, cookies)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct LocationConditions {
+    /// Accepted HTTP methods (any of, case-insensitive); empty accepts all
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<ValueCondition>,
+    #[serde(default)]
+    pub query: Vec<ValueCondition>,
+    #[serde(default)]
+    pub cookies: Vec<ValueCondition>,
+}
+
+/// A named request attribute that must be present, and equal `value` when one is given
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ValueCondition {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// A URL rewrite rule (nginx `rewrite`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewriteRule {
+    /// Regex matched against the request path
+    pub pattern: String,
+    /// New path or absolute URL; `$1`/`${name}` insert captures, a trailing `?` drops the query
+    pub replacement: String,
+    /// "" (continue with the next rule), "last", "break", "redirect" or "permanent"
+    #[serde(default)]
+    pub flag: String,
+}
+
+/// Active health check of a location's upstreams
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckConfig {
+    /// "tcp" (connect only) or "http" (request `path`, expect `expected_status`)
+    #[serde(alias = "type", default = "default_health_check_type")]
+    pub check_type: String,
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    /// Host header (and SNI) of HTTP checks; defaults to the first upstream's server
+    pub host: Option<String>,
+    #[serde(alias = "expectedStatus", default = "default_health_check_status")]
+    pub expected_status: u16,
+    #[serde(alias = "intervalSecs", default = "default_health_check_interval")]
+    pub interval_secs: u64,
+    /// Connect and read timeout of a single check
+    #[serde(alias = "timeoutSecs", default = "default_health_check_timeout")]
+    pub timeout_secs: u64,
+    /// Consecutive successes that mark an unhealthy upstream healthy again
+    #[serde(default = "default_health_check_rise")]
+    pub rise: usize,
+    /// Consecutive failures that mark a healthy upstream unhealthy
+    #[serde(default = "default_health_check_fall")]
+    pub fall: usize,
+}
+
+fn default_health_check_type() -> String {
+    "tcp".to_string()
+}
+
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_status() -> u16 {
+    200
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_health_check_timeout() -> u64 {
+    2
+}
+
+fn default_health_check_rise() -> usize {
+    2
+}
+
+fn default_health_check_fall() -> usize {
+    3
+}
+
+/// Passive outlier ejection of a location's upstreams (nginx `max_fails`/`fail_timeout`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutlierDetectionConfig {
+    /// Consecutive failed requests (connect errors, timeouts, 5xx) that eject an upstream
+    #[serde(alias = "maxFails", default = "default_max_fails")]
+    pub max_fails: u32,
+    /// First ejection; doubled each time the upstream fails again right after re-admission
+    #[serde(alias = "failTimeoutSecs", default = "default_fail_timeout")]
+    pub fail_timeout_secs: u64,
+    #[serde(alias = "maxEjectionSecs", default = "default_max_ejection")]
+    pub max_ejection_secs: u64,
+}
+
+fn default_max_fails() -> u32 {
+    1
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+fn default_max_ejection() -> u64 {
+    300
+}
+
+/// TLS options for connections from a location to its upstreams
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// SNI and verified name; defaults to the upstream's server name (none for IP addresses)
+    pub sni: Option<String>,
+    /// Verify the upstream certificate chain and name
+    #[serde(default = "default_upstream_verify")]
+    pub verify: bool,
+    /// PEM bundle trusted instead of the system roots
+    #[serde(alias = "caBundle")]
+    pub ca_bundle: Option<String>,
+    /// Client certificate chain and key presented to the upstream (mutual TLS)
+    #[serde(alias = "clientCertPath")]
+    pub client_cert_path: Option<String>,
+    #[serde(alias = "clientKeyPath")]
+    pub client_key_path: Option<String>,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        UpstreamTlsConfig {
+            sni: None,
+            verify: default_upstream_verify(),
+            ca_bundle: None,
+            client_cert_path: None,
+            client_key_path: None,
+        }
+    }
+}
+
+fn default_upstream_verify() -> bool {
+    true
+}
+
 /// Stream port configuration (TCP/UDP forwarding) within a host
 #[derive(Debug, Clone, Deserialize)]
 pub struct StreamPortConfig {
@@ -173,6 +592,7 @@ pub struct StreamPortConfig {
     #[serde(default)]
     pub upstreams: Vec<UpstreamConfig>,
     #[serde(default = "default_balance_method")]
+    #[allow(dead_code)] // deserialized for schema completeness
     pub balance_method: String,
 }
 
@@ -193,6 +613,9 @@ pub struct AccessListConfig {
     pub clients: Vec<AccessListClient>,
     #[serde(default)]
     pub auth: Vec<AccessListAuthEntry>,
+    /// Require a TLS client certificate (mutual TLS)
+    #[serde(alias = "clientCert")]
+    pub client_cert: Option<ClientCertRule>,
 }
 
 fn default_satisfy() -> String {
@@ -228,6 +651,52 @@ pub struct AccessListAuthEntry {
     pub password: String,
 }
 
+/// Client certificate requirement in an access list
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCertRule {
+    /// PEM bundle of the CAs allowed to sign client certificates
+    #[serde(alias = "caBundle")]
+    pub ca_bundle: String,
+    /// Patterns ("*" matches any run of characters, case-insensitive) checked against the
+    /// subject CN and SAN entries; empty accepts any certificate issued by the CAs
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    /// Trust store loaded from `ca_bundle` at config load time (None = nothing verifies)
+    #[serde(skip)]
+    pub trust: Option<ClientCaStore>,
+}
+
+/// CA certificates trusted for client authentication
+#[derive(Clone)]
+pub struct ClientCaStore(pub Arc<openssl::x509::store::X509Store>);
+
+impl std::fmt::Debug for ClientCaStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientCaStore")
+    }
+}
+
+/// Load a PEM CA bundle into a store that verifies certificates for TLS client use
+pub fn load_client_ca(path: &str) -> Result<ClientCaStore, String> {
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509PurposeId, X509};
+
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let certs = X509::stack_from_pem(&pem)
+        .map_err(|e| format!("invalid certificate PEM in {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", path));
+    }
+    let mut builder = X509StoreBuilder::new().map_err(|e| e.to_string())?;
+    for cert in certs {
+        builder.add_cert(cert).map_err(|e| e.to_string())?;
+    }
+    builder
+        .set_purpose(X509PurposeId::SSL_CLIENT)
+        .map_err(|e| e.to_string())?;
+    Ok(ClientCaStore(Arc::new(builder.build())))
+}
+
 /// Parse a CIDR string like "10.0.0.0/24" or "192.168.1.1" into IP + prefix length
 fn parse_cidr(address: &str) -> Option<ParsedCidr> {
     if address == "all" {
@@ -264,13 +733,13 @@ impl AppConfig {
             serde_yaml::from_str(&content)?
         } else {
             log::warn!("global.yaml not found, using defaults");
-            serde_yaml::from_str(
-                "listen:\n  http: 80\n  https: 443\n  admin: 81\nadmin_upstream: '127.0.0.1:3001'",
-            )?
+            GlobalConfig::default()
         };
 
         // Load host configs
         let mut hosts: Vec<HostConfig> = Self::load_glob(configs_dir, "host-*.yaml")?;
+        // Glob order is by file name (host-10 before host-2); keep hosts in ID order
+        hosts.sort_by_key(|h| h.id);
 
         // Pre-compile headers for each location
         for host in &mut hosts {
@@ -299,6 +768,12 @@ impl AppConfig {
             for client in &mut acl.clients {
                 client.parsed_cidr = parse_cidr(&client.address);
             }
+            if let Some(ref mut rule) = acl.client_cert {
+                match load_client_ca(&rule.ca_bundle) {
+                    Ok(store) => rule.trust = Some(store),
+                    Err(e) => log::error!("Access list {}: client CA bundle rejected: {}", acl.id, e),
+                }
+            }
         }
 
         Ok(AppConfig {
@@ -366,6 +841,83 @@ mod tests {
         assert_eq!(cfg.error_pages_dir, "/data/error-pages");
         assert_eq!(cfg.logs_dir, "/data/logs");
         assert_eq!(cfg.ssl_dir, "/etc/letsencrypt");
+        assert_eq!(cfg.cert_expiry_warn_days, 14);
+    }
+
+    #[test]
+    fn test_global_config_acme_defaults() {
+        let yaml = "listen: {}\nadmin_upstream: 'x'";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(!cfg.acme.enabled);
+        assert_eq!(cfg.acme.directory_url, "https://acme-v02.api.letsencrypt.org/directory");
+        assert_eq!(cfg.acme.challenge, "http-01");
+        assert_eq!(cfg.acme.renew_before_days, 30);
+        assert!(cfg.acme.ca_bundle.is_none());
+    }
+
+    #[test]
+    fn test_global_config_acme_custom_directory() {
+        let yaml = r#"
+listen: {}
+admin_upstream: 'x'
+acme:
+  enabled: true
+  directoryUrl: "https://localhost:14000/dir"
+  email: admin@example.com
+  challenge: tls-alpn-01
+  caBundle: /etc/pebble/root.pem
+"#;
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(cfg.acme.enabled);
+        assert_eq!(cfg.acme.directory_url, "https://localhost:14000/dir");
+        assert_eq!(cfg.acme.email.as_deref(), Some("admin@example.com"));
+        assert_eq!(cfg.acme.challenge, "tls-alpn-01");
+        assert_eq!(cfg.acme.ca_bundle.as_deref(), Some("/etc/pebble/root.pem"));
+        assert_eq!(cfg.acme.check_interval_secs, 60);
+    }
+
+    #[test]
+    fn test_global_config_unknown_host() {
+        let yaml = "listen: {}\nadmin_upstream: 'x'";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.unknown_host, "default_page");
+        let yaml = "listen: {}\nadmin_upstream: 'x'\nunknownHost: close";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.unknown_host, "close");
+    }
+
+    #[test]
+    fn test_global_config_default_cert() {
+        let yaml = "listen: {}\nadmin_upstream: 'x'";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.default_cert.unknown_sni, "default");
+        assert!(cfg.default_cert.cert_path.is_none());
+
+        let yaml = r#"
+listen: {}
+admin_upstream: 'x'
+defaultCert:
+  unknownSni: reject
+  certPath: /data/ssl/default/fullchain.pem
+  keyPath: /data/ssl/default/privkey.pem
+"#;
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.default_cert.unknown_sni, "reject");
+        assert_eq!(cfg.default_cert.cert_path.as_deref(), Some("/data/ssl/default/fullchain.pem"));
+        assert_eq!(cfg.default_cert.key_path.as_deref(), Some("/data/ssl/default/privkey.pem"));
+    }
+
+    #[test]
+    fn test_global_config_ocsp() {
+        let yaml = "listen: {}\nadmin_upstream: 'x'";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(cfg.ocsp.stapling);
+        assert_eq!(cfg.ocsp.check_interval_secs, 300);
+
+        let yaml = "listen: {}\nadmin_upstream: 'x'\nocsp:\n  stapling: false\n  checkIntervalSecs: 60";
+        let cfg: GlobalConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(!cfg.ocsp.stapling);
+        assert_eq!(cfg.ocsp.check_interval_secs, 60);
     }
 
     #[test]
@@ -464,6 +1016,16 @@ mod tests {
         assert!(cfg.compression);
         assert!(cfg.ssl.is_none());
         assert!(cfg.group_id.is_none());
+        assert!(!cfg.default_server);
+    }
+
+    #[test]
+    fn test_host_config_default_server() {
+        let cfg: HostConfig = serde_yaml::from_str("id: 1
+default_server: true").unwrap();
+        assert!(cfg.default_server);
+        let cfg: HostConfig = serde_json::from_str(r#"{"id": 1, "defaultServer": true}"#).unwrap();
+        assert!(cfg.default_server);
     }
 
     #[test]
@@ -656,6 +1218,178 @@ mod tests {
         assert!(!cfg.force_https);
         assert!(cfg.cert_path.is_none());
         assert!(cfg.key_path.is_none());
+        assert!(cfg.min_version.is_none());
+        assert!(cfg.max_version.is_none());
+        assert!(cfg.ciphers.is_none());
+        assert!(cfg.ciphersuites.is_none());
+        assert!(cfg.ocsp_file.is_none());
+    }
+
+    #[test]
+    fn test_ssl_config_ocsp_file() {
+        let yaml = "type: custom\nocspFile: /data/ssl/example.ocsp";
+        let cfg: SslConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.ocsp_file.as_deref(), Some("/data/ssl/example.ocsp"));
+    }
+
+    #[test]
+    fn test_ssl_config_tls_policy() {
+        let yaml = r#"
+type: custom
+minVersion: TLSv1.3
+max_version: TLSv1.3
+ciphers: "ECDHE+AESGCM"
+ciphersuites: "TLS_AES_256_GCM_SHA384"
+"#;
+        let cfg: SslConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.min_version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(cfg.max_version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(cfg.ciphers.as_deref(), Some("ECDHE+AESGCM"));
+        assert_eq!(cfg.ciphersuites.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+    }
+
+    #[test]
+    fn test_location_upstream_tls() {
+        let yaml = "path: /";
+        let loc: LocationConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(loc.upstream_scheme.is_none());
+        assert!(loc.upstream_tls.is_none());
+
+        let yaml = r#"
+path: /
+upstreamScheme: https
+upstreamTls:
+  sni: backend.internal
+  caBundle: /data/ssl/internal-ca.pem
+  clientCertPath: /data/ssl/proxy.pem
+  clientKeyPath: /data/ssl/proxy.key
+"#;
+        let loc: LocationConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(loc.upstream_scheme.as_deref(), Some("https"));
+        let tls = loc.upstream_tls.unwrap();
+        assert_eq!(tls.sni.as_deref(), Some("backend.internal"));
+        assert!(tls.verify, "verification is on unless disabled");
+        assert_eq!(tls.ca_bundle.as_deref(), Some("/data/ssl/internal-ca.pem"));
+        assert_eq!(tls.client_cert_path.as_deref(), Some("/data/ssl/proxy.pem"));
+        assert_eq!(tls.client_key_path.as_deref(), Some("/data/ssl/proxy.key"));
+
+        let yaml = "path: /\nupstream_scheme: https\nupstream_tls: {verify: false}";
+        let loc: LocationConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(!loc.upstream_tls.unwrap().verify);
+    }
+
+    #[test]
+    fn test_location_upstream_http2() {
+        let loc: LocationConfig = serde_yaml::from_str("path: /").unwrap();
+        assert!(!loc.upstream_http2);
+        let loc: LocationConfig = serde_yaml::from_str("path: /\nupstream_http2: true").unwrap();
+        assert!(loc.upstream_http2);
+        let loc: LocationConfig = serde_json::from_str(r#"{"path": "/", "upstreamHttp2": true}"#).unwrap();
+        assert!(loc.upstream_http2);
+    }
+
+    #[test]
+    fn test_location_conditions() {
+        let loc: LocationConfig = serde_yaml::from_str("path: /").unwrap();
+        assert!(loc.conditions.is_none());
+        let yaml = r#"
+path: /api
+conditions:
+  methods: [POST]
+  headers:
+    - name: X-Canary
+      value: "1"
+  cookies:
+    - name: session
+"#;
+        let loc: LocationConfig = serde_yaml::from_str(yaml).unwrap();
+        let cond = loc.conditions.unwrap();
+        assert_eq!(cond.methods, vec!["POST"]);
+        assert_eq!(cond.headers[0].name, "X-Canary");
+        assert_eq!(cond.headers[0].value.as_deref(), Some("1"));
+        assert!(cond.cookies[0].value.is_none());
+        assert!(cond.query.is_empty());
+    }
+
+    #[test]
+    fn test_location_rewrites() {
+        let yaml = r#"
+path: /
+rewrites:
+  - pattern: ^/old/(.*)$
+    replacement: /new/$1
+    flag: permanent
+  - pattern: ^/a$
+    replacement: /b
+"#;
+        let loc: LocationConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(loc.rewrites.len(), 2);
+        assert_eq!(loc.rewrites[0].replacement, "/new/$1");
+        assert_eq!(loc.rewrites[0].flag, "permanent");
+        assert_eq!(loc.rewrites[1].flag, "");
+    }
+
+    #[test]
+    fn test_location_health_check() {
+        let loc: LocationConfig = serde_yaml::from_str("path: /").unwrap();
+        assert!(loc.health_check.is_none());
+
+        let yaml = "path: /\nhealthCheck: {}";
+        let hc = serde_yaml::from_str::<LocationConfig>(yaml).unwrap().health_check.unwrap();
+        assert_eq!(hc.check_type, "tcp");
+        assert_eq!(hc.path, "/");
+        assert_eq!(hc.expected_status, 200);
+        assert_eq!((hc.interval_secs, hc.timeout_secs, hc.rise, hc.fall), (10, 2, 2, 3));
+
+        let yaml = r#"
+path: /api
+health_check:
+  type: http
+  path: /healthz
+  host: api.internal
+  expectedStatus: 204
+  intervalSecs: 5
+  rise: 1
+  fall: 2
+"#;
+        let hc = serde_yaml::from_str::<LocationConfig>(yaml).unwrap().health_check.unwrap();
+        assert_eq!(hc.check_type, "http");
+        assert_eq!(hc.path, "/healthz");
+        assert_eq!(hc.host.as_deref(), Some("api.internal"));
+        assert_eq!(hc.expected_status, 204);
+        assert_eq!((hc.interval_secs, hc.rise, hc.fall), (5, 1, 2));
+    }
+
+    #[test]
+    fn test_location_outlier_detection() {
+        let loc: LocationConfig = serde_yaml::from_str("path: /").unwrap();
+        assert!(loc.outlier_detection.is_none());
+
+        let yaml = "path: /\noutlierDetection: {}";
+        let od = serde_yaml::from_str::<LocationConfig>(yaml).unwrap().outlier_detection.unwrap();
+        assert_eq!((od.max_fails, od.fail_timeout_secs, od.max_ejection_secs), (1, 10, 300));
+
+        let yaml = "path: /\noutlier_detection: {maxFails: 3, failTimeoutSecs: 30, maxEjectionSecs: 600}";
+        let od = serde_yaml::from_str::<LocationConfig>(yaml).unwrap().outlier_detection.unwrap();
+        assert_eq!((od.max_fails, od.fail_timeout_secs, od.max_ejection_secs), (3, 30, 600));
+    }
+
+    #[test]
+    fn test_location_is_regex() {
+        let cases = [
+            ("prefix", false),
+            ("prefix_priority", false),
+            ("^~", false),
+            ("exact", false),
+            ("regex", true),
+            ("~", true),
+            ("regex_case_insensitive", true),
+            ("~*", true),
+        ];
+        for (match_type, regex) in cases {
+            let loc: LocationConfig = serde_yaml::from_str(&format!("path: /\nmatch_type: '{}'", match_type)).unwrap();
+            assert_eq!(loc.is_regex(), regex, "{}", match_type);
+        }
     }
 
     #[test]
@@ -794,6 +1528,24 @@ mod tests {
         let _ = fs::remove_dir_all(&dir);
     }
 
+    #[test]
+    fn test_load_orders_hosts_by_id() {
+        let dir = std::env::temp_dir().join("pingora-test-config-host-order");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(dir.join("global.yaml"), "listen: {}\nadmin_upstream: 'x'").unwrap();
+        for id in [10, 2, 1] {
+            fs::write(dir.join(format!("host-{}.yaml", id)), format!("id: {}", id)).unwrap();
+        }
+
+        let cfg = AppConfig::load(dir.to_str().unwrap()).unwrap();
+        let ids: Vec<u64> = cfg.hosts.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![1, 2, 10]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_load_access_lists_yaml() {
         let dir = std::env::temp_dir().join("pingora-test-config-acl");
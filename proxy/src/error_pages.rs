@@ -91,7 +91,7 @@ fn builtin_error_page(status_code: u16) -> ErrorPageResponse {
 }
 
 /// Get a human-readable reason for common HTTP status codes
-fn status_reason(code: u16) -> &'static str {
+pub fn status_reason(code: u16) -> &'static str {
     match code {
         400 => "Bad Request",
         401 => "Unauthorized",
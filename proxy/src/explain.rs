@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::router::percent_decode;
+
+/// Request described by the query string of the admin listener's route explain endpoint
+#[derive(Debug)]
+pub struct ExplainRequest {
+    pub host: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub method: String,
+    pub client_ip: Option<IpAddr>,
+    /// Listener port the request arrives on; None for the HTTP listener
+    pub port: Option<u16>,
+    pub headers: http::HeaderMap,
+}
+
+impl ExplainRequest {
+    /// Parse `host=..&path=..&method=..&ip=..&port=..&header=Name:value`; `path` may carry
+    /// its own (encoded) query string and `header` may repeat
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut host = None;
+        let mut request = ExplainRequest {
+            host: String::new(),
+            path: "/".to_string(),
+            query: None,
+            method: "GET".to_string(),
+            client_ip: None,
+            port: None,
+            headers: http::HeaderMap::new(),
+        };
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value);
+            match percent_decode(name).as_str() {
+                "host" => host = Some(value),
+                "path" => match value.split_once('?') {
+                    Some((path, query)) => {
+                        request.path = path.to_string();
+                        request.query = Some(query.to_string());
+                    }
+                    None => request.path = value,
+                },
+                "method" => request.method = value.to_ascii_uppercase(),
+                "ip" => {
+                    let ip = value.parse().map_err(|_| format!("invalid client ip '{}'", value))?;
+                    request.client_ip = Some(ip);
+                }
+                "port" => {
+                    let port = value.parse().map_err(|_| format!("invalid port '{}'", value))?;
+                    request.port = Some(port);
+                }
+                "header" => {
+                    let (name, value) = value
+                        .split_once(':')
+                        .ok_or_else(|| format!("header '{}' is not 'Name: value'", value))?;
+                    let name = http::header::HeaderName::from_bytes(name.trim().as_bytes())
+                        .map_err(|_| format!("invalid header name '{}'", name))?;
+                    // Credentials are never checked, so the endpoint cannot be used to guess them
+                    if name == http::header::AUTHORIZATION {
+                        return Err("the Authorization header is not evaluated".to_string());
+                    }
+                    let value = http::header::HeaderValue::from_str(value.trim())
+                        .map_err(|_| format!("invalid value for header '{}'", name))?;
+                    request.headers.append(name, value);
+                }
+                _ => {}
+            }
+        }
+        request.host = host.ok_or("missing 'host' parameter")?;
+        Ok(request)
+    }
+}
+
+/// Routing decisions recorded while a request is resolved
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RouteTrace {
+    pub host_id: Option<u64>,
+    /// Location serving the request, after rewrite rules matched again
+    pub location_index: Option<usize>,
+    pub access_list_id: Option<u64>,
+    /// "allowed", "denied", "auth_required" or "client_cert_required"
+    pub access: Option<&'static str>,
+}
+
+/// JSON document served by the admin listener's route explain endpoint
+#[derive(Debug, Serialize)]
+pub struct RouteExplanation {
+    pub host: String,
+    /// Path after normalization, before rewrite rules
+    pub path: String,
+    pub host_id: Option<u64>,
+    pub location_index: Option<usize>,
+    pub location_path: Option<String>,
+    pub match_type: Option<String>,
+    pub access_list_id: Option<u64>,
+    /// "allowed", "denied", "auth_required" or "client_cert_required"; None without an
+    /// access list. Credentials are not evaluated and no client certificate is presented.
+    pub access: Option<&'static str>,
+    /// What the proxy would do with the request
+    pub action: serde_json::Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_full_request() {
+        let req = ExplainRequest::parse(
+            "host=app.com&path=/api/items%3Fpage%3D2&method=post&ip=10.0.0.5&port=443\
+             &header=X-Canary:%201&header=Cookie:%20a=1",
+        )
+        .unwrap();
+        assert_eq!(req.host, "app.com");
+        assert_eq!(req.path, "/api/items");
+        assert_eq!(req.query.as_deref(), Some("page=2"));
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.client_ip, Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(req.port, Some(443));
+        assert_eq!(req.headers.get("x-canary").unwrap(), "1");
+        assert_eq!(req.headers.get("cookie").unwrap(), "a=1");
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let req = ExplainRequest::parse("host=app.com").unwrap();
+        assert_eq!(req.path, "/");
+        assert_eq!(req.method, "GET");
+        assert!(req.query.is_none() && req.client_ip.is_none() && req.port.is_none());
+        assert!(req.headers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ExplainRequest::parse("path=/").unwrap_err().contains("host"));
+        assert!(ExplainRequest::parse("host=a.com&ip=nope").unwrap_err().contains("client ip"));
+        assert!(ExplainRequest::parse("host=a.com&port=99999").unwrap_err().contains("port"));
+        assert!(ExplainRequest::parse("host=a.com&header=NoColon").is_err());
+        assert!(ExplainRequest::parse("host=a.com&header=Bad%20Name:1").is_err());
+        let err = ExplainRequest::parse("host=a.com&header=authorization:%20Basic%20eDp5").unwrap_err();
+        assert!(err.contains("Authorization"));
+    }
+}
@@ -0,0 +1,117 @@
+use pingora_core::ErrorType;
+use pingora_http::ResponseHeader;
+
+use crate::error_pages::status_reason;
+
+/// gRPC status codes used for proxy-generated errors
+pub const UNKNOWN: u32 = 2;
+pub const DEADLINE_EXCEEDED: u32 = 4;
+pub const PERMISSION_DENIED: u32 = 7;
+pub const UNIMPLEMENTED: u32 = 12;
+pub const INTERNAL: u32 = 13;
+pub const UNAVAILABLE: u32 = 14;
+pub const UNAUTHENTICATED: u32 = 16;
+
+/// gRPC status for an HTTP status the proxy would otherwise have answered with
+/// (the mapping gRPC clients apply to non-gRPC responses)
+pub fn status_from_http(code: u16) -> u32 {
+    match code {
+        400 => INTERNAL,
+        401 => UNAUTHENTICATED,
+        403 => PERMISSION_DENIED,
+        404 => UNIMPLEMENTED,
+        429 | 502 | 503 | 504 => UNAVAILABLE,
+        _ => UNKNOWN,
+    }
+}
+
+/// gRPC status for a proxy failure; timeouts talking to the upstream are reported as deadlines
+pub fn status_for_error(etype: &ErrorType, code: u16) -> u32 {
+    match etype {
+        ErrorType::ReadTimedout | ErrorType::WriteTimedout => DEADLINE_EXCEEDED,
+        ErrorType::ConnectTimedout
+        | ErrorType::ConnectRefused
+        | ErrorType::ConnectNoRoute
+        | ErrorType::ConnectError
+        | ErrorType::TLSHandshakeFailure
+        | ErrorType::InvalidCert => UNAVAILABLE,
+        _ => status_from_http(code),
+    }
+}
+
+/// `grpc-message` describing a proxy failure, e.g. "Bad Gateway (ConnectRefused)"
+pub fn error_message(etype: &ErrorType, code: u16) -> String {
+    match etype {
+        ErrorType::HTTPStatus(_) => status_reason(code).to_string(),
+        other => format!("{} ({})", status_reason(code), other.as_str()),
+    }
+}
+
+/// Trailers-Only response: HTTP 200 whose headers carry the gRPC status and end the stream
+pub fn error_response(status: u32, message: &str) -> ResponseHeader {
+    let mut resp = ResponseHeader::build(200, Some(3)).unwrap();
+    let _ = resp.insert_header(http::header::CONTENT_TYPE, "application/grpc");
+    let _ = resp.insert_header("grpc-status", status.to_string());
+    let _ = resp.insert_header("grpc-message", percent_encode(message));
+    resp
+}
+
+/// Percent-encode a `grpc-message` value: everything outside printable ASCII, and '%'
+fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_from_http() {
+        assert_eq!(status_from_http(401), UNAUTHENTICATED);
+        assert_eq!(status_from_http(403), PERMISSION_DENIED);
+        assert_eq!(status_from_http(404), UNIMPLEMENTED);
+        assert_eq!(status_from_http(502), UNAVAILABLE);
+        assert_eq!(status_from_http(504), UNAVAILABLE);
+        assert_eq!(status_from_http(500), UNKNOWN);
+    }
+
+    #[test]
+    fn test_status_for_error() {
+        assert_eq!(status_for_error(&ErrorType::ConnectRefused, 502), UNAVAILABLE);
+        assert_eq!(status_for_error(&ErrorType::ReadTimedout, 502), DEADLINE_EXCEEDED);
+        assert_eq!(status_for_error(&ErrorType::HTTPStatus(403), 403), PERMISSION_DENIED);
+        assert_eq!(status_for_error(&ErrorType::InternalError, 500), UNKNOWN);
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(error_message(&ErrorType::ConnectRefused, 502), "Bad Gateway (ConnectRefused)");
+        assert_eq!(error_message(&ErrorType::HTTPStatus(404), 404), "Not Found");
+    }
+
+    #[test]
+    fn test_error_response_is_trailers_only() {
+        let resp = error_response(UNAVAILABLE, "Bad Gateway (ConnectRefused)");
+        assert_eq!(resp.status.as_u16(), 200);
+        assert_eq!(resp.headers.get("content-type").unwrap(), "application/grpc");
+        assert_eq!(resp.headers.get("grpc-status").unwrap(), "14");
+        assert_eq!(resp.headers.get("grpc-message").unwrap(), "Bad Gateway (ConnectRefused)");
+        assert!(resp.headers.get("content-length").is_none());
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("no upstream"), "no upstream");
+        assert_eq!(percent_encode("100% done"), "100%25 done");
+        assert_eq!(percent_encode("line\nbreak"), "line%0Abreak");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+    }
+}
@@ -0,0 +1,6 @@
+//! Configuration model and request routing, shared by the proxy binary and the benches.
+
+pub mod config;
+pub mod rewrite;
+pub mod router;
+pub mod trie;
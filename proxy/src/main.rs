@@ -1,23 +1,31 @@
 mod access_control;
-mod config;
+mod acme;
 mod error_pages;
-mod router;
+mod explain;
+mod grpc;
 mod ssl;
 mod static_files;
 mod streams;
 mod log_writer;
+mod ocsp;
+mod tls;
 mod upstream;
 
+use pingora_manager_proxy::{config, rewrite, router};
+
 use async_trait::async_trait;
 use config::AppConfig;
 use pingora_core::modules::http::HttpModules;
 use pingora_core::modules::http::compression::{ResponseCompressionBuilder, ResponseCompression};
 use pingora_core::prelude::*;
+use pingora_core::protocols::ALPN;
 use pingora_core::upstreams::peer::Peer;
 use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::{http_proxy_service, ProxyHttp, Session};
-use router::Router;
+use router::{expand_captures, RequestView, Router};
+use serde_json::json;
 use ssl::SslCertManager;
+use std::borrow::Cow;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Duration;
@@ -25,14 +33,58 @@ use upstream::UpstreamSelector;
 
 const CONFIGS_DIR: &str = "/data/configs";
 
+/// Set by SIGHUP or after ACME issuance; the reload thread polls it every second
+static RELOAD_REQUESTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Admin listener path answered with the certificate expiry report (local clients only)
+const CERTIFICATE_REPORT_PATH: &str = "/_proxy/certificates";
+
+/// Admin listener path that explains how a described request would be routed (local clients only)
+const ROUTE_EXPLAIN_PATH: &str = "/_proxy/explain";
+
+/// How often certificate expiry warnings are repeated between reloads
+const CERT_EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6 * 3600);
+
+/// Location re-matches allowed after rewrites before answering 500 (as nginx)
+const REWRITE_MAX_CYCLES: usize = 10;
+
+/// Concurrent streams per HTTP/2 upstream connection
+const UPSTREAM_H2_MAX_STREAMS: usize = 100;
+
+/// Handling of requests whose host matches no `HostConfig` (`global.unknown_host`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnknownHost {
+    DefaultPage,
+    NotFound,
+    Close,
+}
+
+impl UnknownHost {
+    fn from_config(value: &str) -> Self {
+        match value {
+            "default_page" => UnknownHost::DefaultPage,
+            "not_found" => UnknownHost::NotFound,
+            "close" => UnknownHost::Close,
+            other => {
+                log::warn!("Unknown global.unknown_host '{}', using 'default_page'", other);
+                UnknownHost::DefaultPage
+            }
+        }
+    }
+}
+
 /// Shared application state that can be reloaded via SIGHUP.
 /// Uses Arc<str> for frequently-cloned strings to avoid allocation.
 struct SharedState {
     config: AppConfig,
     router: Router,
-    ssl_manager: SslCertManager,
+    ssl_manager: Arc<SslCertManager>,
     /// Location-level load balancers keyed by (host_id, location_index)
-    location_lbs: std::collections::HashMap<(u64, usize), UpstreamSelector>,
+    location_lbs: std::collections::HashMap<(u64, usize), Arc<UpstreamSelector>>,
+    /// Upstream TLS settings of `upstream_scheme: https` locations, same keys
+    location_tls: std::collections::HashMap<(u64, usize), Arc<upstream::UpstreamTls>>,
+    /// Passive outlier ejection of locations with `outlier_detection`, same keys
+    location_outliers: std::collections::HashMap<(u64, usize), Arc<upstream::OutlierDetector>>,
     /// Pre-formatted upstream addresses: SocketAddr → Arc<str>
     /// Avoids per-request to_string() allocation in resolve_request
     addr_cache: std::collections::HashMap<SocketAddr, Arc<str>>,
@@ -42,17 +94,23 @@ struct SharedState {
     error_pages_dir: Arc<str>,
     /// Cached default_page path (Arc<str>)
     default_page: Arc<str>,
+    /// Response to requests no host matches
+    unknown_host: UnknownHost,
     /// Pre-formatted per-host log file paths: host_id → (access_log_path, error_log_path)
     host_log_paths: std::collections::HashMap<u64, (Arc<str>, Arc<str>)>,
     log_sender: log_writer::LogSender,
 }
 
 impl SharedState {
-    fn build(config: AppConfig, log_sender: log_writer::LogSender) -> Self {
+    /// Build the state for `config`. Upstream health and outlier ejections are carried over
+    /// from `previous`, the state being replaced on reload, for locations with the same host and path.
+    fn build(config: AppConfig, log_sender: log_writer::LogSender, previous: Option<&SharedState>) -> Self {
         let router = Router::build(&config.hosts);
-        let ssl_manager = SslCertManager::build(&config);
+        let ssl_manager = Arc::new(SslCertManager::build(&config));
 
         let mut location_lbs = std::collections::HashMap::new();
+        let mut location_tls = std::collections::HashMap::new();
+        let mut location_outliers = std::collections::HashMap::new();
 
         for host in &config.hosts {
             if !host.enabled {
@@ -61,8 +119,41 @@ impl SharedState {
             // Build location-level load balancers
             for (i, loc) in host.locations.iter().enumerate() {
                 if !loc.upstreams.is_empty() {
-                    if let Some(lb) = upstream::create_upstream_selector(&loc.upstreams, &loc.balance_method) {
-                        location_lbs.insert((host.id, i), lb);
+                    // Never fall back to plaintext: a location with broken TLS settings gets no upstreams
+                    let tls = match upstream::UpstreamTls::from_location(loc) {
+                        Ok(tls) => tls.map(Arc::new),
+                        Err(e) => {
+                            log::error!("Host {} location {}: upstream TLS: {}", host.id, loc.path, e);
+                            continue;
+                        }
+                    };
+                    // A broken health check leaves the upstreams unchecked rather than unreachable
+                    let mut health_check = match upstream::ActiveHealthCheck::from_location(host.id, loc, tls.as_deref()) {
+                        Ok(hc) => hc,
+                        Err(e) => {
+                            log::error!("Host {} location {}: health check: {}", host.id, loc.path, e);
+                            None
+                        }
+                    };
+                    // The same location in the previous state, wherever it now sits
+                    let previous_key = previous
+                        .and_then(|p| Some((p, (host.id, p.location_index(host.id, loc)?))));
+                    let previous_lb = previous_key.and_then(|(p, key)| p.location_lbs.get(&key));
+                    if let (Some(hc), Some(lb)) = (health_check.as_mut(), previous_lb) {
+                        hc.carry_over(lb);
+                    }
+                    if let Some(tls) = tls {
+                        location_tls.insert((host.id, i), tls);
+                    }
+                    if let Some(lb) = upstream::create_upstream_selector(&loc.upstreams, &loc.balance_method, health_check) {
+                        location_lbs.insert((host.id, i), Arc::new(lb));
+                        if let Some(outliers) = upstream::OutlierDetector::from_location(host.id, loc) {
+                            let previous_outliers = previous_key.and_then(|(p, key)| p.location_outliers.get(&key));
+                            if let Some(previous_outliers) = previous_outliers {
+                                outliers.carry_over(previous_outliers);
+                            }
+                            location_outliers.insert((host.id, i), Arc::new(outliers));
+                        }
                     }
                 }
             }
@@ -94,20 +185,88 @@ impl SharedState {
         let admin_upstream: Arc<str> = config.global.admin_upstream.as_str().into();
         let error_pages_dir: Arc<str> = config.global.error_pages_dir.as_str().into();
         let default_page: Arc<str> = config.global.default_page.as_str().into();
+        let unknown_host = UnknownHost::from_config(&config.global.unknown_host);
 
         SharedState {
             config,
             router,
             ssl_manager,
             location_lbs,
+            location_tls,
+            location_outliers,
             addr_cache,
             admin_upstream,
             error_pages_dir,
             default_page,
+            unknown_host,
             host_log_paths,
             log_sender,
         }
     }
+
+    /// Index of the location of host `host_id` that matches the same requests as `loc`.
+    /// Locations sharing a path differ by match type or conditions, so the path alone is ambiguous.
+    fn location_index(&self, host_id: u64, loc: &config::LocationConfig) -> Option<usize> {
+        self.config
+            .hosts
+            .iter()
+            .find(|h| h.id == host_id)?
+            .locations
+            .iter()
+            .position(|l| l.same_route(loc))
+    }
+}
+
+/// A request made directly from this machine (the web app), not relayed by a proxy.
+/// Relayed requests carry the X-Forwarded-For header this proxy adds upstream.
+fn is_local_client(client_ip: Option<IpAddr>, request: &RequestView) -> bool {
+    client_ip.is_some_and(|ip| ip.to_canonical().is_loopback())
+        && !request.headers.is_some_and(|h| h.contains_key("x-forwarded-for"))
+}
+
+/// Host the request is addressed to: the Host header, or the URI authority
+/// (HTTP/2 `:authority`) when the header is absent
+fn request_host(req: &RequestHeader) -> Option<&str> {
+    match req.headers.get(http::header::HOST) {
+        Some(value) => value.to_str().ok(),
+        None => req.uri.authority().map(|authority| authority.as_str()),
+    }
+}
+
+/// Upstream path rewrite configured by a proxy location's `forward_path`
+#[derive(Debug, Clone, PartialEq)]
+enum PathRewrite {
+    /// Swap the location prefix for the replacement, keeping the rest of the path
+    Prefix { prefix: Arc<str>, replacement: Arc<str> },
+    /// Replace the whole path (regex locations, captures already expanded)
+    Full(String),
+    /// Final path and query from the location's rewrite rules
+    Uri(String),
+}
+
+impl PathRewrite {
+    /// Rewritten path and query; None when the rewrite does not apply
+    fn rewrite(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let new_path = match self {
+            PathRewrite::Prefix { prefix, replacement } => {
+                let suffix = path.strip_prefix(prefix.as_ref())?;
+                format!("{}{}", replacement, suffix)
+            }
+            PathRewrite::Full(path) => path.clone(),
+            PathRewrite::Uri(uri) => return Some(uri.clone()),
+        };
+        // A query in the rewritten path comes first, the original one is appended
+        Some(match query {
+            Some(q) if new_path.contains('?') => format!("{}&{}", new_path, q),
+            Some(q) => format!("{}?{}", new_path, q),
+            None => new_path,
+        })
+    }
+
+    /// Rewritten URI keeping the query string; None when the rewrite does not apply
+    fn apply(&self, uri: &http::Uri) -> Option<http::Uri> {
+        self.rewrite(uri.path(), uri.query())?.parse().ok()
+    }
 }
 
 /// Outcome of the synchronous request routing phase (no borrows held after this)
@@ -122,8 +281,20 @@ enum RequestAction {
         compression: bool,
         /// Pre-compiled custom headers from the matched location (cheap Arc clones)
         custom_headers: Vec<(http::header::HeaderName, Arc<str>)>,
-        /// Path rewrite: (location_prefix, forward_path) — rewrites prefix before proxying
-        rewrite_path: Option<(Arc<str>, Arc<str>)>,
+        /// Path rewrite from the location's forward_path
+        rewrite_path: Option<PathRewrite>,
+        /// Verified client certificate, forwarded in `X-Client-Cert-*` headers
+        client_identity: Option<access_control::ClientIdentity>,
+        /// TLS settings when the location proxies over HTTPS
+        upstream_tls: Option<Arc<upstream::UpstreamTls>>,
+        /// SNI for the selected upstream (empty: none sent)
+        upstream_sni: String,
+        /// Speak HTTP/2 (h2 or h2c) to the upstream
+        upstream_http2: bool,
+        /// gRPC location: failures are reported as gRPC statuses
+        grpc: bool,
+        /// Outlier detection told how the request to the selected upstream went
+        outliers: Option<(Arc<upstream::OutlierDetector>, SocketAddr)>,
     },
     /// Send a redirect response (from a redirect-type location)
     Redirect {
@@ -138,6 +309,8 @@ enum RequestAction {
     ServeStatic {
         static_dir: Arc<str>,
         location_path: Arc<str>,
+        /// Request path after rewrite rules, when they changed it
+        rewritten_path: Option<String>,
         cache_expires: Option<Arc<str>>,
         host_id: Option<u64>,
         group_id: Option<u64>,
@@ -160,34 +333,145 @@ enum RequestAction {
         default_page: Arc<str>,
         error_pages_dir: Arc<str>,
     },
-    /// Access denied (403)
+    /// No matching host, answered with the 404 error page
+    NotFound {
+        error_pages_dir: Arc<str>,
+    },
+    /// No matching host, connection closed without a response
+    Close,
+    /// Access denied (403, or gRPC PERMISSION_DENIED for gRPC locations)
     AccessDenied {
         error_pages_dir: Arc<str>,
         host_id: Option<u64>,
         group_id: Option<u64>,
+        grpc: bool,
+    },
+    /// Auth required (401, or gRPC UNAUTHENTICATED for gRPC locations)
+    AuthRequired {
+        grpc: bool,
+    },
+    /// Rewrite rules kept re-matching locations (500)
+    RewriteCycle {
+        error_pages_dir: Arc<str>,
+        host_id: Option<u64>,
+        group_id: Option<u64>,
+    },
+    /// Rewrite rules produced a path that normalization rejects (400)
+    RejectedRewrite {
+        error_pages_dir: Arc<str>,
+        host_id: Option<u64>,
+        group_id: Option<u64>,
     },
-    /// Auth required (401)
-    AuthRequired,
     /// Serve ACME challenge response
     AcmeChallenge {
         token: String,
     },
-    /// No upstream available (502)
+    /// Certificate expiry report (admin listener, local clients only)
+    CertificateReport {
+        json: String,
+    },
+    /// Route explanation (admin listener, local clients only); 400 when the query is invalid
+    RouteExplain {
+        status: u16,
+        json: String,
+    },
+    /// No upstream available (502, or gRPC UNAVAILABLE for gRPC locations)
     NoUpstream {
         error_pages_dir: Arc<str>,
         host_id: Option<u64>,
         group_id: Option<u64>,
+        grpc: bool,
     },
 }
 
+impl RequestAction {
+    /// JSON summary for the route explain endpoint; `path` and `query` give the upstream URI
+    fn describe(&self, path: &str, query: Option<&str>) -> serde_json::Value {
+        match self {
+            RequestAction::Proxy {
+                upstream_addr,
+                host_id,
+                rewrite_path,
+                upstream_tls,
+                upstream_sni,
+                upstream_http2,
+                grpc,
+                custom_headers,
+                ..
+            } => {
+                let uri = rewrite_path
+                    .as_ref()
+                    .and_then(|r| r.rewrite(path, query))
+                    .unwrap_or_else(|| match query {
+                        Some(q) => format!("{}?{}", path, q),
+                        None => path.to_string(),
+                    });
+                json!({
+                    "type": if *grpc { "grpc" } else { "proxy" },
+                    "host_id": host_id,
+                    "upstream": upstream_addr.as_ref(),
+                    "scheme": if upstream_tls.is_some() { "https" } else { "http" },
+                    "sni": upstream_sni,
+                    "http2": upstream_http2,
+                    "upstream_uri": uri,
+                    "response_headers": custom_headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect::<Vec<_>>(),
+                })
+            }
+            RequestAction::Redirect { status_code, location } => {
+                json!({ "type": "redirect", "status": status_code, "location": location })
+            }
+            RequestAction::ForceHttps { location } => {
+                json!({ "type": "force_https", "status": 301, "location": location })
+            }
+            RequestAction::ServeStatic { static_dir, location_path, rewritten_path, .. } => json!({
+                "type": "static",
+                "static_dir": static_dir.as_ref(),
+                "location_path": location_path.as_ref(),
+                "request_path": rewritten_path.as_deref().unwrap_or(path),
+            }),
+            RequestAction::ServeFile { file_path, .. } => json!({ "type": "file", "file_path": file_path.as_ref() }),
+            RequestAction::ServeDefault { .. } => json!({ "type": "default_page" }),
+            RequestAction::NotFound { .. } => json!({ "type": "not_found", "status": 404 }),
+            RequestAction::Close => json!({ "type": "close" }),
+            RequestAction::AccessDenied { grpc, .. } => json!({
+                "type": "access_denied",
+                "status": if *grpc { 200 } else { 403 },
+                "grpc_status": if *grpc { Some(grpc::PERMISSION_DENIED) } else { None },
+            }),
+            RequestAction::AuthRequired { grpc } => json!({
+                "type": "auth_required",
+                "status": if *grpc { 200 } else { 401 },
+                "grpc_status": if *grpc { Some(grpc::UNAUTHENTICATED) } else { None },
+            }),
+            RequestAction::RewriteCycle { .. } => json!({ "type": "rewrite_cycle", "status": 500 }),
+            RequestAction::RejectedRewrite { .. } => json!({ "type": "rejected_rewrite", "status": 400 }),
+            RequestAction::AcmeChallenge { token } => json!({ "type": "acme_challenge", "token": token }),
+            RequestAction::CertificateReport { .. } => json!({ "type": "certificate_report" }),
+            RequestAction::RouteExplain { .. } => json!({ "type": "route_explain" }),
+            RequestAction::NoUpstream { grpc, .. } => json!({
+                "type": "no_upstream",
+                "status": if *grpc { 200 } else { 502 },
+                "grpc_status": if *grpc { Some(grpc::UNAVAILABLE) } else { None },
+            }),
+        }
+    }
+}
+
 /// Per-request context passed through the ProxyHttp callbacks
 pub struct ProxyCtx {
     /// The selected upstream address (host:port) — Arc<str> avoids String clone
     upstream_addr: Option<Arc<str>>,
-    /// Whether this request should use TLS to upstream
-    upstream_tls: bool,
+    /// TLS settings when this request goes to the upstream over HTTPS
+    upstream_tls: Option<Arc<upstream::UpstreamTls>>,
     /// SNI for upstream TLS
     upstream_sni: String,
+    /// Whether the upstream connection uses HTTP/2
+    upstream_http2: bool,
+    /// Request matched a gRPC location
+    grpc: bool,
     /// Host ID for error page resolution
     host_id: Option<u64>,
     /// Group ID for error page resolution
@@ -206,15 +490,21 @@ pub struct ProxyCtx {
     error_log_path: Option<Arc<str>>,
     /// Async log sender (non-blocking channel send instead of file I/O)
     log_sender: log_writer::LogSender,
-    /// Path rewrite: (location_prefix, forward_path) for upstream URI rewriting
-    rewrite_path: Option<(Arc<str>, Arc<str>)>,
+    /// Path rewrite applied to the upstream URI
+    rewrite_path: Option<PathRewrite>,
+    /// Client certificate identity verified by the location's access list
+    client_identity: Option<access_control::ClientIdentity>,
+    /// Outlier detection of the selected upstream, told the outcome in `logging`
+    outliers: Option<(Arc<upstream::OutlierDetector>, SocketAddr)>,
 }
 
 impl ProxyCtx {
     fn new(error_pages_dir: Arc<str>, log_sender: log_writer::LogSender) -> Self {
         ProxyCtx {
             upstream_addr: None,
-            upstream_tls: false,
+            upstream_tls: None,
+            upstream_http2: false,
+            grpc: false,
             upstream_sni: String::new(),
             host_id: None,
             group_id: None,
@@ -226,6 +516,8 @@ impl ProxyCtx {
             error_log_path: None,
             log_sender,
             rewrite_path: None,
+            client_identity: None,
+            outliers: None,
         }
     }
 
@@ -238,34 +530,199 @@ impl ProxyCtx {
             }
         }
     }
+
+    /// Build the peer for the resolved upstream, applying its TLS and protocol settings
+    fn build_upstream_peer(&self) -> Result<HttpPeer> {
+        let addr = self
+            .upstream_addr
+            .as_ref()
+            .ok_or_else(|| {
+                pingora_core::Error::because(
+                    pingora_core::ErrorType::ConnectNoRoute,
+                    "no upstream address resolved",
+                    pingora_core::Error::new(pingora_core::ErrorType::ConnectNoRoute),
+                )
+            })?;
+
+        let mut peer = HttpPeer::new(
+            addr.as_ref(),
+            self.upstream_tls.is_some(),
+            self.upstream_sni.clone(),
+        );
+        if let Some(ref tls) = self.upstream_tls {
+            tls.configure(&mut peer);
+        }
+
+        // Configure connection pooling and keepalive for better performance
+        let options = peer.get_mut_peer_options().unwrap();
+        options.connection_timeout = Some(Duration::from_secs(5));
+        options.total_connection_timeout = Some(Duration::from_secs(10));
+        options.read_timeout = Some(Duration::from_secs(60));
+        options.write_timeout = Some(Duration::from_secs(60));
+        options.idle_timeout = Some(Duration::from_secs(60));
+        if self.upstream_http2 {
+            // h2 only via ALPN over TLS; prior-knowledge h2c on plaintext
+            options.alpn = ALPN::H2;
+            // Multiplex concurrent requests over one connection
+            options.max_h2_streams = UPSTREAM_H2_MAX_STREAMS;
+        }
+
+        Ok(peer)
+    }
+}
+
+/// Client side of a request, as checked against access lists
+#[derive(Clone, Copy)]
+struct Client<'a> {
+    ip: Option<IpAddr>,
+    auth_header: Option<&'a str>,
+    cert: Option<&'a access_control::ClientCert>,
 }
 
 /// The main proxy application.
 /// Uses arc_swap::ArcSwap for lock-free read access on the hot path.
 pub struct ProxyApp {
     state: Arc<arc_swap::ArcSwap<SharedState>>,
+    /// In-memory HTTP-01 responses from the built-in ACME client (survive reloads)
+    acme_challenges: Arc<acme::ChallengeStore>,
 }
 
 impl ProxyApp {
-    fn new(state: Arc<arc_swap::ArcSwap<SharedState>>) -> Self {
-        ProxyApp { state }
+    fn new(
+        state: Arc<arc_swap::ArcSwap<SharedState>>,
+        acme_challenges: Arc<acme::ChallengeStore>,
+    ) -> Self {
+        ProxyApp {
+            state,
+            acme_challenges,
+        }
+    }
+
+    /// `uri` with its path normalized for routing, `None` when it is already normal.
+    /// The admin listener is not routed by host: its requests reach the admin app unchanged.
+    fn normalized_uri(&self, uri: &http::Uri, server_port: Option<u16>) -> Result<Option<http::Uri>, &'static str> {
+        if server_port == Some(self.state.load().config.global.listen.admin) {
+            return Ok(None);
+        }
+        let path = match router::normalize_path(uri.path())? {
+            Cow::Owned(path) => path,
+            Cow::Borrowed(_) => return Ok(None),
+        };
+        let path_and_query = match uri.query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        Ok(http::Uri::from_parts(parts).ok())
+    }
+
+    /// Explain how the request described by `query` would be routed, without sending it
+    fn explain_route(&self, query: &str) -> Result<String, String> {
+        let req = explain::ExplainRequest::parse(query)?;
+        let path = router::normalize_path(&req.path).map_err(|e| format!("path rejected: {}", e))?;
+        let state = self.state.load();
+        let port = req.port.unwrap_or(state.config.global.listen.http);
+        if port == state.config.global.listen.admin {
+            return Err("the admin listener is not routed by host".to_string());
+        }
+        let view = RequestView {
+            path: &path,
+            method: &req.method,
+            query: req.query.as_deref(),
+            headers: Some(&req.headers),
+        };
+        let mut trace = explain::RouteTrace::default();
+        // No credentials or certificate: only the client IP is evaluated
+        let client = Client {
+            ip: req.client_ip,
+            auth_header: None,
+            cert: None,
+        };
+        let action = self.route(Some(&req.host), &view, Some(port), &client, &mut trace);
+
+        let location = match (trace.host_id, trace.location_index) {
+            (Some(host_id), Some(index)) => state
+                .config
+                .hosts
+                .iter()
+                .find(|h| h.id == host_id)
+                .and_then(|h| h.locations.get(index)),
+            _ => None,
+        };
+        let explanation = explain::RouteExplanation {
+            host: req.host.clone(),
+            path: path.to_string(),
+            host_id: trace.host_id,
+            location_index: trace.location_index,
+            location_path: location.map(|l| l.path.clone()),
+            match_type: location.map(|l| l.match_type.clone()),
+            access_list_id: trace.access_list_id,
+            access: trace.access,
+            action: action.describe(&path, req.query.as_deref()),
+        };
+        serde_json::to_string(&explanation).map_err(|e| e.to_string())
     }
 
     /// Determine the action for this request. Lock-free read via ArcSwap.
     fn resolve_request(
         &self,
         host_header: Option<&str>,
-        path: &str,
+        request: &RequestView,
         server_port: Option<u16>,
         client_ip: Option<IpAddr>,
         auth_header: Option<&str>,
+        client_cert: Option<&access_control::ClientCert>,
+    ) -> RequestAction {
+        let client = Client {
+            ip: client_ip,
+            auth_header,
+            cert: client_cert,
+        };
+        self.route(host_header, request, server_port, &client, &mut explain::RouteTrace::default())
+    }
+
+    /// `resolve_request`, recording the host, location and access verdict in `trace`
+    fn route(
+        &self,
+        host_header: Option<&str>,
+        request: &RequestView,
+        server_port: Option<u16>,
+        client: &Client,
+        trace: &mut explain::RouteTrace,
     ) -> RequestAction {
+        let Client {
+            ip: client_ip,
+            auth_header,
+            cert: client_cert,
+        } = *client;
         let state = self.state.load();
         let host_str = host_header.unwrap_or("");
+        let path = request.path;
 
         // Check if this is an admin port request
         if let Some(port) = server_port {
             if port == state.config.global.listen.admin {
+                // The proxy's own endpoints are answered for the web app in this container
+                // only; other clients reach these paths through the admin app and its login
+                if is_local_client(client_ip, request) {
+                    if path == CERTIFICATE_REPORT_PATH {
+                        let report = state.ssl_manager.expiry_report(
+                            state.config.global.cert_expiry_warn_days,
+                            chrono::Utc::now(),
+                        );
+                        return RequestAction::CertificateReport {
+                            json: serde_json::to_string(&report).unwrap_or_else(|_| "{}".to_string()),
+                        };
+                    }
+                    if path == ROUTE_EXPLAIN_PATH {
+                        let (status, json) = match self.explain_route(request.query.unwrap_or("")) {
+                            Ok(json) => (200, json),
+                            Err(e) => (400, json!({ "error": e }).to_string()),
+                        };
+                        return RequestAction::RouteExplain { status, json };
+                    }
+                }
                 return RequestAction::Proxy {
                     upstream_addr: Arc::clone(&state.admin_upstream),
                     host_id: None,
@@ -274,6 +731,12 @@ impl ProxyApp {
                     compression: true,
                     custom_headers: Vec::new(),
                     rewrite_path: None,
+                    client_identity: None,
+                    upstream_tls: None,
+                    upstream_http2: false,
+                    upstream_sni: String::new(),
+                    grpc: false,
+                    outliers: None,
                 };
             }
         }
@@ -288,16 +751,24 @@ impl ProxyApp {
         }
 
         // Resolve host and location (router returns index directly, no ptr::eq scan needed)
-        let resolved = state.router.resolve(host_str, path);
+        let resolved = state.router.resolve(host_str, request);
         if resolved.is_none() {
-            return RequestAction::ServeDefault {
-                default_page: Arc::clone(&state.default_page),
-                error_pages_dir: Arc::clone(&state.error_pages_dir),
+            return match state.unknown_host {
+                UnknownHost::DefaultPage => RequestAction::ServeDefault {
+                    default_page: Arc::clone(&state.default_page),
+                    error_pages_dir: Arc::clone(&state.error_pages_dir),
+                },
+                UnknownHost::NotFound => RequestAction::NotFound {
+                    error_pages_dir: Arc::clone(&state.error_pages_dir),
+                },
+                UnknownHost::Close => RequestAction::Close,
             };
         }
 
-        let (host_config, location, loc_idx) = resolved.unwrap();
+        let (host_config, mut location, mut loc_idx) = resolved.unwrap();
         let host_id = Some(host_config.id);
+        trace.host_id = host_id;
+        trace.location_index = loc_idx;
         let group_id = host_config.group_id;
         let hsts = host_config.hsts;
 
@@ -327,23 +798,114 @@ impl ProxyApp {
             }
         }
 
+        // Location rewrite rules; unless a rule says `break`, the new URI is matched
+        // against the locations again (and their rules run in turn)
+        let mut rewritten: Option<rewrite::Rewritten> = None;
+        for cycle in 0.. {
+            let rules = loc_idx.map_or(&[][..], |idx| state.router.rewrites(host_config.id, idx));
+            let (cur_path, cur_query) = match rewritten {
+                Some(ref rw) => (rw.path.as_str(), rw.query.as_deref()),
+                None => (path, request.query),
+            };
+            let mut result = match rewrite::apply(rules, cur_path, cur_query) {
+                Some(result) => result,
+                None => break,
+            };
+            // The new path is normalized like the request path, so `//admin` or `..`
+            // segments cannot slip past the access list of the location they end up in
+            if !matches!(result.flag, rewrite::Flag::Redirect | rewrite::Flag::Permanent) {
+                match router::normalize_path(&result.path) {
+                    Ok(Cow::Owned(normalized)) => result.path = normalized,
+                    Ok(Cow::Borrowed(_)) => {}
+                    Err(reason) => {
+                        log::debug!("Host {}: rewritten path {:?} rejected: {}", host_config.id, result.path, reason);
+                        return RequestAction::RejectedRewrite {
+                            error_pages_dir: Arc::clone(&state.error_pages_dir),
+                            host_id,
+                            group_id,
+                        };
+                    }
+                }
+            }
+            match result.flag {
+                rewrite::Flag::Redirect | rewrite::Flag::Permanent => {
+                    let scheme = if server_port == Some(state.config.global.listen.https) { "https" } else { "http" };
+                    return RequestAction::Redirect {
+                        status_code: if result.flag == rewrite::Flag::Permanent { 301 } else { 302 },
+                        location: result.redirect_url(scheme, host_str),
+                    };
+                }
+                rewrite::Flag::Break => {
+                    rewritten = Some(result);
+                    break;
+                }
+                rewrite::Flag::Continue | rewrite::Flag::Last => {
+                    if cycle >= REWRITE_MAX_CYCLES {
+                        log::warn!("Rewrite cycle for host {} at '{}'", host_config.id, result.uri());
+                        return RequestAction::RewriteCycle {
+                            error_pages_dir: Arc::clone(&state.error_pages_dir),
+                            host_id,
+                            group_id,
+                        };
+                    }
+                    let view = RequestView {
+                        path: &result.path,
+                        query: result.query.as_deref(),
+                        ..*request
+                    };
+                    (location, loc_idx) = state.router.match_location(host_config, &view);
+                    rewritten = Some(result);
+                }
+            }
+        }
+        // Path the location matched (for captures), and the path being served
+        let match_path = match rewritten {
+            Some(ref rw) if rw.flag != rewrite::Flag::Break => rw.path.as_str(),
+            _ => path,
+        };
+        let path = rewritten.as_ref().map_or(path, |rw| rw.path.as_str());
+
         // Access control check (from matched location)
         let access_list_id = location.and_then(|l| l.access_list_id);
+        let mut client_identity = None;
+        trace.location_index = loc_idx;
+        trace.access_list_id = access_list_id;
 
         if let Some(acl_id) = access_list_id {
             if let Some(acl) = state.config.access_lists.get(&acl_id) {
-                let result =
-                    access_control::check_access(acl, client_ip.as_ref(), auth_header);
+                let grpc = location.is_some_and(|l| l.location_type.as_deref() == Some("grpc"));
+                let (result, identity) = match client_cert {
+                    Some(cert) => access_control::check_access_with_identity(
+                        acl,
+                        client_ip.as_ref(),
+                        auth_header,
+                        Some(cert),
+                    ),
+                    // Plain HTTP, or TLS without a client certificate: no identity to forward
+                    None => (access_control::check_access(acl, client_ip.as_ref(), auth_header), None),
+                };
+                client_identity = identity;
+                trace.access = Some(match result {
+                    access_control::AccessResult::Allowed => "allowed",
+                    access_control::AccessResult::AuthRequired => "auth_required",
+                    access_control::AccessResult::Denied
+                        if client_cert.is_none() && access_control::client_cert_would_allow(acl, client_ip.as_ref()) =>
+                    {
+                        "client_cert_required"
+                    }
+                    access_control::AccessResult::Denied => "denied",
+                });
                 match result {
                     access_control::AccessResult::Denied => {
                         return RequestAction::AccessDenied {
                             error_pages_dir: Arc::clone(&state.error_pages_dir),
                             host_id,
                             group_id,
+                            grpc,
                         };
                     }
                     access_control::AccessResult::AuthRequired => {
-                        return RequestAction::AuthRequired;
+                        return RequestAction::AuthRequired { grpc };
                     }
                     access_control::AccessResult::Allowed => {}
                 }
@@ -353,16 +915,25 @@ impl ProxyApp {
         // Check location type
         if let Some(loc) = location {
             let loc_type = loc.location_type.as_deref().unwrap_or("proxy");
+            // Regex captures, only when the location's targets or headers reference them
+            let captures = loc_idx.and_then(|idx| state.router.captures(host_config.id, idx, match_path));
             // Use pre-compiled headers (cheap Arc clones instead of String clones)
-            let custom_headers = loc.compiled_headers.clone();
+            let custom_headers = match captures {
+                Some(ref caps) => loc
+                    .compiled_headers
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Arc::from(expand_captures(value, Some(caps)).as_ref())))
+                    .collect(),
+                None => loc.compiled_headers.clone(),
+            };
 
             match loc_type {
                 "redirect" => {
                     let scheme = loc.forward_scheme.as_deref().unwrap_or("https");
-                    let domain = loc.forward_domain.as_deref().unwrap_or("");
-                    let fwd_path = loc.forward_path.as_deref().unwrap_or("/");
+                    let domain = expand_captures(loc.forward_domain.as_deref().unwrap_or(""), captures.as_ref());
+                    let fwd_path = expand_captures(loc.forward_path.as_deref().unwrap_or("/"), captures.as_ref());
                     let status = loc.status_code.unwrap_or(301);
-                    let target_path = if loc.preserve_path { path } else { fwd_path };
+                    let target_path = if loc.preserve_path { path } else { fwd_path.as_ref() };
                     let location_url = format!("{}://{}{}", scheme, domain, target_path);
                     return RequestAction::Redirect {
                         status_code: status,
@@ -374,6 +945,7 @@ impl ProxyApp {
                         return RequestAction::ServeStatic {
                             static_dir: Arc::from(static_dir.as_str()),
                             location_path: Arc::from(loc.path.as_str()),
+                            rewritten_path: rewritten.as_ref().map(|rw| rw.path.clone()),
                             cache_expires: loc.cache_expires.as_deref().map(Arc::from),
                             host_id,
                             group_id,
@@ -395,7 +967,8 @@ impl ProxyApp {
                     }
                 }
                 _ => {
-                    // Proxy type — determine upstream from location-level LB
+                    // Proxy and gRPC types — determine upstream from location-level LB
+                    let grpc = loc_type == "grpc";
                     // Use raw IP octets as key (zero-alloc) instead of ip.to_string()
                     let mut key_buf = [0u8; 16];
                     let key_bytes: &[u8] = match client_ip {
@@ -404,35 +977,68 @@ impl ProxyApp {
                         None => &[],
                     };
 
-                    let upstream_addr = loc_idx.and_then(|idx| {
+                    let outliers = loc_idx.and_then(|idx| state.location_outliers.get(&(host_config.id, idx)));
+                    let selected = loc_idx.and_then(|idx| {
                         state.location_lbs.get(&(host_config.id, idx))
                     }).and_then(|lb| {
-                        lb.select(key_bytes)
-                            .and_then(|b| b.addr.as_inet().map(|a| {
-                                state.addr_cache.get(&a)
-                                    .map(Arc::clone)
-                                    .unwrap_or_else(|| Arc::from(a.to_string().as_str()))
-                            }))
+                        lb.select_admitted(key_bytes, outliers.map(Arc::as_ref))
+                            .and_then(|b| Some((*b.addr.as_inet()?, b)))
                     });
 
-                    if let Some(addr) = upstream_addr {
-                        let rewrite_path = loc.forward_path.as_deref()
+                    if let Some((socket_addr, backend)) = selected {
+                        let addr = state.addr_cache.get(&socket_addr)
+                            .map(Arc::clone)
+                            .unwrap_or_else(|| Arc::from(socket_addr.to_string().as_str()));
+                        let upstream_tls = loc_idx
+                            .and_then(|idx| state.location_tls.get(&(host_config.id, idx)))
+                            .map(Arc::clone);
+                        let upstream_sni = upstream_tls
+                            .as_ref()
+                            .map(|tls| tls.sni_for(&backend))
+                            .unwrap_or_default();
+                        let forward = loc.forward_path.as_deref()
                             .filter(|fp| !fp.is_empty() && *fp != "/")
-                            .map(|fp| (Arc::from(loc.path.as_str()), Arc::from(fp)));
+                            .map(|fp| if loc.is_regex() {
+                                PathRewrite::Full(expand_captures(fp, captures.as_ref()).into_owned())
+                            } else {
+                                PathRewrite::Prefix {
+                                    prefix: Arc::from(loc.path.as_str()),
+                                    replacement: Arc::from(fp),
+                                }
+                            });
+                        // A URI rewritten with `break` is sent as is; otherwise the
+                        // location's forward_path applies to the new URI
+                        let rewrite_path = match rewritten {
+                            None => forward,
+                            Some(ref rw) => {
+                                let uri = forward
+                                    .filter(|_| rw.flag != rewrite::Flag::Break)
+                                    .and_then(|f| f.rewrite(&rw.path, rw.query.as_deref()));
+                                Some(PathRewrite::Uri(uri.unwrap_or_else(|| rw.uri())))
+                            }
+                        };
                         return RequestAction::Proxy {
                             upstream_addr: addr,
                             host_id,
                             group_id,
                             hsts,
-                            compression: host_config.compression,
+                            // gRPC messages carry their own compression
+                            compression: host_config.compression && !grpc,
                             custom_headers,
                             rewrite_path,
+                            client_identity,
+                            upstream_tls,
+                            upstream_sni,
+                            upstream_http2: loc.upstream_http2 || grpc,
+                            grpc,
+                            outliers: outliers.map(|o| (Arc::clone(o), socket_addr)),
                         };
                     } else {
                         return RequestAction::NoUpstream {
                             error_pages_dir: Arc::clone(&state.error_pages_dir),
                             host_id,
                             group_id,
+                            grpc,
                         };
                     }
                 }
@@ -444,6 +1050,7 @@ impl ProxyApp {
             error_pages_dir: Arc::clone(&state.error_pages_dir),
             host_id,
             group_id,
+            grpc: false,
         }
     }
 }
@@ -466,12 +1073,31 @@ impl ProxyHttp for ProxyApp {
 
     /// Handle the incoming request: access control, redirects, static files
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        let server_port = session
+            .downstream_session
+            .server_addr()
+            .and_then(|a| a.as_inet())
+            .map(|inet| inet.port());
+
+        // Normalize the path once: routing, access control and the upstream all see it
+        match self.normalized_uri(&session.req_header().uri, server_port) {
+            Err(reason) => {
+                log::debug!("Rejecting request path {:?}: {}", session.req_header().uri.path(), reason);
+                let err_resp = error_pages::serve_error_page(&ctx.error_pages_dir, 400, None, None);
+                session
+                    .write_response_header(Box::new(err_resp.header), false)
+                    .await?;
+                session
+                    .write_response_body(Some(err_resp.body), true)
+                    .await?;
+                return Ok(true);
+            }
+            Ok(Some(uri)) => session.req_header_mut().set_uri(uri),
+            Ok(None) => {}
+        }
+
         // Extract host header without allocating if possible
-        let host_header: Option<&str> = session
-            .req_header()
-            .headers
-            .get("host")
-            .and_then(|v| v.to_str().ok());
+        let host_header: Option<&str> = request_host(session.req_header());
 
         let path = session.req_header().uri.path();
         let client_ip = session
@@ -484,19 +1110,28 @@ impl ProxyHttp for ProxyApp {
             .headers
             .get(http::header::AUTHORIZATION)
             .and_then(|v| v.to_str().ok());
-        let server_port = session
+
+        // Client certificate captured at the end of the TLS handshake
+        let client_cert = session
             .downstream_session
-            .server_addr()
-            .and_then(|a| a.as_inet())
-            .map(|inet| inet.port());
+            .digest()
+            .and_then(|d| d.ssl_digest.as_ref())
+            .and_then(|ssl| ssl.extension.get::<access_control::ClientCert>());
 
         // Resolve the request action (lock-free via ArcSwap)
+        let request = RequestView {
+            path,
+            method: session.req_header().method.as_str(),
+            query: session.req_header().uri.query(),
+            headers: Some(&session.req_header().headers),
+        };
         let action = self.resolve_request(
             host_header,
-            path,
+            &request,
             server_port,
             client_ip,
             auth_header,
+            client_cert,
         );
 
         match action {
@@ -508,14 +1143,26 @@ impl ProxyHttp for ProxyApp {
                 compression,
                 custom_headers,
                 rewrite_path,
+                client_identity,
+                upstream_tls,
+                upstream_sni,
+                upstream_http2,
+                grpc,
+                outliers,
             } => {
                 ctx.upstream_addr = Some(upstream_addr);
+                ctx.outliers = outliers;
+                ctx.upstream_tls = upstream_tls;
+                ctx.upstream_sni = upstream_sni;
+                ctx.upstream_http2 = upstream_http2;
+                ctx.grpc = grpc;
                 ctx.host_id = host_id;
                 ctx.group_id = group_id;
                 ctx.hsts = hsts;
                 ctx.compression = compression;
                 ctx.custom_headers = custom_headers;
                 ctx.rewrite_path = rewrite_path;
+                ctx.client_identity = client_identity;
                 ctx.populate_log_paths(host_id, &self.state.load());
                 Ok(false)
             }
@@ -546,6 +1193,7 @@ impl ProxyHttp for ProxyApp {
             RequestAction::ServeStatic {
                 static_dir,
                 location_path,
+                rewritten_path,
                 cache_expires,
                 host_id,
                 group_id,
@@ -555,7 +1203,7 @@ impl ProxyHttp for ProxyApp {
                 ctx.host_id = host_id;
                 ctx.group_id = group_id;
                 ctx.populate_log_paths(host_id, &self.state.load());
-                let path_owned = path.to_string();
+                let path_owned = rewritten_path.unwrap_or_else(|| path.to_string());
                 let ims: Option<&str> = session
                     .req_header()
                     .headers
@@ -674,14 +1322,40 @@ impl ProxyHttp for ProxyApp {
                 Ok(true)
             }
 
+            RequestAction::NotFound { error_pages_dir } => {
+                let err_resp = error_pages::serve_error_page(&error_pages_dir, 404, None, None);
+                session
+                    .write_response_header(Box::new(err_resp.header), false)
+                    .await?;
+                session
+                    .write_response_body(Some(err_resp.body), true)
+                    .await?;
+                Ok(true)
+            }
+
+            RequestAction::Close => {
+                // Drop the connection (an h2 stream is reset instead)
+                session.set_keepalive(None);
+                session.downstream_session.shutdown().await;
+                Ok(true)
+            }
+
             RequestAction::AccessDenied {
                 error_pages_dir,
                 host_id,
                 group_id,
+                grpc,
             } => {
                 ctx.host_id = host_id;
                 ctx.group_id = group_id;
                 ctx.populate_log_paths(host_id, &self.state.load());
+                if grpc {
+                    let resp = grpc::error_response(grpc::status_from_http(403), error_pages::status_reason(403));
+                    session
+                        .write_response_header(Box::new(resp), true)
+                        .await?;
+                    return Ok(true);
+                }
                 let err_resp = error_pages::serve_error_page(
                     &error_pages_dir,
                     403,
@@ -697,49 +1371,30 @@ impl ProxyHttp for ProxyApp {
                 Ok(true)
             }
 
-            RequestAction::AcmeChallenge { token } => {
-                let challenge_path =
-                    std::path::PathBuf::from("/data/acme-challenge").join(&token);
-                if challenge_path.is_file() {
-                    if let Ok(body) = std::fs::read(&challenge_path) {
-                        let mut resp = ResponseHeader::build(200, Some(3)).unwrap();
-                        let _ = resp.insert_header(
-                            http::header::CONTENT_TYPE,
-                            "text/plain",
-                        );
-                        let _ =
-                            resp.insert_header(http::header::CONTENT_LENGTH, body.len());
-                        session
-                            .write_response_header(Box::new(resp), false)
-                            .await?;
-                        session
-                            .write_response_body(Some(bytes::Bytes::from(body)), true)
-                            .await?;
-                        return Ok(true);
-                    }
-                }
-                let mut resp = ResponseHeader::build(404, Some(1)).unwrap();
-                let _ = resp.insert_header(http::header::CONTENT_LENGTH, 0);
+            RequestAction::RewriteCycle {
+                error_pages_dir,
+                host_id,
+                group_id,
+            } => {
+                ctx.host_id = host_id;
+                ctx.group_id = group_id;
+                ctx.populate_log_paths(host_id, &self.state.load());
+                let err_resp = error_pages::serve_error_page(
+                    &error_pages_dir,
+                    500,
+                    host_id,
+                    group_id,
+                );
                 session
-                    .write_response_header(Box::new(resp), true)
+                    .write_response_header(Box::new(err_resp.header), false)
                     .await?;
-                Ok(true)
-            }
-
-            RequestAction::AuthRequired => {
-                let mut resp = ResponseHeader::build(401, Some(2)).unwrap();
-                let _ = resp.insert_header(
-                    http::header::WWW_AUTHENTICATE,
-                    "Basic realm=\"Restricted\"",
-                );
-                let _ = resp.insert_header(http::header::CONTENT_LENGTH, 0);
                 session
-                    .write_response_header(Box::new(resp), true)
+                    .write_response_body(Some(err_resp.body), true)
                     .await?;
                 Ok(true)
             }
 
-            RequestAction::NoUpstream {
+            RequestAction::RejectedRewrite {
                 error_pages_dir,
                 host_id,
                 group_id,
@@ -747,8 +1402,12 @@ impl ProxyHttp for ProxyApp {
                 ctx.host_id = host_id;
                 ctx.group_id = group_id;
                 ctx.populate_log_paths(host_id, &self.state.load());
-                let err_resp =
-                    error_pages::serve_error_page(&error_pages_dir, 502, host_id, group_id);
+                let err_resp = error_pages::serve_error_page(
+                    &error_pages_dir,
+                    400,
+                    host_id,
+                    group_id,
+                );
                 session
                     .write_response_header(Box::new(err_resp.header), false)
                     .await?;
@@ -757,41 +1416,128 @@ impl ProxyHttp for ProxyApp {
                     .await?;
                 Ok(true)
             }
-        }
-    }
 
-    /// Select the upstream peer based on the resolved upstream address
-    async fn upstream_peer(
-        &self,
-        _session: &mut Session,
-        ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        let addr = ctx
-            .upstream_addr
-            .as_ref()
-            .ok_or_else(|| {
-                pingora_core::Error::because(
-                    pingora_core::ErrorType::ConnectNoRoute,
-                    "no upstream address resolved",
-                    pingora_core::Error::new(pingora_core::ErrorType::ConnectNoRoute),
-                )
-            })?;
+            RequestAction::AcmeChallenge { token } => {
+                // Built-in ACME client first, then tokens written by the web app
+                let body = match self.acme_challenges.http01(&token) {
+                    Some(key_authorization) => Some(key_authorization.into_bytes()),
+                    None => {
+                        let challenge_path =
+                            std::path::PathBuf::from("/data/acme-challenge").join(&token);
+                        if challenge_path.is_file() {
+                            std::fs::read(&challenge_path).ok()
+                        } else {
+                            None
+                        }
+                    }
+                };
+                if let Some(body) = body {
+                    let mut resp = ResponseHeader::build(200, Some(3)).unwrap();
+                    let _ = resp.insert_header(
+                        http::header::CONTENT_TYPE,
+                        "text/plain",
+                    );
+                    let _ = resp.insert_header(http::header::CONTENT_LENGTH, body.len());
+                    session
+                        .write_response_header(Box::new(resp), false)
+                        .await?;
+                    session
+                        .write_response_body(Some(bytes::Bytes::from(body)), true)
+                        .await?;
+                    return Ok(true);
+                }
+                let mut resp = ResponseHeader::build(404, Some(1)).unwrap();
+                let _ = resp.insert_header(http::header::CONTENT_LENGTH, 0);
+                session
+                    .write_response_header(Box::new(resp), true)
+                    .await?;
+                Ok(true)
+            }
 
-        let mut peer = HttpPeer::new(
-            addr.as_ref(),
-            ctx.upstream_tls,
-            ctx.upstream_sni.clone(),
-        );
+            RequestAction::CertificateReport { json } => {
+                let mut resp = ResponseHeader::build(200, Some(4)).unwrap();
+                let _ = resp.insert_header(http::header::CONTENT_TYPE, "application/json");
+                let _ = resp.insert_header(http::header::CONTENT_LENGTH, json.len());
+                let _ = resp.insert_header(http::header::CACHE_CONTROL, "no-store");
+                session
+                    .write_response_header(Box::new(resp), false)
+                    .await?;
+                session
+                    .write_response_body(Some(bytes::Bytes::from(json)), true)
+                    .await?;
+                Ok(true)
+            }
 
-        // Configure connection pooling and keepalive for better performance
-        let options = peer.get_mut_peer_options().unwrap();
-        options.connection_timeout = Some(Duration::from_secs(5));
-        options.total_connection_timeout = Some(Duration::from_secs(10));
-        options.read_timeout = Some(Duration::from_secs(60));
-        options.write_timeout = Some(Duration::from_secs(60));
-        options.idle_timeout = Some(Duration::from_secs(60));
+            RequestAction::RouteExplain { status, json } => {
+                let mut resp = ResponseHeader::build(status, Some(4)).unwrap();
+                let _ = resp.insert_header(http::header::CONTENT_TYPE, "application/json");
+                let _ = resp.insert_header(http::header::CONTENT_LENGTH, json.len());
+                let _ = resp.insert_header(http::header::CACHE_CONTROL, "no-store");
+                session
+                    .write_response_header(Box::new(resp), false)
+                    .await?;
+                session
+                    .write_response_body(Some(bytes::Bytes::from(json)), true)
+                    .await?;
+                Ok(true)
+            }
+
+            RequestAction::AuthRequired { grpc } => {
+                if grpc {
+                    let resp = grpc::error_response(grpc::status_from_http(401), error_pages::status_reason(401));
+                    session
+                        .write_response_header(Box::new(resp), true)
+                        .await?;
+                    return Ok(true);
+                }
+                let mut resp = ResponseHeader::build(401, Some(2)).unwrap();
+                let _ = resp.insert_header(
+                    http::header::WWW_AUTHENTICATE,
+                    "Basic realm=\"Restricted\"",
+                );
+                let _ = resp.insert_header(http::header::CONTENT_LENGTH, 0);
+                session
+                    .write_response_header(Box::new(resp), true)
+                    .await?;
+                Ok(true)
+            }
+
+            RequestAction::NoUpstream {
+                error_pages_dir,
+                host_id,
+                group_id,
+                grpc,
+            } => {
+                ctx.host_id = host_id;
+                ctx.group_id = group_id;
+                ctx.populate_log_paths(host_id, &self.state.load());
+                if grpc {
+                    let resp = grpc::error_response(grpc::UNAVAILABLE, "no upstream available");
+                    session
+                        .write_response_header(Box::new(resp), true)
+                        .await?;
+                    return Ok(true);
+                }
+                let err_resp =
+                    error_pages::serve_error_page(&error_pages_dir, 502, host_id, group_id);
+                session
+                    .write_response_header(Box::new(err_resp.header), false)
+                    .await?;
+                session
+                    .write_response_body(Some(err_resp.body), true)
+                    .await?;
+                Ok(true)
+            }
+        }
+    }
 
-        Ok(Box::new(peer))
+    /// Select the upstream peer based on the resolved upstream address
+    async fn upstream_peer(
+        &self,
+        _session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        ctx.build_upstream_peer().map(Box::new)
     }
 
     /// Modify the request before sending to upstream
@@ -802,32 +1548,26 @@ impl ProxyHttp for ProxyApp {
         ctx: &mut Self::CTX,
     ) -> Result<()> {
         // Rewrite path if configured (e.g., /api → /api-test)
-        if let Some((ref prefix, ref replacement)) = ctx.rewrite_path {
-            let orig_path = upstream_request.uri.path();
-            if let Some(suffix) = orig_path.strip_prefix(prefix.as_ref()) {
-                let new_path = format!("{}{}", replacement, suffix);
-                // Preserve query string if present
-                let new_pq = if let Some(q) = upstream_request.uri.query() {
-                    format!("{}?{}", new_path, q)
-                } else {
-                    new_path
-                };
-                if let Ok(new_uri) = new_pq.parse::<http::Uri>() {
-                    upstream_request.set_uri(new_uri);
-                }
-            }
+        if let Some(new_uri) = ctx.rewrite_path.as_ref().and_then(|r| r.apply(&upstream_request.uri)) {
+            upstream_request.set_uri(new_uri);
         }
 
-        // Forward the original Host header
-        if let Some(host) = session
-            .req_header()
-            .headers
-            .get("host")
-            .cloned()
-        {
+        // Forward the original Host header (HTTP/2 clients send :authority instead)
+        if let Some(host) = request_host(session.req_header()).map(str::to_string) {
             upstream_request.insert_header("Host", host)?;
         }
 
+        // Client certificate identity: only ever set by the proxy itself
+        for name in access_control::CLIENT_CERT_HEADERS {
+            upstream_request.remove_header(name);
+        }
+        if let Some(ref identity) = ctx.client_identity {
+            for (name, value) in identity.headers() {
+                // Skip values that are not valid header text rather than failing the request
+                let _ = upstream_request.insert_header(name, value);
+            }
+        }
+
         // Add X-Forwarded-For and X-Real-IP
         if let Some(client_ip) = session
             .downstream_session
@@ -899,7 +1639,13 @@ impl ProxyHttp for ProxyApp {
             },
         };
 
-        if code > 0 {
+        if code > 0 && ctx.grpc {
+            let resp = grpc::error_response(
+                grpc::status_for_error(e.etype(), code),
+                &grpc::error_message(e.etype(), code),
+            );
+            let _ = session.write_response_header(Box::new(resp), true).await;
+        } else if code > 0 {
             let err_resp =
                 error_pages::serve_error_page(&ctx.error_pages_dir, code, ctx.host_id, ctx.group_id);
             let _ = session
@@ -929,12 +1675,17 @@ impl ProxyHttp for ProxyApp {
             .unwrap_or(0);
         let method = session.req_header().method.as_str();
         let path = session.req_header().uri.path();
-        let host = session
-            .req_header()
-            .headers
-            .get("host")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("-");
+        let host = request_host(session.req_header()).unwrap_or("-");
+
+        // Upstream errors (connect, timeout, broken response) and 5xx responses count as
+        // failures; errors caused by the client or the proxy itself count as neither
+        if let Some((ref outliers, addr)) = ctx.outliers {
+            match e.map(|err| err.esource()) {
+                Some(pingora_core::ErrorSource::Upstream) => outliers.report(addr, false),
+                Some(_) => {}
+                None => outliers.report(addr, status < 500),
+            }
+        }
 
         if let Some(err) = e {
             log::error!("{} {} {} {} - error: {}", method, host, path, status, err);
@@ -974,15 +1725,11 @@ fn main() {
         Err(e) => {
             log::warn!("Failed to load config from {}: {}. Using defaults.", CONFIGS_DIR, e);
             // Create minimal default config
-            serde_yaml::from_str::<config::GlobalConfig>(
-                "listen:\n  http: 80\n  https: 443\n  admin: 81\nadmin_upstream: '127.0.0.1:3001'",
-            )
-            .map(|global| AppConfig {
-                global,
+            AppConfig {
+                global: config::GlobalConfig::default(),
                 hosts: Vec::new(),
                 access_lists: std::collections::HashMap::new(),
-            })
-            .expect("Failed to create default config")
+            }
         }
     };
 
@@ -998,21 +1745,30 @@ fn main() {
 
     // Build shared state with ArcSwap for lock-free reads
     let (log_sender, log_receiver) = log_writer::create_log_channel();
-    let shared_state = Arc::new(arc_swap::ArcSwap::from_pointee(SharedState::build(config, log_sender.clone())));
+    let shared_state = Arc::new(arc_swap::ArcSwap::from_pointee(SharedState::build(config, log_sender.clone(), None)));
+
+    // Parsed TLS material, swapped together with shared_state on SIGHUP
+    let cert_store: tls::SharedCertStore = Arc::new(arc_swap::ArcSwap::from_pointee(
+        tls::CertStore::load(Arc::clone(&shared_state.load().ssl_manager)),
+    ));
 
-    // Check SSL before moving shared_state
-    let has_ssl_certs = shared_state.load().ssl_manager.has_certs();
+    // Challenge responses of the built-in ACME client, shared by the HTTP and TLS listeners
+    let acme_challenges = Arc::new(acme::ChallengeStore::default());
+
+    // OCSP responses stapled by the TLS listener, kept across reloads
+    let ocsp_staples = Arc::new(ocsp::OcspCache::default());
 
     // Create the proxy apps (both share the same ArcSwap)
-    let proxy_app = ProxyApp::new(Arc::clone(&shared_state));
-    let admin_proxy_app = ProxyApp::new(Arc::clone(&shared_state));
+    let proxy_app = ProxyApp::new(Arc::clone(&shared_state), Arc::clone(&acme_challenges));
+    let tls_proxy_app = ProxyApp::new(Arc::clone(&shared_state), Arc::clone(&acme_challenges));
+    let admin_proxy_app = ProxyApp::new(Arc::clone(&shared_state), Arc::clone(&acme_challenges));
 
     // Set up SIGHUP handler for config reload
     let reload_state = Arc::clone(&shared_state);
+    let reload_certs = Arc::clone(&cert_store);
     let log_sender_reload = log_sender.clone();
     std::thread::spawn(move || {
-        use std::sync::atomic::{AtomicBool, Ordering};
-        static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+        use std::sync::atomic::Ordering;
 
         // Register SIGHUP signal handler
         unsafe {
@@ -1021,11 +1777,22 @@ fn main() {
 
         loop {
             std::thread::sleep(std::time::Duration::from_secs(1));
-            if SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) {
-                log::info!("SIGHUP received, reloading configuration...");
+            if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+                log::info!("Reload requested, reloading configuration...");
                 match AppConfig::load(CONFIGS_DIR) {
                     Ok(new_config) => {
-                        let new_state = Arc::new(SharedState::build(new_config, log_sender_reload.clone()));
+                        let new_state = Arc::new(SharedState::build(
+                            new_config,
+                            log_sender_reload.clone(),
+                            Some(&reload_state.load()),
+                        ));
+                        let new_certs = tls::CertStore::load(Arc::clone(&new_state.ssl_manager));
+                        log::info!(
+                            "Reloaded {} TLS certificates ({} hosts with certificate problems)",
+                            new_certs.loaded_count(),
+                            new_state.ssl_manager.problems().len()
+                        );
+                        reload_certs.store(Arc::new(new_certs));
                         reload_state.store(new_state);
                         log::info!("Configuration reloaded successfully");
                     }
@@ -1037,13 +1804,46 @@ fn main() {
         }
 
         extern "C" fn sighup_handler(_sig: libc::c_int) {
-            SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+            RELOAD_REQUESTED.store(true, Ordering::SeqCst);
         }
     });
 
+    // Issue and renew certificates for letsencrypt hosts when `acme.enabled` is set,
+    // then reload so the new certificates are served
+    let acme_state = Arc::clone(&shared_state);
+    acme::spawn_renewal(
+        Arc::clone(&acme_challenges),
+        move || acme_state.load().config.clone(),
+        || RELOAD_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst),
+    );
+
+    // Fetch and refresh OCSP responses for the served certificates
+    let ocsp_state = Arc::clone(&shared_state);
+    ocsp::spawn_refresh(
+        Arc::clone(&ocsp_staples),
+        Arc::clone(&cert_store),
+        move || ocsp_state.load().config.global.ocsp.clone(),
+    );
+
+    // Active health checks of location upstreams, following reloads
+    let health_state = Arc::clone(&shared_state);
+    upstream::spawn_health_checks(move || health_state.load().location_lbs.values().cloned().collect());
+
+    // Repeat certificate expiry warnings so long-running instances keep reporting them
+    let expiry_state = Arc::clone(&shared_state);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CERT_EXPIRY_CHECK_INTERVAL);
+        let state = expiry_state.load();
+        state
+            .ssl_manager
+            .log_expiry_warnings(state.config.global.cert_expiry_warn_days, chrono::Utc::now());
+    });
+
     // Create Pingora server with optimized configuration
-    let mut server_conf = pingora_core::server::configuration::ServerConf::default();
-    server_conf.upstream_keepalive_pool_size = 128;
+    let server_conf = pingora_core::server::configuration::ServerConf {
+        upstream_keepalive_pool_size: 128,
+        ..Default::default()
+    };
     let opt = pingora_core::server::configuration::Opt::default();
     let mut server = Server::new_with_opt_and_conf(opt, server_conf);
     server.bootstrap();
@@ -1051,15 +1851,36 @@ fn main() {
     // Create HTTP proxy service
     let mut http_service = http_proxy_service(&server.configuration, proxy_app);
 
-    // Add HTTP listener
+    // Add HTTP listener, accepting prior-knowledge h2c next to HTTP/1.1 (plaintext gRPC).
+    // TLS connections negotiate h2 via ALPN in their own service: the h2c preface check
+    // cannot peek into TLS streams and would treat every one of them as HTTP/2.
+    let mut h2c_options = pingora_core::apps::HttpServerOptions::default();
+    h2c_options.h2c = true;
+    if let Some(app) = http_service.app_logic_mut() {
+        app.server_options = Some(h2c_options);
+    }
     http_service.add_tcp(&format!("0.0.0.0:{}", http_port));
-
-    // Add HTTPS listener if SSL certs are available
-    if has_ssl_certs {
-        log::info!("HTTPS port {} configured (TLS certs found)", https_port);
-        http_service.add_tcp(&format!("0.0.0.0:{}", https_port));
-    } else {
-        log::info!("No TLS certificates found, HTTPS listener not started");
+    let mut https_service = http_proxy_service(&server.configuration, tls_proxy_app);
+
+    // Add HTTPS listener. It is always bound so that certificates added or renewed
+    // later are served after a SIGHUP reload (certificate picked per handshake from SNI).
+    let loaded = cert_store.load().loaded_count();
+    match tls::tls_settings(
+        Arc::clone(&cert_store),
+        Arc::clone(&acme_challenges),
+        Arc::clone(&ocsp_staples),
+    ) {
+        Ok(settings) => {
+            if loaded == 0 {
+                log::info!("HTTPS port {} configured, no TLS certificates loaded yet", https_port);
+            } else {
+                log::info!("HTTPS port {} configured ({} TLS certificates loaded)", https_port, loaded);
+            }
+            https_service.add_tls_with_settings(&format!("0.0.0.0:{}", https_port), None, settings);
+        }
+        Err(e) => {
+            log::error!("Failed to configure TLS on port {}: {}", https_port, e);
+        }
     }
 
     // Add admin listener
@@ -1068,6 +1889,7 @@ fn main() {
 
     // Register services
     server.add_service(http_service);
+    server.add_service(https_service);
     server.add_service(admin_service);
 
     // Start TCP stream proxies in the background
@@ -1117,46 +1939,33 @@ mod tests {
         hosts: Vec<HostConfig>,
         access_lists: HashMap<u64, AccessListConfig>,
     ) -> ProxyApp {
-        let global = GlobalConfig {
-            listen: ListenConfig { http: 80, https: 443, admin: 81 },
-            admin_upstream: "127.0.0.1:3001".to_string(),
-            default_page: "/data/default-page/index.html".to_string(),
-            error_pages_dir: "/data/error-pages".to_string(),
-            logs_dir: "/data/logs".to_string(),
-            ssl_dir: "/etc/letsencrypt".to_string(),
-        };
-        let config = AppConfig {
-            global,
+        app_from_config(AppConfig {
+            global: test_global(),
             hosts,
             access_lists,
-        };
+        })
+    }
+
+    fn test_global() -> GlobalConfig {
+        GlobalConfig::default()
+    }
+
+    fn app_from_config(config: AppConfig) -> ProxyApp {
         let (log_sender, _log_receiver) = log_writer::create_log_channel();
-        let state = SharedState::build(config, log_sender);
+        let state = SharedState::build(config, log_sender, None);
         let swap = Arc::new(arc_swap::ArcSwap::from_pointee(state));
-        ProxyApp::new(swap)
+        ProxyApp::new(swap, Arc::new(acme::ChallengeStore::default()))
     }
 
     fn make_proxy_location(path: &str, server: &str, port: u16) -> LocationConfig {
         LocationConfig {
             path: path.to_string(),
-            match_type: "prefix".to_string(),
-            location_type: Some("proxy".to_string()),
             upstreams: vec![UpstreamConfig {
                 server: server.to_string(),
                 port,
                 weight: 1,
             }],
-            balance_method: "round_robin".to_string(),
-            static_dir: None,
-            cache_expires: None,
-            forward_scheme: None,
-            forward_domain: None,
-            forward_path: None,
-            preserve_path: false,
-            status_code: None,
-            headers: HashMap::new(),
-            access_list_id: None,
-            compiled_headers: Vec::new(),
+            ..Default::default()
         }
     }
 
@@ -1164,15 +1973,8 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
-            ssl: None,
             locations: vec![make_proxy_location("/", "10.0.0.1", 8080)],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1180,20 +1982,14 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
             ssl: Some(SslConfig {
                 ssl_type: "letsencrypt".to_string(),
                 force_https: true,
-                cert_path: None,
-                key_path: None,
+                ..Default::default()
             }),
             locations: vec![make_proxy_location("/", "10.0.0.1", 8080)],
-            stream_ports: vec![],
             hsts: true,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1203,15 +1999,8 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
-            ssl: None,
             locations: vec![loc],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1220,30 +2009,14 @@ mod tests {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
             group_id: Some(10),
-            ssl: None,
             locations: vec![LocationConfig {
                 path: "/static".to_string(),
-                match_type: "prefix".to_string(),
                 location_type: Some("static".to_string()),
-                upstreams: vec![],
-                balance_method: "round_robin".to_string(),
                 static_dir: Some("/var/www/static".to_string()),
                 cache_expires: Some("30d".to_string()),
-                forward_scheme: None,
-                forward_domain: None,
-                forward_path: None,
-                preserve_path: false,
-                status_code: None,
-                headers: HashMap::new(),
-                access_list_id: None,
-                compiled_headers: Vec::new(),
+                ..Default::default()
             }],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1251,31 +2024,16 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
-            ssl: None,
             locations: vec![LocationConfig {
-                path: "/".to_string(),
-                match_type: "prefix".to_string(),
                 location_type: Some("redirect".to_string()),
-                upstreams: vec![],
-                balance_method: "round_robin".to_string(),
-                static_dir: None,
-                cache_expires: None,
                 forward_scheme: Some("https".to_string()),
                 forward_domain: Some("new.example.com".to_string()),
                 forward_path: Some("/".to_string()),
                 preserve_path: true,
                 status_code: Some(301),
-                headers: HashMap::new(),
-                access_list_id: None,
-                compiled_headers: Vec::new(),
+                ..Default::default()
             }],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1283,15 +2041,7 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
-            ssl: None,
-            locations: vec![],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1306,6 +2056,7 @@ mod tests {
                 parsed_cidr: None,
             }],
             auth: vec![],
+            client_cert: None,
         }
     }
 
@@ -1319,6 +2070,7 @@ mod tests {
                 username: "admin".to_string(),
                 password: "secret".to_string(),
             }],
+            client_cert: None,
         }
     }
 
@@ -1327,7 +2079,7 @@ mod tests {
     #[test]
     fn test_admin_port_routes_to_admin_upstream() {
         let app = build_app(vec![], HashMap::new());
-        let action = app.resolve_request(Some("anything.com"), "/", Some(81), None, None);
+        let action = app.resolve_request(Some("anything.com"), &RequestView::get("/"), Some(81), None, None, None);
         match action {
             RequestAction::Proxy { upstream_addr, .. } => {
                 assert_eq!(&*upstream_addr, "127.0.0.1:3001");
@@ -1342,7 +2094,7 @@ mod tests {
             vec![host_with_upstream(1, &["evil.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(Some("evil.com"), "/", Some(81), None, None);
+        let action = app.resolve_request(Some("evil.com"), &RequestView::get("/"), Some(81), None, None, None);
         match action {
             RequestAction::Proxy { upstream_addr, .. } => {
                 assert_eq!(&*upstream_addr, "127.0.0.1:3001");
@@ -1351,6 +2103,199 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_path_normalized_only_on_host_routed_listeners() {
+        let app = build_app(vec![], HashMap::new());
+        let uri: http::Uri = "/a//b/../c?x=1".parse().unwrap();
+        for port in [Some(80), Some(443), None] {
+            let normalized = app.normalized_uri(&uri, port).unwrap().unwrap();
+            assert_eq!(normalized.path_and_query().unwrap().as_str(), "/a/c?x=1");
+            assert!(app.normalized_uri(&"/a%2Fb".parse().unwrap(), port).is_err());
+        }
+        assert!(app.normalized_uri(&"/a/c".parse().unwrap(), Some(80)).unwrap().is_none());
+        // Admin requests reach the admin app exactly as sent
+        assert!(app.normalized_uri(&uri, Some(81)).unwrap().is_none());
+        assert!(app.normalized_uri(&"/a%2Fb".parse().unwrap(), Some(81)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_admin_port_serves_certificate_report_to_local_clients() {
+        let app = build_app(vec![], HashMap::new());
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let action = app.resolve_request(Some("anything.com"), &RequestView::get("/_proxy/certificates"), Some(81), Some(local), None, None);
+        match action {
+            RequestAction::CertificateReport { json } => {
+                let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+                assert_eq!(report["warn_days"], 14);
+                assert!(report["certificates"].as_array().unwrap().is_empty());
+                assert!(report["problems"].as_array().unwrap().is_empty());
+            }
+            _ => panic!("expected CertificateReport"),
+        }
+    }
+
+    #[test]
+    fn test_certificate_report_only_for_local_admin_clients() {
+        let app = build_app(vec![host_with_upstream(1, &["app.com"])], HashMap::new());
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "203.0.113.7".parse().unwrap();
+        let report = RequestView::get("/_proxy/certificates");
+        let action = app.resolve_request(Some("anything.com"), &report, Some(80), Some(local), None, None);
+        assert!(matches!(action, RequestAction::ServeDefault { .. }));
+        // Remote admin clients reach the web app, which authenticates them
+        let action = app.resolve_request(Some("anything.com"), &report, Some(81), Some(remote), None, None);
+        assert!(matches!(action, RequestAction::Proxy { .. }));
+        // So do requests relayed from loopback by a proxy, e.g. a host forwarding to the admin port
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        let relayed = RequestView { headers: Some(&headers), ..RequestView::get("/_proxy/certificates") };
+        let action = app.resolve_request(Some("anything.com"), &relayed, Some(81), Some(local), None, None);
+        assert!(matches!(action, RequestAction::Proxy { .. }));
+        // Other admin paths are always proxied
+        let action = app.resolve_request(Some("app.com"), &RequestView::get("/_proxy/certificates/x"), Some(81), Some(local), None, None);
+        assert!(matches!(action, RequestAction::Proxy { .. }));
+    }
+
+    // ─── Route explain ──────────────────────────────────────
+
+    fn explain(app: &ProxyApp, query: &str) -> (u16, serde_json::Value) {
+        let request = RequestView { query: Some(query), ..RequestView::get("/_proxy/explain") };
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        match app.resolve_request(Some("localhost"), &request, Some(81), Some(local), None, None) {
+            RequestAction::RouteExplain { status, json } => (status, serde_json::from_str(&json).unwrap()),
+            _ => panic!("expected RouteExplain"),
+        }
+    }
+
+    #[test]
+    fn test_explain_proxied_request() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        let mut writes = make_proxy_location("/api", "10.0.0.2", 8080);
+        writes.forward_path = Some("/v2".to_string());
+        writes.conditions = Some(LocationConditions {
+            methods: vec!["POST".to_string()],
+            ..LocationConditions::default()
+        });
+        host.locations.push(writes);
+        let app = build_app(vec![host], HashMap::new());
+
+        let (status, report) = explain(&app, "host=app.com&path=/x/../api/items%3Fpage%3D2&method=POST&ip=10.0.0.9");
+        assert_eq!(status, 200);
+        assert_eq!(report["path"], "/api/items");
+        assert_eq!(report["host_id"], 1);
+        assert_eq!(report["location_index"], 1);
+        assert_eq!(report["location_path"], "/api");
+        assert_eq!(report["match_type"], "prefix");
+        assert!(report["access"].is_null());
+        assert_eq!(report["action"]["type"], "proxy");
+        assert_eq!(report["action"]["upstream"], "10.0.0.2:8080");
+        assert_eq!(report["action"]["upstream_uri"], "/v2/items?page=2");
+
+        let (_, report) = explain(&app, "host=app.com&path=/api/items");
+        assert_eq!(report["location_index"], 0);
+        assert_eq!(report["action"]["upstream"], "10.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_explain_access_verdict() {
+        let mut acls = HashMap::new();
+        acls.insert(1, make_acl_deny_all(1));
+        acls.insert(2, make_acl_with_auth(2));
+        let app = build_app(
+            vec![host_with_acl(1, &["denied.com"], 1), host_with_acl(2, &["auth.com"], 2)],
+            acls,
+        );
+
+        let (_, report) = explain(&app, "host=denied.com&ip=1.2.3.4");
+        assert_eq!(report["access_list_id"], 1);
+        assert_eq!(report["access"], "denied");
+        assert_eq!(report["action"]["type"], "access_denied");
+
+        let (_, report) = explain(&app, "host=auth.com");
+        assert_eq!(report["access"], "auth_required");
+        assert_eq!(report["action"]["status"], 401);
+
+        // Credentials are never evaluated, so explain cannot be used to guess passwords
+        let (status, _) = explain(&app, "host=auth.com&header=Authorization:%20Basic%20YWRtaW46c2VjcmV0");
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn test_explain_client_cert_required() {
+        let mut acl = make_acl_deny_all(1);
+        acl.clients.clear();
+        acl.client_cert = Some(ClientCertRule {
+            ca_bundle: "/nonexistent/ca.pem".to_string(),
+            subjects: vec![],
+            trust: None,
+        });
+        let mut acls = HashMap::new();
+        acls.insert(1, acl);
+        let app = build_app(vec![host_with_acl(1, &["internal.com"], 1)], acls);
+
+        let (_, report) = explain(&app, "host=internal.com&ip=10.0.0.9");
+        assert_eq!(report["access"], "client_cert_required");
+        assert_eq!(report["action"]["type"], "access_denied");
+    }
+
+    #[test]
+    fn test_explain_reports_location_after_rewrite() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/old(.*)$", "/admin$1", "last")];
+        let mut admin = make_proxy_location("/admin", "10.0.0.2", 8080);
+        admin.access_list_id = Some(1);
+        host.locations.push(admin);
+        let mut acls = HashMap::new();
+        acls.insert(1, make_acl_deny_all(1));
+        let app = build_app(vec![host], acls);
+
+        let (_, report) = explain(&app, "host=app.com&path=/old/users&ip=1.2.3.4");
+        assert_eq!(report["path"], "/old/users");
+        assert_eq!(report["location_index"], 1);
+        assert_eq!(report["location_path"], "/admin");
+        assert_eq!(report["access_list_id"], 1);
+        assert_eq!(report["access"], "denied");
+        assert_eq!(report["action"]["type"], "access_denied");
+    }
+
+    #[test]
+    fn test_explain_unknown_host() {
+        let app = build_app(vec![host_with_upstream(1, &["app.com"])], HashMap::new());
+        let (status, report) = explain(&app, "host=other.com&path=/");
+        assert_eq!(status, 200);
+        assert!(report["host_id"].is_null());
+        assert!(report["location_index"].is_null());
+        assert_eq!(report["action"]["type"], "default_page");
+    }
+
+    #[test]
+    fn test_explain_rejects_bad_queries() {
+        let app = build_app(vec![host_with_upstream(1, &["app.com"])], HashMap::new());
+        for query in ["path=/", "host=app.com&path=/a%252Fb", "host=app.com&port=81", "host=app.com&ip=x"] {
+            let (status, report) = explain(&app, query);
+            assert_eq!(status, 400, "{}", query);
+            assert!(report["error"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_explain_only_for_local_admin_clients() {
+        let app = build_app(vec![], HashMap::new());
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "203.0.113.7".parse().unwrap();
+        let request = RequestView { query: Some("host=app.com"), ..RequestView::get("/_proxy/explain") };
+        let action = app.resolve_request(Some("anything.com"), &request, Some(80), Some(local), None, None);
+        assert!(matches!(action, RequestAction::ServeDefault { .. }));
+        // Remote admin clients get the admin app like on any other path
+        let action = app.resolve_request(Some("anything.com"), &request, Some(81), Some(remote), None, None);
+        match action {
+            RequestAction::Proxy { upstream_addr, .. } => assert_eq!(&*upstream_addr, "127.0.0.1:3001"),
+            _ => panic!("expected Proxy for admin port"),
+        }
+        let action = app.resolve_request(Some("anything.com"), &request, Some(81), Some(local), None, None);
+        assert!(matches!(action, RequestAction::RouteExplain { status: 200, .. }));
+    }
+
     // ─── ACME challenge ─────────────────────────────────────
 
     #[test]
@@ -1358,10 +2303,11 @@ mod tests {
         let app = build_app(vec![], HashMap::new());
         let action = app.resolve_request(
             Some("example.com"),
-            "/.well-known/acme-challenge/some-token-123",
+            &RequestView::get("/.well-known/acme-challenge/some-token-123"),
             Some(80),
             None,
             None,
+            None,
         );
         match action {
             RequestAction::AcmeChallenge { token } => {
@@ -1376,10 +2322,11 @@ mod tests {
         let app = build_app(vec![], HashMap::new());
         let action = app.resolve_request(
             Some("example.com"),
-            "/.well-known/acme-challenge/",
+            &RequestView::get("/.well-known/acme-challenge/"),
             Some(80),
             None,
             None,
+            None,
         );
         assert!(!matches!(action, RequestAction::AcmeChallenge { .. }));
     }
@@ -1389,10 +2336,11 @@ mod tests {
         let app = build_app(vec![], HashMap::new());
         let action = app.resolve_request(
             Some("example.com"),
-            "/.well-known/acme-challenge/../../etc/passwd",
+            &RequestView::get("/.well-known/acme-challenge/../../etc/passwd"),
             Some(80),
             None,
             None,
+            None,
         );
         match action {
             RequestAction::AcmeChallenge { token } => {
@@ -1410,7 +2358,7 @@ mod tests {
             vec![host_with_redirect_location(1, &["old.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(Some("old.com"), "/path", Some(80), None, None);
+        let action = app.resolve_request(Some("old.com"), &RequestView::get("/path"), Some(80), None, None, None);
         match action {
             RequestAction::Redirect { status_code, location } => {
                 assert_eq!(status_code, 301);
@@ -1425,7 +2373,7 @@ mod tests {
     #[test]
     fn test_unknown_host_serves_default() {
         let app = build_app(vec![], HashMap::new());
-        let action = app.resolve_request(Some("unknown.com"), "/", Some(80), None, None);
+        let action = app.resolve_request(Some("unknown.com"), &RequestView::get("/"), Some(80), None, None, None);
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
 
@@ -1435,7 +2383,7 @@ mod tests {
             vec![host_with_upstream(1, &["example.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(None, "/", Some(80), None, None);
+        let action = app.resolve_request(None, &RequestView::get("/"), Some(80), None, None, None);
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
 
@@ -1445,10 +2393,99 @@ mod tests {
             vec![host_with_upstream(1, &["example.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(Some(""), "/", Some(80), None, None);
+        let action = app.resolve_request(Some(""), &RequestView::get("/"), Some(80), None, None, None);
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
 
+    // ─── Unknown host handling ──────────────────────────────
+
+    fn app_with_unknown_host(hosts: Vec<HostConfig>, unknown_host: &str) -> ProxyApp {
+        let mut global = test_global();
+        global.unknown_host = unknown_host.to_string();
+        app_from_config(AppConfig {
+            global,
+            hosts,
+            access_lists: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn test_unknown_host_modes() {
+        let hosts = vec![host_with_upstream(1, &["example.com"])];
+        let app = app_with_unknown_host(hosts.clone(), "not_found");
+        let action = app.resolve_request(Some("other.com"), &RequestView::get("/"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::NotFound { .. }));
+
+        let app = app_with_unknown_host(hosts.clone(), "close");
+        let action = app.resolve_request(Some("other.com"), &RequestView::get("/"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::Close));
+        let action = app.resolve_request(None, &RequestView::get("/"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::Close));
+
+        // Known hosts are unaffected
+        let action = app.resolve_request(Some("example.com"), &RequestView::get("/"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::Proxy { .. }));
+
+        let app = app_with_unknown_host(hosts, "bogus");
+        let action = app.resolve_request(Some("other.com"), &RequestView::get("/"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::ServeDefault { .. }));
+    }
+
+    #[test]
+    fn test_default_server_runs_location_pipeline() {
+        let mut catch_all = host_with_upstream(2, &["fallback.example.com"]);
+        catch_all.default_server = true;
+        catch_all.locations.push(LocationConfig {
+            location_type: Some("redirect".to_string()),
+            forward_domain: Some("www.example.com".to_string()),
+            match_type: "exact".to_string(),
+            ..make_proxy_location("/old", "10.0.0.1", 8080)
+        });
+        let hosts = vec![host_with_upstream(1, &["example.com"]), catch_all];
+        // The default server takes precedence over unknown_host
+        let app = app_with_unknown_host(hosts, "close");
+
+        match app.resolve_request(Some("unknown.com"), &RequestView::get("/"), Some(80), None, None, None) {
+            RequestAction::Proxy { host_id, .. } => assert_eq!(host_id, Some(2)),
+            _ => panic!("expected Proxy"),
+        }
+        match app.resolve_request(None, &RequestView::get("/old"), Some(80), None, None, None) {
+            RequestAction::Redirect { location, .. } => assert_eq!(location, "https://www.example.com/"),
+            _ => panic!("expected Redirect"),
+        }
+        match app.resolve_request(Some("example.com"), &RequestView::get("/"), Some(80), None, None, None) {
+            RequestAction::Proxy { host_id, .. } => assert_eq!(host_id, Some(1)),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_default_server_access_list_applies() {
+        let mut catch_all = host_with_upstream(2, &["fallback.example.com"]);
+        catch_all.default_server = true;
+        catch_all.locations[0].access_list_id = Some(1);
+        let acl: AccessListConfig = serde_yaml::from_str("id: 1
+clients:
+  - {address: 10.0.0.0/8, directive: allow}
+  - {address: all, directive: deny}").unwrap();
+        let app = build_app(vec![catch_all], HashMap::from([(1, acl)]));
+        let outside: IpAddr = "192.168.1.1".parse().unwrap();
+        let action = app.resolve_request(Some("unknown.com"), &RequestView::get("/"), Some(80), Some(outside), None, None);
+        assert!(matches!(action, RequestAction::AccessDenied { .. }));
+    }
+
+    #[test]
+    fn test_request_host_prefers_header_then_authority() {
+        let mut req = RequestHeader::build("GET", b"/path", None).unwrap();
+        req.set_uri(http::Uri::from_static("https://h2.example.com/path"));
+        assert_eq!(request_host(&req), Some("h2.example.com"));
+        req.insert_header("Host", "header.example.com").unwrap();
+        assert_eq!(request_host(&req), Some("header.example.com"));
+
+        let req = RequestHeader::build("GET", b"/path", None).unwrap();
+        assert_eq!(request_host(&req), None);
+    }
+
     // ─── Force HTTPS ────────────────────────────────────────
 
     #[test]
@@ -1457,7 +2494,7 @@ mod tests {
             vec![host_with_ssl_force_https(1, &["secure.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(Some("secure.com"), "/page", Some(80), None, None);
+        let action = app.resolve_request(Some("secure.com"), &RequestView::get("/page"), Some(80), None, None, None);
         match action {
             RequestAction::ForceHttps { location } => {
                 assert_eq!(location, "https://secure.com/page");
@@ -1473,7 +2510,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("secure.com"), "/page", Some(443), Some(ip), None);
+        let action = app.resolve_request(Some("secure.com"), &RequestView::get("/page"), Some(443), Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1489,7 +2526,7 @@ mod tests {
             acls,
         );
         let ip: IpAddr = "1.2.3.4".parse().unwrap();
-        let action = app.resolve_request(Some("protected.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("protected.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::AccessDenied { .. }));
     }
 
@@ -1503,8 +2540,8 @@ mod tests {
             acls,
         );
         let ip: IpAddr = "1.2.3.4".parse().unwrap();
-        let action = app.resolve_request(Some("auth.com"), "/", Some(80), Some(ip), None);
-        assert!(matches!(action, RequestAction::AuthRequired));
+        let action = app.resolve_request(Some("auth.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
+        assert!(matches!(action, RequestAction::AuthRequired { .. }));
     }
 
     #[test]
@@ -1520,7 +2557,7 @@ mod tests {
         use base64::Engine;
         let encoded = base64::engine::general_purpose::STANDARD.encode("admin:secret");
         let auth = format!("Basic {}", encoded);
-        let action = app.resolve_request(Some("auth.com"), "/", Some(80), Some(ip), Some(&auth));
+        let action = app.resolve_request(Some("auth.com"), &RequestView::get("/"), Some(80), Some(ip), Some(&auth), None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1537,8 +2574,48 @@ mod tests {
         use base64::Engine;
         let encoded = base64::engine::general_purpose::STANDARD.encode("admin:WRONG");
         let auth = format!("Basic {}", encoded);
-        let action = app.resolve_request(Some("auth.com"), "/", Some(80), Some(ip), Some(&auth));
-        assert!(matches!(action, RequestAction::AuthRequired));
+        let action = app.resolve_request(Some("auth.com"), &RequestView::get("/"), Some(80), Some(ip), Some(&auth), None);
+        assert!(matches!(action, RequestAction::AuthRequired { .. }));
+    }
+
+    #[test]
+    fn test_client_cert_acl_forwards_identity() {
+        use crate::ssl::test_certs;
+        let dir = std::env::temp_dir().join("pingora-test-main-mtls");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = test_certs::ca("Internal CA");
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, ca.leaf.to_pem().unwrap()).unwrap();
+
+        let mut acl = make_acl_deny_all(1);
+        acl.clients.clear();
+        acl.client_cert = Some(ClientCertRule {
+            ca_bundle: ca_path.to_str().unwrap().to_string(),
+            subjects: vec![],
+            trust: Some(config::load_client_ca(ca_path.to_str().unwrap()).unwrap()),
+        });
+        let mut acls = HashMap::new();
+        acls.insert(1, acl);
+        let app = build_app(vec![host_with_acl(1, &["internal.com"], 1)], acls);
+
+        let leaf = test_certs::issue(&ca, "alice", &[]);
+        let cert = access_control::ClientCert {
+            leaf: leaf.leaf.clone(),
+            chain: vec![],
+        };
+        let action = app.resolve_request(Some("internal.com"), &RequestView::get("/"), Some(443), None, None, Some(&cert));
+        match action {
+            RequestAction::Proxy { client_identity, .. } => {
+                assert_eq!(client_identity.unwrap().subject, "CN=alice,O=Ops");
+            }
+            _ => panic!("expected Proxy"),
+        }
+
+        let action = app.resolve_request(Some("internal.com"), &RequestView::get("/"), Some(443), None, None, None);
+        assert!(matches!(action, RequestAction::AccessDenied { .. }));
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -1548,19 +2625,12 @@ mod tests {
         let host = HostConfig {
             id: 1,
             domains: vec!["x.com".to_string()],
-            group_id: None,
-            ssl: None,
             locations: vec![loc],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         };
         let app = build_app(vec![host], HashMap::new());
         let ip: IpAddr = "1.2.3.4".parse().unwrap();
-        let action = app.resolve_request(Some("x.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1573,7 +2643,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("static.com"), "/static/file.js", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("static.com"), &RequestView::get("/static/file.js"), Some(80), Some(ip), None, None);
         match action {
             RequestAction::ServeStatic { static_dir, location_path, cache_expires, .. } => {
                 assert_eq!(&*static_dir, "/var/www/static");
@@ -1593,7 +2663,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("empty.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("empty.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::NoUpstream { .. }));
     }
 
@@ -1607,10 +2677,11 @@ mod tests {
         );
         let action = app.resolve_request(
             Some("example.com\0.evil.com"),
-            "/",
+            &RequestView::get("/"),
             Some(80),
             None,
             None,
+            None,
         );
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
@@ -1619,7 +2690,7 @@ mod tests {
     fn test_very_long_host_header() {
         let app = build_app(vec![], HashMap::new());
         let long_host = "a".repeat(100_000);
-        let action = app.resolve_request(Some(&long_host), "/", Some(80), None, None);
+        let action = app.resolve_request(Some(&long_host), &RequestView::get("/"), Some(80), None, None, None);
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
 
@@ -1631,7 +2702,7 @@ mod tests {
         );
         let long_path = format!("/{}", "a".repeat(100_000));
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("x.com"), &long_path, Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get(&long_path), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1642,7 +2713,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("x.com"), "/../../../etc/passwd", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get("/../../../etc/passwd"), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1651,10 +2722,11 @@ mod tests {
         let app = build_app(vec![], HashMap::new());
         let action = app.resolve_request(
             Some("<script>alert(1)</script>"),
-            "/",
+            &RequestView::get("/"),
             Some(80),
             None,
             None,
+            None,
         );
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
@@ -1664,10 +2736,11 @@ mod tests {
         let app = build_app(vec![], HashMap::new());
         let action = app.resolve_request(
             Some("'; DROP TABLE hosts; --"),
-            "/",
+            &RequestView::get("/"),
             Some(80),
             None,
             None,
+            None,
         );
         assert!(matches!(action, RequestAction::ServeDefault { .. }));
     }
@@ -1684,12 +2757,13 @@ mod tests {
         let ip: IpAddr = "1.2.3.4".parse().unwrap();
         let action = app.resolve_request(
             Some("x.com"),
-            "/",
+            &RequestView::get("/"),
             Some(80),
             Some(ip),
             Some("NotBasic garbage!!!"),
+            None,
         );
-        assert!(matches!(action, RequestAction::AuthRequired));
+        assert!(matches!(action, RequestAction::AuthRequired { .. }));
     }
 
     #[test]
@@ -1699,7 +2773,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("secure.com"), "/", None, Some(ip), None);
+        let action = app.resolve_request(Some("secure.com"), &RequestView::get("/"), None, Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1709,7 +2783,7 @@ mod tests {
             vec![host_with_upstream(1, &["x.com"])],
             HashMap::new(),
         );
-        let action = app.resolve_request(Some("x.com"), "/", Some(80), None, None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), None, None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1720,7 +2794,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "::1".parse().unwrap();
-        let action = app.resolve_request(Some("x.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         assert!(matches!(action, RequestAction::Proxy { .. }));
     }
 
@@ -1731,7 +2805,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("secure.com"), "/", Some(443), Some(ip), None);
+        let action = app.resolve_request(Some("secure.com"), &RequestView::get("/"), Some(443), Some(ip), None, None);
         match action {
             RequestAction::Proxy { hsts, .. } => {
                 assert!(hsts);
@@ -1744,7 +2818,7 @@ mod tests {
     fn test_proxy_action_carries_compression_true_by_default() {
         let ip = "10.0.0.1".parse().unwrap();
         let app = build_app(vec![host_with_upstream(1, &["x.com"])], HashMap::new());
-        let action = app.resolve_request(Some("x.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         match action {
             RequestAction::Proxy { compression, .. } => assert!(compression),
             _ => panic!("expected Proxy"),
@@ -1757,31 +2831,492 @@ mod tests {
         host.compression = false;
         let ip = "10.0.0.1".parse().unwrap();
         let app = build_app(vec![host], HashMap::new());
-        let action = app.resolve_request(Some("nocomp.com"), "/", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("nocomp.com"), &RequestView::get("/"), Some(80), Some(ip), None, None);
         match action {
             RequestAction::Proxy { compression, .. } => assert!(!compression),
             _ => panic!("expected Proxy"),
         }
     }
 
+    // ─── Upstream TLS ───────────────────────────────────────
+
+    #[test]
+    fn test_proxy_action_plain_http_by_default() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host_with_upstream(1, &["x.com"])], HashMap::new());
+        match app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), Some(ip), None, None) {
+            RequestAction::Proxy { upstream_tls, upstream_sni, .. } => {
+                assert!(upstream_tls.is_none());
+                assert!(upstream_sni.is_empty());
+            }
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_proxy_action_carries_upstream_tls_and_sni() {
+        let mut host = host_with_upstream(1, &["tls.com"]);
+        host.locations = vec![make_proxy_location("/", "localhost", 8443)];
+        host.locations[0].upstream_scheme = Some("https".to_string());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let app = build_app(vec![host.clone()], HashMap::new());
+        match app.resolve_request(Some("tls.com"), &RequestView::get("/"), Some(443), Some(ip), None, None) {
+            RequestAction::Proxy { upstream_tls, upstream_sni, .. } => {
+                assert!(upstream_tls.is_some());
+                assert_eq!(upstream_sni, "localhost");
+            }
+            _ => panic!("expected Proxy"),
+        }
+
+        host.locations[0].upstream_tls = Some(UpstreamTlsConfig {
+            sni: Some("backend.internal".to_string()),
+            ..UpstreamTlsConfig::default()
+        });
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("tls.com"), &RequestView::get("/"), Some(443), Some(ip), None, None) {
+            RequestAction::Proxy { upstream_sni, .. } => assert_eq!(upstream_sni, "backend.internal"),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_broken_upstream_tls_disables_location() {
+        let mut host = host_with_upstream(1, &["broken.com"]);
+        host.locations[0].upstream_scheme = Some("https".to_string());
+        host.locations[0].upstream_tls = Some(UpstreamTlsConfig {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..UpstreamTlsConfig::default()
+        });
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host], HashMap::new());
+        assert!(matches!(
+            app.resolve_request(Some("broken.com"), &RequestView::get("/"), Some(443), Some(ip), None, None),
+            RequestAction::NoUpstream { .. }
+        ));
+    }
+
+    // ─── Location conditions ────────────────────────────────
+
+    fn proxied_to(action: RequestAction) -> String {
+        match action {
+            RequestAction::Proxy { upstream_addr, .. } => upstream_addr.to_string(),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_conditions_route_to_different_upstreams() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        let mut writes = make_proxy_location("/api", "10.0.0.2", 8080);
+        writes.conditions = Some(LocationConditions {
+            methods: vec!["POST".to_string()],
+            ..LocationConditions::default()
+        });
+        let mut canary = make_proxy_location("/", "10.0.0.3", 8080);
+        canary.conditions = Some(LocationConditions {
+            headers: vec![ValueCondition {
+                name: "X-Canary".to_string(),
+                value: Some("1".to_string()),
+            }],
+            ..LocationConditions::default()
+        });
+        host.locations.push(writes);
+        host.locations.push(canary);
+        let app = build_app(vec![host], HashMap::new());
+        let ip = Some("10.0.0.9".parse().unwrap());
+
+        let post = RequestView { method: "POST", ..RequestView::get("/api/items") };
+        assert_eq!(proxied_to(app.resolve_request(Some("app.com"), &post, Some(80), ip, None, None)), "10.0.0.2:8080");
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-canary", "1".parse().unwrap());
+        let flagged = RequestView { headers: Some(&headers), ..RequestView::get("/api/items") };
+        assert_eq!(proxied_to(app.resolve_request(Some("app.com"), &flagged, Some(80), ip, None, None)), "10.0.0.3:8080");
+
+        let plain = RequestView::get("/api/items");
+        assert_eq!(proxied_to(app.resolve_request(Some("app.com"), &plain, Some(80), ip, None, None)), "10.0.0.1:8080");
+    }
+
+    // ─── Regex captures ─────────────────────────────────────
+
+    #[test]
+    fn test_redirect_expands_regex_captures() {
+        let mut host = host_with_redirect_location(1, &["old.com"]);
+        let loc = &mut host.locations[0];
+        loc.path = r"^/users/(?P<user>\w+)/(\d+)$".to_string();
+        loc.match_type = "regex".to_string();
+        loc.forward_domain = Some("${user}.example.com".to_string());
+        loc.forward_path = Some("/posts/$2".to_string());
+        loc.preserve_path = false;
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("old.com"), &RequestView::get("/users/alice/42"), Some(80), None, None, None) {
+            RequestAction::Redirect { location, .. } => {
+                assert_eq!(location, "https://alice.example.com/posts/42");
+            }
+            _ => panic!("expected Redirect"),
+        }
+    }
+
+    #[test]
+    fn test_proxy_rewrite_and_headers_expand_captures() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        let mut loc = make_proxy_location(r"^/v(\d+)/(.*)$", "10.0.0.1", 8080);
+        loc.match_type = "regex".to_string();
+        loc.forward_path = Some("/api/${2}?version=$1".to_string());
+        loc.compiled_headers = vec![(http::header::HeaderName::from_static("x-api-version"), Arc::from("v$1"))];
+        // headers feed the router's capture detection; compiled_headers are what gets sent
+        loc.headers.insert("X-Api-Version".to_string(), "v$1".to_string());
+        host.locations = vec![loc];
+        let app = build_app(vec![host], HashMap::new());
+        let ip = Some("10.0.0.9".parse().unwrap());
+        match app.resolve_request(Some("app.com"), &RequestView::get("/v2/users/7"), Some(80), ip, None, None) {
+            RequestAction::Proxy { rewrite_path, custom_headers, .. } => {
+                assert_eq!(rewrite_path, Some(PathRewrite::Full("/api/users/7?version=2".to_string())));
+                assert_eq!(custom_headers[0].1.as_ref(), "v2");
+            }
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_prefix_location_keeps_prefix_rewrite() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].path = "/api".to_string();
+        host.locations[0].forward_path = Some("/v1/$1".to_string());
+        let app = build_app(vec![host], HashMap::new());
+        let ip = Some("10.0.0.9".parse().unwrap());
+        match app.resolve_request(Some("app.com"), &RequestView::get("/api/users"), Some(80), ip, None, None) {
+            RequestAction::Proxy { rewrite_path, .. } => assert_eq!(
+                rewrite_path,
+                Some(PathRewrite::Prefix { prefix: Arc::from("/api"), replacement: Arc::from("/v1/$1") })
+            ),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_path_rewrite_apply() {
+        let prefix = PathRewrite::Prefix { prefix: Arc::from("/api"), replacement: Arc::from("/backend") };
+        let uri: http::Uri = "/api/users?page=2".parse().unwrap();
+        assert_eq!(prefix.apply(&uri).unwrap(), "/backend/users?page=2");
+        assert!(prefix.apply(&"/other".parse().unwrap()).is_none());
+
+        let full = PathRewrite::Full("/users/7".to_string());
+        assert_eq!(full.apply(&uri).unwrap(), "/users/7?page=2");
+        let with_query = PathRewrite::Full("/users?id=7".to_string());
+        assert_eq!(with_query.apply(&uri).unwrap(), "/users?id=7&page=2");
+
+        // Rewrite rules already settled the query
+        let rewritten = PathRewrite::Uri("/v2/users".to_string());
+        assert_eq!(rewritten.apply(&uri).unwrap(), "/v2/users");
+    }
+
+    // ─── Rewrite rules ──────────────────────────────────────
+
+    fn rewrite_rule(pattern: &str, replacement: &str, flag: &str) -> RewriteRule {
+        RewriteRule {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            flag: flag.to_string(),
+        }
+    }
+
+    fn proxied_with_rewrite(action: RequestAction) -> (String, Option<PathRewrite>) {
+        match action {
+            RequestAction::Proxy { upstream_addr, rewrite_path, .. } => (upstream_addr.to_string(), rewrite_path),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_last_rematches_location() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/legacy/(.*)$", "/api/$1", "last")];
+        let mut api = make_proxy_location("/api", "10.0.0.2", 8080);
+        api.forward_path = Some("/v2".to_string());
+        host.locations.push(api);
+        let app = build_app(vec![host], HashMap::new());
+        let ip = Some("10.0.0.9".parse().unwrap());
+        let request = RequestView { query: Some("page=2"), ..RequestView::get("/legacy/users") };
+        let (addr, rewrite_path) =
+            proxied_with_rewrite(app.resolve_request(Some("app.com"), &request, Some(80), ip, None, None));
+        assert_eq!(addr, "10.0.0.2:8080");
+        assert_eq!(rewrite_path, Some(PathRewrite::Uri("/v2/users?page=2".to_string())));
+    }
+
+    #[test]
+    fn test_rewrite_break_stays_in_location() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/legacy/(.*)$", "/api/$1?", "break")];
+        host.locations.push(make_proxy_location("/api", "10.0.0.2", 8080));
+        let app = build_app(vec![host], HashMap::new());
+        let ip = Some("10.0.0.9".parse().unwrap());
+        let request = RequestView { query: Some("page=2"), ..RequestView::get("/legacy/users") };
+        let (addr, rewrite_path) =
+            proxied_with_rewrite(app.resolve_request(Some("app.com"), &request, Some(80), ip, None, None));
+        assert_eq!(addr, "10.0.0.1:8080");
+        assert_eq!(rewrite_path, Some(PathRewrite::Uri("/api/users".to_string())));
+    }
+
+    #[test]
+    fn test_rewrite_redirect_flags() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![
+            rewrite_rule("^/old$", "/new", "permanent"),
+            rewrite_rule("^/tmp$", "/elsewhere", "redirect"),
+        ];
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("app.com"), &RequestView::get("/old"), Some(80), None, None, None) {
+            RequestAction::Redirect { status_code, location } => {
+                assert_eq!(status_code, 301);
+                assert_eq!(location, "http://app.com/new");
+            }
+            _ => panic!("expected Redirect"),
+        }
+        match app.resolve_request(Some("app.com"), &RequestView::get("/tmp"), Some(443), None, None, None) {
+            RequestAction::Redirect { status_code, location } => {
+                assert_eq!(status_code, 302);
+                assert_eq!(location, "https://app.com/elsewhere");
+            }
+            _ => panic!("expected Redirect"),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_cycle_is_stopped() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/(.*)$", "/$1", "last")];
+        let app = build_app(vec![host], HashMap::new());
+        let action = app.resolve_request(Some("app.com"), &RequestView::get("/loop"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::RewriteCycle { .. }));
+    }
+
+    #[test]
+    fn test_rewritten_path_is_normalized_before_access_check() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/api(.*)$", "/$1", "last")];
+        let mut admin = make_proxy_location("/admin", "10.0.0.2", 8080);
+        admin.access_list_id = Some(1);
+        host.locations.push(admin);
+        let mut acls = HashMap::new();
+        acls.insert(1, make_acl_deny_all(1));
+        let app = build_app(vec![host], acls);
+        let ip = Some("1.2.3.4".parse().unwrap());
+
+        // "/api/admin" becomes "//admin", which must still hit the /admin ACL
+        let action = app.resolve_request(Some("app.com"), &RequestView::get("/api/admin"), Some(80), ip, None, None);
+        assert!(matches!(action, RequestAction::AccessDenied { .. }));
+    }
+
+    #[test]
+    fn test_rejected_rewritten_path_is_bad_request() {
+        let mut host = host_with_upstream(1, &["app.com"]);
+        host.locations[0].rewrites = vec![rewrite_rule("^/files/(.*)$", "/a%2F$1", "break")];
+        let app = build_app(vec![host], HashMap::new());
+        let action = app.resolve_request(Some("app.com"), &RequestView::get("/files/x"), Some(80), None, None, None);
+        assert!(matches!(action, RequestAction::RejectedRewrite { .. }));
+    }
+
+    #[test]
+    fn test_rewrite_into_static_location() {
+        let mut host = host_with_static_location(1, &["static.com"]);
+        let mut assets = make_proxy_location("/assets", "10.0.0.1", 8080);
+        assets.rewrites = vec![rewrite_rule("^/assets/(.*)$", "/static/$1", "last")];
+        host.locations.push(assets);
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("static.com"), &RequestView::get("/assets/app.js"), Some(80), None, None, None) {
+            RequestAction::ServeStatic { location_path, rewritten_path, .. } => {
+                assert_eq!(&*location_path, "/static");
+                assert_eq!(rewritten_path.as_deref(), Some("/static/app.js"));
+            }
+            _ => panic!("expected ServeStatic"),
+        }
+    }
+
+    // ─── Upstream HTTP/2 ────────────────────────────────────
+
+    #[test]
+    fn test_proxy_action_carries_upstream_http2() {
+        let mut host = host_with_upstream(1, &["grpc.com"]);
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host.clone()], HashMap::new());
+        match app.resolve_request(Some("grpc.com"), &RequestView::get("/"), Some(80), Some(ip), None, None) {
+            RequestAction::Proxy { upstream_http2, .. } => assert!(!upstream_http2),
+            _ => panic!("expected Proxy"),
+        }
+
+        host.locations[0].upstream_http2 = true;
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("grpc.com"), &RequestView::get("/"), Some(80), Some(ip), None, None) {
+            RequestAction::Proxy { upstream_http2, .. } => assert!(upstream_http2),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_upstream_peer_protocol() {
+        let app = build_app(Vec::new(), HashMap::new());
+        let mut ctx = app.new_ctx();
+        assert!(ctx.build_upstream_peer().is_err(), "no upstream resolved");
+
+        ctx.upstream_addr = Some(Arc::from("127.0.0.1:8080"));
+        let peer = ctx.build_upstream_peer().unwrap();
+        assert!(!peer.is_tls());
+        assert_eq!(peer.options.alpn, ALPN::H1);
+
+        // Cleartext h2c
+        ctx.upstream_http2 = true;
+        let peer = ctx.build_upstream_peer().unwrap();
+        assert!(!peer.is_tls());
+        assert_eq!(peer.options.alpn, ALPN::H2);
+        assert_eq!(peer.options.max_h2_streams, UPSTREAM_H2_MAX_STREAMS);
+
+        // h2 over TLS
+        let mut loc = make_proxy_location("/", "localhost", 8443);
+        loc.upstream_scheme = Some("https".to_string());
+        ctx.upstream_tls = upstream::UpstreamTls::from_location(&loc).unwrap().map(Arc::new);
+        ctx.upstream_sni = "localhost".to_string();
+        let peer = ctx.build_upstream_peer().unwrap();
+        assert!(peer.is_tls());
+        assert_eq!(peer.sni(), "localhost");
+        assert_eq!(peer.options.alpn, ALPN::H2);
+    }
+
+    // ─── gRPC ───────────────────────────────────────────────
+
+    #[test]
+    fn test_grpc_location_proxies_over_http2_uncompressed() {
+        let mut host = host_with_upstream(1, &["grpc.com"]);
+        host.locations[0].location_type = Some("grpc".to_string());
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("grpc.com"), &RequestView::get("/pkg.Service/Method"), Some(443), Some(ip), None, None) {
+            RequestAction::Proxy { grpc, upstream_http2, compression, .. } => {
+                assert!(grpc);
+                assert!(upstream_http2);
+                assert!(!compression);
+            }
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_proxy_location_is_not_grpc() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host_with_upstream(1, &["x.com"])], HashMap::new());
+        match app.resolve_request(Some("x.com"), &RequestView::get("/"), Some(80), Some(ip), None, None) {
+            RequestAction::Proxy { grpc, .. } => assert!(!grpc),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
+    #[test]
+    fn test_grpc_location_without_upstreams() {
+        let mut host = host_with_upstream(1, &["grpc.com"]);
+        host.locations[0].location_type = Some("grpc".to_string());
+        host.locations[0].upstreams.clear();
+        let ip = "10.0.0.1".parse().unwrap();
+        let app = build_app(vec![host], HashMap::new());
+        match app.resolve_request(Some("grpc.com"), &RequestView::get("/pkg.Service/Method"), Some(443), Some(ip), None, None) {
+            RequestAction::NoUpstream { grpc, .. } => assert!(grpc),
+            _ => panic!("expected NoUpstream"),
+        }
+    }
+
+    #[test]
+    fn test_grpc_location_access_errors_are_grpc() {
+        let mut denied = host_with_acl(1, &["denied.com"], 1);
+        denied.locations[0].location_type = Some("grpc".to_string());
+        let mut auth = host_with_acl(2, &["auth.com"], 2);
+        auth.locations[0].location_type = Some("grpc".to_string());
+        let mut acls = HashMap::new();
+        acls.insert(1, make_acl_deny_all(1));
+        acls.insert(2, make_acl_with_auth(2));
+        let app = build_app(vec![denied, auth, host_with_acl(3, &["web.com"], 1)], acls);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        let request = RequestView::get("/pkg.Service/Method");
+
+        match app.resolve_request(Some("denied.com"), &request, Some(443), ip, None, None) {
+            RequestAction::AccessDenied { grpc, .. } => assert!(grpc),
+            _ => panic!("expected AccessDenied"),
+        }
+        match app.resolve_request(Some("auth.com"), &request, Some(443), ip, None, None) {
+            RequestAction::AuthRequired { grpc } => assert!(grpc),
+            _ => panic!("expected AuthRequired"),
+        }
+        match app.resolve_request(Some("web.com"), &request, Some(443), ip, None, None) {
+            RequestAction::AccessDenied { grpc, .. } => assert!(!grpc),
+            _ => panic!("expected AccessDenied"),
+        }
+    }
+
+    // ─── Outlier detection ──────────────────────────────────
+
+    fn outlier_app() -> ProxyApp {
+        let mut host = host_with_upstream(1, &["od.com"]);
+        host.locations[0].upstreams.push(UpstreamConfig {
+            server: "10.0.0.2".to_string(),
+            port: 8080,
+            weight: 1,
+        });
+        host.locations[0].outlier_detection = Some(OutlierDetectionConfig {
+            max_fails: 1,
+            fail_timeout_secs: 60,
+            max_ejection_secs: 300,
+        });
+        build_app(vec![host], HashMap::new())
+    }
+
+    fn proxied_with_outliers(app: &ProxyApp) -> (String, Arc<upstream::OutlierDetector>, SocketAddr) {
+        match app.resolve_request(Some("od.com"), &RequestView::get("/"), None, None, None, None) {
+            RequestAction::Proxy { upstream_addr, outliers: Some((outliers, addr)), .. } => {
+                (upstream_addr.to_string(), outliers, addr)
+            }
+            _ => panic!("expected Proxy with outlier detection"),
+        }
+    }
+
+    #[test]
+    fn test_ejected_upstream_is_skipped() {
+        let app = outlier_app();
+        let (_, outliers, _) = proxied_with_outliers(&app);
+        outliers.report("10.0.0.1:8080".parse().unwrap(), false);
+        for _ in 0..4 {
+            assert_eq!(proxied_with_outliers(&app).0, "10.0.0.2:8080");
+        }
+    }
+
+    #[test]
+    fn test_all_upstreams_ejected_still_proxies() {
+        let app = outlier_app();
+        let (_, outliers, _) = proxied_with_outliers(&app);
+        outliers.report("10.0.0.1:8080".parse().unwrap(), false);
+        outliers.report("10.0.0.2:8080".parse().unwrap(), false);
+        let (upstream, _, addr) = proxied_with_outliers(&app);
+        assert_eq!(upstream, addr.to_string());
+    }
+
+    #[test]
+    fn test_no_outlier_detection_by_default() {
+        let app = build_app(vec![host_with_upstream(1, &["plain.com"])], HashMap::new());
+        match app.resolve_request(Some("plain.com"), &RequestView::get("/"), None, None, None, None) {
+            RequestAction::Proxy { outliers, .. } => assert!(outliers.is_none()),
+            _ => panic!("expected Proxy"),
+        }
+    }
+
     // ─── SharedState::build ─────────────────────────────────
 
     #[test]
     fn test_shared_state_build_empty_config() {
         let config = AppConfig {
-            global: GlobalConfig {
-                listen: ListenConfig { http: 80, https: 443, admin: 81 },
-                admin_upstream: "127.0.0.1:3001".to_string(),
-                default_page: "/data/default-page/index.html".to_string(),
-                error_pages_dir: "/data/error-pages".to_string(),
-                logs_dir: "/data/logs".to_string(),
-                ssl_dir: "/etc/letsencrypt".to_string(),
-            },
+            global: GlobalConfig::default(),
             hosts: vec![],
             access_lists: HashMap::new(),
         };
         let (log_sender, _) = log_writer::create_log_channel();
-        let state = SharedState::build(config, log_sender);
+        let state = SharedState::build(config, log_sender, None);
         assert!(!state.ssl_manager.has_certs());
         assert!(state.location_lbs.is_empty());
     }
@@ -1791,53 +3326,136 @@ mod tests {
         let host = HostConfig {
             id: 1,
             domains: vec!["x.com".to_string()],
-            group_id: None,
-            ssl: None,
             locations: vec![LocationConfig {
                 path: "/api".to_string(),
-                match_type: "prefix".to_string(),
-                location_type: Some("proxy".to_string()),
                 upstreams: vec![UpstreamConfig {
                     server: "10.0.0.2".to_string(),
                     port: 9090,
                     weight: 1,
                 }],
                 balance_method: "ip_hash".to_string(),
-                static_dir: None,
-                cache_expires: None,
-                forward_scheme: None,
-                forward_domain: None,
-                forward_path: None,
-                preserve_path: false,
-                status_code: None,
-                headers: HashMap::new(),
-                access_list_id: None,
-                compiled_headers: Vec::new(),
+                ..Default::default()
             }],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         };
         let config = AppConfig {
-            global: GlobalConfig {
-                listen: ListenConfig { http: 80, https: 443, admin: 81 },
-                admin_upstream: "127.0.0.1:3001".to_string(),
-                default_page: "/data/default-page/index.html".to_string(),
-                error_pages_dir: "/data/error-pages".to_string(),
-                logs_dir: "/data/logs".to_string(),
-                ssl_dir: "/etc/letsencrypt".to_string(),
-            },
+            global: GlobalConfig::default(),
             hosts: vec![host],
             access_lists: HashMap::new(),
         };
         let (log_sender, _) = log_writer::create_log_channel();
-        let state = SharedState::build(config, log_sender);
+        let state = SharedState::build(config, log_sender, None);
         assert!(state.location_lbs.contains_key(&(1, 0)));
     }
 
+    #[test]
+    fn test_shared_state_build_keeps_upstream_health() {
+        let live = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let dead_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut api = make_proxy_location("/api", "127.0.0.1", live_port);
+        api.upstreams.push(UpstreamConfig {
+            server: "127.0.0.1".to_string(),
+            port: dead_port,
+            weight: 1,
+        });
+        api.health_check = Some(serde_yaml::from_str("{fall: 1}").unwrap());
+        let mut host = HostConfig {
+            id: 1,
+            domains: vec!["x.com".to_string()],
+            locations: vec![api],
+            ..Default::default()
+        };
+        let config = |host: &HostConfig| AppConfig {
+            global: GlobalConfig::default(),
+            hosts: vec![host.clone()],
+            access_lists: HashMap::new(),
+        };
+        let (log_sender, _) = log_writer::create_log_channel();
+        let old = SharedState::build(config(&host), log_sender.clone(), None);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(old.location_lbs[&(1, 0)].run_health_check());
+
+        // The location moves to another index; its upstreams keep their health
+        host.locations.insert(0, make_proxy_location("/", "10.0.0.1", 8080));
+        let new = SharedState::build(config(&host), log_sender, Some(&old));
+        let dead: SocketAddr = ([127, 0, 0, 1], dead_port).into();
+        assert_eq!(new.location_lbs[&(1, 1)].unhealthy_backends(), std::collections::HashSet::from([dead]));
+    }
+
+    #[test]
+    fn test_shared_state_build_keeps_health_per_conditional_location() {
+        let live = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let dead_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let checked = |methods: Option<&str>| {
+            let mut loc = make_proxy_location("/api", "127.0.0.1", live_port);
+            loc.upstreams.push(UpstreamConfig {
+                server: "127.0.0.1".to_string(),
+                port: dead_port,
+                weight: 1,
+            });
+            loc.health_check = Some(serde_yaml::from_str("{fall: 1}").unwrap());
+            loc.conditions = methods.map(|m| serde_yaml::from_str(&format!("{{methods: [{}]}}", m)).unwrap());
+            loc
+        };
+        // Same path: a POST-only location and a catch-all
+        let mut host = HostConfig {
+            id: 1,
+            domains: vec!["x.com".to_string()],
+            locations: vec![checked(None), checked(Some("POST"))],
+            ..Default::default()
+        };
+        let config = |host: &HostConfig| AppConfig {
+            global: GlobalConfig::default(),
+            hosts: vec![host.clone()],
+            access_lists: HashMap::new(),
+        };
+        let (log_sender, _) = log_writer::create_log_channel();
+        let old = SharedState::build(config(&host), log_sender.clone(), None);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        // Only the POST location has seen the dead upstream fail
+        rt.block_on(old.location_lbs[&(1, 1)].run_health_check());
+
+        host.locations.reverse();
+        let new = SharedState::build(config(&host), log_sender, Some(&old));
+        let dead: SocketAddr = ([127, 0, 0, 1], dead_port).into();
+        assert_eq!(new.location_lbs[&(1, 0)].unhealthy_backends(), std::collections::HashSet::from([dead]));
+        assert!(new.location_lbs[&(1, 1)].unhealthy_backends().is_empty());
+    }
+
+    #[test]
+    fn test_shared_state_build_keeps_ejections_per_conditional_location() {
+        let with_outliers = |methods: Option<&str>| {
+            let mut loc = make_proxy_location("/api", "10.0.0.1", 8080);
+            loc.outlier_detection = Some(serde_yaml::from_str("{maxFails: 1, failTimeoutSecs: 60}").unwrap());
+            loc.conditions = methods.map(|m| serde_yaml::from_str(&format!("{{methods: [{}]}}", m)).unwrap());
+            loc
+        };
+        let mut host = HostConfig {
+            id: 1,
+            domains: vec!["x.com".to_string()],
+            locations: vec![with_outliers(None), with_outliers(Some("POST"))],
+            ..Default::default()
+        };
+        let config = |host: &HostConfig| AppConfig {
+            global: GlobalConfig::default(),
+            hosts: vec![host.clone()],
+            access_lists: HashMap::new(),
+        };
+        let (log_sender, _) = log_writer::create_log_channel();
+        let old = SharedState::build(config(&host), log_sender.clone(), None);
+        let upstream: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        // Only the POST location ejected the upstream
+        old.location_outliers[&(1, 1)].report(upstream, false);
+
+        host.locations.reverse();
+        let new = SharedState::build(config(&host), log_sender, Some(&old));
+        let now = std::time::Instant::now();
+        assert!(new.location_outliers[&(1, 0)].is_ejected(&upstream, now));
+        assert!(!new.location_outliers[&(1, 1)].is_ejected(&upstream, now));
+    }
+
     // ─── File location routing ─────────────────────────────
 
     fn host_with_file_location(id: u64, domains: &[&str]) -> HostConfig {
@@ -1845,30 +3463,15 @@ mod tests {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
             group_id: Some(10),
-            ssl: None,
             locations: vec![LocationConfig {
                 path: "/sitemap.xml".to_string(),
                 match_type: "exact".to_string(),
                 location_type: Some("file".to_string()),
-                upstreams: vec![],
-                balance_method: "round_robin".to_string(),
                 static_dir: Some("/var/www/sitemap.xml".to_string()),
                 cache_expires: Some("1h".to_string()),
-                forward_scheme: None,
-                forward_domain: None,
-                forward_path: None,
-                preserve_path: false,
-                status_code: None,
-                headers: HashMap::new(),
-                access_list_id: None,
-                compiled_headers: Vec::new(),
+                ..Default::default()
             }],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
-            enabled: true,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -1879,7 +3482,7 @@ mod tests {
             HashMap::new(),
         );
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let action = app.resolve_request(Some("files.com"), "/sitemap.xml", Some(80), Some(ip), None);
+        let action = app.resolve_request(Some("files.com"), &RequestView::get("/sitemap.xml"), Some(80), Some(ip), None, None);
         match action {
             RequestAction::ServeFile { file_path, cache_expires, .. } => {
                 assert_eq!(&*file_path, "/var/www/sitemap.xml");
@@ -0,0 +1,552 @@
+use crate::acme::AcmeHttp;
+use crate::config::OcspConfig;
+use crate::ssl::{CertPair, LoadedCert};
+use crate::tls::{CertStore, SharedCertStore};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use dashmap::DashMap;
+use openssl::asn1::Asn1GeneralizedTimeRef;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus};
+use openssl::ssl::SslRef;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::X509Ref;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Clock skew tolerated when checking a response's thisUpdate/nextUpdate
+const MAX_CLOCK_SKEW_SECS: u32 = 300;
+/// Refresh period for responses that carry no nextUpdate
+const NO_NEXT_UPDATE_REFRESH: chrono::Duration = chrono::Duration::hours(1);
+
+/// A validated OCSP response for one certificate
+pub struct Staple {
+    /// DER-encoded OCSPResponse, sent as-is in the handshake
+    pub der: Vec<u8>,
+    pub this_update: DateTime<Utc>,
+    pub next_update: Option<DateTime<Utc>>,
+}
+
+impl Staple {
+    /// Whether clients would still accept the response
+    pub fn is_current(&self, now: DateTime<Utc>) -> bool {
+        self.next_update.is_none_or(|next| now < next)
+    }
+
+    /// Refresh halfway through the validity window, leaving time for retries
+    fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        match self.next_update {
+            Some(next) => now >= self.this_update + (next - self.this_update) / 2,
+            None => now >= self.this_update + NO_NEXT_UPDATE_REFRESH,
+        }
+    }
+}
+
+/// OCSP responses keyed by the SHA-256 of the leaf certificate they cover, shared
+/// by the TLS listener and the refresh loop. Keying by certificate rather than by
+/// path means a renewed certificate is never stapled with its predecessor's response.
+#[derive(Default)]
+pub struct OcspCache {
+    staples: DashMap<Vec<u8>, Arc<Staple>>,
+}
+
+impl OcspCache {
+    /// Response for a leaf certificate, if one has been fetched
+    pub fn get(&self, leaf: &X509Ref) -> Option<Arc<Staple>> {
+        let key = cert_key(leaf).ok()?;
+        self.staples.get(&key).map(|v| Arc::clone(&v))
+    }
+}
+
+fn cert_key(leaf: &X509Ref) -> Result<Vec<u8>, ErrorStack> {
+    Ok(leaf.digest(MessageDigest::sha256())?.to_vec())
+}
+
+/// Status callback of the TLS listener: staple the response for the certificate
+/// selected for this handshake. Only called when the client asked for one.
+pub fn staple(cache: &OcspCache, ssl: &mut SslRef) -> Result<bool, ErrorStack> {
+    let staple = match ssl.certificate().and_then(|leaf| cache.get(leaf)) {
+        Some(staple) if staple.is_current(Utc::now()) => staple,
+        _ => return Ok(false),
+    };
+    ssl.set_ocsp_status(&staple.der)?;
+    Ok(true)
+}
+
+/// Check a DER OCSP response for `leaf`: successful, signed by `issuer` or a
+/// responder it delegated to, status "good", and inside its validity window.
+fn validate(der: &[u8], leaf: &X509Ref, issuer: &X509Ref) -> Result<Staple, String> {
+    let response = OcspResponse::from_der(der).map_err(|e| format!("invalid OCSP response: {}", e))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(format!("responder returned status {}", response.status().as_raw()));
+    }
+    let basic = response
+        .basic()
+        .map_err(|e| format!("invalid OCSP response: {}", e))?;
+
+    // The issuer is usually an intermediate, so trust it directly
+    let issuer_stack = || -> Result<_, ErrorStack> {
+        let mut certs = Stack::new()?;
+        certs.push(issuer.to_owned())?;
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(issuer.to_owned())?;
+        store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        Ok((certs, store.build()))
+    };
+    let (certs, store) = issuer_stack().map_err(|e| e.to_string())?;
+    basic
+        .verify(&certs, &store, OcspFlag::empty())
+        .map_err(|e| format!("response signature not trusted: {}", e))?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).map_err(|e| e.to_string())?;
+    let status = basic
+        .find_status(&id)
+        .ok_or_else(|| "response does not cover the certificate".to_string())?;
+    if status.status == OcspCertStatus::REVOKED {
+        return Err("certificate is revoked".to_string());
+    }
+    if status.status != OcspCertStatus::GOOD {
+        return Err("certificate status is unknown to the responder".to_string());
+    }
+    status
+        .check_validity(MAX_CLOCK_SKEW_SECS, None)
+        .map_err(|e| format!("response outside its validity period: {}", e))?;
+
+    let this_update =
+        generalized_to_utc(status.this_update).ok_or_else(|| "unreadable thisUpdate".to_string())?;
+    let next_update = match status.next_update() {
+        Some(time) => Some(generalized_to_utc(time).ok_or_else(|| "unreadable nextUpdate".to_string())?),
+        None => None,
+    };
+    Ok(Staple {
+        der: der.to_vec(),
+        this_update,
+        next_update,
+    })
+}
+
+/// Convert an ASN.1 GeneralizedTime (printed as "Jan  2 03:04:05 2026 GMT")
+fn generalized_to_utc(time: &Asn1GeneralizedTimeRef) -> Option<DateTime<Utc>> {
+    let printed = time.to_string();
+    let printed = printed.strip_suffix(" GMT")?;
+    let normalized = printed.split_whitespace().collect::<Vec<_>>().join(" ");
+    NaiveDateTime::parse_from_str(&normalized, "%b %d %H:%M:%S%.f %Y")
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// POST an OCSP request for `leaf` to the responder at `url`
+async fn fetch(http: &AcmeHttp, url: &str, leaf: &X509Ref, issuer: &X509Ref) -> Result<Vec<u8>, String> {
+    let build = || -> Result<Vec<u8>, ErrorStack> {
+        let mut request = OcspRequest::new()?;
+        request.add_id(OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer)?)?;
+        request.to_der()
+    };
+    let body = build().map_err(|e| format!("cannot build OCSP request: {}", e))?;
+    let resp = http
+        .request("POST", url, Some(("application/ocsp-request", body)))
+        .await?;
+    if resp.status != 200 {
+        return Err(format!("responder {} returned HTTP {}", url, resp.status));
+    }
+    Ok(resp.body)
+}
+
+/// Obtain a fresh response for one certificate, from its `ocsp_file` when set,
+/// otherwise from the first responder URL in the certificate
+async fn obtain(http: &AcmeHttp, pair: &CertPair, cert: &LoadedCert) -> Result<Staple, String> {
+    let issuer = cert
+        .chain
+        .first()
+        .ok_or_else(|| "chain has no issuer certificate".to_string())?;
+    let der = match &pair.ocsp_file {
+        Some(path) => std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?,
+        None => {
+            // Fails when the certificate has no authorityInfoAccess extension
+            let url = cert
+                .leaf
+                .ocsp_responders()
+                .ok()
+                .and_then(|responders| responders.iter().next().map(|url| url.to_string()))
+                .ok_or_else(|| "certificate names no OCSP responder".to_string())?;
+            fetch(http, &url, &cert.leaf, issuer).await?
+        }
+    };
+    validate(&der, &cert.leaf, issuer)
+}
+
+/// Whether a certificate has anywhere to get a response from
+fn has_source(pair: &CertPair, cert: &LoadedCert) -> bool {
+    pair.ocsp_file.is_some()
+        || cert
+            .leaf
+            .ocsp_responders()
+            .is_ok_and(|responders| !responders.is_empty())
+}
+
+/// Fetch responses that are missing or due for refresh and drop those of
+/// certificates no longer served. A failed refresh keeps the previous response
+/// until it expires. Returns the number of responses updated.
+async fn refresh(cache: &OcspCache, store: &CertStore, http: &AcmeHttp) -> usize {
+    let now = Utc::now();
+    let mut served = HashSet::new();
+    let mut updated = 0;
+    for (pair, cert) in store.served() {
+        let key = match cert_key(&cert.leaf) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        served.insert(key.clone());
+        if !has_source(pair, cert) {
+            continue;
+        }
+        let due = cache.staples.get(&key).is_none_or(|s| s.needs_refresh(now));
+        // Files are re-read every pass so externally refreshed responses are picked up
+        if !due && pair.ocsp_file.is_none() {
+            continue;
+        }
+        match obtain(http, pair, cert).await {
+            Ok(staple) => {
+                let unchanged = cache.staples.get(&key).is_some_and(|s| s.der == staple.der);
+                if !unchanged {
+                    log::debug!(
+                        "OCSP response for {} valid until {}",
+                        pair.cert_path,
+                        staple
+                            .next_update
+                            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                            .unwrap_or_else(|| "replaced".to_string())
+                    );
+                    cache.staples.insert(key, Arc::new(staple));
+                    updated += 1;
+                }
+            }
+            Err(e) => log::warn!("OCSP response for {} unavailable: {}", pair.cert_path, e),
+        }
+    }
+    cache.staples.retain(|key, _| served.contains(key));
+    updated
+}
+
+/// Start the background refresh loop. The first pass runs at startup; the
+/// certificate store and `current_config` are read again on every pass, so
+/// reloaded certificates get responses within one check interval.
+pub fn spawn_refresh<F>(cache: Arc<OcspCache>, store: SharedCertStore, current_config: F)
+where
+    F: Fn() -> OcspConfig + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let http = match AcmeHttp::new(None) {
+                Ok(http) => http,
+                Err(e) => {
+                    log::error!("OCSP stapling disabled: {}", e);
+                    return;
+                }
+            };
+            loop {
+                let config = current_config();
+                if config.stapling {
+                    let updated = refresh(&cache, &store.load_full(), &http).await;
+                    if updated > 0 {
+                        log::info!("Updated {} OCSP responses", updated);
+                    }
+                } else {
+                    cache.staples.clear();
+                }
+                tokio::time::sleep(Duration::from_secs(config.check_interval_secs.max(1))).await;
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssl::test_certs;
+    use foreign_types::{ForeignType, ForeignTypeRef};
+    use openssl::asn1::Asn1Time;
+    use openssl::ocsp::OcspBasicResponse;
+    use openssl::pkey::{PKey, Private};
+    use pingora_core::tls::ssl_sys;
+    use std::fs;
+    use std::os::raw::{c_int, c_ulong, c_void};
+
+    // Response signing is not wrapped by the openssl crate
+    extern "C" {
+        fn OCSP_basic_add1_status(
+            rsp: *mut ssl_sys::OCSP_BASICRESP,
+            cid: *mut ssl_sys::OCSP_CERTID,
+            status: c_int,
+            reason: c_int,
+            revtime: *mut ssl_sys::ASN1_TIME,
+            thisupd: *mut ssl_sys::ASN1_TIME,
+            nextupd: *mut ssl_sys::ASN1_TIME,
+        ) -> *mut c_void;
+        fn OCSP_basic_sign(
+            brsp: *mut ssl_sys::OCSP_BASICRESP,
+            signer: *mut ssl_sys::X509,
+            key: *mut ssl_sys::EVP_PKEY,
+            dgst: *const ssl_sys::EVP_MD,
+            certs: *mut ssl_sys::stack_st_X509,
+            flags: c_ulong,
+        ) -> c_int;
+    }
+
+    /// DER OCSP response for `leaf` (issued by `issuer`), signed with `signer`/`signer_key`
+    fn signed_response(
+        leaf: &X509Ref,
+        issuer: &X509Ref,
+        signer: &X509Ref,
+        signer_key: &PKey<Private>,
+        status: OcspCertStatus,
+        valid_days: Option<u32>,
+    ) -> Vec<u8> {
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).unwrap();
+        let this_update = Asn1Time::days_from_now(0).unwrap();
+        let next_update = valid_days.map(|days| Asn1Time::days_from_now(days).unwrap());
+        // SAFETY: every pointer comes from a live owned object; add1/sign copy what they keep
+        let basic = unsafe {
+            let basic = OcspBasicResponse::from_ptr(ssl_sys::OCSP_BASICRESP_new());
+            let revoked = status == OcspCertStatus::REVOKED;
+            let single = OCSP_basic_add1_status(
+                basic.as_ptr(),
+                id.as_ptr(),
+                status.as_raw(),
+                -1,
+                if revoked { this_update.as_ptr() } else { std::ptr::null_mut() },
+                this_update.as_ptr(),
+                next_update.as_ref().map_or(std::ptr::null_mut(), |t| t.as_ptr()),
+            );
+            assert!(!single.is_null());
+            let signed = OCSP_basic_sign(
+                basic.as_ptr(),
+                signer.as_ptr(),
+                signer_key.as_ptr(),
+                MessageDigest::sha256().as_ptr(),
+                std::ptr::null_mut(),
+                0,
+            );
+            assert_eq!(signed, 1);
+            basic
+        };
+        OcspResponse::create(OcspResponseStatus::SUCCESSFUL, Some(&basic))
+            .unwrap()
+            .to_der()
+            .unwrap()
+    }
+
+    /// CA and a leaf it issued, with the CA as the leaf's chain
+    fn ca_and_leaf() -> (LoadedCert, LoadedCert) {
+        let ca = test_certs::ca("Test OCSP CA");
+        let mut leaf = test_certs::issue(&ca, "ocsp.example.com", &["ocsp.example.com"]);
+        leaf.chain = vec![ca.leaf.clone()];
+        (ca, leaf)
+    }
+
+    fn good_response(ca: &LoadedCert, leaf: &LoadedCert, valid_days: Option<u32>) -> Vec<u8> {
+        signed_response(&leaf.leaf, &ca.leaf, &ca.leaf, &ca.key, OcspCertStatus::GOOD, valid_days)
+    }
+
+    // ─── Validation ─────────────────────────────────────────
+
+    #[test]
+    fn test_validate_good_response() {
+        let (ca, leaf) = ca_and_leaf();
+        let der = good_response(&ca, &leaf, Some(7));
+        let staple = validate(&der, &leaf.leaf, &ca.leaf).unwrap();
+        assert_eq!(staple.der, der);
+        let next = staple.next_update.unwrap();
+        assert!((next - staple.this_update - chrono::Duration::days(7)).num_seconds().abs() < 5);
+        assert!((staple.this_update - Utc::now()).num_seconds().abs() < 60);
+    }
+
+    #[test]
+    fn test_validate_rejects_revoked() {
+        let (ca, leaf) = ca_and_leaf();
+        let der = signed_response(&leaf.leaf, &ca.leaf, &ca.leaf, &ca.key, OcspCertStatus::REVOKED, Some(7));
+        let err = validate(&der, &leaf.leaf, &ca.leaf).err().unwrap();
+        assert!(err.contains("revoked"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_rejects_untrusted_signer() {
+        let (ca, leaf) = ca_and_leaf();
+        let rogue = test_certs::ca("Rogue CA");
+        let der = signed_response(&leaf.leaf, &ca.leaf, &rogue.leaf, &rogue.key, OcspCertStatus::GOOD, Some(7));
+        let err = validate(&der, &leaf.leaf, &ca.leaf).err().unwrap();
+        assert!(err.contains("not trusted"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_rejects_other_certificate() {
+        let (ca, leaf) = ca_and_leaf();
+        let other = test_certs::issue(&ca, "other.example.com", &["other.example.com"]);
+        let der = good_response(&ca, &other, Some(7));
+        let err = validate(&der, &leaf.leaf, &ca.leaf).err().unwrap();
+        assert!(err.contains("does not cover"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_rejects_garbage() {
+        let (ca, leaf) = ca_and_leaf();
+        let err = validate(b"not ocsp", &leaf.leaf, &ca.leaf).err().unwrap();
+        assert!(err.contains("invalid OCSP response"), "{}", err);
+    }
+
+    // ─── Refresh timing ─────────────────────────────────────
+
+    #[test]
+    fn test_refresh_halfway_to_next_update() {
+        let now = Utc::now();
+        let hours = chrono::Duration::hours;
+        let staple = Staple {
+            der: Vec::new(),
+            this_update: now,
+            next_update: Some(now + hours(48)),
+        };
+        assert!(!staple.needs_refresh(now + hours(23)));
+        assert!(staple.needs_refresh(now + hours(24)));
+        assert!(staple.is_current(now + hours(47)));
+        assert!(!staple.is_current(now + hours(48)));
+
+        let open_ended = Staple {
+            der: Vec::new(),
+            this_update: now,
+            next_update: None,
+        };
+        assert!(open_ended.is_current(now + hours(1000)));
+        assert!(!open_ended.needs_refresh(now + chrono::Duration::minutes(59)));
+        assert!(open_ended.needs_refresh(now + hours(1)));
+    }
+
+    // ─── Sources ────────────────────────────────────────────
+
+    fn obtain_blocking(pair: &CertPair, cert: &LoadedCert) -> Result<Staple, String> {
+        let http = AcmeHttp::new(None).unwrap();
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(obtain(&http, pair, cert))
+    }
+
+    fn pair_with_file(ocsp_file: Option<String>) -> CertPair {
+        CertPair {
+            cert_path: "/c.pem".to_string(),
+            key_path: "/k.pem".to_string(),
+            not_after: Utc::now(),
+            ocsp_file,
+        }
+    }
+
+    #[test]
+    fn test_obtain_from_prefetched_file() {
+        let dir = std::env::temp_dir().join("pingora-test-ocsp-file");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (ca, leaf) = ca_and_leaf();
+        let der = good_response(&ca, &leaf, None);
+        let path = dir.join("cert.ocsp");
+        fs::write(&path, &der).unwrap();
+
+        let pair = pair_with_file(Some(path.to_string_lossy().to_string()));
+        let staple = obtain_blocking(&pair, &leaf).unwrap();
+        assert_eq!(staple.der, der);
+        assert!(staple.next_update.is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_obtain_without_source_or_issuer() {
+        let (ca, leaf) = ca_and_leaf();
+        // Test certificates carry no authorityInfoAccess responder
+        assert!(!has_source(&pair_with_file(None), &leaf));
+        let err = obtain_blocking(&pair_with_file(None), &leaf).err().unwrap();
+        assert!(err.contains("no OCSP responder"), "{}", err);
+
+        let unchained = test_certs::issue(&ca, "ocsp.example.com", &["ocsp.example.com"]);
+        let err = obtain_blocking(&pair_with_file(Some("/x.ocsp".to_string())), &unchained)
+            .err()
+            .unwrap();
+        assert!(err.contains("no issuer"), "{}", err);
+
+        let err = obtain_blocking(&pair_with_file(Some("/nonexistent/x.ocsp".to_string())), &leaf)
+            .err()
+            .unwrap();
+        assert!(err.contains("/nonexistent/x.ocsp"), "{}", err);
+    }
+
+    // ─── Handshake ──────────────────────────────────────────
+
+    /// Handshake against a server stapling from `cache`; returns the stapled bytes
+    fn stapled(cache: Arc<OcspCache>, cert: &LoadedCert, request_status: bool) -> Option<Vec<u8>> {
+        use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode, StatusType};
+        use std::os::unix::net::UnixStream;
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert.leaf).unwrap();
+        acceptor.set_private_key(&cert.key).unwrap();
+        acceptor
+            .set_status_callback(move |ssl| staple(&cache, ssl))
+            .unwrap();
+        let acceptor = acceptor.build();
+        let (client_sock, server_sock) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || acceptor.accept(server_sock).map(|_| ()));
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut config = connector.build().configure().unwrap();
+        if request_status {
+            config.set_status_type(StatusType::OCSP).unwrap();
+        }
+        let stream = config.connect("ocsp.example.com", client_sock).unwrap();
+        let response = stream.ssl().ocsp_status().map(<[u8]>::to_vec);
+        let _ = server.join();
+        response
+    }
+
+    #[test]
+    fn test_handshake_staples_cached_response() {
+        let (ca, leaf) = ca_and_leaf();
+        let der = good_response(&ca, &leaf, Some(7));
+        let cache = Arc::new(OcspCache::default());
+        cache.staples.insert(
+            cert_key(&leaf.leaf).unwrap(),
+            Arc::new(validate(&der, &leaf.leaf, &ca.leaf).unwrap()),
+        );
+
+        assert_eq!(stapled(Arc::clone(&cache), &leaf, true), Some(der));
+        assert_eq!(stapled(cache, &leaf, false), None);
+    }
+
+    #[test]
+    fn test_handshake_skips_missing_or_expired_response() {
+        let (ca, leaf) = ca_and_leaf();
+        let cache = Arc::new(OcspCache::default());
+        assert_eq!(stapled(Arc::clone(&cache), &leaf, true), None);
+
+        // A response for a previous certificate is never sent for the current one
+        let (old_ca, old_leaf) = ca_and_leaf();
+        let old = validate(&good_response(&old_ca, &old_leaf, Some(7)), &old_leaf.leaf, &old_ca.leaf).unwrap();
+        cache.staples.insert(cert_key(&old_leaf.leaf).unwrap(), Arc::new(old));
+        assert_eq!(stapled(Arc::clone(&cache), &leaf, true), None);
+
+        let now = Utc::now();
+        let expired = Staple {
+            der: good_response(&ca, &leaf, Some(7)),
+            this_update: now - chrono::Duration::days(8),
+            next_update: Some(now - chrono::Duration::days(1)),
+        };
+        cache.staples.insert(cert_key(&leaf.leaf).unwrap(), Arc::new(expired));
+        assert_eq!(stapled(cache, &leaf, true), None);
+    }
+}
@@ -0,0 +1,238 @@
+use regex::Regex;
+
+use crate::config::RewriteRule;
+use crate::router::expand_captures;
+
+/// What happens after a rewrite rule matches (nginx `rewrite` flags)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    /// No flag: keep applying the following rules, then match the location again
+    Continue,
+    /// Stop and match the location again with the new URI
+    Last,
+    /// Stop and keep serving from the current location
+    Break,
+    /// Stop and answer with a 302 redirect
+    Redirect,
+    /// Stop and answer with a 301 redirect
+    Permanent,
+}
+
+impl Flag {
+    fn parse(flag: &str) -> Option<Flag> {
+        match flag {
+            "" => Some(Flag::Continue),
+            "last" => Some(Flag::Last),
+            "break" => Some(Flag::Break),
+            "redirect" => Some(Flag::Redirect),
+            "permanent" => Some(Flag::Permanent),
+            _ => None,
+        }
+    }
+}
+
+/// A rewrite rule with its pattern compiled
+#[derive(Debug)]
+pub struct CompiledRewrite {
+    regex: Regex,
+    replacement: String,
+    flag: Flag,
+}
+
+impl CompiledRewrite {
+    pub fn compile(rule: &RewriteRule) -> Result<Self, String> {
+        let regex = Regex::new(&rule.pattern)
+            .map_err(|e| format!("invalid rewrite pattern '{}': {}", rule.pattern, e))?;
+        let flag = Flag::parse(&rule.flag)
+            .ok_or_else(|| format!("unknown rewrite flag '{}'", rule.flag))?;
+        Ok(CompiledRewrite {
+            regex,
+            replacement: rule.replacement.clone(),
+            flag,
+        })
+    }
+}
+
+/// Request URI after a location's rewrite rules
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewritten {
+    /// New path, or an absolute URL for redirects
+    pub path: String,
+    pub query: Option<String>,
+    /// Flag of the last rule that matched
+    pub flag: Flag,
+}
+
+impl Rewritten {
+    /// Path and query, as sent upstream
+    pub fn uri(&self) -> String {
+        match self.query {
+            Some(ref q) => format!("{}?{}", self.path, q),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Location header for a redirect; relative paths are made absolute for `host`
+    pub fn redirect_url(&self, scheme: &str, host: &str) -> String {
+        if is_absolute(&self.path) {
+            self.uri()
+        } else {
+            format!("{}://{}{}", scheme, host, self.uri())
+        }
+    }
+}
+
+/// Run `rules` in order against `path`; None when no rule matched
+pub fn apply(rules: &[CompiledRewrite], path: &str, query: Option<&str>) -> Option<Rewritten> {
+    let mut current: Option<Rewritten> = None;
+    for rule in rules {
+        let (cur_path, cur_query) = match current {
+            Some(ref r) => (r.path.as_str(), r.query.as_deref()),
+            None => (path, query),
+        };
+        let captures = match rule.regex.captures(cur_path) {
+            Some(c) => c,
+            None => continue,
+        };
+        let target = expand_captures(&rule.replacement, Some(&captures));
+        let (new_path, new_query) = split_target(&target, cur_query);
+        // An absolute URL always redirects
+        let flag = match rule.flag {
+            Flag::Continue | Flag::Last | Flag::Break if is_absolute(&new_path) => Flag::Redirect,
+            flag => flag,
+        };
+        current = Some(Rewritten {
+            path: new_path,
+            query: new_query,
+            flag,
+        });
+        if flag != Flag::Continue {
+            break;
+        }
+    }
+    current
+}
+
+/// Split a replacement into path and query: its own arguments come first, then the
+/// original ones unless the replacement ends with `?`
+fn split_target(target: &str, query: Option<&str>) -> (String, Option<String>) {
+    let (target, keep_original) = match target.strip_suffix('?') {
+        Some(t) => (t, false),
+        None => (target, true),
+    };
+    let (path, own) = match target.split_once('?') {
+        Some((p, q)) => (p, Some(q).filter(|q| !q.is_empty())),
+        None => (target, None),
+    };
+    let original = query.filter(|q| keep_original && !q.is_empty());
+    let query = match (own, original) {
+        (Some(own), Some(original)) => Some(format!("{}&{}", own, original)),
+        (Some(q), None) | (None, Some(q)) => Some(q.to_string()),
+        (None, None) => None,
+    };
+    (path.to_string(), query)
+}
+
+fn is_absolute(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, replacement: &str, flag: &str) -> CompiledRewrite {
+        CompiledRewrite::compile(&RewriteRule {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            flag: flag.to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_no_match() {
+        let rules = vec![rule("^/old/(.*)$", "/new/$1", "last")];
+        assert!(apply(&rules, "/other", None).is_none());
+        assert!(apply(&[], "/old/x", None).is_none());
+    }
+
+    #[test]
+    fn test_rules_chain_until_a_flag_stops() {
+        let rules = vec![
+            rule("^/a/(.*)$", "/b/$1", ""),
+            rule("^/b/(.*)$", "/c/$1", "break"),
+            rule("^/c/(.*)$", "/d/$1", "last"),
+        ];
+        let r = apply(&rules, "/a/x", None).unwrap();
+        assert_eq!(r.path, "/c/x");
+        assert_eq!(r.flag, Flag::Break);
+
+        let r = apply(&rules, "/c/x", None).unwrap();
+        assert_eq!(r.path, "/d/x");
+        assert_eq!(r.flag, Flag::Last);
+    }
+
+    #[test]
+    fn test_unflagged_rules_end_with_continue() {
+        let rules = vec![rule("^/a$", "/b", ""), rule("^/z$", "/y", "last")];
+        let r = apply(&rules, "/a", None).unwrap();
+        assert_eq!(r.path, "/b");
+        assert_eq!(r.flag, Flag::Continue);
+    }
+
+    #[test]
+    fn test_capture_followed_by_word_characters() {
+        let rules = vec![rule("^/img/(\\w+)\\.png$", "/img/$1_large.png", "break")];
+        assert_eq!(apply(&rules, "/img/logo.png", None).unwrap().path, "/img/logo_large.png");
+    }
+
+    #[test]
+    fn test_query_handling() {
+        // Original arguments are kept
+        let r = apply(&[rule("^/p/(\\d+)$", "/post", "break")], "/p/1", Some("ref=home")).unwrap();
+        assert_eq!(r.uri(), "/post?ref=home");
+        // Replacement arguments come first
+        let r = apply(&[rule("^/p/(\\d+)$", "/post?id=$1", "break")], "/p/1", Some("ref=home")).unwrap();
+        assert_eq!(r.uri(), "/post?id=1&ref=home");
+        // A trailing '?' drops the original arguments
+        let r = apply(&[rule("^/p/(\\d+)$", "/post?id=$1?", "break")], "/p/1", Some("ref=home")).unwrap();
+        assert_eq!(r.uri(), "/post?id=1");
+        let r = apply(&[rule("^/p/(\\d+)$", "/post?", "break")], "/p/1", Some("ref=home")).unwrap();
+        assert_eq!(r.uri(), "/post");
+    }
+
+    #[test]
+    fn test_redirect_flags() {
+        let r = apply(&[rule("^/old$", "/new", "permanent")], "/old", None).unwrap();
+        assert_eq!(r.flag, Flag::Permanent);
+        assert_eq!(r.redirect_url("https", "example.com"), "https://example.com/new");
+
+        let r = apply(&[rule("^/old$", "/new", "redirect")], "/old", Some("a=1")).unwrap();
+        assert_eq!(r.flag, Flag::Redirect);
+        assert_eq!(r.redirect_url("http", "example.com:8080"), "http://example.com:8080/new?a=1");
+    }
+
+    #[test]
+    fn test_absolute_replacement_redirects() {
+        let r = apply(&[rule("^/docs/(.*)$", "https://docs.example.com/$1", "last")], "/docs/intro", None).unwrap();
+        assert_eq!(r.flag, Flag::Redirect);
+        assert_eq!(r.redirect_url("http", "example.com"), "https://docs.example.com/intro");
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let bad_pattern = RewriteRule {
+            pattern: "^/(unclosed".to_string(),
+            replacement: "/".to_string(),
+            flag: String::new(),
+        };
+        assert!(CompiledRewrite::compile(&bad_pattern).is_err());
+        let bad_flag = RewriteRule {
+            pattern: "^/".to_string(),
+            replacement: "/".to_string(),
+            flag: "forever".to_string(),
+        };
+        assert!(CompiledRewrite::compile(&bad_flag).unwrap_err().contains("forever"));
+    }
+}
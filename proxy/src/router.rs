@@ -1,5 +1,9 @@
-use crate::config::{HostConfig, LocationConfig};
-use regex::Regex;
+use crate::config::{HostConfig, LocationConditions, LocationConfig, ValueCondition};
+use crate::rewrite::CompiledRewrite;
+use crate::trie::PrefixTrie;
+use regex::{Captures, Regex, RegexBuilder, RegexSet};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
 
@@ -8,49 +12,292 @@ use std::sync::Arc;
 pub struct CompiledLocation {
     pub index: usize,
     pub match_type: MatchType,
+    /// The location has request conditions besides its path
+    pub conditional: bool,
+    /// Regex location whose targets or header values reference capture groups
+    pub uses_captures: bool,
+    /// URL rewrite rules, in order
+    pub rewrites: Vec<CompiledRewrite>,
 }
 
 #[derive(Debug)]
 pub enum MatchType {
     Prefix(String),
+    /// Prefix that, when it is the longest match, stops the regex search (nginx `^~`)
+    PriorityPrefix(String),
     Exact(String),
+    /// Case-insensitive patterns carry a leading `(?i)`
     Regex(Regex),
 }
 
-/// Router resolves domain names to host configs and matches request paths to locations
+/// Location matchers of one host, indexed so lookups do not scan every location:
+/// exact paths in a map, prefixes in a radix tree, regexes in one `RegexSet`
+#[derive(Debug, Default)]
+struct HostLocations {
+    /// Compiled locations in precedence order; the indexes below hold positions in it
+    compiled: Vec<CompiledLocation>,
+    /// Location index (configuration order) -> position
+    by_index: HashMap<usize, usize>,
+    exact: HashMap<String, Vec<usize>>,
+    prefixes: PrefixTrie<Vec<usize>>,
+    /// Regex locations in precedence order, and their patterns combined in that order
+    regexes: Vec<usize>,
+    regex_set: Option<RegexSet>,
+}
+
+impl HostLocations {
+    fn new(host_id: u64, compiled: Vec<CompiledLocation>) -> Self {
+        let mut index = HostLocations::default();
+        let mut patterns = Vec::new();
+        for (pos, cl) in compiled.iter().enumerate() {
+            index.by_index.insert(cl.index, pos);
+            match &cl.match_type {
+                MatchType::Exact(p) => index.exact.entry(p.clone()).or_default().push(pos),
+                MatchType::Prefix(p) | MatchType::PriorityPrefix(p) => {
+                    index.prefixes.get_or_insert_with(p.as_bytes(), Vec::new).push(pos)
+                }
+                MatchType::Regex(re) => {
+                    index.regexes.push(pos);
+                    patterns.push(re.as_str());
+                }
+            }
+        }
+        if !patterns.is_empty() {
+            match RegexSet::new(&patterns) {
+                Ok(set) => index.regex_set = Some(set),
+                Err(e) => log::warn!("Host {}: regex locations matched one by one: {}", host_id, e),
+            }
+        }
+        index.compiled = compiled;
+        index
+    }
+
+    fn get(&self, index: usize) -> Option<&CompiledLocation> {
+        self.by_index.get(&index).map(|&pos| &self.compiled[pos])
+    }
+
+    /// Position of the location serving the request, following nginx: an exact match,
+    /// else the longest prefix if it is a priority prefix, else the first matching regex,
+    /// else the longest prefix
+    fn find(&self, host_config: &HostConfig, request: &RequestView) -> Option<usize> {
+        let path = request.path;
+        let accepts = |pos: &usize| {
+            let cl = &self.compiled[*pos];
+            !cl.conditional
+                || host_config
+                    .locations
+                    .get(cl.index)
+                    .and_then(|l| l.conditions.as_ref())
+                    .is_some_and(|c| conditions_hold(c, request))
+        };
+
+        if let Some(pos) = self.exact.get(path).and_then(|positions| positions.iter().find(|p| accepts(p))) {
+            return Some(*pos);
+        }
+        let longest_prefix = self
+            .prefixes
+            .prefixes_of(path.as_bytes())
+            .into_iter()
+            .find_map(|positions| positions.iter().find(|p| accepts(p)))
+            .copied();
+        if let Some(pos) = longest_prefix {
+            if matches!(self.compiled[pos].match_type, MatchType::PriorityPrefix(_)) {
+                return Some(pos);
+            }
+        }
+        if !self.regexes.is_empty() {
+            let found = match self.regex_set {
+                Some(ref set) => set.matches(path).iter().map(|i| self.regexes[i]).find(|p| accepts(p)),
+                None => self.regexes.iter().copied().find(|p| {
+                    matches!(&self.compiled[*p].match_type, MatchType::Regex(re) if re.is_match(path)) && accepts(p)
+                }),
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+        longest_prefix
+    }
+}
+
+/// Request attributes that location conditions are checked against
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequestView<'a> {
+    pub path: &'a str,
+    pub method: &'a str,
+    /// Raw query string, without the `?`
+    pub query: Option<&'a str>,
+    pub headers: Option<&'a http::HeaderMap>,
+}
+
+impl<'a> RequestView<'a> {
+    /// A plain GET for `path`
+    pub fn get(path: &'a str) -> Self {
+        RequestView {
+            path,
+            method: "GET",
+            ..RequestView::default()
+        }
+    }
+}
+
+/// A server name claimed by more than one enabled host
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainConflict {
+    pub domain: String,
+    /// Host that serves the name
+    pub kept_host: u64,
+    /// Host whose claim was ignored
+    pub dropped_host: u64,
+}
+
+/// Server name table looked up like nginx: the exact name, then `*.suffix` wildcards
+/// (longest suffix first, any number of labels), then `~regex` names in insertion order
+pub struct ServerNames<T> {
+    /// Lowercase exact names
+    exact: HashMap<String, T>,
+    /// Wildcard suffix ("example.com" for `*.example.com`)
+    wildcards: HashMap<String, T>,
+    /// Regex server names, matched case-insensitively
+    regexes: Vec<(Regex, T)>,
+}
+
+impl<T> Default for ServerNames<T> {
+    fn default() -> Self {
+        ServerNames {
+            exact: HashMap::new(),
+            wildcards: HashMap::new(),
+            regexes: Vec::new(),
+        }
+    }
+}
+
+impl<T> ServerNames<T> {
+    /// Register a lowercase exact or `*.suffix` name for `value`.
+    /// The first claim keeps the name; a later one gets the holder back.
+    pub fn claim(&mut self, name: &str, value: T) -> Option<&T> {
+        let (map, key) = match name.strip_prefix("*.") {
+            Some(suffix) => (&mut self.wildcards, suffix),
+            None => (&mut self.exact, name),
+        };
+        match map.entry(key.to_string()) {
+            Entry::Occupied(held) => Some(held.into_mut()),
+            Entry::Vacant(free) => {
+                free.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Register a regex server name (without the leading `~`)
+    pub fn add_regex(&mut self, pattern: &str, value: T) -> Result<(), regex::Error> {
+        let re = RegexBuilder::new(pattern).case_insensitive(true).build()?;
+        self.regexes.push((re, value));
+        Ok(())
+    }
+
+    /// Value serving a lowercase name without port: exact > wildcard > regex
+    pub fn lookup(&self, name: &str) -> Option<&T> {
+        if let Some(value) = self.exact.get(name) {
+            return Some(value);
+        }
+        // Walk parent domains from the longest, so `*.a.example.com` beats `*.example.com`
+        let mut rest = name;
+        while let Some((label, parent)) = rest.split_once('.') {
+            if label.is_empty() {
+                break;
+            }
+            if let Some(value) = self.wildcards.get(parent) {
+                return Some(value);
+            }
+            rest = parent;
+        }
+        if name.is_empty() {
+            return None;
+        }
+        self.regexes
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(_, value)| value)
+    }
+}
+
+/// Router resolves domain names to host configs and matches request paths to locations.
+/// Server names are tried as exact names, then `*.suffix` wildcards (longest suffix
+/// first, any number of labels like nginx), then `~regex` names in configuration order;
+/// anything else goes to the `default_server` host, if one is marked.
+///
+/// When several hosts claim the same name, the host with the lowest ID keeps it, except
+/// that a name listed in `domains` always beats the automatic `www.` alias of a
+/// `redirect_www` host. Conflicts are logged and kept in `conflicts`.
 pub struct Router {
-    /// Domain -> HostConfig mapping
-    hosts: HashMap<String, Arc<HostConfig>>,
+    /// Server name -> HostConfig mapping
+    names: ServerNames<Arc<HostConfig>>,
+    /// Host serving requests no server name matches
+    default_host: Option<Arc<HostConfig>>,
     /// Host ID -> compiled location matchers
-    locations: HashMap<u64, Vec<CompiledLocation>>,
+    locations: HashMap<u64, HostLocations>,
+    /// Server names claimed by more than one host
+    conflicts: Vec<DomainConflict>,
 }
 
 impl Router {
     /// Build a new router from host configs
     pub fn build(hosts: &[HostConfig]) -> Self {
-        let mut host_map: HashMap<String, Arc<HostConfig>> = HashMap::new();
-        let mut location_map: HashMap<u64, Vec<CompiledLocation>> = HashMap::new();
+        let mut names: ServerNames<Arc<HostConfig>> = ServerNames::default();
+        let mut default_host: Option<Arc<HostConfig>> = None;
+        let mut location_map: HashMap<u64, HostLocations> = HashMap::new();
+        let mut conflicts: Vec<DomainConflict> = Vec::new();
+
+        // Claim names in ascending host ID order, independent of config file order
+        let mut enabled: Vec<Arc<HostConfig>> = hosts
+            .iter()
+            .filter(|h| h.enabled)
+            .map(|h| Arc::new(h.clone()))
+            .collect();
+        enabled.sort_by_key(|h| h.id);
 
-        for host in hosts {
-            if !host.enabled {
-                continue;
+        for host_arc in &enabled {
+            let host = host_arc.as_ref();
+            if host.default_server {
+                match default_host {
+                    Some(ref first) => log::warn!(
+                        "Host {} is marked default_server, keeping host {}",
+                        host.id,
+                        first.id
+                    ),
+                    None => default_host = Some(host_arc.clone()),
+                }
             }
-            let host_arc = Arc::new(host.clone());
             for domain in &host.domains {
-                let domain_lower = domain.to_lowercase();
-                host_map.insert(domain_lower.clone(), host_arc.clone());
-                // Auto-register www.{domain} for redirect_www hosts
-                if host.redirect_www && !domain_lower.starts_with("www.") {
-                    host_map.insert(format!("www.{}", domain_lower), host_arc.clone());
+                if let Some(pattern) = domain.strip_prefix('~') {
+                    if let Err(e) = names.add_regex(pattern, host_arc.clone()) {
+                        log::error!("Invalid server name regex '{}' for host {}: {}", pattern, host.id, e);
+                    }
+                    continue;
                 }
+                claim(&mut names, &domain.to_lowercase(), host_arc, &mut conflicts);
             }
 
             // Compile locations for this host
             let mut compiled = Vec::new();
             for (i, loc) in host.locations.iter().enumerate() {
                 let match_type = match loc.match_type.as_str() {
-                    "exact" => MatchType::Exact(loc.path.clone()),
-                    "regex" => match Regex::new(&loc.path) {
+                    "exact" | "=" => MatchType::Exact(loc.path.clone()),
+                    "prefix_priority" | "^~" => MatchType::PriorityPrefix(loc.path.clone()),
+                    "regex" | "~" => match Regex::new(&loc.path) {
+                        Ok(re) => MatchType::Regex(re),
+                        Err(e) => {
+                            log::error!(
+                                "Invalid regex '{}' for host {}: {}",
+                                loc.path,
+                                host.id,
+                                e
+                            );
+                            continue;
+                        }
+                    },
+                    "regex_case_insensitive" | "~*" => match Regex::new(&format!("(?i){}", loc.path)) {
                         Ok(re) => MatchType::Regex(re),
                         Err(e) => {
                             log::error!(
@@ -64,29 +311,80 @@ impl Router {
                     },
                     _ => MatchType::Prefix(loc.path.clone()),
                 };
+                let uses_captures = matches!(match_type, MatchType::Regex(_))
+                    && loc
+                        .forward_path
+                        .iter()
+                        .chain(loc.forward_domain.iter())
+                        .chain(loc.headers.values())
+                        .any(|target| target.contains('$'));
+                let rewrites = loc
+                    .rewrites
+                    .iter()
+                    .filter_map(|rule| match CompiledRewrite::compile(rule) {
+                        Ok(r) => Some(r),
+                        Err(e) => {
+                            log::error!("Skipping rewrite for host {} location '{}': {}", host.id, loc.path, e);
+                            None
+                        }
+                    })
+                    .collect();
                 compiled.push(CompiledLocation {
                     index: i,
                     match_type,
+                    conditional: loc.conditions.is_some(),
+                    uses_captures,
+                    rewrites,
                 });
             }
-            // Sort by specificity: exact first, then regex, then prefix by longest path
-            // (mirrors nginx behavior where regex locations take precedence over prefix)
-            compiled.sort_by(|a, b| {
-                fn priority(m: &MatchType) -> (u8, usize) {
-                    match m {
-                        MatchType::Exact(p) => (0, usize::MAX - p.len()),
-                        MatchType::Regex(_) => (1, 0),
-                        MatchType::Prefix(p) => (2, usize::MAX - p.len()),
-                    }
+            // Sort by specificity: exact first, then regexes in declaration order, then
+            // prefixes by longest path. For the same path or pattern, locations with
+            // conditions are tried before plain ones.
+            let mut first_declared: HashMap<String, usize> = HashMap::new();
+            for cl in &compiled {
+                if let MatchType::Regex(re) = &cl.match_type {
+                    first_declared.entry(re.as_str().to_string()).or_insert(cl.index);
                 }
-                priority(&a.match_type).cmp(&priority(&b.match_type))
-            });
-            location_map.insert(host.id, compiled);
+            }
+            let priority = |cl: &CompiledLocation| -> (u8, usize, bool) {
+                let (class, rank) = match &cl.match_type {
+                    MatchType::Exact(p) => (0, usize::MAX - p.len()),
+                    MatchType::Regex(re) => (1, first_declared[re.as_str()]),
+                    MatchType::Prefix(p) | MatchType::PriorityPrefix(p) => (2, usize::MAX - p.len()),
+                };
+                (class, rank, !cl.conditional)
+            };
+            compiled.sort_by_key(priority);
+            location_map.insert(host.id, HostLocations::new(host.id, compiled));
+        }
+
+        // Auto-register www.{domain} for redirect_www hosts, after every explicit name
+        for host_arc in enabled.iter().filter(|h| h.redirect_www) {
+            for domain in &host_arc.domains {
+                let domain_lower = domain.to_lowercase();
+                if domain_lower.starts_with("www.") || domain_lower.starts_with("*.") || domain_lower.starts_with('~') {
+                    continue;
+                }
+                let alias = format!("www.{}", domain_lower);
+                claim(&mut names, &alias, host_arc, &mut conflicts);
+            }
+        }
+
+        for c in &conflicts {
+            log::warn!(
+                "Domain {} is claimed by hosts {} and {}; host {} serves it",
+                c.domain,
+                c.kept_host,
+                c.dropped_host,
+                c.kept_host
+            );
         }
 
         Router {
-            hosts: host_map,
+            names,
+            default_host,
             locations: location_map,
+            conflicts,
         }
     }
 
@@ -94,11 +392,14 @@ impl Router {
     pub fn resolve<'a>(
         &'a self,
         host: &str,
-        path: &str,
+        request: &RequestView,
     ) -> Option<(&'a HostConfig, Option<&'a LocationConfig>, Option<usize>)> {
         let host_name = Self::normalize_host(host);
-        let host_config = self.hosts.get(host_name.as_ref())?;
-        let (location, loc_idx) = self.match_location(host_config, path);
+        let host_config = self
+            .names
+            .lookup(host_name.as_ref())
+            .or(self.default_host.as_ref())?;
+        let (location, loc_idx) = self.match_location(host_config, request);
         Some((host_config, location, loc_idx))
     }
 
@@ -113,37 +414,253 @@ impl Router {
         }
     }
 
-    /// Match a path against the compiled locations for a host.
+    /// Capture groups of a regex location for `path`; None unless the location
+    /// references captures in its targets or headers
+    pub fn captures<'p>(&self, host_id: u64, index: usize, path: &'p str) -> Option<Captures<'p>> {
+        let cl = self.locations.get(&host_id)?.get(index)?;
+        match &cl.match_type {
+            MatchType::Regex(re) if cl.uses_captures => re.captures(path),
+            _ => None,
+        }
+    }
+
+    /// Rewrite rules of the location at `index`
+    pub fn rewrites(&self, host_id: u64, index: usize) -> &[CompiledRewrite] {
+        self.locations
+            .get(&host_id)
+            .and_then(|host_locations| host_locations.get(index))
+            .map_or(&[], |cl| cl.rewrites.as_slice())
+    }
+
+    /// Match a request against the compiled locations for a host with nginx precedence
+    /// (locations with conditions first for the same path).
     /// Returns the matched location and its index (eliminates ptr::eq scan in resolve_request).
-    fn match_location<'a>(
+    pub fn match_location<'a>(
         &'a self,
         host_config: &'a HostConfig,
-        path: &str,
+        request: &RequestView,
     ) -> (Option<&'a LocationConfig>, Option<usize>) {
-        let compiled = match self.locations.get(&host_config.id) {
-            Some(c) => c,
+        let host_locations = match self.locations.get(&host_config.id) {
+            Some(l) => l,
             None => return (None, None),
         };
-
-        for cl in compiled {
-            let matched = match &cl.match_type {
-                MatchType::Exact(p) => path == p,
-                MatchType::Prefix(p) => path.starts_with(p),
-                MatchType::Regex(re) => re.is_match(path),
-            };
-            if matched {
-                return (host_config.locations.get(cl.index), Some(cl.index));
+        match host_locations.find(host_config, request) {
+            Some(pos) => {
+                let index = host_locations.compiled[pos].index;
+                (host_config.locations.get(index), Some(index))
             }
+            None => (None, None),
         }
+    }
 
-        (None, None)
+    /// Server names claimed by more than one enabled host
+    #[allow(dead_code)] // public API, tested
+    pub fn conflicts(&self) -> &[DomainConflict] {
+        &self.conflicts
     }
 
     /// Check if a host has any entry
     #[allow(dead_code)] // public API, tested
     pub fn has_domain(&self, host: &str) -> bool {
         let host_name = Self::normalize_host(host);
-        self.hosts.contains_key(host_name.as_ref())
+        self.names.lookup(host_name.as_ref()).is_some()
+    }
+}
+
+/// Whether a request satisfies every condition of a location
+fn conditions_hold(conditions: &LocationConditions, request: &RequestView) -> bool {
+    if !conditions.methods.is_empty()
+        && !conditions.methods.iter().any(|m| m.eq_ignore_ascii_case(request.method))
+    {
+        return false;
+    }
+    let headers = request.headers;
+    let header_ok = |c: &ValueCondition| {
+        headers.is_some_and(|h| {
+            h.get_all(c.name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| value_matches(c, v))
+        })
+    };
+    let cookie_ok = |c: &ValueCondition| {
+        headers.is_some_and(|h| {
+            h.get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .any(|(name, value)| name == c.name && value_matches(c, value))
+        })
+    };
+    let query_ok = |c: &ValueCondition| {
+        request.query.is_some_and(|q| {
+            q.split('&').any(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                percent_decode(name) == c.name && value_matches(c, &percent_decode(value))
+            })
+        })
+    };
+    conditions.headers.iter().all(header_ok)
+        && conditions.cookies.iter().all(cookie_ok)
+        && conditions.query.iter().all(query_ok)
+}
+
+/// Expand nginx-style capture references in `template`: `$1`..`$9` (always a single
+/// digit, so `$1_large` is group 1 followed by `_large`), `${name}` or `${10}`, and `$name`
+/// for a named group. `$$` is a literal `$`; groups that did not match expand to nothing.
+pub fn expand_captures<'t>(template: &'t str, captures: Option<&Captures>) -> Cow<'t, str> {
+    let caps = match captures {
+        Some(caps) if template.contains('$') => caps,
+        _ => return Cow::Borrowed(template),
+    };
+    let group = |name: &str| {
+        let m = match name.parse::<usize>() {
+            Ok(index) => caps.get(index),
+            Err(_) => caps.name(name),
+        };
+        m.map_or("", |m| m.as_str())
+    };
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let (value, consumed) = match after.as_bytes().first() {
+            Some(b'$') => ("$", 1),
+            Some(c) if c.is_ascii_digit() => (group(&after[..1]), 1),
+            Some(b'{') => match after.find('}') {
+                Some(end) => (group(&after[1..end]), end + 1),
+                None => ("$", 0),
+            },
+            Some(c) if c.is_ascii_alphabetic() || *c == b'_' => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (group(&after[..end]), end)
+            }
+            _ => ("$", 0),
+        };
+        out.push_str(value);
+        rest = &after[consumed..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn value_matches(condition: &ValueCondition, value: &str) -> bool {
+    condition.value.as_deref().is_none_or(|expected| expected == value)
+}
+
+/// Normalize a request path for routing: decode percent-escaped unreserved characters,
+/// collapse duplicate slashes and resolve `.`/`..` segments (never above the root).
+/// Encodings that backends disagree on (`%2F`, `%5C`, `%00`, backslashes, malformed
+/// escapes) are rejected.
+pub fn normalize_path(path: &str) -> Result<Cow<'_, str>, &'static str> {
+    if !path.starts_with('/') {
+        // "*" (OPTIONS) and other non-origin forms are not routed by path
+        return Ok(Cow::Borrowed(path));
+    }
+    if path.contains('\\') {
+        return Err("backslash in path");
+    }
+    let needs_work = path.contains('%')
+        || path.contains("//")
+        || path.split('/').any(|segment| segment == "." || segment == "..");
+    if !needs_work {
+        return Ok(Cow::Borrowed(path));
+    }
+
+    let mut decoded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(pos) = rest.find('%') {
+        decoded.push_str(&rest[..pos]);
+        let escape = rest.get(pos..pos + 3).unwrap_or("");
+        let byte = match escape.as_bytes() {
+            [_, high, low] => match (hex_digit(*high), hex_digit(*low)) {
+                (Some(high), Some(low)) => high << 4 | low,
+                _ => return Err("malformed percent-encoding"),
+            },
+            _ => return Err("malformed percent-encoding"),
+        };
+        match byte {
+            b'/' | b'\\' | 0 => return Err("encoded slash, backslash or NUL"),
+            b if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') => decoded.push(b as char),
+            _ => decoded.push_str(escape),
+        }
+        rest = &rest[pos + 3..];
+    }
+    decoded.push_str(rest);
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/').skip(1) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+
+    if normalized == path {
+        Ok(Cow::Borrowed(path))
+    } else {
+        Ok(Cow::Owned(normalized))
+    }
+}
+
+/// Decode `%XX` escapes and `+` (space) in a query string component
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex_digit(bytes[i + 1]), hex_digit(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    out.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Register `domain` for `host` unless another host already holds it
+fn claim(
+    names: &mut ServerNames<Arc<HostConfig>>,
+    domain: &str,
+    host: &Arc<HostConfig>,
+    conflicts: &mut Vec<DomainConflict>,
+) {
+    if let Some(existing) = names.claim(domain, Arc::clone(host)) {
+        if existing.id != host.id {
+            conflicts.push(DomainConflict {
+                domain: domain.to_string(),
+                kept_host: existing.id,
+                dropped_host: host.id,
+            });
+        }
     }
 }
 
@@ -151,21 +668,14 @@ impl Router {
 mod tests {
     use super::*;
     use crate::config::*;
-    use std::collections::HashMap;
 
     fn make_host(id: u64, domains: &[&str], locations: Vec<LocationConfig>, enabled: bool) -> HostConfig {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
-            ssl: None,
             locations,
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
             enabled,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -173,19 +683,7 @@ mod tests {
         LocationConfig {
             path: path.to_string(),
             match_type: match_type.to_string(),
-            location_type: Some("proxy".to_string()),
-            upstreams: vec![],
-            balance_method: "round_robin".to_string(),
-            static_dir: None,
-            cache_expires: None,
-            forward_scheme: None,
-            forward_domain: None,
-            forward_path: None,
-            preserve_path: false,
-            status_code: None,
-            headers: HashMap::new(),
-            access_list_id: None,
-            compiled_headers: Vec::new(),
+            ..Default::default()
         }
     }
 
@@ -193,7 +691,7 @@ mod tests {
     fn test_resolve_known_domain() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        let result = router.resolve("example.com", "/");
+        let result = router.resolve("example.com", &RequestView::get("/"));
         assert!(result.is_some());
         assert_eq!(result.unwrap().0.id, 1);
     }
@@ -202,28 +700,28 @@ mod tests {
     fn test_resolve_unknown_domain() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("unknown.com", "/").is_none());
+        assert!(router.resolve("unknown.com", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_resolve_case_insensitive() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("Example.COM", "/").is_some());
+        assert!(router.resolve("Example.COM", &RequestView::get("/")).is_some());
     }
 
     #[test]
     fn test_resolve_strips_port() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("example.com:8080", "/").is_some());
+        assert!(router.resolve("example.com:8080", &RequestView::get("/")).is_some());
     }
 
     #[test]
     fn test_disabled_host_skipped() {
         let hosts = vec![make_host(1, &["example.com"], vec![], false)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("example.com", "/").is_none());
+        assert!(router.resolve("example.com", &RequestView::get("/")).is_none());
     }
 
     #[test]
@@ -236,12 +734,12 @@ mod tests {
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
 
-        let (_, loc, _) = router.resolve("example.com", "/api/users").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/users")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().path, "/api");
 
         // "/" should still match for non-api paths
-        let (_, loc, _) = router.resolve("example.com", "/about").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/about")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().path, "/");
     }
@@ -256,12 +754,12 @@ mod tests {
         let router = Router::build(&hosts);
 
         // exact "/api" should win
-        let (_, loc, _) = router.resolve("example.com", "/api").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().match_type, "exact");
 
         // "/api/users" should NOT match exact, falls through to prefix
-        let (_, loc, _) = router.resolve("example.com", "/api/users").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/users")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().match_type, "prefix");
     }
@@ -276,12 +774,12 @@ mod tests {
         let router = Router::build(&hosts);
 
         // regex should win over prefix for matching path
-        let (_, loc, _) = router.resolve("example.com", "/api/v2/users").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/v2/users")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().match_type, "regex");
 
         // non-matching regex falls through to prefix
-        let (_, loc, _) = router.resolve("example.com", "/api/other").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/other")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().match_type, "prefix");
     }
@@ -291,7 +789,7 @@ mod tests {
         let locs = vec![make_location("/api", "prefix")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/api/users").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/users")).unwrap();
         assert!(loc.is_some());
         assert_eq!(loc.unwrap().path, "/api");
     }
@@ -301,7 +799,7 @@ mod tests {
         let locs = vec![make_location("/api", "prefix")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/other").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/other")).unwrap();
         assert!(loc.is_none());
     }
 
@@ -311,10 +809,10 @@ mod tests {
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
 
-        let (_, loc, _) = router.resolve("example.com", "/health").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/health")).unwrap();
         assert!(loc.is_some());
 
-        let (_, loc, _) = router.resolve("example.com", "/health/check").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/health/check")).unwrap();
         assert!(loc.is_none());
     }
 
@@ -323,59 +821,493 @@ mod tests {
         let locs = vec![make_location(r"^/files/.*\.pdf$", "regex")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/files/report.pdf").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/files/report.pdf")).unwrap();
         assert!(loc.is_some());
     }
 
+    // ─── Request conditions ─────────────────────────────────
+
+    fn conditional_location(path: &str, conditions: &str) -> LocationConfig {
+        let mut loc = make_location(path, "prefix");
+        loc.conditions = Some(serde_yaml::from_str(conditions).unwrap());
+        loc
+    }
+
+    fn resolve_index(router: &Router, request: &RequestView) -> Option<usize> {
+        router.resolve("example.com", request).unwrap().2
+    }
+
+    #[test]
+    fn test_method_condition() {
+        let locs = vec![
+            make_location("/api", "prefix"),
+            conditional_location("/api", "methods: [POST, put]"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        let post = RequestView { method: "POST", ..RequestView::get("/api/items") };
+        let put = RequestView { method: "PUT", ..RequestView::get("/api/items") };
+        assert_eq!(resolve_index(&router, &post), Some(1));
+        assert_eq!(resolve_index(&router, &put), Some(1));
+        assert_eq!(resolve_index(&router, &RequestView::get("/api/items")), Some(0));
+    }
+
+    #[test]
+    fn test_header_condition_presence_and_value() {
+        let locs = vec![
+            make_location("/", "prefix"),
+            conditional_location("/", "headers: [{name: X-Canary, value: \"1\"}]"),
+            conditional_location("/", "headers: [{name: X-Debug}]"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-canary", "1".parse().unwrap());
+        let canary = RequestView { headers: Some(&headers), ..RequestView::get("/") };
+        assert_eq!(resolve_index(&router, &canary), Some(1));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-canary", "0".parse().unwrap());
+        headers.insert("x-debug", "".parse().unwrap());
+        let debug = RequestView { headers: Some(&headers), ..RequestView::get("/") };
+        assert_eq!(resolve_index(&router, &debug), Some(2));
+
+        assert_eq!(resolve_index(&router, &RequestView::get("/")), Some(0));
+    }
+
+    #[test]
+    fn test_query_condition_decodes_values() {
+        let locs = vec![
+            make_location("/search", "prefix"),
+            conditional_location("/search", "query: [{name: q, value: \"a b/c\"}, {name: beta}]"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        let matching = RequestView { query: Some("beta&q=a+b%2Fc"), ..RequestView::get("/search") };
+        assert_eq!(resolve_index(&router, &matching), Some(1));
+        let missing = RequestView { query: Some("q=a+b%2Fc"), ..RequestView::get("/search") };
+        assert_eq!(resolve_index(&router, &missing), Some(0));
+    }
+
+    #[test]
+    fn test_cookie_condition() {
+        let locs = vec![
+            make_location("/", "prefix"),
+            conditional_location("/", "cookies: [{name: variant, value: b}]"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::COOKIE, "session=xyz; variant=b".parse().unwrap());
+        let with_cookie = RequestView { headers: Some(&headers), ..RequestView::get("/") };
+        assert_eq!(resolve_index(&router, &with_cookie), Some(1));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::COOKIE, "variant=a".parse().unwrap());
+        let other = RequestView { headers: Some(&headers), ..RequestView::get("/") };
+        assert_eq!(resolve_index(&router, &other), Some(0));
+    }
+
+    #[test]
+    fn test_all_conditions_must_hold() {
+        let locs = vec![conditional_location("/api", "methods: [POST]\nheaders: [{name: X-Canary}]")];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-canary", "1".parse().unwrap());
+        let both = RequestView { method: "POST", headers: Some(&headers), ..RequestView::get("/api") };
+        assert_eq!(resolve_index(&router, &both), Some(0));
+        let get = RequestView { headers: Some(&headers), ..RequestView::get("/api") };
+        assert_eq!(resolve_index(&router, &get), None);
+    }
+
+    #[test]
+    fn test_unmet_conditions_fall_through_to_shorter_prefix() {
+        let locs = vec![
+            make_location("/", "prefix"),
+            conditional_location("/api", "methods: [POST]"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(resolve_index(&router, &RequestView::get("/api/items")), Some(0));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%2f%2F"), "//");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    // ─── Regex captures ─────────────────────────────────────
+
+    #[test]
+    fn test_captures_only_for_locations_that_use_them() {
+        let mut with_refs = make_location(r"^/img/(\w+)\.png$", "regex");
+        with_refs.forward_path = Some("/images/$1".to_string());
+        let without_refs = make_location(r"^/css/(\w+)\.css$", "regex");
+        let mut prefix = make_location("/js", "prefix");
+        prefix.forward_path = Some("/$1".to_string());
+        let hosts = vec![make_host(1, &["example.com"], vec![with_refs, without_refs, prefix], true)];
+        let router = Router::build(&hosts);
+
+        let caps = router.captures(1, 0, "/img/logo.png").unwrap();
+        assert_eq!(&caps[1], "logo");
+        assert!(router.captures(1, 0, "/img/logo.gif").is_none());
+        assert!(router.captures(1, 1, "/css/site.css").is_none());
+        assert!(router.captures(1, 2, "/js/app.js").is_none());
+    }
+
+    #[test]
+    fn test_expand_captures() {
+        let re = Regex::new(r"^/(?P<section>\w+)/(\d+)$").unwrap();
+        let caps = re.captures("/news/42").unwrap();
+        assert_eq!(expand_captures("/$section/${2}/", Some(&caps)), "/news/42/");
+        assert_eq!(expand_captures("/$3", Some(&caps)), "/");
+        assert_eq!(expand_captures("$$1", Some(&caps)), "$1");
+        assert_eq!(expand_captures("/static/$1", None), "/static/$1");
+    }
+
+    #[test]
+    fn test_expand_captures_single_digit_groups() {
+        let re = Regex::new(r"^/img/(\w+)\.(png|jpg)$").unwrap();
+        let caps = re.captures("/img/logo.png").unwrap();
+        // nginx reads `$N` as one digit: `$1_large` is group 1, then "_large"
+        assert_eq!(expand_captures("/thumbs/$1_large.$2", Some(&caps)), "/thumbs/logo_large.png");
+        assert_eq!(expand_captures("/$12", Some(&caps)), "/logo2");
+        assert_eq!(expand_captures("/${1}0", Some(&caps)), "/logo0");
+        assert_eq!(expand_captures("/${12}", Some(&caps)), "/");
+        // A lone or unterminated `$` stays as written
+        assert_eq!(expand_captures("/a$/b${1", Some(&caps)), "/a$/b${1");
+    }
+
+    #[test]
+    fn test_invalid_rewrite_rules_are_skipped() {
+        let mut loc = make_location("/", "prefix");
+        loc.rewrites = vec![
+            RewriteRule { pattern: "^/(broken".to_string(), replacement: "/".to_string(), flag: String::new() },
+            RewriteRule { pattern: "^/a$".to_string(), replacement: "/b".to_string(), flag: "nope".to_string() },
+            RewriteRule { pattern: "^/c$".to_string(), replacement: "/d".to_string(), flag: "last".to_string() },
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], vec![loc], true)]);
+        assert_eq!(router.rewrites(1, 0).len(), 1);
+        assert!(router.rewrites(1, 5).is_empty());
+        assert!(router.rewrites(2, 0).is_empty());
+    }
+
+    // ─── Indexed location lookup ────────────────────────────
+
+    /// Reference: scan every compiled location in precedence order
+    fn linear_match(router: &Router, host: &HostConfig, request: &RequestView) -> Option<usize> {
+        let mut candidates = router.locations[&host.id].compiled.iter().filter(|cl| {
+            let matched = match &cl.match_type {
+                MatchType::Exact(p) => request.path == p,
+                MatchType::Prefix(p) | MatchType::PriorityPrefix(p) => request.path.starts_with(p.as_str()),
+                MatchType::Regex(re) => re.is_match(request.path),
+            };
+            matched
+                && (!cl.conditional
+                    || host.locations[cl.index]
+                        .conditions
+                        .as_ref()
+                        .is_some_and(|c| conditions_hold(c, request)))
+        });
+        let exact = candidates.clone().find(|cl| matches!(cl.match_type, MatchType::Exact(_)));
+        let prefix = candidates
+            .clone()
+            .find(|cl| matches!(cl.match_type, MatchType::Prefix(_) | MatchType::PriorityPrefix(_)));
+        let regex = candidates.find(|cl| matches!(cl.match_type, MatchType::Regex(_)));
+        let priority = prefix.filter(|cl| matches!(cl.match_type, MatchType::PriorityPrefix(_)));
+        exact.or(priority).or(regex).or(prefix).map(|cl| cl.index)
+    }
+
+    #[test]
+    fn test_indexed_lookup_matches_linear_scan() {
+        let mut post_api = make_location("/api", "prefix");
+        post_api.conditions = Some(LocationConditions { methods: vec!["POST".to_string()], ..Default::default() });
+        let mut post_php = make_location(r"\.php$", "regex");
+        post_php.conditions = post_api.conditions.clone();
+        let locs = vec![
+            make_location("/", "prefix"),
+            make_location("/api", "prefix"),
+            make_location("/api/v1", "prefix"),
+            make_location("/apiary", "prefix"),
+            make_location("/api", "exact"),
+            make_location("/health", "exact"),
+            make_location(r"\.php$", "regex"),
+            make_location(r"^/api/v\d+/users", "regex"),
+            post_api,
+            post_php,
+            make_location("/static/", "prefix"),
+            make_location("/api", "prefix"),
+            make_location("/api/v1/legacy", "prefix_priority"),
+            make_location(r"(?i)\.PHP$", "regex"),
+        ];
+        let host = make_host(1, &["example.com"], locs, true);
+        let router = Router::build(std::slice::from_ref(&host));
+        let paths = [
+            "/", "/api", "/api/", "/api/v1", "/api/v1/users", "/api/v2/users/7", "/apiary", "/apiar",
+            "/health", "/health/x", "/index.php", "/api/index.php", "/static", "/static/app.js", "", "/x",
+            "/api/v1/legacy/index.php", "/api/v1/legacy/users", "/INDEX.PHP",
+        ];
+        for path in paths {
+            for method in ["GET", "POST"] {
+                let request = RequestView { method, ..RequestView::get(path) };
+                assert_eq!(
+                    router.match_location(&host, &request).1,
+                    linear_match(&router, &host, &request),
+                    "{} {}",
+                    method,
+                    path
+                );
+            }
+        }
+        // Spot-check the precedence itself
+        assert_eq!(router.match_location(&host, &RequestView::get("/api")).1, Some(4));
+        assert_eq!(router.match_location(&host, &RequestView::get("/api/v3/users")).1, Some(7));
+        assert_eq!(router.match_location(&host, &RequestView::get("/apiary/x")).1, Some(3));
+        let post = RequestView { method: "POST", ..RequestView::get("/api/x.php") };
+        assert_eq!(router.match_location(&host, &post).1, Some(9));
+    }
+
+    // ─── nginx precedence ───────────────────────────────────
+
+    fn matched_index(router: &Router, path: &str) -> Option<usize> {
+        router.resolve("example.com", &RequestView::get(path)).unwrap().2
+    }
+
+    #[test]
+    fn test_priority_prefix_skips_regex() {
+        let locs = vec![make_location(r"\.png$", "regex"), make_location("/static", "prefix_priority")];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(matched_index(&router, "/static/logo.png"), Some(1));
+        assert_eq!(matched_index(&router, "/img/logo.png"), Some(0));
+    }
+
+    #[test]
+    fn test_priority_prefix_applies_only_when_longest() {
+        let locs = vec![
+            make_location("/static", "prefix_priority"),
+            make_location("/static/img", "prefix"),
+            make_location(r"\.png$", "regex"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        // The longest prefix is a plain one, so regexes are still checked
+        assert_eq!(matched_index(&router, "/static/img/logo.png"), Some(2));
+        assert_eq!(matched_index(&router, "/static/img/readme.txt"), Some(1));
+        assert_eq!(matched_index(&router, "/static/logo.png"), Some(0));
+    }
+
+    #[test]
+    fn test_exact_beats_priority_prefix() {
+        let locs = vec![make_location("/static", "prefix_priority"), make_location("/static", "exact")];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(matched_index(&router, "/static"), Some(1));
+        assert_eq!(matched_index(&router, "/static/x"), Some(0));
+    }
+
+    #[test]
+    fn test_regexes_in_declaration_order() {
+        let mut admin_posts = make_location("^/admin", "regex");
+        admin_posts.conditions = Some(LocationConditions { methods: vec!["POST".to_string()], ..Default::default() });
+        let locs = vec![
+            make_location(r"\.php$", "regex"),
+            admin_posts,
+            make_location("^/api", "regex"),
+            make_location("^/api/v1", "regex"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(matched_index(&router, "/api/v1/users"), Some(2));
+        // The first declared regex wins even over a later conditional one
+        let post = RequestView { method: "POST", ..RequestView::get("/admin/index.php") };
+        assert_eq!(router.resolve("example.com", &post).unwrap().2, Some(0));
+        let post = RequestView { method: "POST", ..RequestView::get("/admin/users") };
+        assert_eq!(router.resolve("example.com", &post).unwrap().2, Some(1));
+    }
+
+    #[test]
+    fn test_case_insensitive_regex() {
+        let mut images = make_location(r"^/(\w+)\.PNG$", "regex_case_insensitive");
+        images.forward_path = Some("/img/$1.png".to_string());
+        let locs = vec![images, make_location(r"\.JPG$", "regex")];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(matched_index(&router, "/logo.png"), Some(0));
+        assert_eq!(matched_index(&router, "/Logo.Png"), Some(0));
+        assert_eq!(matched_index(&router, "/photo.jpg"), None);
+        assert_eq!(matched_index(&router, "/photo.JPG"), Some(1));
+        assert_eq!(&router.captures(1, 0, "/Logo.PNG").unwrap()[1], "Logo");
+    }
+
+    #[test]
+    fn test_nginx_modifiers_as_match_types() {
+        let locs = vec![
+            make_location("/", "prefix"),
+            make_location("/exact", "="),
+            make_location("/assets", "^~"),
+            make_location(r"\.css$", "~"),
+            make_location(r"\.JS$", "~*"),
+        ];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        assert_eq!(matched_index(&router, "/exact"), Some(1));
+        assert_eq!(matched_index(&router, "/assets/site.css"), Some(2));
+        assert_eq!(matched_index(&router, "/site.css"), Some(3));
+        assert_eq!(matched_index(&router, "/app.js"), Some(4));
+        assert_eq!(matched_index(&router, "/other"), Some(0));
+    }
+
+    // ─── Wildcard and regex server names ────────────────────
+
+    #[test]
+    fn test_wildcard_matches_subdomains() {
+        let hosts = vec![make_host(1, &["*.example.com"], vec![], true)];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("api.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("a.b.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("API.Example.com:8443", &RequestView::get("/")).unwrap().0.id, 1);
+        assert!(router.resolve("example.com", &RequestView::get("/")).is_none());
+        assert!(router.resolve(".example.com", &RequestView::get("/")).is_none());
+        assert!(router.resolve("badexample.com", &RequestView::get("/")).is_none());
+    }
+
+    #[test]
+    fn test_longest_wildcard_wins() {
+        let hosts = vec![
+            make_host(1, &["*.example.com"], vec![], true),
+            make_host(2, &["*.eu.example.com"], vec![], true),
+        ];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("shop.eu.example.com", &RequestView::get("/")).unwrap().0.id, 2);
+        assert_eq!(router.resolve("eu.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("shop.us.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+    }
+
+    #[test]
+    fn test_regex_server_name() {
+        let hosts = vec![make_host(1, &[r"~^(?<tenant>.+)\.apps\.example\.com$"], vec![], true)];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("acme.apps.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("Acme.Apps.Example.com:443", &RequestView::get("/")).unwrap().0.id, 1);
+        assert!(router.resolve("apps.example.com", &RequestView::get("/")).is_none());
+        assert!(router.has_domain("x.apps.example.com"));
+    }
+
+    #[test]
+    fn test_server_name_precedence_exact_wildcard_regex() {
+        let hosts = vec![
+            make_host(1, &[r"~\.example\.com$"], vec![], true),
+            make_host(2, &["*.example.com"], vec![], true),
+            make_host(3, &["www.example.com"], vec![], true),
+        ];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("www.example.com", &RequestView::get("/")).unwrap().0.id, 3);
+        assert_eq!(router.resolve("api.example.com", &RequestView::get("/")).unwrap().0.id, 2);
+    }
+
+    #[test]
+    fn test_first_matching_regex_wins() {
+        let hosts = vec![
+            make_host(1, &[r"~^api\."], vec![], true),
+            make_host(2, &[r"~\.example\.com$"], vec![], true),
+        ];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("api.example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("www.example.com", &RequestView::get("/")).unwrap().0.id, 2);
+    }
+
+    #[test]
+    fn test_invalid_server_name_regex_is_skipped() {
+        let hosts = vec![make_host(1, &["~[invalid", "ok.com"], vec![], true)];
+        let router = Router::build(&hosts);
+        assert!(router.resolve("ok.com", &RequestView::get("/")).is_some());
+        assert!(router.resolve("[invalid", &RequestView::get("/")).is_none());
+    }
+
+    #[test]
+    fn test_wildcard_host_routes_locations() {
+        let locs = vec![make_location("/api", "prefix")];
+        let hosts = vec![make_host(1, &["*.example.com"], locs, true)];
+        let router = Router::build(&hosts);
+        let (_, loc, idx) = router.resolve("tenant.example.com", &RequestView::get("/api/users")).unwrap();
+        assert_eq!(loc.unwrap().path, "/api");
+        assert_eq!(idx, Some(0));
+    }
+
+    // ─── Default server ─────────────────────────────────────
+
+    #[test]
+    fn test_default_server_catches_unmatched_hosts() {
+        let mut fallback = make_host(2, &["fallback.com"], vec![], true);
+        fallback.default_server = true;
+        let hosts = vec![make_host(1, &["example.com"], vec![], true), fallback];
+        let router = Router::build(&hosts);
+        assert_eq!(router.resolve("example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(router.resolve("unknown.com", &RequestView::get("/")).unwrap().0.id, 2);
+        assert_eq!(router.resolve("", &RequestView::get("/")).unwrap().0.id, 2);
+        assert!(!router.has_domain("unknown.com"));
+    }
+
+    #[test]
+    fn test_first_default_server_wins() {
+        let mut first = make_host(1, &["a.com"], vec![], true);
+        first.default_server = true;
+        let mut second = make_host(2, &["b.com"], vec![], true);
+        second.default_server = true;
+        let router = Router::build(&[first, second]);
+        assert_eq!(router.resolve("unknown.com", &RequestView::get("/")).unwrap().0.id, 1);
+    }
+
+    #[test]
+    fn test_disabled_default_server_ignored() {
+        let mut fallback = make_host(1, &["fallback.com"], vec![], false);
+        fallback.default_server = true;
+        let router = Router::build(&[fallback]);
+        assert!(router.resolve("unknown.com", &RequestView::get("/")).is_none());
+    }
+
     // ─── Security: host header injection / malformed domains ─
 
     #[test]
     fn test_empty_host_header() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("", "/").is_none());
+        assert!(router.resolve("", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_host_with_null_byte() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("example.com\0.evil.com", "/").is_none());
+        assert!(router.resolve("example.com\0.evil.com", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_host_with_path_injection() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("example.com/admin", "/").is_none());
+        assert!(router.resolve("example.com/admin", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_host_with_at_sign_injection() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("attacker@example.com", "/").is_none());
+        assert!(router.resolve("attacker@example.com", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_host_with_unicode_homoglyph() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("exаmple.com", "/").is_none()); // 'а' is U+0430
+        assert!(router.resolve("exаmple.com", &RequestView::get("/")).is_none()); // 'а' is U+0430
     }
 
     #[test]
     fn test_host_only_port() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve(":8080", "/").is_none());
+        assert!(router.resolve(":8080", &RequestView::get("/")).is_none());
     }
 
     #[test]
     fn test_host_multiple_colons() {
         let hosts = vec![make_host(1, &["example.com"], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve("example.com:80:extra", "/").is_some());
+        assert!(router.resolve("example.com:80:extra", &RequestView::get("/")).is_some());
     }
 
     // ─── Security: path traversal / malicious paths ─────────
@@ -385,7 +1317,7 @@ mod tests {
         let locs = vec![make_location("/api", "prefix")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/api/../admin").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api/../admin")).unwrap();
         assert!(loc.is_some());
     }
 
@@ -394,7 +1326,7 @@ mod tests {
         let locs = vec![make_location("/health", "exact")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/health/../secret").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/health/../secret")).unwrap();
         assert!(loc.is_none());
     }
 
@@ -404,7 +1336,7 @@ mod tests {
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
         let long_path = format!("/files/{}", "1".repeat(10000));
-        let (_, loc, _) = router.resolve("example.com", &long_path).unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get(&long_path)).unwrap();
         assert!(loc.is_some());
     }
 
@@ -413,7 +1345,7 @@ mod tests {
         let locs = vec![make_location("[invalid", "regex")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/anything").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/anything")).unwrap();
         assert!(loc.is_none());
     }
 
@@ -422,7 +1354,7 @@ mod tests {
         let locs = vec![make_location("/", "prefix")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("")).unwrap();
         assert!(loc.is_none());
     }
 
@@ -431,21 +1363,130 @@ mod tests {
         let locs = vec![make_location("/api", "prefix")];
         let hosts = vec![make_host(1, &["example.com"], locs, true)];
         let router = Router::build(&hosts);
-        let (_, loc, _) = router.resolve("example.com", "/api\0/admin").unwrap();
+        let (_, loc, _) = router.resolve("example.com", &RequestView::get("/api\0/admin")).unwrap();
         assert!(loc.is_some());
     }
 
+    // ─── Path normalization ─────────────────────────────────
+
+    #[test]
+    fn test_normalize_leaves_clean_paths_borrowed() {
+        for path in ["/", "/api/users", "/a.b/c..d/", "*"] {
+            assert!(matches!(normalize_path(path), Ok(Cow::Borrowed(p)) if p == path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_normalize_dot_segments_and_slashes() {
+        let cases = [
+            ("/admin/../secret", "/secret"),
+            ("//admin", "/admin"),
+            ("/a//b///c", "/a/b/c"),
+            ("/a/./b/.", "/a/b/"),
+            ("/a/b/..", "/a/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/..", "/"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(normalize_path(raw).unwrap(), expected, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_normalize_decodes_unreserved_escapes_only() {
+        assert_eq!(normalize_path("/%61dmin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/public/%2e%2e/admin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/public/%2E%2E/admin").unwrap(), "/admin");
+        assert_eq!(normalize_path("/%7Euser").unwrap(), "/~user");
+        // Reserved and non-ASCII escapes stay encoded
+        assert_eq!(normalize_path("/a%20b/%3F").unwrap(), "/a%20b/%3F");
+        assert_eq!(normalize_path("/caf%C3%A9").unwrap(), "/caf%C3%A9");
+        // No double decoding
+        assert_eq!(normalize_path("/%252e%252e/admin").unwrap(), "/%252e%252e/admin");
+    }
+
+    #[test]
+    fn test_normalize_rejects_ambiguous_encodings() {
+        for path in ["/a%2Fb", "/a%2fb", "/a%5Cb", "/a%00", "/a\\..\\b", "/a%zz", "/a%4", "/a%"] {
+            assert!(normalize_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_normalized_path_cannot_escape_location() {
+        let locs = vec![make_location("/public", "prefix"), make_location("/admin", "prefix")];
+        let router = Router::build(&[make_host(1, &["example.com"], locs, true)]);
+        for raw in ["/public/../admin/x", "/public/%2e%2e/admin/x", "//admin/x"] {
+            let path = normalize_path(raw).unwrap();
+            let (_, loc, _) = router.resolve("example.com", &RequestView::get(&path)).unwrap();
+            assert_eq!(loc.unwrap().path, "/admin", "{}", raw);
+        }
+    }
+
     // ─── Security: duplicate domains / domain conflicts ─────
 
     #[test]
-    fn test_duplicate_domain_last_wins() {
+    fn test_duplicate_domain_lowest_id_wins() {
+        // Same outcome whatever order the host files were loaded in
+        for hosts in [
+            vec![make_host(1, &["dup.com"], vec![], true), make_host(2, &["DUP.com"], vec![], true)],
+            vec![make_host(2, &["DUP.com"], vec![], true), make_host(1, &["dup.com"], vec![], true)],
+        ] {
+            let router = Router::build(&hosts);
+            let (host, _, _) = router.resolve("dup.com", &RequestView::get("/")).unwrap();
+            assert_eq!(host.id, 1);
+            assert_eq!(
+                router.conflicts(),
+                &[DomainConflict { domain: "dup.com".to_string(), kept_host: 1, dropped_host: 2 }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_explicit_domain_beats_www_alias() {
+        let mut redirecting = make_host(1, &["example.com"], vec![], true);
+        redirecting.redirect_www = true;
+        let www = make_host(2, &["www.example.com"], vec![], true);
+        let router = Router::build(&[redirecting, www]);
+        assert_eq!(router.resolve("www.example.com", &RequestView::get("/")).unwrap().0.id, 2);
+        assert_eq!(router.resolve("example.com", &RequestView::get("/")).unwrap().0.id, 1);
+        assert_eq!(
+            router.conflicts(),
+            &[DomainConflict { domain: "www.example.com".to_string(), kept_host: 2, dropped_host: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_www_alias_conflict_between_redirect_hosts() {
+        let mut a = make_host(3, &["example.com"], vec![], true);
+        a.redirect_www = true;
+        let mut b = make_host(4, &["Example.com"], vec![], true);
+        b.redirect_www = true;
+        let router = Router::build(&[b, a]);
+        assert_eq!(router.resolve("www.example.com", &RequestView::get("/")).unwrap().0.id, 3);
+        let domains: Vec<&str> = router.conflicts().iter().map(|c| c.domain.as_str()).collect();
+        assert_eq!(domains, vec!["example.com", "www.example.com"]);
+    }
+
+    #[test]
+    fn test_wildcard_conflict_reported() {
         let hosts = vec![
-            make_host(1, &["dup.com"], vec![], true),
-            make_host(2, &["dup.com"], vec![], true),
+            make_host(5, &["*.example.com"], vec![], true),
+            make_host(2, &["*.example.com"], vec![], true),
         ];
         let router = Router::build(&hosts);
-        let (host, _, _) = router.resolve("dup.com", "/").unwrap();
-        assert_eq!(host.id, 2);
+        assert_eq!(router.resolve("a.example.com", &RequestView::get("/")).unwrap().0.id, 2);
+        assert_eq!(router.conflicts()[0].domain, "*.example.com");
+        assert_eq!(router.conflicts()[0].dropped_host, 5);
+    }
+
+    #[test]
+    fn test_no_conflict_within_one_host_or_with_disabled_host() {
+        let mut own = make_host(1, &["example.com", "www.example.com", "example.com"], vec![], true);
+        own.redirect_www = true;
+        let disabled = make_host(2, &["example.com"], vec![], false);
+        let router = Router::build(&[own, disabled]);
+        assert!(router.conflicts().is_empty());
     }
 
     #[test]
@@ -459,6 +1500,6 @@ mod tests {
         let long_domain = format!("{}.com", "a".repeat(1000));
         let hosts = vec![make_host(1, &[&long_domain], vec![], true)];
         let router = Router::build(&hosts);
-        assert!(router.resolve(&long_domain, "/").is_some());
+        assert!(router.resolve(&long_domain, &RequestView::get("/")).is_some());
     }
 }
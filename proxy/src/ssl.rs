@@ -1,60 +1,521 @@
-use crate::config::{AppConfig, HostConfig, SslConfig};
+use crate::config::{AccessListConfig, AppConfig, DefaultCertConfig, HostConfig, SslConfig};
+use crate::router::ServerNames;
+use chrono::{DateTime, SecondsFormat, Utc};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslContextBuilder, SslMethod, SslVersion};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Ref, X509VerifyResult, X509};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 /// Certificate and key file paths for a domain
 #[derive(Debug, Clone)]
-#[allow(dead_code)] // fields read in all_cert_pairs() and tests
 pub struct CertPair {
     pub cert_path: String,
     pub key_path: String,
+    /// Expiry of the leaf certificate, recorded when the pair was validated
+    pub not_after: DateTime<Utc>,
+    /// Pre-fetched OCSP response to staple (`ssl.ocsp_file`); otherwise the responder is queried
+    pub ocsp_file: Option<String>,
+}
+
+/// Parsed certificate chain and private key, ready to be handed to OpenSSL
+pub struct LoadedCert {
+    /// Leaf certificate (first PEM block of the cert file)
+    pub leaf: X509,
+    /// Intermediate certificates that follow the leaf
+    pub chain: Vec<X509>,
+    pub key: PKey<Private>,
+}
+
+impl LoadedCert {
+    /// Read and parse a PEM certificate chain and private key, checking that the
+    /// key belongs to the leaf and that the first intermediate issued the leaf.
+    pub fn from_files(cert_path: &str, key_path: &str) -> Result<LoadedCert, String> {
+        let cert_pem = std::fs::read(cert_path)
+            .map_err(|e| format!("cannot read {}: {}", cert_path, e))?;
+        let mut certs = X509::stack_from_pem(&cert_pem)
+            .map_err(|e| format!("invalid certificate PEM in {}: {}", cert_path, e))?;
+        if certs.is_empty() {
+            return Err(format!("no certificate found in {}", cert_path));
+        }
+        let leaf = certs.remove(0);
+
+        let key_pem = std::fs::read(key_path)
+            .map_err(|e| format!("cannot read {}: {}", key_path, e))?;
+        let key = PKey::private_key_from_pem(&key_pem)
+            .map_err(|e| format!("invalid private key PEM in {}: {}", key_path, e))?;
+
+        let key_matches = leaf
+            .public_key()
+            .map(|public| public.public_eq(&key))
+            .unwrap_or(false);
+        if !key_matches {
+            return Err(format!(
+                "private key {} does not match certificate {}",
+                key_path, cert_path
+            ));
+        }
+        if let Some(issuer) = certs.first() {
+            if issuer.issued(&leaf) != X509VerifyResult::OK {
+                return Err(format!(
+                    "second certificate in {} did not issue the leaf (chain out of order?)",
+                    cert_path
+                ));
+            }
+        }
+
+        Ok(LoadedCert {
+            leaf,
+            chain: certs,
+            key,
+        })
+    }
+
+    /// Generate a self-signed P-256 certificate covering `names` (the first is the CN),
+    /// valid for `days` from now
+    pub fn self_signed(names: &[&str], days: u32) -> Result<LoadedCert, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, names.first().copied().unwrap_or("localhost"))?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(days)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        if !names.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for n in names {
+                san.dns(n);
+            }
+            let ext = san.build(&builder.x509v3_context(None, None))?;
+            builder.append_extension(ext)?;
+        }
+        builder.sign(&key, MessageDigest::sha256())?;
+
+        Ok(LoadedCert {
+            leaf: builder.build(),
+            chain: Vec::new(),
+            key,
+        })
+    }
+}
+
+impl CertPair {
+    /// Read and parse the PEM files referenced by this pair
+    pub fn load(&self) -> Result<LoadedCert, String> {
+        LoadedCert::from_files(&self.cert_path, &self.key_path)
+    }
+
+    /// Classify the expiry relative to `now` and the warning threshold
+    pub fn expiry_status(&self, now: DateTime<Utc>, warn_days: u32) -> ExpiryStatus {
+        if self.not_after <= now {
+            ExpiryStatus::Expired
+        } else if self.not_after - now <= chrono::Duration::days(i64::from(warn_days)) {
+            ExpiryStatus::Expiring
+        } else {
+            ExpiryStatus::Valid
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryStatus {
+    Valid,
+    /// Expires within the configured warning threshold
+    Expiring,
+    Expired,
+}
+
+/// Per-host handshake settings applied when the client hello names one of the host's domains
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsPolicy {
+    pub min_version: Option<SslVersion>,
+    pub max_version: Option<SslVersion>,
+    /// OpenSSL cipher list for TLS 1.2 and below
+    pub ciphers: Option<String>,
+    /// TLS 1.3 cipher suites
+    pub ciphersuites: Option<String>,
+    /// Offer h2 before http/1.1 during ALPN
+    pub http2: bool,
+    /// Ask for a client certificate (a location's access list has a `client_cert` rule)
+    pub request_client_cert: bool,
+}
+
+impl TlsPolicy {
+    /// Build the policy for a host, dropping (and reporting) settings OpenSSL would reject
+    fn from_host(
+        host: &HostConfig,
+        ssl: &SslConfig,
+        access_lists: &HashMap<u64, AccessListConfig>,
+        problems: &mut Vec<CertProblem>,
+    ) -> TlsPolicy {
+        let mut report = |reason: String| {
+            problems.push(CertProblem {
+                host_id: host.id,
+                reason,
+            })
+        };
+        let mut version = |field: &str, value: &Option<String>| {
+            let value = value.as_deref()?;
+            let parsed = parse_tls_version(value);
+            if parsed.is_none() {
+                report(format!("invalid {} '{}'", field, value));
+            }
+            parsed
+        };
+        let mut min_version = version("min_version", &ssl.min_version);
+        let mut max_version = version("max_version", &ssl.max_version);
+        if let (Some(min), Some(max)) = (min_version, max_version) {
+            if tls_version_rank(min) > tls_version_rank(max) {
+                report(format!(
+                    "min_version '{}' is above max_version '{}'",
+                    ssl.min_version.as_deref().unwrap_or(""),
+                    ssl.max_version.as_deref().unwrap_or("")
+                ));
+                min_version = None;
+                max_version = None;
+            }
+        }
+
+        let mut ciphers = ssl.ciphers.clone();
+        let mut ciphersuites = ssl.ciphersuites.clone();
+        if let Ok(mut probe) = SslContextBuilder::new(SslMethod::tls()) {
+            if let Some(list) = ciphers.as_deref() {
+                if let Err(e) = probe.set_cipher_list(list) {
+                    report(format!("invalid ciphers '{}': {}", list, e));
+                    ciphers = None;
+                }
+            }
+            if let Some(list) = ciphersuites.as_deref() {
+                if let Err(e) = probe.set_ciphersuites(list) {
+                    report(format!("invalid ciphersuites '{}': {}", list, e));
+                    ciphersuites = None;
+                }
+            }
+        }
+
+        let request_client_cert = host
+            .locations
+            .iter()
+            .filter_map(|loc| loc.access_list_id)
+            .any(|id| access_lists.get(&id).is_some_and(|acl| acl.client_cert.is_some()));
+
+        TlsPolicy {
+            min_version,
+            max_version,
+            ciphers,
+            ciphersuites,
+            // gRPC clients only speak h2
+            http2: host.http2
+                || host.locations.iter().any(|loc| loc.location_type.as_deref() == Some("grpc")),
+            request_client_cert,
+        }
+    }
+}
+
+/// Parse "TLSv1.2" (or "1.2") into an OpenSSL protocol version
+fn parse_tls_version(value: &str) -> Option<SslVersion> {
+    let lower = value.trim().to_ascii_lowercase();
+    match lower.strip_prefix("tlsv").unwrap_or(&lower) {
+        "1" | "1.0" => Some(SslVersion::TLS1),
+        "1.1" => Some(SslVersion::TLS1_1),
+        "1.2" => Some(SslVersion::TLS1_2),
+        "1.3" => Some(SslVersion::TLS1_3),
+        _ => None,
+    }
+}
+
+fn tls_version_rank(version: SslVersion) -> u8 {
+    match version {
+        SslVersion::TLS1 => 0,
+        SslVersion::TLS1_1 => 1,
+        SslVersion::TLS1_2 => 2,
+        _ => 3,
+    }
+}
+
+/// A host whose certificate material was rejected or only partly usable
+#[derive(Debug, Clone, Serialize)]
+pub struct CertProblem {
+    pub host_id: u64,
+    pub reason: String,
+}
+
+/// Certificate served for a host and the domains it covers
+#[derive(Debug, Clone)]
+pub struct HostCert {
+    pub host_id: u64,
+    pub domains: Vec<String>,
+    pub pair: CertPair,
+}
+
+/// JSON document served by the admin listener's certificate report
+#[derive(Debug, Serialize)]
+pub struct ExpiryReport {
+    pub generated_at: String,
+    pub warn_days: u32,
+    /// Soonest expiry first
+    pub certificates: Vec<CertExpiry>,
+    pub problems: Vec<CertProblem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CertExpiry {
+    pub host_id: u64,
+    pub domains: Vec<String>,
+    pub cert_path: String,
+    pub not_after: String,
+    /// Whole days left; negative once expired
+    pub days_remaining: i64,
+    pub status: ExpiryStatus,
 }
 
 /// SSL certificate manager that maps SNI hostnames to certificate paths
 pub struct SslCertManager {
     /// Domain -> CertPair mapping
     certs: HashMap<String, CertPair>,
+    /// Hosts whose certificates failed validation, in config order
+    problems: Vec<CertProblem>,
+    /// Validated certificate per host, in config order
+    host_certs: Vec<HostCert>,
+    /// Server name -> TLS policy of the host the router sends it to (None without SSL)
+    policies: ServerNames<Option<Arc<TlsPolicy>>>,
+    /// Configured certificate for unknown or missing SNI
+    default_cert: Option<CertPair>,
+    /// Abort handshakes for unknown or missing SNI instead of using a default certificate
+    reject_unknown_sni: bool,
 }
 
 impl SslCertManager {
-    /// Build the SSL certificate manager from the application config
+    /// Build the SSL certificate manager from the application config.
+    /// Every pair is parsed and validated here, so bad material is reported
+    /// per host instead of surfacing as failed handshakes.
     pub fn build(config: &AppConfig) -> Self {
         let ssl_dir = &config.global.ssl_dir;
         let mut certs = HashMap::new();
+        let mut problems = Vec::new();
+        let mut host_certs = Vec::new();
+        let mut policies = ServerNames::default();
+        let mut www_aliases = Vec::new();
+        // Names only found in a certificate's SAN list; explicit host domains take precedence
+        let mut san_certs: HashMap<String, CertPair> = HashMap::new();
 
-        for host in &config.hosts {
-            if !host.enabled {
-                continue;
-            }
-            if let Some(ref ssl) = host.ssl {
-                if ssl.ssl_type == "none" {
-                    continue;
+        // A domain claimed by several hosts goes to the lowest host ID, as in the router
+        let mut hosts: Vec<&HostConfig> = config.hosts.iter().filter(|h| h.enabled).collect();
+        hosts.sort_by_key(|h| h.id);
+
+        for host in hosts {
+            let ssl = host.ssl.as_ref().filter(|ssl| ssl.ssl_type != "none");
+            let policy = ssl.map(|ssl| Arc::new(TlsPolicy::from_host(host, ssl, &config.access_lists, &mut problems)));
+            // Every enabled host claims its names like the router does, so a name the
+            // router sends to a plain HTTP host is not picked up by an SSL wildcard
+            for domain in &host.domains {
+                match domain.strip_prefix('~') {
+                    Some(pattern) => {
+                        // An invalid pattern is reported by the router
+                        let _ = policies.add_regex(pattern, policy.clone());
+                    }
+                    None => {
+                        policies.claim(&domain.to_lowercase(), policy.clone());
+                    }
                 }
-                if let Some(pair) = resolve_cert_pair(host, ssl, ssl_dir) {
-                    for domain in &host.domains {
-                        certs.insert(domain.to_lowercase(), pair.clone());
+            }
+            if host.redirect_www {
+                www_aliases.push((host, policy));
+            }
+            if let Some(ssl) = ssl {
+                let (pair, san_names) = match resolve_cert_pair(host, ssl, ssl_dir) {
+                    Ok(resolved) => resolved,
+                    Err(reason) => {
+                        problems.push(CertProblem {
+                            host_id: host.id,
+                            reason,
+                        });
+                        continue;
                     }
+                };
+
+                // Only domains the certificate actually covers are served with it
+                let mut covered = Vec::new();
+                let mut uncovered = Vec::new();
+                for domain in host.domains.iter().filter(|d| !is_regex_name(d)) {
+                    let domain = domain.to_lowercase();
+                    if covers(&san_names, &domain) {
+                        certs.entry(domain.clone()).or_insert_with(|| pair.clone());
+                        covered.push(domain);
+                    } else {
+                        uncovered.push(domain);
+                    }
+                }
+                if !uncovered.is_empty() {
+                    problems.push(CertProblem {
+                        host_id: host.id,
+                        reason: format!(
+                            "certificate {} does not cover {}",
+                            pair.cert_path,
+                            uncovered.join(", ")
+                        ),
+                    });
                 }
+                for name in san_names {
+                    san_certs.entry(name).or_insert_with(|| pair.clone());
+                }
+                host_certs.push(HostCert {
+                    host_id: host.id,
+                    domains: covered,
+                    pair,
+                });
             }
         }
 
-        SslCertManager { certs }
+        // `www.` aliases come after every explicit name, as in the router
+        for (host, policy) in www_aliases {
+            for domain in &host.domains {
+                let domain_lower = domain.to_lowercase();
+                if domain_lower.starts_with("www.") || domain_lower.starts_with("*.") || is_regex_name(&domain_lower) {
+                    continue;
+                }
+                policies.claim(&format!("www.{}", domain_lower), policy.clone());
+            }
+        }
+
+        for (name, pair) in san_certs {
+            certs.entry(name).or_insert(pair);
+        }
+        for problem in &problems {
+            log::warn!("Host {}: TLS certificate problem: {}", problem.host_id, problem.reason);
+        }
+
+        let default_cert = resolve_default_cert(&config.global.default_cert);
+        let reject_unknown_sni = match config.global.default_cert.unknown_sni.as_str() {
+            "default" => false,
+            "reject" => true,
+            other => {
+                log::warn!("Unknown default_cert.unknown_sni '{}', using 'default'", other);
+                false
+            }
+        };
+
+        let manager = SslCertManager {
+            certs,
+            problems,
+            host_certs,
+            policies,
+            default_cert,
+            reject_unknown_sni,
+        };
+        manager.log_expiry_warnings(config.global.cert_expiry_warn_days, Utc::now());
+        manager
     }
 
-    /// Look up the certificate pair for a given SNI hostname
-    #[allow(dead_code)] // public API for TLS listener setup, tested
+    /// Look up the certificate pair for a given SNI hostname.
+    /// An exact match wins; otherwise a `*.parent` wildcard entry covering
+    /// exactly one extra label is used (`a.example.com` → `*.example.com`).
     pub fn get_cert(&self, sni: &str) -> Option<&CertPair> {
         let sni_lower = sni.to_lowercase();
-        self.certs.get(&sni_lower)
+        if let Some(pair) = self.certs.get(&sni_lower) {
+            return Some(pair);
+        }
+        self.certs.get(&wildcard_parent(&sni_lower)?)
+    }
+
+    /// TLS policy for an SNI hostname, resolved to a host like the router does
+    /// (exact name, then wildcards at any depth, then regex names)
+    pub fn get_policy(&self, sni: &str) -> Option<&Arc<TlsPolicy>> {
+        self.policies.lookup(&sni.to_lowercase())?.as_ref()
+    }
+
+    /// Configured certificate for handshakes whose SNI matches no host
+    pub fn default_cert(&self) -> Option<&CertPair> {
+        self.default_cert.as_ref()
+    }
+
+    /// Whether handshakes with an unknown or missing SNI are aborted
+    pub fn rejects_unknown_sni(&self) -> bool {
+        self.reject_unknown_sni
     }
 
     /// Check if any SSL certificates are configured
+    #[allow(dead_code)] // public API, tested
     pub fn has_certs(&self) -> bool {
         !self.certs.is_empty()
     }
 
+    /// Hosts whose certificate material was rejected during `build`
+    pub fn problems(&self) -> &[CertProblem] {
+        &self.problems
+    }
+
+    /// Log a warning for every host certificate that is expired or expiring within `warn_days`
+    pub fn log_expiry_warnings(&self, warn_days: u32, now: DateTime<Utc>) {
+        for host_cert in &self.host_certs {
+            let pair = &host_cert.pair;
+            match pair.expiry_status(now, warn_days) {
+                ExpiryStatus::Expired => log::error!(
+                    "Host {}: TLS certificate {} expired on {}",
+                    host_cert.host_id,
+                    pair.cert_path,
+                    pair.not_after.format("%Y-%m-%d")
+                ),
+                ExpiryStatus::Expiring => log::warn!(
+                    "Host {}: TLS certificate {} expires in {} days ({})",
+                    host_cert.host_id,
+                    pair.cert_path,
+                    (pair.not_after - now).num_days(),
+                    pair.not_after.format("%Y-%m-%d")
+                ),
+                ExpiryStatus::Valid => {}
+            }
+        }
+    }
+
+    /// Expiry of every host certificate plus the hosts whose material was rejected
+    pub fn expiry_report(&self, warn_days: u32, now: DateTime<Utc>) -> ExpiryReport {
+        let mut certificates: Vec<CertExpiry> = self
+            .host_certs
+            .iter()
+            .map(|hc| CertExpiry {
+                host_id: hc.host_id,
+                domains: hc.domains.clone(),
+                cert_path: hc.pair.cert_path.clone(),
+                not_after: hc.pair.not_after.to_rfc3339_opts(SecondsFormat::Secs, true),
+                days_remaining: (hc.pair.not_after - now).num_days(),
+                status: hc.pair.expiry_status(now, warn_days),
+            })
+            .collect();
+        certificates.sort_by(|a, b| a.not_after.cmp(&b.not_after).then(a.host_id.cmp(&b.host_id)));
+        ExpiryReport {
+            generated_at: now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            warn_days,
+            certificates,
+            problems: self.problems.clone(),
+        }
+    }
+
     /// Get all unique certificate pairs for pre-loading
-    #[allow(dead_code)] // public API for TLS cert pre-loading, tested
     pub fn all_cert_pairs(&self) -> Vec<&CertPair> {
         let mut seen = Vec::new();
         let mut result = Vec::new();
@@ -69,61 +530,219 @@ impl SslCertManager {
     }
 }
 
-/// Resolve the certificate and key paths for a host's SSL configuration.
+/// `~pattern` server names are regular expressions matched by the router
+pub fn is_regex_name(name: &str) -> bool {
+    name.starts_with('~')
+}
+
+/// The `*.parent` name that would cover `name` (one extra label only)
+fn wildcard_parent(name: &str) -> Option<String> {
+    let (label, parent) = name.split_once('.')?;
+    if label.is_empty() || label == "*" || parent.is_empty() {
+        return None;
+    }
+    Some(format!("*.{}", parent))
+}
+
+/// Whether a certificate with these SAN names is valid for `domain` (lowercase)
+fn covers(san_names: &[String], domain: &str) -> bool {
+    if san_names.iter().any(|n| n == domain) {
+        return true;
+    }
+    wildcard_parent(domain).is_some_and(|wc| san_names.contains(&wc))
+}
+
+/// DNS names from the subjectAltName extension of a certificate, lowercased
+pub fn dns_names(cert: &X509Ref) -> Vec<String> {
+    cert.subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|n| n.dnsname().map(|d| d.to_lowercase()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Convert an ASN.1 time to UTC
+fn asn1_to_utc(time: &Asn1TimeRef) -> Option<DateTime<Utc>> {
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(time).ok()?;
+    DateTime::from_timestamp(i64::from(diff.days) * 86_400 + i64::from(diff.secs), 0)
+}
+
+/// Validate the configured default certificate; problems are logged and the
+/// TLS layer falls back to a generated self-signed certificate.
+fn resolve_default_cert(config: &DefaultCertConfig) -> Option<CertPair> {
+    let (cert_path, key_path) = match (&config.cert_path, &config.key_path) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) => return None,
+        _ => {
+            log::error!("Default TLS certificate needs both cert_path and key_path");
+            return None;
+        }
+    };
+    match validate_pair(cert_path, key_path) {
+        Ok((pair, _)) => Some(pair),
+        Err(reason) => {
+            log::error!("Default TLS certificate rejected: {}", reason);
+            None
+        }
+    }
+}
+
+/// Resolve and validate the certificate for a host's SSL configuration.
 /// For Let's Encrypt, looks in `ssl_dir/live/{domain}/`
 /// For custom certs, uses the explicit paths from config.
-fn resolve_cert_pair(host: &HostConfig, ssl: &SslConfig, ssl_dir: &str) -> Option<CertPair> {
-    match ssl.ssl_type.as_str() {
+/// Returns the pair together with the leaf's SAN names, or why it is unusable.
+fn resolve_cert_pair(
+    host: &HostConfig,
+    ssl: &SslConfig,
+    ssl_dir: &str,
+) -> Result<(CertPair, Vec<String>), String> {
+    let (cert_path, key_path) = match ssl.ssl_type.as_str() {
         "letsencrypt" => {
             // Let's Encrypt certs are stored in ssl_dir/live/{domain}/
-            let primary_domain = host.domains.first()?;
-            let domain_lower = primary_domain.to_lowercase();
-            let cert_path = PathBuf::from(ssl_dir)
-                .join("live")
-                .join(&domain_lower)
-                .join("fullchain.pem");
-            let key_path = PathBuf::from(ssl_dir)
+            let primary_domain = host
+                .domains
+                .first()
+                .ok_or_else(|| "letsencrypt host has no domains".to_string())?;
+            let live = PathBuf::from(ssl_dir)
                 .join("live")
-                .join(&domain_lower)
-                .join("privkey.pem");
-
-            // Only return if the files actually exist
-            if cert_path.exists() && key_path.exists() {
-                Some(CertPair {
-                    cert_path: cert_path.to_string_lossy().to_string(),
-                    key_path: key_path.to_string_lossy().to_string(),
-                })
-            } else {
-                log::warn!(
-                    "Let's Encrypt cert not found for domain '{}': {} or {}",
-                    primary_domain,
-                    cert_path.display(),
-                    key_path.display()
-                );
-                None
-            }
+                .join(primary_domain.to_lowercase());
+            (
+                live.join("fullchain.pem").to_string_lossy().to_string(),
+                live.join("privkey.pem").to_string_lossy().to_string(),
+            )
         }
         "custom" => {
             // Custom certs use explicit paths
-            let cert = ssl.cert_path.as_ref()?;
-            let key = ssl.key_path.as_ref()?;
-
-            if PathBuf::from(cert).exists() && PathBuf::from(key).exists() {
-                Some(CertPair {
-                    cert_path: cert.clone(),
-                    key_path: key.clone(),
-                })
-            } else {
-                log::warn!(
-                    "Custom cert not found for host {}: cert={}, key={}",
-                    host.id,
-                    cert,
-                    key
-                );
-                None
+            let cert = ssl
+                .cert_path
+                .clone()
+                .ok_or_else(|| "custom SSL without cert_path".to_string())?;
+            let key = ssl
+                .key_path
+                .clone()
+                .ok_or_else(|| "custom SSL without key_path".to_string())?;
+            (cert, key)
+        }
+        other => return Err(format!("unknown SSL type '{}'", other)),
+    };
+    let (mut pair, san_names) = validate_pair(cert_path, key_path)?;
+    pair.ocsp_file = ssl.ocsp_file.clone();
+    Ok((pair, san_names))
+}
+
+/// Check that both files exist and hold a usable certificate chain and matching key
+fn validate_pair(cert_path: String, key_path: String) -> Result<(CertPair, Vec<String>), String> {
+    for path in [&cert_path, &key_path] {
+        if !PathBuf::from(path).is_file() {
+            return Err(format!("{} not found", path));
+        }
+    }
+
+    let loaded = LoadedCert::from_files(&cert_path, &key_path)?;
+    let not_after = asn1_to_utc(loaded.leaf.not_after())
+        .ok_or_else(|| format!("unreadable expiry date in {}", cert_path))?;
+    let san_names = dns_names(&loaded.leaf);
+    Ok((
+        CertPair {
+            cert_path,
+            key_path,
+            not_after,
+            ocsp_file: None,
+        },
+        san_names,
+    ))
+}
+
+/// Certificate fixtures shared by the TLS-related unit tests
+#[cfg(test)]
+pub(crate) mod test_certs {
+    use super::LoadedCert;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::path::{Path, PathBuf};
+
+    /// Self-signed CA certificate that can sign leaves with `issue`
+    pub fn ca(name: &str) -> LoadedCert {
+        build(name, &[], None, true)
+    }
+
+    /// Client-auth leaf certificate with CN `cn` and DNS/email SANs, signed by `ca`
+    pub fn issue(ca: &LoadedCert, cn: &str, sans: &[&str]) -> LoadedCert {
+        build(cn, sans, Some(ca), false)
+    }
+
+    fn build(cn: &str, sans: &[&str], issuer: Option<&LoadedCert>, is_ca: bool) -> LoadedCert {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Ops").unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder
+            .set_issuer_name(issuer.map_or(&name, |ca| ca.leaf.subject_name()))
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(30).unwrap()).unwrap();
+        if is_ca {
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+        } else {
+            builder
+                .append_extension(ExtendedKeyUsage::new().client_auth().build().unwrap())
+                .unwrap();
+        }
+        if !sans.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for n in sans {
+                if n.contains('@') {
+                    san.email(n);
+                } else {
+                    san.dns(n);
+                }
             }
+            let ext = san
+                .build(&builder.x509v3_context(issuer.map(|ca| ca.leaf.as_ref()), None))
+                .unwrap();
+            builder.append_extension(ext).unwrap();
         }
-        _ => None,
+        let signing_key = issuer.map_or(&key, |ca| &ca.key);
+        builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+        LoadedCert {
+            leaf: builder.build(),
+            chain: Vec::new(),
+            key,
+        }
+    }
+
+    /// Write a self-signed P-256 certificate covering `names` (first name is the CN)
+    /// into `dir/cert.pem` and `dir/key.pem`, valid for `days` from now.
+    pub fn write_self_signed(dir: &Path, names: &[&str], days: u32) -> (PathBuf, PathBuf) {
+        let cert = LoadedCert::self_signed(names, days).unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.leaf.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
     }
 }
 
@@ -135,16 +754,8 @@ mod tests {
 
     fn make_global(ssl_dir: &str) -> GlobalConfig {
         GlobalConfig {
-            listen: ListenConfig {
-                http: 80,
-                https: 443,
-                admin: 81,
-            },
-            admin_upstream: "127.0.0.1:3001".to_string(),
-            default_page: "/data/default-page/index.html".to_string(),
-            error_pages_dir: "/data/error-pages".to_string(),
-            logs_dir: "/data/logs".to_string(),
             ssl_dir: ssl_dir.to_string(),
+            ..Default::default()
         }
     }
 
@@ -157,15 +768,9 @@ mod tests {
         HostConfig {
             id,
             domains: domains.iter().map(|s| s.to_string()).collect(),
-            group_id: None,
             ssl,
-            locations: vec![],
-            stream_ports: vec![],
-            hsts: false,
-            http2: false,
             enabled,
-            compression: true,
-            redirect_www: false,
+            ..Default::default()
         }
     }
 
@@ -177,6 +782,13 @@ mod tests {
         }
     }
 
+    /// Lay out a self-signed cert the way Let's Encrypt does in `live/{domain}/`
+    fn write_live_cert(cert_dir: &std::path::Path, names: &[&str]) {
+        let (cert, key) = test_certs::write_self_signed(cert_dir, names, 30);
+        fs::rename(cert, cert_dir.join("fullchain.pem")).unwrap();
+        fs::rename(key, cert_dir.join("privkey.pem")).unwrap();
+    }
+
     // ─── SslCertManager::build ──────────────────────────────
 
     #[test]
@@ -197,12 +809,7 @@ mod tests {
 
     #[test]
     fn test_build_host_ssl_type_none_skipped() {
-        let ssl = SslConfig {
-            ssl_type: "none".to_string(),
-            force_https: false,
-            cert_path: None,
-            key_path: None,
-        };
+        let ssl = SslConfig::default();
         let host = make_host(1, &["example.com"], Some(ssl), true);
         let config = make_app_config("/nonexistent", vec![host]);
         let mgr = SslCertManager::build(&config);
@@ -214,8 +821,7 @@ mod tests {
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
             force_https: true,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["example.com"], Some(ssl), false);
         let config = make_app_config("/nonexistent", vec![host]);
@@ -228,8 +834,7 @@ mod tests {
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
             force_https: true,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["example.com"], Some(ssl), true);
         let config = make_app_config("/tmp/nonexistent-ssl-dir", vec![host]);
@@ -242,15 +847,12 @@ mod tests {
         let dir = std::env::temp_dir().join("pingora-test-ssl-le");
         let _ = fs::remove_dir_all(&dir);
         let cert_dir = dir.join("live").join("example.com");
-        fs::create_dir_all(&cert_dir).unwrap();
-        fs::write(cert_dir.join("fullchain.pem"), "FAKE CERT").unwrap();
-        fs::write(cert_dir.join("privkey.pem"), "FAKE KEY").unwrap();
+        write_live_cert(&cert_dir, &["example.com", "www.example.com"]);
 
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
             force_https: true,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["example.com", "www.example.com"], Some(ssl), true);
         let config = make_app_config(dir.to_str().unwrap(), vec![host]);
@@ -275,9 +877,9 @@ mod tests {
     fn test_build_custom_certs_files_missing() {
         let ssl = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
             cert_path: Some("/nonexistent/cert.pem".to_string()),
             key_path: Some("/nonexistent/key.pem".to_string()),
+            ..Default::default()
         };
         let host = make_host(1, &["custom.com"], Some(ssl), true);
         let config = make_app_config("/nonexistent", vec![host]);
@@ -289,17 +891,13 @@ mod tests {
     fn test_build_custom_certs_with_real_files() {
         let dir = std::env::temp_dir().join("pingora-test-ssl-custom");
         let _ = fs::remove_dir_all(&dir);
-        fs::create_dir_all(&dir).unwrap();
-        let cert_path = dir.join("cert.pem");
-        let key_path = dir.join("key.pem");
-        fs::write(&cert_path, "FAKE CERT").unwrap();
-        fs::write(&key_path, "FAKE KEY").unwrap();
+        let (cert_path, key_path) = test_certs::write_self_signed(&dir, &["custom.com"], 30);
 
         let ssl = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
             cert_path: Some(cert_path.to_str().unwrap().to_string()),
             key_path: Some(key_path.to_str().unwrap().to_string()),
+            ..Default::default()
         };
         let host = make_host(1, &["custom.com"], Some(ssl), true);
         let config = make_app_config("/whatever", vec![host]);
@@ -314,9 +912,8 @@ mod tests {
     fn test_build_custom_missing_cert_path_field() {
         let ssl = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
-            cert_path: None,
             key_path: Some("/some/key.pem".to_string()),
+            ..Default::default()
         };
         let host = make_host(1, &["x.com"], Some(ssl), true);
         let config = make_app_config("/whatever", vec![host]);
@@ -328,9 +925,8 @@ mod tests {
     fn test_build_custom_missing_key_path_field() {
         let ssl = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
             cert_path: Some("/some/cert.pem".to_string()),
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["x.com"], Some(ssl), true);
         let config = make_app_config("/whatever", vec![host]);
@@ -342,9 +938,7 @@ mod tests {
     fn test_build_unknown_ssl_type_ignored() {
         let ssl = SslConfig {
             ssl_type: "magic-ssl-that-doesnt-exist".to_string(),
-            force_https: false,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["x.com"], Some(ssl), true);
         let config = make_app_config("/whatever", vec![host]);
@@ -359,15 +953,11 @@ mod tests {
         let dir = std::env::temp_dir().join("pingora-test-ssl-case");
         let _ = fs::remove_dir_all(&dir);
         let cert_dir = dir.join("live").join("example.com");
-        fs::create_dir_all(&cert_dir).unwrap();
-        fs::write(cert_dir.join("fullchain.pem"), "CERT").unwrap();
-        fs::write(cert_dir.join("privkey.pem"), "KEY").unwrap();
+        write_live_cert(&cert_dir, &["example.com"]);
 
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
-            force_https: false,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["Example.COM"], Some(ssl), true);
         let config = make_app_config(dir.to_str().unwrap(), vec![host]);
@@ -389,9 +979,7 @@ mod tests {
         // The files won't exist so resolve_cert_pair returns None, but verify no panic
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
-            force_https: false,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         let host = make_host(1, &["../../etc/passwd"], Some(ssl), true);
         let config = make_app_config("/tmp", vec![host]);
@@ -404,9 +992,7 @@ mod tests {
     fn test_letsencrypt_empty_domains() {
         let ssl = SslConfig {
             ssl_type: "letsencrypt".to_string(),
-            force_https: false,
-            cert_path: None,
-            key_path: None,
+            ..Default::default()
         };
         // No domains → domains.first() returns None → resolve_cert_pair returns None
         let host = make_host(1, &[], Some(ssl), true);
@@ -419,9 +1005,9 @@ mod tests {
     fn test_custom_cert_path_with_null_bytes() {
         let ssl = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
             cert_path: Some("/etc/\x00/cert.pem".to_string()),
             key_path: Some("/etc/\x00/key.pem".to_string()),
+            ..Default::default()
         };
         let host = make_host(1, &["x.com"], Some(ssl), true);
         let config = make_app_config("/whatever", vec![host]);
@@ -435,17 +1021,13 @@ mod tests {
     fn test_all_cert_pairs_deduplicates_shared_certs() {
         let dir = std::env::temp_dir().join("pingora-test-ssl-dedup");
         let _ = fs::remove_dir_all(&dir);
-        fs::create_dir_all(&dir).unwrap();
-        let cert_path = dir.join("shared.pem");
-        let key_path = dir.join("shared.key");
-        fs::write(&cert_path, "CERT").unwrap();
-        fs::write(&key_path, "KEY").unwrap();
+        let (cert_path, key_path) = test_certs::write_self_signed(&dir, &["a.com", "b.com"], 30);
 
         let ssl1 = SslConfig {
             ssl_type: "custom".to_string(),
-            force_https: false,
             cert_path: Some(cert_path.to_str().unwrap().to_string()),
             key_path: Some(key_path.to_str().unwrap().to_string()),
+            ..Default::default()
         };
         let ssl2 = ssl1.clone();
 
@@ -473,4 +1055,506 @@ mod tests {
         assert!(mgr.get_cert("").is_none());
         assert!(mgr.get_cert("\x00").is_none());
     }
+
+    // ─── CertPair::load ─────────────────────────────────────
+
+    #[test]
+    fn test_load_parses_generated_cert() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-load");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["load.example.com"], 30);
+        let pair = CertPair {
+            cert_path: cert.to_string_lossy().to_string(),
+            key_path: key.to_string_lossy().to_string(),
+            not_after: Utc::now(),
+            ocsp_file: None,
+        };
+        let loaded = pair.load().unwrap();
+        assert!(loaded.chain.is_empty());
+        assert!(loaded.leaf.public_key().unwrap().public_eq(&loaded.key));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_rejects_garbage_pem() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-load-garbage");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), "FAKE CERT").unwrap();
+        fs::write(dir.join("key.pem"), "FAKE KEY").unwrap();
+        let pair = CertPair {
+            cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            key_path: dir.join("key.pem").to_string_lossy().to_string(),
+            not_after: Utc::now(),
+            ocsp_file: None,
+        };
+        assert!(pair.load().is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_missing_files() {
+        let pair = CertPair {
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            key_path: "/nonexistent/key.pem".to_string(),
+            not_after: Utc::now(),
+            ocsp_file: None,
+        };
+        let err = pair.load().err().unwrap();
+        assert!(err.contains("/nonexistent/cert.pem"));
+    }
+
+    // ─── Wildcard SNI fallback ──────────────────────────────
+
+    fn custom_host(id: u64, domains: &[&str], cert: &std::path::Path, key: &std::path::Path) -> HostConfig {
+        let ssl = SslConfig {
+            ssl_type: "custom".to_string(),
+            cert_path: Some(cert.to_str().unwrap().to_string()),
+            key_path: Some(key.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        make_host(id, domains, Some(ssl), true)
+    }
+
+    #[test]
+    fn test_wildcard_domain_serves_one_label() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-wildcard");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["*.example.com"], 30);
+        let config = make_app_config("/whatever", vec![custom_host(1, &["*.example.com"], &cert, &key)]);
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("api.example.com").is_some());
+        assert!(mgr.get_cert("API.Example.com").is_some());
+        // Wildcards cover exactly one label
+        assert!(mgr.get_cert("a.b.example.com").is_none());
+        assert!(mgr.get_cert("example.com").is_none());
+        assert!(mgr.get_cert(".example.com").is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_exact_match_beats_wildcard() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-wildcard-exact");
+        let _ = fs::remove_dir_all(&dir);
+        let (wc_cert, wc_key) = test_certs::write_self_signed(&dir.join("wc"), &["*.example.com"], 30);
+        let (ex_cert, ex_key) = test_certs::write_self_signed(&dir.join("ex"), &["api.example.com"], 30);
+        let config = make_app_config(
+            "/whatever",
+            vec![
+                custom_host(1, &["*.example.com"], &wc_cert, &wc_key),
+                custom_host(2, &["api.example.com"], &ex_cert, &ex_key),
+            ],
+        );
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("api.example.com").unwrap().cert_path.contains("/ex/"));
+        assert!(mgr.get_cert("www.example.com").unwrap().cert_path.contains("/wc/"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── SAN registration ───────────────────────────────────
+
+    #[test]
+    fn test_san_names_registered_for_custom_cert() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-san");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(
+            &dir,
+            &["main.com", "alt.com", "*.apps.main.com"],
+            30,
+        );
+        let config = make_app_config("/whatever", vec![custom_host(1, &["main.com"], &cert, &key)]);
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("main.com").is_some());
+        assert!(mgr.get_cert("alt.com").is_some());
+        assert!(mgr.get_cert("x.apps.main.com").is_some());
+        assert_eq!(mgr.all_cert_pairs().len(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_explicit_domain_beats_other_hosts_san() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-san-precedence");
+        let _ = fs::remove_dir_all(&dir);
+        let (multi_cert, multi_key) =
+            test_certs::write_self_signed(&dir.join("multi"), &["one.com", "two.com"], 30);
+        let (two_cert, two_key) = test_certs::write_self_signed(&dir.join("two"), &["two.com"], 30);
+        // Host 2 declares two.com explicitly; host 1's SAN must not take it over,
+        // regardless of host ordering.
+        let config = make_app_config(
+            "/whatever",
+            vec![
+                custom_host(2, &["two.com"], &two_cert, &two_key),
+                custom_host(1, &["one.com"], &multi_cert, &multi_key),
+            ],
+        );
+        let mgr = SslCertManager::build(&config);
+        assert!(mgr.get_cert("two.com").unwrap().cert_path.contains("/two/"));
+        assert!(mgr.get_cert("one.com").unwrap().cert_path.contains("/multi/"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dns_names_without_san() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-no-san");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &[], 30);
+        let loaded = LoadedCert::from_files(cert.to_str().unwrap(), key.to_str().unwrap()).unwrap();
+        assert!(dns_names(&loaded.leaf).is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── Validation at load ─────────────────────────────────
+
+    #[test]
+    fn test_key_mismatch_rejected() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-key-mismatch");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, _) = test_certs::write_self_signed(&dir.join("a"), &["a.com"], 30);
+        let (_, other_key) = test_certs::write_self_signed(&dir.join("b"), &["a.com"], 30);
+        let config = make_app_config("/whatever", vec![custom_host(7, &["a.com"], &cert, &other_key)]);
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("a.com").is_none());
+        assert_eq!(mgr.problems().len(), 1);
+        assert_eq!(mgr.problems()[0].host_id, 7);
+        assert!(mgr.problems()[0].reason.contains("does not match"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_chain_out_of_order_rejected() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-chain-order");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir.join("leaf"), &["a.com"], 30);
+        let (unrelated, _) = test_certs::write_self_signed(&dir.join("other"), &["ca.test"], 30);
+        let mut chain = fs::read(&cert).unwrap();
+        chain.extend(fs::read(&unrelated).unwrap());
+        fs::write(&cert, chain).unwrap();
+
+        let err = LoadedCert::from_files(cert.to_str().unwrap(), key.to_str().unwrap())
+            .err()
+            .unwrap();
+        assert!(err.contains("did not issue the leaf"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_uncovered_domain_reported_and_not_served() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-uncovered");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["a.com"], 30);
+        let config = make_app_config(
+            "/whatever",
+            vec![custom_host(3, &["a.com", "B.com"], &cert, &key)],
+        );
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("a.com").is_some());
+        assert!(mgr.get_cert("b.com").is_none());
+        assert_eq!(mgr.problems().len(), 1);
+        assert_eq!(mgr.problems()[0].host_id, 3);
+        assert!(mgr.problems()[0].reason.ends_with("does not cover b.com"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_wildcard_san_covers_host_domain() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-wildcard-covers");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["*.example.com"], 30);
+        let config = make_app_config(
+            "/whatever",
+            vec![custom_host(1, &["api.example.com", "a.b.example.com"], &cert, &key)],
+        );
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.get_cert("api.example.com").is_some());
+        assert_eq!(mgr.problems().len(), 1);
+        assert!(mgr.problems()[0].reason.ends_with("does not cover a.b.example.com"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_duplicate_domain_served_by_lowest_host_id() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-duplicate-domain");
+        let _ = fs::remove_dir_all(&dir);
+        let (c1, k1) = test_certs::write_self_signed(&dir.join("one"), &["dup.com"], 30);
+        let (c2, k2) = test_certs::write_self_signed(&dir.join("two"), &["dup.com"], 30);
+        let config = make_app_config(
+            "/whatever",
+            vec![custom_host(2, &["dup.com"], &c2, &k2), custom_host(1, &["dup.com"], &c1, &k1)],
+        );
+        let mgr = SslCertManager::build(&config);
+        assert!(mgr.get_cert("dup.com").unwrap().cert_path.contains("/one/"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_regex_server_name_served_through_san() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-regex-name");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["*.apps.example.com"], 30);
+        let config = make_app_config(
+            "/whatever",
+            vec![custom_host(1, &[r"~^(?<tenant>[^.]+)\.apps\.example\.com$"], &cert, &key)],
+        );
+        let mgr = SslCertManager::build(&config);
+
+        assert!(mgr.problems().is_empty());
+        assert!(mgr.get_cert("acme.apps.example.com").is_some());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_expiry_recorded() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-expiry");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["exp.com"], 45);
+        let config = make_app_config("/whatever", vec![custom_host(1, &["exp.com"], &cert, &key)]);
+        let mgr = SslCertManager::build(&config);
+
+        let days_left = (mgr.get_cert("exp.com").unwrap().not_after - Utc::now()).num_days();
+        assert!((44..=45).contains(&days_left), "days_left = {}", days_left);
+        assert!(mgr.problems().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_problem_reasons_for_missing_material() {
+        let unknown = SslConfig {
+            ssl_type: "magic".to_string(),
+            ..Default::default()
+        };
+        let no_key = SslConfig {
+            ssl_type: "custom".to_string(),
+            cert_path: Some("/some/cert.pem".to_string()),
+            ..Default::default()
+        };
+        let le = SslConfig {
+            ssl_type: "letsencrypt".to_string(),
+            ..Default::default()
+        };
+        let config = make_app_config(
+            "/nonexistent",
+            vec![
+                make_host(1, &["a.com"], Some(unknown), true),
+                make_host(2, &["b.com"], Some(no_key), true),
+                make_host(3, &["c.com"], Some(le), true),
+            ],
+        );
+        let mgr = SslCertManager::build(&config);
+        let reasons: Vec<(u64, &str)> = mgr
+            .problems()
+            .iter()
+            .map(|p| (p.host_id, p.reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, "unknown SSL type 'magic'"),
+                (2, "custom SSL without key_path"),
+                (3, "/nonexistent/live/c.com/fullchain.pem not found"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_garbage_files_reported_with_parse_error() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-garbage-problem");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        fs::write(&cert, "FAKE CERT").unwrap();
+        fs::write(&key, "FAKE KEY").unwrap();
+        let config = make_app_config("/whatever", vec![custom_host(1, &["x.com"], &cert, &key)]);
+        let mgr = SslCertManager::build(&config);
+
+        assert!(!mgr.has_certs());
+        assert!(mgr.problems()[0].reason.contains("cert.pem"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── TLS policy ─────────────────────────────────────────
+
+    fn policy_host(id: u64, domains: &[&str], min: Option<&str>, max: Option<&str>, ciphers: Option<&str>) -> HostConfig {
+        let ssl = SslConfig {
+            ssl_type: "custom".to_string(),
+            cert_path: Some("/nonexistent/cert.pem".to_string()),
+            key_path: Some("/nonexistent/key.pem".to_string()),
+            min_version: min.map(str::to_string),
+            max_version: max.map(str::to_string),
+            ciphers: ciphers.map(str::to_string),
+            ..Default::default()
+        };
+        make_host(id, domains, Some(ssl), true)
+    }
+
+    #[test]
+    fn test_parse_tls_version() {
+        assert_eq!(parse_tls_version("TLSv1"), Some(SslVersion::TLS1));
+        assert_eq!(parse_tls_version("tlsv1.1"), Some(SslVersion::TLS1_1));
+        assert_eq!(parse_tls_version("1.2"), Some(SslVersion::TLS1_2));
+        assert_eq!(parse_tls_version(" TLSv1.3 "), Some(SslVersion::TLS1_3));
+        assert_eq!(parse_tls_version("SSLv3"), None);
+        assert_eq!(parse_tls_version("TLSv1.4"), None);
+    }
+
+    #[test]
+    fn test_policy_registered_for_host_domains() {
+        let mut host = policy_host(1, &["Hard.com", "*.hard.com"], Some("TLSv1.3"), None, None);
+        host.http2 = true;
+        let mgr = SslCertManager::build(&make_app_config("/whatever", vec![host]));
+
+        let policy = mgr.get_policy("hard.com").unwrap();
+        assert_eq!(policy.min_version, Some(SslVersion::TLS1_3));
+        assert_eq!(policy.max_version, None);
+        assert!(policy.http2);
+        assert!(Arc::ptr_eq(policy, mgr.get_policy("api.hard.com").unwrap()));
+        assert!(mgr.get_policy("other.com").is_none());
+        // Policy applies even though the certificate itself is missing
+        assert!(mgr.get_cert("hard.com").is_none());
+    }
+
+    #[test]
+    fn test_policy_follows_router_host_resolution() {
+        let wildcard = policy_host(1, &["*.deep.com"], Some("TLSv1.3"), None, None);
+        let regex = policy_host(2, &["~^api-\\d+\\.svc\\.net$"], Some("TLSv1.2"), None, None);
+        let mut plain = make_host(3, &["legacy.deep.com"], None, true);
+        plain.redirect_www = true;
+        let mut www = policy_host(4, &["shop.com"], Some("TLSv1.3"), None, None);
+        www.redirect_www = true;
+        let mgr = SslCertManager::build(&make_app_config("/whatever", vec![wildcard, regex, plain, www]));
+
+        // Wildcards cover any number of labels, like the router
+        assert_eq!(mgr.get_policy("a.b.deep.com").unwrap().min_version, Some(SslVersion::TLS1_3));
+        assert_eq!(mgr.get_policy("API-7.svc.net").unwrap().min_version, Some(SslVersion::TLS1_2));
+        assert!(mgr.get_policy("api-x.svc.net").is_none());
+        // A plain HTTP host keeps its exact name from the SSL wildcard
+        assert!(mgr.get_policy("legacy.deep.com").is_none());
+        assert!(mgr.get_policy("www.shop.com").is_some());
+    }
+
+    #[test]
+    fn test_policy_http2_for_grpc_locations() {
+        let mut host = policy_host(1, &["grpc.com"], None, None, None);
+        host.locations = vec![serde_yaml::from_str("path: /\ntype: grpc").unwrap()];
+        let plain = policy_host(2, &["web.com"], None, None, None);
+        let mgr = SslCertManager::build(&make_app_config("/whatever", vec![host, plain]));
+        assert!(mgr.get_policy("grpc.com").unwrap().http2);
+        assert!(!mgr.get_policy("web.com").unwrap().http2);
+    }
+
+    #[test]
+    fn test_policy_not_registered_without_ssl() {
+        let mut host = make_host(1, &["plain.com"], None, true);
+        host.http2 = true;
+        let mgr = SslCertManager::build(&make_app_config("/whatever", vec![host]));
+        assert!(mgr.get_policy("plain.com").is_none());
+    }
+
+    #[test]
+    fn test_invalid_policy_settings_reported_and_dropped() {
+        let hosts = vec![
+            policy_host(1, &["a.com"], Some("TLSv9"), Some("TLSv1.2"), None),
+            policy_host(2, &["b.com"], Some("TLSv1.3"), Some("TLSv1.2"), None),
+            policy_host(3, &["c.com"], None, None, Some("NOT-A-CIPHER")),
+        ];
+        let mgr = SslCertManager::build(&make_app_config("/whatever", hosts));
+        let reasons = |id: u64| -> Vec<String> {
+            mgr.problems()
+                .iter()
+                .filter(|p| p.host_id == id)
+                .map(|p| p.reason.clone())
+                .collect()
+        };
+
+        assert!(reasons(1).iter().any(|r| r == "invalid min_version 'TLSv9'"));
+        let a = mgr.get_policy("a.com").unwrap();
+        assert_eq!((a.min_version, a.max_version), (None, Some(SslVersion::TLS1_2)));
+
+        assert!(reasons(2).iter().any(|r| r.contains("is above max_version")));
+        let b = mgr.get_policy("b.com").unwrap();
+        assert_eq!((b.min_version, b.max_version), (None, None));
+
+        assert!(reasons(3).iter().any(|r| r.starts_with("invalid ciphers 'NOT-A-CIPHER'")));
+        assert!(mgr.get_policy("c.com").unwrap().ciphers.is_none());
+    }
+
+    // ─── Expiry report ──────────────────────────────────────
+
+    fn pair_expiring_at(not_after: DateTime<Utc>) -> CertPair {
+        CertPair {
+            cert_path: "/c.pem".to_string(),
+            key_path: "/k.pem".to_string(),
+            not_after,
+            ocsp_file: None,
+        }
+    }
+
+    #[test]
+    fn test_expiry_status_thresholds() {
+        let now = Utc::now();
+        let days = chrono::Duration::days;
+        assert_eq!(pair_expiring_at(now + days(30)).expiry_status(now, 14), ExpiryStatus::Valid);
+        assert_eq!(pair_expiring_at(now + days(14)).expiry_status(now, 14), ExpiryStatus::Expiring);
+        assert_eq!(pair_expiring_at(now + days(1)).expiry_status(now, 14), ExpiryStatus::Expiring);
+        assert_eq!(pair_expiring_at(now).expiry_status(now, 14), ExpiryStatus::Expired);
+        assert_eq!(pair_expiring_at(now - days(3)).expiry_status(now, 14), ExpiryStatus::Expired);
+        // A zero threshold disables the warning band
+        assert_eq!(pair_expiring_at(now + days(1)).expiry_status(now, 0), ExpiryStatus::Valid);
+    }
+
+    #[test]
+    fn test_expiry_report_sorted_with_problems() {
+        let dir = std::env::temp_dir().join("pingora-test-ssl-expiry-report");
+        let _ = fs::remove_dir_all(&dir);
+        let (late_cert, late_key) = test_certs::write_self_signed(&dir.join("late"), &["late.com"], 90);
+        let (soon_cert, soon_key) = test_certs::write_self_signed(&dir.join("soon"), &["soon.com"], 5);
+        let config = make_app_config(
+            "/whatever",
+            vec![
+                custom_host(1, &["late.com"], &late_cert, &late_key),
+                custom_host(2, &["soon.com", "other.com"], &soon_cert, &soon_key),
+            ],
+        );
+        let mgr = SslCertManager::build(&config);
+        let report = mgr.expiry_report(14, Utc::now());
+
+        assert_eq!(report.warn_days, 14);
+        assert_eq!(report.certificates.len(), 2);
+        let soon = &report.certificates[0];
+        assert_eq!(soon.host_id, 2);
+        assert_eq!(soon.domains, vec!["soon.com"]);
+        assert_eq!(soon.status, ExpiryStatus::Expiring);
+        assert!((4..=5).contains(&soon.days_remaining));
+        assert_eq!(report.certificates[1].host_id, 1);
+        assert_eq!(report.certificates[1].status, ExpiryStatus::Valid);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].host_id, 2);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["certificates"][0]["status"], "expiring");
+        assert!(json["certificates"][0]["not_after"].as_str().unwrap().ends_with('Z'));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,810 @@
+use crate::access_control::ClientCert;
+use crate::acme::{self, ChallengeStore};
+use crate::ocsp::{self, OcspCache};
+use crate::ssl::{CertPair, LoadedCert, SslCertManager, TlsPolicy};
+use async_trait::async_trait;
+use foreign_types::ForeignTypeRef;
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    select_next_proto, AlpnError, ClientHelloResponse, NameType, SslAcceptorBuilder, SslAlert, SslRef,
+    SslVerifyMode, SslVersion,
+};
+use pingora_core::listeners::tls::TlsSettings;
+use pingora_core::listeners::TlsAccept;
+use pingora_core::protocols::tls::TlsRef;
+use pingora_core::tls::{ext, ssl_sys};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+/// Common name of the generated certificate used when no default certificate is configured
+const SELF_SIGNED_NAME: &str = "pingora-manager.invalid";
+
+/// Sessions are only resumed on the listener that issued them
+const SESSION_ID_CONTEXT: &[u8] = b"pingora-manager-https";
+
+/// Parsed TLS material for every certificate pair known to an `SslCertManager`.
+/// All pairs are loaded up front so handshakes never touch the filesystem.
+pub struct CertStore {
+    manager: Arc<SslCertManager>,
+    /// (cert_path, key_path) -> parsed certificate chain and key
+    loaded: HashMap<(String, String), Arc<LoadedCert>>,
+    /// Served when the SNI is missing or unknown; `None` rejects those handshakes
+    fallback: Option<Arc<LoadedCert>>,
+}
+
+impl CertStore {
+    /// Parse every pair from `all_cert_pairs()`. Pairs that fail to parse are
+    /// logged and skipped; their domains will fail the handshake.
+    pub fn load(manager: Arc<SslCertManager>) -> Self {
+        let mut loaded = HashMap::new();
+        for pair in manager.all_cert_pairs() {
+            match pair.load() {
+                Ok(cert) => {
+                    log::debug!(
+                        "Loaded TLS certificate {} (expires {})",
+                        pair.cert_path,
+                        pair.not_after.format("%Y-%m-%d %H:%M:%S UTC")
+                    );
+                    loaded.insert(
+                        (pair.cert_path.clone(), pair.key_path.clone()),
+                        Arc::new(cert),
+                    );
+                }
+                Err(e) => {
+                    log::error!("Failed to load TLS certificate {}: {}", pair.cert_path, e);
+                }
+            }
+        }
+        let fallback = if manager.rejects_unknown_sni() {
+            None
+        } else {
+            load_fallback(&manager)
+        };
+        CertStore {
+            manager,
+            loaded,
+            fallback,
+        }
+    }
+
+    /// Resolve the parsed certificate for an SNI hostname
+    pub fn lookup(&self, sni: &str) -> Option<&Arc<LoadedCert>> {
+        let pair = self.manager.get_cert(sni)?;
+        self.loaded
+            .get(&(pair.cert_path.clone(), pair.key_path.clone()))
+    }
+
+    /// Certificate for a handshake: the SNI's own certificate, else the fallback
+    pub fn resolve(&self, sni: Option<&str>) -> Option<&Arc<LoadedCert>> {
+        sni.and_then(|name| self.lookup(name))
+            .or(self.fallback.as_ref())
+    }
+
+    /// TLS policy of the host serving an SNI hostname
+    pub fn policy(&self, sni: &str) -> Option<&Arc<TlsPolicy>> {
+        self.manager.get_policy(sni)
+    }
+
+    /// Number of certificate pairs that were parsed successfully
+    pub fn loaded_count(&self) -> usize {
+        self.loaded.len()
+    }
+
+    /// Every parsed certificate with its pair, including a configured default certificate
+    pub fn served(&self) -> Vec<(&CertPair, &Arc<LoadedCert>)> {
+        let mut served: Vec<(&CertPair, &Arc<LoadedCert>)> = self
+            .manager
+            .all_cert_pairs()
+            .into_iter()
+            .filter_map(|pair| {
+                self.loaded
+                    .get(&(pair.cert_path.clone(), pair.key_path.clone()))
+                    .map(|cert| (pair, cert))
+            })
+            .collect();
+        if let (Some(pair), Some(cert)) = (self.manager.default_cert(), self.fallback.as_ref()) {
+            served.push((pair, cert));
+        }
+        served
+    }
+}
+
+/// Configured default certificate, or the process-wide self-signed one
+fn load_fallback(manager: &SslCertManager) -> Option<Arc<LoadedCert>> {
+    if let Some(pair) = manager.default_cert() {
+        match pair.load() {
+            Ok(cert) => return Some(Arc::new(cert)),
+            Err(e) => log::error!("Failed to load default TLS certificate {}: {}", pair.cert_path, e),
+        }
+    }
+    self_signed_fallback()
+}
+
+/// Generated once so clients see the same certificate across reloads
+fn self_signed_fallback() -> Option<Arc<LoadedCert>> {
+    static SELF_SIGNED: OnceLock<Option<Arc<LoadedCert>>> = OnceLock::new();
+    SELF_SIGNED
+        .get_or_init(|| match LoadedCert::self_signed(&[SELF_SIGNED_NAME], 3650) {
+            Ok(cert) => Some(Arc::new(cert)),
+            Err(e) => {
+                log::error!("Failed to generate self-signed default certificate: {}", e);
+                None
+            }
+        })
+        .clone()
+}
+
+/// Certificate store shared between the TLS listener and the SIGHUP reload thread.
+/// Reloads swap in a freshly loaded store; in-flight handshakes keep the old one.
+pub type SharedCertStore = Arc<arc_swap::ArcSwap<CertStore>>;
+
+/// TLS handshake callback that selects the certificate from the client's SNI
+pub struct SniCertResolver {
+    store: SharedCertStore,
+    /// Pending TLS-ALPN-01 validations, answered instead of the regular certificate
+    challenges: Arc<ChallengeStore>,
+}
+
+impl SniCertResolver {
+    pub fn new(store: SharedCertStore, challenges: Arc<ChallengeStore>) -> Self {
+        SniCertResolver { store, challenges }
+    }
+}
+
+#[async_trait]
+impl TlsAccept for SniCertResolver {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        // Without a certificate the handshake is aborted by OpenSSL
+        let sni = ssl.servername(NameType::HOST_NAME).map(str::to_string);
+        if ssl.selected_alpn_protocol() == Some(acme::ACME_TLS_ALPN) {
+            let sni = match sni {
+                Some(name) => name,
+                None => return,
+            };
+            match self.challenges.tls_alpn01(&sni) {
+                Some(cert) => {
+                    if let Err(e) = use_cert(ssl, &cert) {
+                        log::error!("Failed to apply ACME challenge certificate for '{}': {}", sni, e);
+                    }
+                }
+                None => log::debug!("No pending TLS-ALPN-01 challenge for '{}'", sni),
+            }
+            return;
+        }
+        let store = self.store.load();
+        let sni = sni.as_deref();
+        let cert = match store.resolve(sni) {
+            Some(c) => Arc::clone(c),
+            None => {
+                log::debug!("Rejecting TLS handshake for unknown SNI '{}'", sni.unwrap_or(""));
+                return;
+            }
+        };
+        if let Err(e) = use_cert(ssl, &cert) {
+            log::error!("Failed to apply TLS certificate for '{}': {}", sni.unwrap_or(""), e);
+        }
+    }
+
+    /// Keep the client certificate (if one was requested and sent) for access checks
+    async fn handshake_complete_callback(&self, ssl: &TlsRef) -> Option<Arc<dyn Any + Send + Sync>> {
+        let leaf = ssl.peer_certificate()?;
+        let chain = ssl
+            .peer_cert_chain()
+            .map(|stack| stack.iter().map(|c| c.to_owned()).collect())
+            .unwrap_or_default();
+        Some(Arc::new(ClientCert { leaf, chain }))
+    }
+}
+
+/// Attach the leaf, its chain and the private key to an in-progress handshake
+fn use_cert(ssl: &mut TlsRef, cert: &LoadedCert) -> Result<(), ErrorStack> {
+    ext::ssl_use_certificate(ssl, &cert.leaf)?;
+    for intermediate in &cert.chain {
+        ext::ssl_add_chain_cert(ssl, intermediate)?;
+    }
+    ext::ssl_use_private_key(ssl, &cert.key)
+}
+
+/// Apply the SNI host's protocol versions and cipher lists. Runs on the client
+/// hello, before OpenSSL negotiates the version or picks a cipher.
+fn apply_policy(
+    store: &SharedCertStore,
+    challenges: &ChallengeStore,
+    ssl: &mut SslRef,
+) -> Result<ClientHelloResponse, ErrorStack> {
+    let sni = match client_hello_sni(ssl) {
+        Some(name) => name,
+        None => return Ok(ClientHelloResponse::SUCCESS),
+    };
+    // TLS-ALPN-01 validation needs TLS 1.3 whatever the host allows
+    if challenges.has_tls_alpn01(&sni) {
+        return Ok(ClientHelloResponse::SUCCESS);
+    }
+    let store = store.load();
+    let policy = match store.policy(&sni) {
+        Some(policy) => policy,
+        None => return Ok(ClientHelloResponse::SUCCESS),
+    };
+    if policy.min_version.is_some() {
+        ssl.set_min_proto_version(policy.min_version)?;
+    }
+    if policy.max_version.is_some() {
+        ssl.set_max_proto_version(policy.max_version)?;
+    }
+    if let Some(ciphers) = &policy.ciphers {
+        ssl.set_cipher_list(ciphers)?;
+    }
+    if let Some(ciphersuites) = &policy.ciphersuites {
+        ssl.set_ciphersuites(ciphersuites)?;
+    }
+    if policy.request_client_cert {
+        // Optional at the TLS layer: the certificate is checked against the
+        // matched location's access list once the request is known
+        ssl.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+    }
+    Ok(ClientHelloResponse::SUCCESS)
+}
+
+/// Host name from the client hello's server_name extension. OpenSSL only
+/// records the SNI after the client hello callback, so it is parsed here.
+fn client_hello_sni(ssl: &SslRef) -> Option<String> {
+    const TLSEXT_TYPE_SERVER_NAME: u32 = 0;
+    let mut data: *const u8 = std::ptr::null();
+    let mut len: usize = 0;
+    // SAFETY: only called from the client hello callback, where OpenSSL keeps the
+    // extension bytes alive for the duration of the call
+    let ext = unsafe {
+        if ssl_sys::SSL_client_hello_get0_ext(ssl.as_ptr(), TLSEXT_TYPE_SERVER_NAME, &mut data, &mut len) != 1
+            || data.is_null()
+        {
+            return None;
+        }
+        std::slice::from_raw_parts(data, len)
+    };
+    parse_server_name(ext)
+}
+
+/// First host_name entry of a server_name extension body (RFC 6066 section 3)
+fn parse_server_name(ext: &[u8]) -> Option<String> {
+    let list_len = usize::from(u16::from_be_bytes([*ext.first()?, *ext.get(1)?]));
+    let mut list = ext.get(2..2 + list_len)?;
+    while list.len() >= 3 {
+        let name_type = list[0];
+        let name_len = usize::from(u16::from_be_bytes([list[1], list[2]]));
+        let name = list.get(3..3 + name_len)?;
+        if name_type == 0 {
+            return std::str::from_utf8(name).ok().map(str::to_string);
+        }
+        list = &list[3 + name_len..];
+    }
+    None
+}
+
+/// Negotiate `acme-tls/1` for a pending TLS-ALPN-01 validation, otherwise h2
+/// (for `http2` hosts) or HTTP/1.1.
+/// Only TLS 1.3 runs ALPN selection before the certificate callback, so the
+/// challenge is answered on TLS 1.3 handshakes (what ACME validators use).
+fn select_alpn<'a>(
+    store: &SharedCertStore,
+    challenges: &ChallengeStore,
+    ssl: &mut SslRef,
+    alpn_in: &'a [u8],
+) -> Result<&'a [u8], AlpnError> {
+    if ssl.version2() == Some(SslVersion::TLS1_3) {
+        if let Some(proto) = select_next_proto(acme::ACME_TLS_ALPN_WIRE, alpn_in) {
+            let pending = ssl
+                .servername(NameType::HOST_NAME)
+                .is_some_and(|sni| challenges.has_tls_alpn01(sni));
+            if pending {
+                return Ok(proto);
+            }
+        }
+    }
+    let http2 = ssl
+        .servername(NameType::HOST_NAME)
+        .and_then(|sni| store.load().policy(sni).map(|policy| policy.http2))
+        .unwrap_or(false);
+    let server_protos: &[u8] = if http2 {
+        b"\x02h2\x08http/1.1"
+    } else {
+        b"\x08http/1.1"
+    };
+    select_next_proto(server_protos, alpn_in).ok_or(AlpnError::NOACK)
+}
+
+/// Install the per-host policy and ALPN callbacks on the acceptor.
+/// mTLS hosts switch the verify mode to PEER per handshake, and OpenSSL refuses to
+/// resume a session under PEER unless the context carries a session id context.
+fn configure_acceptor(
+    acceptor: &mut SslAcceptorBuilder,
+    store: SharedCertStore,
+    challenges: Arc<ChallengeStore>,
+) -> Result<(), ErrorStack> {
+    acceptor.set_session_id_context(SESSION_ID_CONTEXT)?;
+    let hello_store = Arc::clone(&store);
+    let hello_challenges = Arc::clone(&challenges);
+    acceptor.set_client_hello_callback(move |ssl: &mut SslRef, _alert: &mut SslAlert| {
+        apply_policy(&hello_store, &hello_challenges, ssl)
+    });
+    acceptor.set_alpn_select_callback(move |ssl, alpn_in| {
+        select_alpn(&store, &challenges, ssl, alpn_in)
+    });
+    Ok(())
+}
+
+/// Build listener settings that terminate TLS with SNI-based certificate selection,
+/// per-host TLS policy and OCSP stapling.
+/// The store is read on every handshake, so swapping it takes effect immediately.
+pub fn tls_settings(
+    store: SharedCertStore,
+    challenges: Arc<ChallengeStore>,
+    staples: Arc<OcspCache>,
+) -> pingora_core::Result<TlsSettings> {
+    let resolver = SniCertResolver::new(Arc::clone(&store), Arc::clone(&challenges));
+    let mut settings = TlsSettings::with_callbacks(Box::new(resolver))?;
+    configure_acceptor(&mut settings, store, challenges).map_err(|e| {
+        pingora_core::Error::because(
+            pingora_core::ErrorType::InternalError,
+            "failed to configure TLS acceptor",
+            e,
+        )
+    })?;
+    settings
+        .set_status_callback(move |ssl| ocsp::staple(&staples, ssl))
+        .map_err(|e| {
+            pingora_core::Error::because(
+                pingora_core::ErrorType::InternalError,
+                "failed to register OCSP stapling callback",
+                e,
+            )
+        })?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::ssl::test_certs;
+    use std::fs;
+
+    fn make_app_config(hosts: Vec<HostConfig>) -> AppConfig {
+        AppConfig {
+            global: GlobalConfig {
+                ssl_dir: "/nonexistent".to_string(),
+                ..Default::default()
+            },
+            hosts,
+            access_lists: HashMap::new(),
+        }
+    }
+
+    fn custom_ssl_host(id: u64, domains: &[&str], cert: &str, key: &str) -> HostConfig {
+        HostConfig {
+            id,
+            domains: domains.iter().map(|s| s.to_string()).collect(),
+            ssl: Some(SslConfig {
+                ssl_type: "custom".to_string(),
+                cert_path: Some(cert.to_string()),
+                key_path: Some(key.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_store_resolves_loaded_cert_by_sni() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-store");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["tls.example.com"], 30);
+        let host = custom_ssl_host(
+            1,
+            &["tls.example.com"],
+            cert.to_str().unwrap(),
+            key.to_str().unwrap(),
+        );
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![host])));
+        let store = CertStore::load(manager);
+
+        assert_eq!(store.loaded_count(), 1);
+        assert!(store.lookup("tls.example.com").is_some());
+        assert!(store.lookup("TLS.Example.com").is_some());
+        assert!(store.lookup("other.example.com").is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_skips_unparseable_pairs() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-store-bad");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        fs::write(&cert, "FAKE CERT").unwrap();
+        fs::write(&key, "FAKE KEY").unwrap();
+        let host = custom_ssl_host(1, &["bad.com"], cert.to_str().unwrap(), key.to_str().unwrap());
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![host])));
+        let store = CertStore::load(manager);
+
+        assert_eq!(store.loaded_count(), 0);
+        assert!(store.lookup("bad.com").is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_store_shared_pair_loaded_once() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-store-shared");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["a.com", "b.com"], 30);
+        let hosts = vec![
+            custom_ssl_host(1, &["a.com"], cert.to_str().unwrap(), key.to_str().unwrap()),
+            custom_ssl_host(2, &["b.com"], cert.to_str().unwrap(), key.to_str().unwrap()),
+        ];
+        let manager = Arc::new(SslCertManager::build(&make_app_config(hosts)));
+        let store = CertStore::load(manager);
+
+        assert_eq!(store.loaded_count(), 1);
+        let a = store.lookup("a.com").unwrap();
+        let b = store.lookup("b.com").unwrap();
+        assert!(Arc::ptr_eq(a, b));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tls_settings_builds_with_empty_store() {
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![])));
+        let shared = Arc::new(arc_swap::ArcSwap::from_pointee(CertStore::load(manager)));
+        let settings = tls_settings(
+            shared,
+            Arc::new(ChallengeStore::default()),
+            Arc::new(OcspCache::default()),
+        );
+        assert!(settings.is_ok());
+    }
+
+    #[test]
+    fn test_swapped_store_picks_up_new_cert() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-store-swap");
+        let _ = fs::remove_dir_all(&dir);
+
+        // Start without any SSL hosts
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![])));
+        let shared: SharedCertStore =
+            Arc::new(arc_swap::ArcSwap::from_pointee(CertStore::load(manager)));
+        let resolver = SniCertResolver::new(Arc::clone(&shared), Arc::new(ChallengeStore::default()));
+        assert!(resolver.store.load().lookup("new.example.com").is_none());
+
+        // A reload adds the first SSL host
+        let (cert, key) = test_certs::write_self_signed(&dir, &["new.example.com"], 30);
+        let host = custom_ssl_host(
+            1,
+            &["new.example.com"],
+            cert.to_str().unwrap(),
+            key.to_str().unwrap(),
+        );
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![host])));
+        shared.store(Arc::new(CertStore::load(manager)));
+        assert!(resolver.store.load().lookup("new.example.com").is_some());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reload_rereads_renewed_cert_at_same_path() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-store-renew");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["renew.example.com"], 10);
+        let host = custom_ssl_host(
+            1,
+            &["renew.example.com"],
+            cert.to_str().unwrap(),
+            key.to_str().unwrap(),
+        );
+        let config = make_app_config(vec![host]);
+        let first = CertStore::load(Arc::new(SslCertManager::build(&config)));
+        let first_serial = first.lookup("renew.example.com").unwrap().leaf.serial_number().to_bn().unwrap();
+
+        // Renewal overwrites the files in place
+        test_certs::write_self_signed(&dir, &["renew.example.com"], 90);
+        let second = CertStore::load(Arc::new(SslCertManager::build(&config)));
+        let second_serial = second.lookup("renew.example.com").unwrap().leaf.serial_number().to_bn().unwrap();
+        assert_ne!(first_serial, second_serial);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── Unknown SNI fallback ───────────────────────────────
+
+    #[test]
+    fn test_unknown_sni_gets_self_signed_fallback() {
+        let manager = Arc::new(SslCertManager::build(&make_app_config(vec![])));
+        let store = CertStore::load(manager);
+
+        let fallback = store.resolve(Some("unknown.example.com")).unwrap();
+        assert!(crate::ssl::dns_names(&fallback.leaf).contains(&SELF_SIGNED_NAME.to_string()));
+        // Handshakes without SNI get it too
+        assert!(Arc::ptr_eq(fallback, store.resolve(None).unwrap()));
+
+        // The same certificate survives a reload
+        let reloaded = CertStore::load(Arc::new(SslCertManager::build(&make_app_config(vec![]))));
+        assert!(Arc::ptr_eq(fallback, reloaded.resolve(None).unwrap()));
+    }
+
+    #[test]
+    fn test_known_sni_not_replaced_by_fallback() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-fallback-known");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["known.com"], 30);
+        let host = custom_ssl_host(1, &["known.com"], cert.to_str().unwrap(), key.to_str().unwrap());
+        let store = CertStore::load(Arc::new(SslCertManager::build(&make_app_config(vec![host]))));
+
+        let served = store.resolve(Some("known.com")).unwrap();
+        assert!(Arc::ptr_eq(served, store.lookup("known.com").unwrap()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_configured_default_cert_used_for_unknown_sni() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-fallback-configured");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["default.example.com"], 30);
+        let mut config = make_app_config(vec![]);
+        config.global.default_cert.cert_path = Some(cert.to_str().unwrap().to_string());
+        config.global.default_cert.key_path = Some(key.to_str().unwrap().to_string());
+        let store = CertStore::load(Arc::new(SslCertManager::build(&config)));
+
+        let served = store.resolve(Some("unknown.example.com")).unwrap();
+        assert_eq!(crate::ssl::dns_names(&served.leaf), vec!["default.example.com"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_invalid_default_cert_falls_back_to_self_signed() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-fallback-invalid");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, _) = test_certs::write_self_signed(&dir.join("a"), &["a.com"], 30);
+        let (_, other_key) = test_certs::write_self_signed(&dir.join("b"), &["b.com"], 30);
+        let mut config = make_app_config(vec![]);
+        config.global.default_cert.cert_path = Some(cert.to_str().unwrap().to_string());
+        config.global.default_cert.key_path = Some(other_key.to_str().unwrap().to_string());
+        let manager = Arc::new(SslCertManager::build(&config));
+        assert!(manager.default_cert().is_none());
+
+        let store = CertStore::load(manager);
+        let served = store.resolve(Some("x.com")).unwrap();
+        assert_eq!(crate::ssl::dns_names(&served.leaf), vec![SELF_SIGNED_NAME]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reject_mode_has_no_fallback() {
+        let dir = std::env::temp_dir().join("pingora-test-tls-fallback-reject");
+        let _ = fs::remove_dir_all(&dir);
+        let (cert, key) = test_certs::write_self_signed(&dir, &["only.com"], 30);
+        let host = custom_ssl_host(1, &["only.com"], cert.to_str().unwrap(), key.to_str().unwrap());
+        let mut config = make_app_config(vec![host]);
+        config.global.default_cert.unknown_sni = "reject".to_string();
+        let store = CertStore::load(Arc::new(SslCertManager::build(&config)));
+
+        assert!(store.resolve(Some("only.com")).is_some());
+        assert!(store.resolve(Some("unknown.com")).is_none());
+        assert!(store.resolve(None).is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // ─── Per-host TLS policy ────────────────────────────────
+
+    #[test]
+    fn test_parse_server_name() {
+        let name = b"api.example.com";
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        ext.push(0);
+        ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        ext.extend_from_slice(name);
+        assert_eq!(parse_server_name(&ext).as_deref(), Some("api.example.com"));
+
+        assert_eq!(parse_server_name(&[]), None);
+        // Truncated name
+        assert_eq!(parse_server_name(&ext[..ext.len() - 1]), None);
+        // Non host_name entries are skipped
+        let other = [0x00, 0x04, 0x05, 0x00, 0x01, b'x'];
+        assert_eq!(parse_server_name(&other), None);
+    }
+
+    fn policy_ssl_host(id: u64, domain: &str, min: Option<&str>, max: Option<&str>, http2: bool) -> HostConfig {
+        let mut host = custom_ssl_host(id, &[domain], "/nonexistent/cert.pem", "/nonexistent/key.pem");
+        let ssl = host.ssl.as_mut().unwrap();
+        ssl.min_version = min.map(str::to_string);
+        ssl.max_version = max.map(str::to_string);
+        host.http2 = http2;
+        host
+    }
+
+    /// Run a real handshake over a socket pair against the policy callbacks.
+    /// Returns the negotiated version and ALPN protocol, or the client error.
+    fn handshake(
+        hosts: Vec<HostConfig>,
+        sni: &str,
+        client_max: Option<SslVersion>,
+        alpn: &[u8],
+    ) -> Result<(SslVersion, Option<Vec<u8>>), String> {
+        handshake_config(make_app_config(hosts), sni, client_max, alpn, None).map(|(version, alpn, _)| (version, alpn))
+    }
+
+    /// Server side of the test handshakes: a self-signed certificate for `sni` and the
+    /// listener's policy and ALPN callbacks
+    fn test_acceptor(config: &AppConfig, sni: &str) -> openssl::ssl::SslAcceptor {
+        use openssl::ssl::{SslAcceptor, SslMethod};
+
+        let manager = Arc::new(SslCertManager::build(config));
+        let store: SharedCertStore = Arc::new(arc_swap::ArcSwap::from_pointee(CertStore::load(manager)));
+        let challenges = Arc::new(ChallengeStore::default());
+        let cert = LoadedCert::self_signed(&[sni], 1).unwrap();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert.leaf).unwrap();
+        acceptor.set_private_key(&cert.key).unwrap();
+        configure_acceptor(&mut acceptor, store, challenges).unwrap();
+        acceptor.build()
+    }
+
+    /// Like `handshake`, with a full config and an optional client certificate.
+    /// Also reports whether the server received a peer certificate.
+    fn handshake_config(
+        config: AppConfig,
+        sni: &str,
+        client_max: Option<SslVersion>,
+        alpn: &[u8],
+        client_cert: Option<&LoadedCert>,
+    ) -> Result<(SslVersion, Option<Vec<u8>>, bool), String> {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use std::os::unix::net::UnixStream;
+
+        let acceptor = test_acceptor(&config, sni);
+
+        let (client_sock, server_sock) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            acceptor
+                .accept(server_sock)
+                .map(|stream| stream.ssl().peer_certificate().is_some())
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_max_proto_version(client_max).unwrap();
+        connector.set_alpn_protos(alpn).unwrap();
+        if let Some(client) = client_cert {
+            connector.set_certificate(&client.leaf).unwrap();
+            connector.set_private_key(&client.key).unwrap();
+        }
+        let result = connector
+            .build()
+            .configure()
+            .unwrap()
+            .connect(sni, client_sock)
+            .map(|stream| {
+                (
+                    stream.ssl().version2().unwrap(),
+                    stream.ssl().selected_alpn_protocol().map(<[u8]>::to_vec),
+                )
+            })
+            .map_err(|e| e.to_string());
+        let peer_cert = matches!(server.join(), Ok(Ok(true)));
+        result.map(|(version, alpn)| (version, alpn, peer_cert))
+    }
+
+    #[test]
+    fn test_policy_min_version_rejects_older_client() {
+        let hosts = vec![policy_ssl_host(1, "hard.com", Some("TLSv1.3"), None, false)];
+        assert!(handshake(hosts.clone(), "hard.com", Some(SslVersion::TLS1_2), b"").is_err());
+        let (version, _) = handshake(hosts.clone(), "hard.com", None, b"").unwrap();
+        assert_eq!(version, SslVersion::TLS1_3);
+        // Other names keep the listener defaults
+        let (version, _) = handshake(hosts, "other.com", Some(SslVersion::TLS1_2), b"").unwrap();
+        assert_eq!(version, SslVersion::TLS1_2);
+    }
+
+    #[test]
+    fn test_policy_max_version_caps_negotiation() {
+        let hosts = vec![policy_ssl_host(1, "legacy.com", None, Some("TLSv1.2"), false)];
+        let (version, _) = handshake(hosts, "legacy.com", None, b"").unwrap();
+        assert_eq!(version, SslVersion::TLS1_2);
+    }
+
+    #[test]
+    fn test_alpn_h2_only_for_http2_hosts() {
+        let offer = b"\x02h2\x08http/1.1";
+        let hosts = vec![
+            policy_ssl_host(1, "h2.com", None, None, true),
+            policy_ssl_host(2, "h1.com", None, None, false),
+        ];
+        let (_, alpn) = handshake(hosts.clone(), "h2.com", None, offer).unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+        let (_, alpn) = handshake(hosts.clone(), "h1.com", None, offer).unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+        let (_, alpn) = handshake(hosts, "unknown.com", None, offer).unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+    }
+
+    /// admin.com requires a client certificate (access list 7), public.com does not
+    fn mtls_config() -> AppConfig {
+        let mut mtls = policy_ssl_host(1, "admin.com", None, None, false);
+        mtls.locations = vec![serde_json::from_str(r#"{"path": "/", "accessListId": 7}"#).unwrap()];
+        let plain = policy_ssl_host(2, "public.com", None, None, false);
+        let mut config = make_app_config(vec![mtls, plain]);
+        config.access_lists.insert(
+            7,
+            serde_json::from_str(r#"{"id": 7, "clientCert": {"caBundle": "/nonexistent/ca.pem"}}"#).unwrap(),
+        );
+        config
+    }
+
+    #[test]
+    fn test_client_cert_requested_only_for_mtls_hosts() {
+        let ca = test_certs::ca("Test Client CA");
+        let client = test_certs::issue(&ca, "alice", &["alice@example.com"]);
+        let config = mtls_config();
+
+        let (_, _, peer_cert) = handshake_config(config.clone(), "admin.com", None, b"", Some(&client)).unwrap();
+        assert!(peer_cert, "mTLS host should request and receive the client certificate");
+        let (_, _, peer_cert) = handshake_config(config, "public.com", None, b"", Some(&client)).unwrap();
+        assert!(!peer_cert, "other hosts must not request a client certificate");
+    }
+
+    #[test]
+    fn test_mtls_host_resumes_session() {
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use std::os::unix::net::UnixStream;
+
+        let ca = test_certs::ca("Test Client CA");
+        let client = test_certs::issue(&ca, "alice", &["alice@example.com"]);
+        let acceptor = Arc::new(test_acceptor(&mtls_config(), "admin.com"));
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        // TLS 1.2 hands the client its session during the handshake
+        connector.set_max_proto_version(Some(SslVersion::TLS1_2)).unwrap();
+        connector.set_certificate(&client.leaf).unwrap();
+        connector.set_private_key(&client.key).unwrap();
+        let connector = connector.build();
+
+        let connect = |session: Option<&openssl::ssl::SslSessionRef>| {
+            let (client_sock, server_sock) = UnixStream::pair().unwrap();
+            let acceptor = Arc::clone(&acceptor);
+            let server = std::thread::spawn(move || {
+                acceptor
+                    .accept(server_sock)
+                    .map(|stream| stream.ssl().peer_certificate().is_some())
+                    .map_err(|e| e.to_string())
+            });
+            let mut ssl = connector.configure().unwrap();
+            if let Some(session) = session {
+                // SAFETY: the session comes from a connector on the same SslContext
+                unsafe { ssl.set_session(session).unwrap() };
+            }
+            let stream = ssl.connect("admin.com", client_sock).map_err(|e| e.to_string());
+            (stream, server.join().unwrap())
+        };
+
+        let (first, server) = connect(None);
+        let first = first.unwrap();
+        assert!(server.unwrap());
+        assert!(!first.ssl().session_reused());
+        let session = first.ssl().session().unwrap().to_owned();
+
+        let (second, server) = connect(Some(&session));
+        let second = second.expect("resuming a session on an mTLS host must not fail the handshake");
+        assert!(second.ssl().session_reused());
+        assert!(server.unwrap(), "the resumed session keeps the client certificate");
+    }
+}
@@ -0,0 +1,143 @@
+/// Radix tree keyed by byte strings, answering "which keys are prefixes of this path"
+/// in time proportional to the path length rather than the number of keys.
+#[derive(Debug)]
+pub struct PrefixTrie<T> {
+    root: Node<T>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    /// Bytes on the edge leading to this node
+    label: Vec<u8>,
+    value: Option<T>,
+    /// Children, each starting with a different byte
+    children: Vec<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new(label: &[u8], value: Option<T>) -> Self {
+        Node {
+            label: label.to_vec(),
+            value,
+            children: Vec::new(),
+        }
+    }
+
+    fn child_index(&self, first: u8) -> Option<usize> {
+        self.children.iter().position(|c| c.label[0] == first)
+    }
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        PrefixTrie {
+            root: Node::new(b"", None),
+        }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    /// Value stored under `key`, inserting `init()` first if there is none
+    pub fn get_or_insert_with(&mut self, key: &[u8], init: impl FnOnce() -> T) -> &mut T {
+        let mut node = &mut self.root;
+        let mut key = key;
+        while !key.is_empty() {
+            let idx = match node.child_index(key[0]) {
+                Some(idx) => idx,
+                None => {
+                    node.children.push(Node::new(key, None));
+                    node = node.children.last_mut().unwrap();
+                    break;
+                }
+            };
+            let common = common_prefix(&node.children[idx].label, key);
+            if common < node.children[idx].label.len() {
+                // Split the edge: the shared part becomes a new node above the old child
+                let mut old = std::mem::replace(&mut node.children[idx], Node::new(&key[..common], None));
+                old.label.drain(..common);
+                node.children[idx].children.push(old);
+            }
+            node = &mut node.children[idx];
+            key = &key[common..];
+        }
+        node.value.get_or_insert_with(init)
+    }
+
+    /// Values whose keys are prefixes of `path`, longest key first
+    pub fn prefixes_of(&self, path: &[u8]) -> Vec<&T> {
+        let mut found = Vec::new();
+        let mut node = &self.root;
+        let mut rest = path;
+        loop {
+            if let Some(ref value) = node.value {
+                found.push(value);
+            }
+            let child = match rest.first().and_then(|b| node.child_index(*b)) {
+                Some(idx) => &node.children[idx],
+                None => break,
+            };
+            if !rest.starts_with(&child.label) {
+                break;
+            }
+            rest = &rest[child.label.len()..];
+            node = child;
+        }
+        found.reverse();
+        found
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(keys: &[&str]) -> PrefixTrie<String> {
+        let mut t = PrefixTrie::default();
+        for key in keys {
+            t.get_or_insert_with(key.as_bytes(), || key.to_string());
+        }
+        t
+    }
+
+    fn prefixes(t: &PrefixTrie<String>, path: &str) -> Vec<String> {
+        t.prefixes_of(path.as_bytes()).into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_prefixes_longest_first() {
+        let t = trie(&["/", "/api", "/api/v1", "/apiary", "/static"]);
+        assert_eq!(prefixes(&t, "/api/v1/users"), vec!["/api/v1", "/api", "/"]);
+        assert_eq!(prefixes(&t, "/apiary/bees"), vec!["/apiary", "/api", "/"]);
+        assert_eq!(prefixes(&t, "/ap"), vec!["/"]);
+        assert_eq!(prefixes(&t, "/static"), vec!["/static", "/"]);
+        assert_eq!(prefixes(&t, "other"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_edge_split_keeps_existing_values() {
+        // Inserting a shorter key splits the "/abcdef" edge
+        let t = trie(&["/abcdef", "/abc", "/abx"]);
+        assert_eq!(prefixes(&t, "/abcdefg"), vec!["/abcdef", "/abc"]);
+        assert_eq!(prefixes(&t, "/abxyz"), vec!["/abx"]);
+        assert_eq!(prefixes(&t, "/ab"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_empty_key_matches_everything() {
+        let t = trie(&["", "/a"]);
+        assert_eq!(prefixes(&t, "/a/b"), vec!["/a", ""]);
+        assert_eq!(prefixes(&t, ""), vec![""]);
+    }
+
+    #[test]
+    fn test_get_or_insert_with_returns_existing() {
+        let mut t: PrefixTrie<Vec<usize>> = PrefixTrie::default();
+        t.get_or_insert_with(b"/api", Vec::new).push(1);
+        t.get_or_insert_with(b"/api", Vec::new).push(2);
+        assert_eq!(t.prefixes_of(b"/api/x"), vec![&vec![1, 2]]);
+    }
+}
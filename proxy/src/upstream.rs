@@ -1,9 +1,21 @@
-use crate::config::UpstreamConfig;
+use crate::config::{LocationConfig, UpstreamConfig};
+use crate::ssl::LoadedCert;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use openssl::x509::X509;
+use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::utils::tls::CertKey;
+use pingora_load_balancing::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck};
 use pingora_load_balancing::selection::{Consistent, Random, RoundRobin};
 use pingora_load_balancing::{discovery, Backend, Backends, LoadBalancer};
-use std::collections::BTreeSet;
-use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// How often the health check runner looks for checks that are due
+const HEALTH_CHECK_TICK: Duration = Duration::from_secs(1);
 
 /// Enum wrapping different load balancer selection algorithms
 pub enum UpstreamSelector {
@@ -22,6 +34,337 @@ impl UpstreamSelector {
             UpstreamSelector::Random(lb) => lb.select(key, 256),
         }
     }
+
+    /// Like `select`, skipping backends `accept` refuses
+    pub fn select_with(&self, key: &[u8], accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let accept = |b: &Backend, healthy: bool| healthy && accept(b);
+        match self {
+            UpstreamSelector::RoundRobin(lb) => lb.select_with(key, 256, accept),
+            UpstreamSelector::Consistent(lb) => lb.select_with(key, 256, accept),
+            UpstreamSelector::Random(lb) => lb.select_with(key, 256, accept),
+        }
+    }
+
+    /// Select a backend that is not ejected by `outliers`. When every backend is
+    /// ejected, ejection is ignored rather than failing all requests.
+    pub fn select_admitted(&self, key: &[u8], outliers: Option<&OutlierDetector>) -> Option<Backend> {
+        match outliers {
+            Some(outliers) => {
+                let now = Instant::now();
+                self.select_with(key, |b| match b.addr.as_inet() {
+                    Some(addr) => !outliers.is_ejected(addr, now),
+                    None => true,
+                })
+                .or_else(|| self.select(key))
+            }
+            None => self.select(key),
+        }
+    }
+
+    /// How often the active health check runs; None without one
+    pub fn health_check_interval(&self) -> Option<Duration> {
+        match self {
+            UpstreamSelector::RoundRobin(lb) => lb.health_check_frequency,
+            UpstreamSelector::Consistent(lb) => lb.health_check_frequency,
+            UpstreamSelector::Random(lb) => lb.health_check_frequency,
+        }
+    }
+
+    /// Check every backend once, updating the health `select` skips unhealthy ones by
+    pub async fn run_health_check(&self) {
+        match self {
+            UpstreamSelector::RoundRobin(lb) => lb.backends().run_health_check(true).await,
+            UpstreamSelector::Consistent(lb) => lb.backends().run_health_check(true).await,
+            UpstreamSelector::Random(lb) => lb.backends().run_health_check(true).await,
+        }
+    }
+
+    /// Backends the active health check currently marks as unhealthy
+    pub fn unhealthy_backends(&self) -> HashSet<SocketAddr> {
+        let backends = match self {
+            UpstreamSelector::RoundRobin(lb) => lb.backends(),
+            UpstreamSelector::Consistent(lb) => lb.backends(),
+            UpstreamSelector::Random(lb) => lb.backends(),
+        };
+        backends
+            .get_backend()
+            .iter()
+            .filter(|b| !backends.ready(b))
+            .filter_map(|b| b.addr.as_inet().copied())
+            .collect()
+    }
+}
+
+/// Active health check of one location's upstreams
+pub struct ActiveHealthCheck {
+    check: Box<dyn HealthCheck + Send + Sync>,
+    interval: Duration,
+    label: String,
+    /// Backends that were unhealthy in the selector this one replaces
+    unhealthy: HashSet<SocketAddr>,
+}
+
+impl ActiveHealthCheck {
+    /// Health check configured for a location, `None` when it has none
+    pub fn from_location(
+        host_id: u64,
+        loc: &LocationConfig,
+        tls: Option<&UpstreamTls>,
+    ) -> Result<Option<ActiveHealthCheck>, String> {
+        let config = match loc.health_check {
+            Some(ref c) => c,
+            None => return Ok(None),
+        };
+        if config.rise == 0 || config.fall == 0 {
+            return Err("health check rise and fall must be at least 1".to_string());
+        }
+        let timeout = Some(Duration::from_secs(config.timeout_secs.max(1)));
+        let check: Box<dyn HealthCheck + Send + Sync> = match config.check_type.as_str() {
+            "tcp" => {
+                let mut check = TcpHealthCheck::new();
+                check.consecutive_success = config.rise;
+                check.consecutive_failure = config.fall;
+                check.peer_template.options.connection_timeout = timeout;
+                check
+            }
+            "http" => {
+                if !config.path.starts_with('/') {
+                    return Err(format!("health check path '{}' must start with '/'", config.path));
+                }
+                let host = match config.host {
+                    Some(ref host) => host.clone(),
+                    None => loc.upstreams.first().map(|u| u.server.clone()).unwrap_or_default(),
+                };
+                let mut check = HttpHealthCheck::new(&host, tls.is_some());
+                check.consecutive_success = config.rise;
+                check.consecutive_failure = config.fall;
+                check.req.set_uri(
+                    config
+                        .path
+                        .parse()
+                        .map_err(|_| format!("invalid health check path '{}'", config.path))?,
+                );
+                check.peer_template.options.connection_timeout = timeout;
+                check.peer_template.options.read_timeout = timeout;
+                if let Some(tls) = tls {
+                    tls.configure(&mut check.peer_template);
+                    // IP addresses are not valid SNI
+                    check.peer_template.sni = match tls.sni {
+                        Some(ref sni) => sni.clone(),
+                        None if host.parse::<IpAddr>().is_ok() => String::new(),
+                        None => host,
+                    };
+                }
+                let expected = config.expected_status;
+                check.validator = Some(Box::new(move |resp| {
+                    if resp.status.as_u16() == expected {
+                        Ok(())
+                    } else {
+                        pingora_core::Error::e_explain(
+                            pingora_core::ErrorType::CustomCode("unexpected status", resp.status.as_u16()),
+                            format!("expected {}", expected),
+                        )
+                    }
+                }));
+                Box::new(check)
+            }
+            other => return Err(format!("unknown health check type '{}'", other)),
+        };
+        let label = format!("Host {} location {}", host_id, loc.path);
+        Ok(Some(ActiveHealthCheck {
+            check: Box::new(LabeledCheck {
+                check,
+                label: label.clone(),
+            }),
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            label,
+            unhealthy: HashSet::new(),
+        }))
+    }
+
+    /// Start the backends `previous` marks as unhealthy as unhealthy, so a reload
+    /// does not send traffic to them until they pass `rise` checks again
+    pub fn carry_over(&mut self, previous: &UpstreamSelector) {
+        self.unhealthy = previous.unhealthy_backends();
+    }
+}
+
+/// Passive outlier ejection of one location's upstreams, fed with the outcome of
+/// proxied requests
+pub struct OutlierDetector {
+    max_fails: u32,
+    fail_timeout: Duration,
+    max_ejection: Duration,
+    label: String,
+    backends: DashMap<SocketAddr, OutlierState>,
+}
+
+#[derive(Clone, Default, PartialEq)]
+struct OutlierState {
+    consecutive_failures: u32,
+    /// Ejections since the last successful request
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierDetector {
+    /// Outlier detection configured for a location, `None` when it has none
+    pub fn from_location(host_id: u64, loc: &LocationConfig) -> Option<OutlierDetector> {
+        let config = loc.outlier_detection.as_ref().filter(|c| c.max_fails > 0)?;
+        let fail_timeout = Duration::from_secs(config.fail_timeout_secs.max(1));
+        Some(OutlierDetector {
+            max_fails: config.max_fails,
+            fail_timeout,
+            max_ejection: Duration::from_secs(config.max_ejection_secs).max(fail_timeout),
+            label: format!("Host {} location {}", host_id, loc.path),
+            backends: DashMap::new(),
+        })
+    }
+
+    /// Whether `addr` is ejected at `now`
+    pub fn is_ejected(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.backends
+            .get(addr)
+            .and_then(|s| s.ejected_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Take over the failure counts and ejections of the detector this one replaces
+    pub fn carry_over(&self, previous: &OutlierDetector) {
+        for entry in previous.backends.iter() {
+            self.backends.insert(*entry.key(), entry.value().clone());
+        }
+    }
+
+    /// Record the outcome of a request proxied to `addr`
+    pub fn report(&self, addr: SocketAddr, success: bool) {
+        self.report_at(addr, success, Instant::now());
+    }
+
+    fn report_at(&self, addr: SocketAddr, success: bool, now: Instant) {
+        // Most requests succeed on an upstream with nothing to reset; skip the write lock
+        if success && self.backends.get(&addr).is_none_or(|s| *s == OutlierState::default()) {
+            return;
+        }
+        let mut state = self.backends.entry(addr).or_default();
+        if state.ejected_until.is_some_and(|until| now < until) {
+            // Requests started before the ejection
+            return;
+        }
+        if success {
+            if state.ejections > 0 {
+                log::info!("{}: upstream {} recovered", self.label, addr);
+            }
+            *state = OutlierState::default();
+            return;
+        }
+        state.consecutive_failures += 1;
+        // A re-admitted upstream that fails again before any success is ejected at once
+        if state.ejections == 0 && state.consecutive_failures < self.max_fails {
+            return;
+        }
+        let ejection = self
+            .fail_timeout
+            .saturating_mul(1 << state.ejections.min(16))
+            .min(self.max_ejection);
+        state.ejections += 1;
+        state.consecutive_failures = 0;
+        state.ejected_until = Some(now + ejection);
+        log::warn!(
+            "{}: upstream {} ejected for {}s after failed requests",
+            self.label,
+            addr,
+            ejection.as_secs()
+        );
+    }
+}
+
+/// Names the location in the health change messages logged by pingora
+struct LabeledCheck {
+    check: Box<dyn HealthCheck + Send + Sync>,
+    label: String,
+}
+
+#[async_trait]
+impl HealthCheck for LabeledCheck {
+    async fn check(&self, target: &Backend) -> pingora_core::Result<()> {
+        self.check.check(target).await
+    }
+
+    fn backend_summary(&self, target: &Backend) -> String {
+        format!("{}: upstream {}", self.label, target.addr)
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.check.health_threshold(success)
+    }
+}
+
+/// Fails the backends that were unhealthy before a reload, once, to seed a new load balancer
+struct CarriedOver {
+    unhealthy: HashSet<SocketAddr>,
+    label: String,
+}
+
+#[async_trait]
+impl HealthCheck for CarriedOver {
+    async fn check(&self, target: &Backend) -> pingora_core::Result<()> {
+        match target.addr.as_inet() {
+            Some(addr) if self.unhealthy.contains(addr) => {
+                pingora_core::Error::e_explain(pingora_core::ErrorType::ConnectError, "unhealthy before reload")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn backend_summary(&self, target: &Backend) -> String {
+        format!("{}: upstream {}", self.label, target.addr)
+    }
+
+    fn health_threshold(&self, _success: bool) -> usize {
+        1
+    }
+}
+
+/// Run the active health checks of the selectors returned by `current` in the background,
+/// each at its own interval. Selectors replaced by a reload are dropped from the schedule
+/// and new ones are checked right away.
+pub fn spawn_health_checks<F>(current: F)
+where
+    F: Fn() -> Vec<Arc<UpstreamSelector>> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            // Next run by selector; the Weak keeps the address from being reused
+            let mut schedule: HashMap<*const UpstreamSelector, (Weak<UpstreamSelector>, Instant)> =
+                HashMap::new();
+            loop {
+                let now = Instant::now();
+                let mut due = Vec::new();
+                for selector in current() {
+                    let interval = match selector.health_check_interval() {
+                        Some(i) => i,
+                        None => continue,
+                    };
+                    let entry = schedule
+                        .entry(Arc::as_ptr(&selector))
+                        .or_insert_with(|| (Arc::downgrade(&selector), now));
+                    if entry.1 <= now {
+                        entry.1 = now + interval;
+                        due.push(selector);
+                    }
+                }
+                futures::future::join_all(due.iter().map(|s| s.run_health_check())).await;
+                drop(due);
+                schedule.retain(|_, (selector, _)| selector.strong_count() > 0);
+                tokio::time::sleep(HEALTH_CHECK_TICK).await;
+            }
+        });
+    });
 }
 
 /// Create a load balancer from upstream configs and the specified method.
@@ -33,6 +376,7 @@ impl UpstreamSelector {
 pub fn create_upstream_selector(
     upstreams: &[UpstreamConfig],
     method: &str,
+    health_check: Option<ActiveHealthCheck>,
 ) -> Option<UpstreamSelector> {
     if upstreams.is_empty() {
         return None;
@@ -40,16 +384,16 @@ pub fn create_upstream_selector(
 
     match method {
         "ip_hash" => {
-            let lb = create_lb_from_upstreams::<Consistent>(upstreams)?;
+            let lb = create_lb_from_upstreams::<Consistent>(upstreams, health_check)?;
             Some(UpstreamSelector::Consistent(Arc::new(lb)))
         }
         "random" => {
-            let lb = create_lb_from_upstreams::<Random>(upstreams)?;
+            let lb = create_lb_from_upstreams::<Random>(upstreams, health_check)?;
             Some(UpstreamSelector::Random(Arc::new(lb)))
         }
         _ => {
             // round_robin, weighted, least_connections all use RoundRobin
-            let lb = create_lb_from_upstreams::<RoundRobin>(upstreams)?;
+            let lb = create_lb_from_upstreams::<RoundRobin>(upstreams, health_check)?;
             Some(UpstreamSelector::RoundRobin(Arc::new(lb)))
         }
     }
@@ -57,7 +401,10 @@ pub fn create_upstream_selector(
 
 /// Create a LoadBalancer with weighted backends from upstream configs.
 /// Uses Static discovery with manually constructed backends that have proper weights.
-fn create_lb_from_upstreams<S>(upstreams: &[UpstreamConfig]) -> Option<LoadBalancer<S>>
+fn create_lb_from_upstreams<S>(
+    upstreams: &[UpstreamConfig],
+    health_check: Option<ActiveHealthCheck>,
+) -> Option<LoadBalancer<S>>
 where
     S: pingora_load_balancing::selection::BackendSelection + 'static,
     S::Iter: pingora_load_balancing::selection::BackendIter,
@@ -82,7 +429,25 @@ where
         };
         let weight = upstream.weight.min(1000);
         match Backend::new_with_weight(&resolved, weight) {
-            Ok(backend) => {
+            Ok(mut backend) => {
+                let server = upstream.server.trim_start_matches('[').trim_end_matches(']');
+                if server.parse::<IpAddr>().is_err() {
+                    backend.ext.insert(ServerName(server.to_string()));
+                }
+                // Backends are told apart by address and weight only
+                if let Some(existing) = backend_set.get(&backend) {
+                    let name = |b: &Backend| b.ext.get::<ServerName>().map(|n| n.0.clone()).unwrap_or_default();
+                    if name(existing) != name(&backend) {
+                        log::warn!(
+                            "Upstreams {} and {} both resolve to {}; only {} is used as server name",
+                            name(existing),
+                            addr_str,
+                            resolved,
+                            name(existing)
+                        );
+                    }
+                    continue;
+                }
                 backend_set.insert(backend);
             }
             Err(e) => {
@@ -97,20 +462,129 @@ where
 
     let disc = discovery::Static::new(backend_set);
     let backends = Backends::new(disc);
-    let lb = LoadBalancer::from_backends(backends);
+    let mut lb = LoadBalancer::from_backends(backends);
 
     // Run the initial discovery update synchronously.
     // Since Static discovery is non-blocking, now_or_never is safe.
     use futures::FutureExt;
     let _ = lb.update().now_or_never();
 
+    if let Some(hc) = health_check {
+        // Backends start healthy; fail the ones that were not before the reload.
+        // The check does no I/O and runs sequentially, so now_or_never completes it.
+        if !hc.unhealthy.is_empty() {
+            lb.set_health_check(Box::new(CarriedOver {
+                unhealthy: hc.unhealthy,
+                label: hc.label,
+            }));
+            let _ = lb.backends().run_health_check(false).now_or_never();
+        }
+        lb.set_health_check(hc.check);
+        lb.health_check_frequency = Some(hc.interval);
+    }
+
     Some(lb)
 }
 
+/// Server name an upstream was configured with, kept on its `Backend` as the default SNI
+#[derive(Clone)]
+pub struct ServerName(pub String);
+
+/// TLS settings for one location's upstream connections, loaded with the config
+pub struct UpstreamTls {
+    /// SNI override (`upstream_tls.sni`)
+    sni: Option<String>,
+    verify: bool,
+    /// Trust roots replacing the system store
+    ca: Option<Arc<Box<[X509]>>>,
+    client_cert: Option<Arc<CertKey>>,
+    /// Keeps pooled connections apart from locations trusting other roots
+    group_key: u64,
+}
+
+impl UpstreamTls {
+    /// TLS settings for a proxy location, `None` when it talks plain HTTP
+    pub fn from_location(loc: &LocationConfig) -> Result<Option<UpstreamTls>, String> {
+        match loc.upstream_scheme.as_deref().unwrap_or("http") {
+            "http" => return Ok(None),
+            "https" => {}
+            other => return Err(format!("unknown upstream_scheme '{}'", other)),
+        }
+        let config = loc.upstream_tls.clone().unwrap_or_default();
+
+        let ca = match config.ca_bundle.as_deref() {
+            Some(path) => Some(Arc::new(load_ca_bundle(path)?)),
+            None => None,
+        };
+        let client_cert = match (&config.client_cert_path, &config.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let loaded = LoadedCert::from_files(cert_path, key_path)?;
+                let mut chain = vec![loaded.leaf];
+                chain.extend(loaded.chain);
+                Some(Arc::new(CertKey::new(chain, loaded.key)))
+            }
+            (None, None) => None,
+            _ => return Err("client certificate needs both client_cert_path and client_key_path".to_string()),
+        };
+
+        // No SNI means no verification (as in nginx), so say so
+        if config.verify && config.sni.is_none() {
+            for upstream in &loc.upstreams {
+                let server = upstream.server.trim_start_matches('[').trim_end_matches(']');
+                if server.parse::<IpAddr>().is_ok() {
+                    log::warn!(
+                        "Location {}: certificate of IP upstream {} is not verified without upstream_tls.sni",
+                        loc.path,
+                        server
+                    );
+                }
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        config.ca_bundle.hash(&mut hasher);
+        Ok(Some(UpstreamTls {
+            sni: config.sni,
+            verify: config.verify,
+            ca,
+            client_cert,
+            group_key: hasher.finish(),
+        }))
+    }
+
+    /// SNI (and verified name) for a connection to `backend`: the override, else the
+    /// server name the upstream was configured with; empty for IP upstreams
+    pub fn sni_for(&self, backend: &Backend) -> String {
+        match &self.sni {
+            Some(sni) => sni.clone(),
+            None => backend.ext.get::<ServerName>().map(|n| n.0.clone()).unwrap_or_default(),
+        }
+    }
+
+    /// Apply verification, trust roots and the client certificate to a TLS peer
+    pub fn configure(&self, peer: &mut HttpPeer) {
+        peer.options.verify_cert = self.verify;
+        peer.options.verify_hostname = self.verify;
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert.clone();
+        peer.group_key = self.group_key;
+    }
+}
+
+fn load_ca_bundle(path: &str) -> Result<Box<[X509]>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|e| format!("invalid CA bundle {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in CA bundle {}", path));
+    }
+    Ok(certs.into_boxed_slice())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::UpstreamConfig;
+    use crate::config::{UpstreamConfig, UpstreamTlsConfig};
+    use crate::ssl::test_certs;
 
     fn upstream(server: &str, port: u16, weight: usize) -> UpstreamConfig {
         UpstreamConfig {
@@ -120,12 +594,416 @@ mod tests {
         }
     }
 
+    // ─── UpstreamTls ────────────────────────────────────────
+
+    fn tls_location(servers: &[(&str, u16)], tls: Option<UpstreamTlsConfig>) -> LocationConfig {
+        let mut loc: LocationConfig = serde_yaml::from_str("path: /\nupstream_scheme: https").unwrap();
+        loc.upstreams = servers.iter().map(|(s, p)| upstream(s, *p, 1)).collect();
+        loc.upstream_tls = tls;
+        loc
+    }
+
+    #[test]
+    fn test_upstream_tls_only_for_https() {
+        let mut loc = tls_location(&[("127.0.0.1", 8080)], None);
+        loc.upstream_scheme = None;
+        assert!(UpstreamTls::from_location(&loc).unwrap().is_none());
+        loc.upstream_scheme = Some("http".to_string());
+        assert!(UpstreamTls::from_location(&loc).unwrap().is_none());
+        loc.upstream_scheme = Some("ftp".to_string());
+        let err = UpstreamTls::from_location(&loc).err().unwrap();
+        assert!(err.contains("ftp"));
+    }
+
+    /// Backends of a location by address
+    fn backends_of(loc: &LocationConfig) -> HashMap<String, Backend> {
+        let lb = create_lb_from_upstreams::<RoundRobin>(&loc.upstreams, None).unwrap();
+        lb.backends().get_backend().iter().map(|b| (b.addr.to_string(), b.clone())).collect()
+    }
+
+    #[test]
+    fn test_upstream_tls_default_sni_is_server_name() {
+        let loc = tls_location(&[("localhost", 8443), ("127.0.0.2", 8443)], None);
+        let tls = UpstreamTls::from_location(&loc).unwrap().unwrap();
+        let backends = backends_of(&loc);
+        assert_eq!(tls.sni_for(&backends["127.0.0.1:8443"]), "localhost");
+        // IP upstreams get no SNI
+        assert_eq!(tls.sni_for(&backends["127.0.0.2:8443"]), "");
+    }
+
+    #[test]
+    fn test_upstream_tls_sni_is_per_backend() {
+        // An upstream given by IP does not borrow the name of another on the same address
+        let mut loc = tls_location(&[("127.0.0.1", 8443), ("localhost", 8443)], None);
+        loc.upstreams[1].weight = 2;
+        let tls = UpstreamTls::from_location(&loc).unwrap().unwrap();
+        let lb = create_lb_from_upstreams::<RoundRobin>(&loc.upstreams, None).unwrap();
+        let mut names: Vec<String> = lb.backends().get_backend().iter().map(|b| tls.sni_for(b)).collect();
+        names.sort();
+        assert_eq!(names, vec!["".to_string(), "localhost".to_string()]);
+    }
+
+    #[test]
+    fn test_upstream_tls_sni_override() {
+        let config = UpstreamTlsConfig {
+            sni: Some("backend.internal".to_string()),
+            ..UpstreamTlsConfig::default()
+        };
+        let loc = tls_location(&[("127.0.0.2", 8443)], Some(config));
+        let tls = UpstreamTls::from_location(&loc).unwrap().unwrap();
+        assert_eq!(tls.sni_for(&backends_of(&loc)["127.0.0.2:8443"]), "backend.internal");
+    }
+
+    #[test]
+    fn test_upstream_tls_configures_peer() {
+        let dir = std::env::temp_dir().join("pingora-test-upstream-tls");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = test_certs::ca("Upstream CA");
+        let client = test_certs::issue(&ca, "proxy", &["proxy.internal"]);
+        let (ca_path, cert_path, key_path) = (dir.join("ca.pem"), dir.join("client.pem"), dir.join("client.key"));
+        std::fs::write(&ca_path, ca.leaf.to_pem().unwrap()).unwrap();
+        std::fs::write(&cert_path, client.leaf.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, client.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let config = UpstreamTlsConfig {
+            sni: None,
+            verify: false,
+            ca_bundle: Some(ca_path.to_string_lossy().to_string()),
+            client_cert_path: Some(cert_path.to_string_lossy().to_string()),
+            client_key_path: Some(key_path.to_string_lossy().to_string()),
+        };
+        let tls = UpstreamTls::from_location(&tls_location(&[("127.0.0.1", 8443)], Some(config)))
+            .unwrap()
+            .unwrap();
+        let mut peer = HttpPeer::new("127.0.0.1:8443", true, String::new());
+        tls.configure(&mut peer);
+        assert!(!peer.options.verify_cert);
+        assert!(!peer.options.verify_hostname);
+        assert_eq!(peer.options.ca.as_ref().unwrap().len(), 1);
+        assert_eq!(peer.client_cert_key.as_ref().unwrap().leaf(), &client.leaf);
+        // Connections trusting a different CA are not pooled together
+        assert_ne!(peer.group_key, 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_upstream_tls_rejects_bad_material() {
+        let missing_ca = UpstreamTlsConfig {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..UpstreamTlsConfig::default()
+        };
+        let err = UpstreamTls::from_location(&tls_location(&[("127.0.0.1", 1)], Some(missing_ca)))
+            .err()
+            .unwrap();
+        assert!(err.contains("/nonexistent/ca.pem"));
+
+        let half_pair = UpstreamTlsConfig {
+            client_cert_path: Some("/data/ssl/proxy.pem".to_string()),
+            ..UpstreamTlsConfig::default()
+        };
+        let err = UpstreamTls::from_location(&tls_location(&[("127.0.0.1", 1)], Some(half_pair)))
+            .err()
+            .unwrap();
+        assert!(err.contains("client_key_path"));
+    }
+
+    // ─── Active health checks ───────────────────────────────
+
+    fn checked_location(servers: &[(&str, u16)], health_check: &str) -> LocationConfig {
+        let yaml = format!("path: /api\nhealth_check: {}", health_check);
+        let mut loc: LocationConfig = serde_yaml::from_str(&yaml).unwrap();
+        loc.upstreams = servers.iter().map(|(s, p)| upstream(s, *p, 1)).collect();
+        loc
+    }
+
+    fn checked_selector(loc: &LocationConfig) -> UpstreamSelector {
+        let hc = ActiveHealthCheck::from_location(1, loc, None).unwrap();
+        create_upstream_selector(&loc.upstreams, "round_robin", hc).unwrap()
+    }
+
+    fn run_checks(sel: &UpstreamSelector, times: usize) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        for _ in 0..times {
+            rt.block_on(sel.run_health_check());
+        }
+    }
+
+    /// A port nothing listens on
+    fn closed_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// Answer every request with `status`, recording the request lines
+    fn status_server(status: u16) -> (u16, Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                seen.lock().unwrap().push(head);
+                let _ = write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+            }
+        });
+        (port, requests)
+    }
+
+    fn selected_ports(sel: &UpstreamSelector) -> BTreeSet<u16> {
+        (0..8u8)
+            .filter_map(|i| sel.select(&[i]))
+            .map(|b| b.addr.as_inet().unwrap().port())
+            .collect()
+    }
+
+    #[test]
+    fn test_no_health_check_configured() {
+        let loc = checked_location(&[("127.0.0.1", 8080)], "null");
+        assert!(ActiveHealthCheck::from_location(1, &loc, None).unwrap().is_none());
+        let sel = create_upstream_selector(&loc.upstreams, "round_robin", None).unwrap();
+        assert!(sel.health_check_interval().is_none());
+    }
+
+    #[test]
+    fn test_tcp_check_skips_dead_upstream_after_fall() {
+        let live = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let dead_port = closed_port();
+        let loc = checked_location(
+            &[("127.0.0.1", live_port), ("127.0.0.1", dead_port)],
+            "{type: tcp, intervalSecs: 7, fall: 2}",
+        );
+        let sel = checked_selector(&loc);
+        assert_eq!(sel.health_check_interval(), Some(Duration::from_secs(7)));
+        // Upstreams start healthy
+        assert_eq!(selected_ports(&sel), BTreeSet::from([live_port, dead_port]));
+        run_checks(&sel, 1);
+        assert_eq!(selected_ports(&sel), BTreeSet::from([live_port, dead_port]), "one failure is below fall");
+        run_checks(&sel, 1);
+        assert_eq!(selected_ports(&sel), BTreeSet::from([live_port]));
+    }
+
+    #[test]
+    fn test_all_upstreams_unhealthy_selects_none() {
+        let loc = checked_location(&[("127.0.0.1", closed_port())], "{fall: 1}");
+        let sel = checked_selector(&loc);
+        run_checks(&sel, 1);
+        assert!(sel.select(b"").is_none());
+    }
+
+    #[test]
+    fn test_http_check_expected_status() {
+        let (ok_port, requests) = status_server(204);
+        let (bad_port, _) = status_server(500);
+        let loc = checked_location(
+            &[("127.0.0.1", ok_port), ("127.0.0.1", bad_port)],
+            "{type: http, path: /healthz, host: api.internal, expectedStatus: 204, fall: 1}",
+        );
+        let sel = checked_selector(&loc);
+        run_checks(&sel, 1);
+        assert_eq!(selected_ports(&sel), BTreeSet::from([ok_port]));
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("GET /healthz HTTP/1.1"), "{}", requests[0]);
+        assert!(requests[0].to_ascii_lowercase().contains("host: api.internal"));
+    }
+
+    #[test]
+    fn test_unhealthy_upstream_recovers_after_rise() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let loc = checked_location(&[("127.0.0.1", port)], "{fall: 1, rise: 2}");
+        let sel = checked_selector(&loc);
+        run_checks(&sel, 1);
+        assert!(sel.select(b"").is_none());
+
+        let _listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        run_checks(&sel, 1);
+        assert!(sel.select(b"").is_none(), "one success is below rise");
+        run_checks(&sel, 1);
+        assert!(sel.select(b"").is_some());
+    }
+
+    #[test]
+    fn test_health_carried_over_to_new_selector() {
+        let live = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let dead_port = closed_port();
+        let loc = checked_location(
+            &[("127.0.0.1", live_port), ("127.0.0.1", dead_port)],
+            "{fall: 1, rise: 2}",
+        );
+        let old = checked_selector(&loc);
+        run_checks(&old, 1);
+        assert_eq!(old.unhealthy_backends(), HashSet::from([([127, 0, 0, 1], dead_port).into()]));
+
+        // Before any check has run, the replacement already skips the dead upstream
+        let mut hc = ActiveHealthCheck::from_location(1, &loc, None).unwrap().unwrap();
+        hc.carry_over(&old);
+        let new = create_upstream_selector(&loc.upstreams, "round_robin", Some(hc)).unwrap();
+        assert_eq!(selected_ports(&new), BTreeSet::from([live_port]));
+
+        // and it needs `rise` passing checks to come back
+        let _dead = std::net::TcpListener::bind(("127.0.0.1", dead_port)).unwrap();
+        run_checks(&new, 1);
+        assert_eq!(selected_ports(&new), BTreeSet::from([live_port]));
+        run_checks(&new, 1);
+        assert_eq!(selected_ports(&new), BTreeSet::from([live_port, dead_port]));
+    }
+
+    #[test]
+    fn test_health_check_config_errors() {
+        let servers = [("127.0.0.1", 8080)];
+        let loc = checked_location(&servers, "{type: icmp}");
+        let err = ActiveHealthCheck::from_location(1, &loc, None).err().unwrap();
+        assert!(err.contains("icmp"));
+        let loc = checked_location(&servers, "{rise: 0}");
+        assert!(ActiveHealthCheck::from_location(1, &loc, None).is_err());
+        let loc = checked_location(&servers, "{type: http, path: healthz}");
+        assert!(ActiveHealthCheck::from_location(1, &loc, None).is_err());
+    }
+
+    // ─── Outlier detection ──────────────────────────────────
+
+    fn detector(max_fails: u32, fail_timeout_secs: u64, max_ejection_secs: u64) -> OutlierDetector {
+        let yaml = format!(
+            "path: /\noutlier_detection: {{max_fails: {}, fail_timeout_secs: {}, max_ejection_secs: {}}}",
+            max_fails, fail_timeout_secs, max_ejection_secs
+        );
+        let loc: LocationConfig = serde_yaml::from_str(&yaml).unwrap();
+        OutlierDetector::from_location(1, &loc).unwrap()
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn test_outlier_detection_disabled() {
+        let loc: LocationConfig = serde_yaml::from_str("path: /").unwrap();
+        assert!(OutlierDetector::from_location(1, &loc).is_none());
+        let loc: LocationConfig = serde_yaml::from_str("path: /\noutlier_detection: {max_fails: 0}").unwrap();
+        assert!(OutlierDetector::from_location(1, &loc).is_none());
+    }
+
+    #[test]
+    fn test_ejected_after_max_fails() {
+        let od = detector(3, 10, 300);
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let t0 = Instant::now();
+        od.report_at(addr, false, t0);
+        od.report_at(addr, false, t0);
+        assert!(!od.is_ejected(&addr, t0));
+        od.report_at(addr, false, t0);
+        assert!(od.is_ejected(&addr, t0));
+        assert!(od.is_ejected(&addr, t0 + secs(9)));
+        assert!(!od.is_ejected(&addr, t0 + secs(10)));
+    }
+
+    #[test]
+    fn test_success_resets_failure_count() {
+        let od = detector(2, 10, 300);
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let t0 = Instant::now();
+        od.report_at(addr, false, t0);
+        od.report_at(addr, true, t0);
+        od.report_at(addr, false, t0);
+        assert!(!od.is_ejected(&addr, t0), "failures were not consecutive");
+    }
+
+    #[test]
+    fn test_readmitted_upstream_failing_again_is_ejected_longer() {
+        let od = detector(3, 10, 35);
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            od.report_at(addr, false, t0);
+        }
+        // Failures of requests sent before the ejection are ignored
+        od.report_at(addr, false, t0 + secs(5));
+        assert!(!od.is_ejected(&addr, t0 + secs(10)));
+
+        // One failure after re-admission ejects again, for twice as long
+        let t1 = t0 + secs(10);
+        od.report_at(addr, false, t1);
+        assert!(od.is_ejected(&addr, t1 + secs(19)));
+        assert!(!od.is_ejected(&addr, t1 + secs(20)));
+
+        // Capped by max_ejection_secs
+        let t2 = t1 + secs(20);
+        od.report_at(addr, false, t2);
+        assert!(od.is_ejected(&addr, t2 + secs(34)));
+        assert!(!od.is_ejected(&addr, t2 + secs(35)));
+
+        // A success ends the probation: the next ejection needs max_fails again
+        let t3 = t2 + secs(35);
+        od.report_at(addr, true, t3);
+        od.report_at(addr, false, t3);
+        assert!(!od.is_ejected(&addr, t3));
+    }
+
+    #[test]
+    fn test_success_on_clean_upstream_records_nothing() {
+        let od = detector(2, 10, 300);
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        od.report(addr, true);
+        assert!(od.backends.is_empty());
+    }
+
+    #[test]
+    fn test_carry_over_keeps_ejections() {
+        let old = detector(2, 10, 300);
+        let ejected: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let failing: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        let t0 = Instant::now();
+        old.report_at(ejected, false, t0);
+        old.report_at(ejected, false, t0);
+        old.report_at(failing, false, t0);
+
+        let new = detector(2, 10, 300);
+        new.carry_over(&old);
+        assert!(new.is_ejected(&ejected, t0 + secs(5)));
+        new.report_at(failing, false, t0);
+        assert!(new.is_ejected(&failing, t0), "the failure before the reload still counts");
+    }
+
+    #[test]
+    fn test_select_admitted_skips_ejected() {
+        let ups = vec![upstream("10.0.0.1", 8080, 1), upstream("10.0.0.2", 8080, 1)];
+        let sel = create_upstream_selector(&ups, "round_robin", None).unwrap();
+        let od = detector(1, 10, 300);
+        od.report("10.0.0.1:8080".parse().unwrap(), false);
+        for _ in 0..4 {
+            let b = sel.select_admitted(b"", Some(&od)).unwrap();
+            assert_eq!(b.addr.as_inet().unwrap().to_string(), "10.0.0.2:8080");
+        }
+        // With every backend ejected, ejection is ignored
+        od.report("10.0.0.2:8080".parse().unwrap(), false);
+        assert!(sel.select_admitted(b"", Some(&od)).is_some());
+        assert!(sel.select_admitted(b"", None).is_some());
+    }
+
     // ─── create_upstream_selector: valid inputs ─────────────
 
     #[test]
     fn test_round_robin_single_upstream() {
         let ups = vec![upstream("127.0.0.1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         assert!(sel.is_some());
     }
 
@@ -136,7 +1014,7 @@ mod tests {
             upstream("10.0.0.2", 8080, 1),
             upstream("10.0.0.3", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "round_robin").unwrap();
+        let sel = create_upstream_selector(&ups, "round_robin", None).unwrap();
         // Should be able to select a backend
         let backend = sel.select(b"test-key");
         assert!(backend.is_some());
@@ -148,7 +1026,7 @@ mod tests {
             upstream("10.0.0.1", 8080, 1),
             upstream("10.0.0.2", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "ip_hash");
+        let sel = create_upstream_selector(&ups, "ip_hash", None);
         assert!(sel.is_some());
     }
 
@@ -158,7 +1036,7 @@ mod tests {
             upstream("10.0.0.1", 8080, 1),
             upstream("10.0.0.2", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "random");
+        let sel = create_upstream_selector(&ups, "random", None);
         assert!(sel.is_some());
     }
 
@@ -168,21 +1046,21 @@ mod tests {
             upstream("10.0.0.1", 8080, 5),
             upstream("10.0.0.2", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "weighted");
+        let sel = create_upstream_selector(&ups, "weighted", None);
         assert!(sel.is_some());
     }
 
     #[test]
     fn test_least_connections_falls_back_to_round_robin() {
         let ups = vec![upstream("10.0.0.1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "least_connections");
+        let sel = create_upstream_selector(&ups, "least_connections", None);
         assert!(sel.is_some());
     }
 
     #[test]
     fn test_unknown_method_falls_back_to_round_robin() {
         let ups = vec![upstream("10.0.0.1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "totally_unknown_method");
+        let sel = create_upstream_selector(&ups, "totally_unknown_method", None);
         assert!(sel.is_some());
     }
 
@@ -190,7 +1068,7 @@ mod tests {
 
     #[test]
     fn test_empty_upstreams_returns_none() {
-        let sel = create_upstream_selector(&[], "round_robin");
+        let sel = create_upstream_selector(&[], "round_robin", None);
         assert!(sel.is_none());
     }
 
@@ -198,7 +1076,7 @@ mod tests {
     fn test_empty_method_string() {
         let ups = vec![upstream("10.0.0.1", 8080, 1)];
         // Empty string falls through to default (round_robin)
-        let sel = create_upstream_selector(&ups, "");
+        let sel = create_upstream_selector(&ups, "", None);
         assert!(sel.is_some());
     }
 
@@ -206,7 +1084,7 @@ mod tests {
     fn test_garbage_server_address() {
         // "not-a-valid-address" is not a valid IP — Backend::new_with_weight may fail
         let ups = vec![upstream("not-a-valid-ip-address!!!", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         // If backend creation fails, the selector should be None (empty backend set)
         // or Some if Pingora accepts the hostname
         // Either way, it shouldn't panic
@@ -217,19 +1095,19 @@ mod tests {
     fn test_injection_in_server_name() {
         let ups = vec![upstream("127.0.0.1; rm -rf /", 8080, 1)];
         // Should not panic
-        let _ = create_upstream_selector(&ups, "round_robin");
+        let _ = create_upstream_selector(&ups, "round_robin", None);
     }
 
     #[test]
     fn test_server_with_null_bytes() {
         let ups = vec![upstream("127.0.0\x001", 8080, 1)];
-        let _ = create_upstream_selector(&ups, "round_robin");
+        let _ = create_upstream_selector(&ups, "round_robin", None);
     }
 
     #[test]
     fn test_zero_weight_upstream() {
         let ups = vec![upstream("127.0.0.1", 8080, 0)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         // Zero weight might be accepted or might result in no selection
         let _ = sel;
     }
@@ -237,21 +1115,21 @@ mod tests {
     #[test]
     fn test_port_zero() {
         let ups = vec![upstream("127.0.0.1", 0, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         assert!(sel.is_some());
     }
 
     #[test]
     fn test_port_max() {
         let ups = vec![upstream("127.0.0.1", 65535, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         assert!(sel.is_some());
     }
 
     #[test]
     fn test_ipv6_server() {
         let ups = vec![upstream("::1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         // Pingora may or may not handle bare IPv6 — should not panic
         let _ = sel;
     }
@@ -262,7 +1140,7 @@ mod tests {
         let ups = vec![
             upstream("", 0, 0),
         ];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         // If the backend address is invalid, backend_set is empty → None
         // Or if Pingira treats "" as valid somehow → Some
         // Key: no panic
@@ -274,7 +1152,7 @@ mod tests {
     #[test]
     fn test_select_with_empty_key() {
         let ups = vec![upstream("127.0.0.1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "round_robin").unwrap();
+        let sel = create_upstream_selector(&ups, "round_robin", None).unwrap();
         let backend = sel.select(b"");
         assert!(backend.is_some());
     }
@@ -282,7 +1160,7 @@ mod tests {
     #[test]
     fn test_select_with_large_key() {
         let ups = vec![upstream("127.0.0.1", 8080, 1)];
-        let sel = create_upstream_selector(&ups, "ip_hash").unwrap();
+        let sel = create_upstream_selector(&ups, "ip_hash", None).unwrap();
         let large_key = vec![0xffu8; 10_000];
         let backend = sel.select(&large_key);
         assert!(backend.is_some());
@@ -294,7 +1172,7 @@ mod tests {
             upstream("10.0.0.1", 8080, 1),
             upstream("10.0.0.2", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "ip_hash").unwrap();
+        let sel = create_upstream_selector(&ups, "ip_hash", None).unwrap();
         let b1 = sel.select(b"192.168.1.1").unwrap();
         let b2 = sel.select(b"192.168.1.1").unwrap();
         assert_eq!(b1.addr, b2.addr);
@@ -307,14 +1185,14 @@ mod tests {
             upstream("10.0.0.1", 8080, 1),
             upstream("10.0.0.1", 8080, 1),
         ];
-        let sel = create_upstream_selector(&ups, "round_robin");
+        let sel = create_upstream_selector(&ups, "round_robin", None);
         assert!(sel.is_some());
     }
 
     #[test]
     fn test_very_large_weight() {
         let ups = vec![upstream("10.0.0.1", 8080, usize::MAX)];
-        let sel = create_upstream_selector(&ups, "weighted");
+        let sel = create_upstream_selector(&ups, "weighted", None);
         // Should not panic
         let _ = sel;
     }